@@ -23,7 +23,7 @@ fn main() -> Result<()> {
     // The index is now ready for querying
     let query = app.query();
     log::info!("synced up to {:?}", query.get_tip()?);
-    log::info!("utxos: {:?}", query.list_unspent(None, 0, None)?);
+    log::info!("utxos: {:?}", query.list_unspent(None, 0, None, false)?);
     log::info!("electrum running on {}", app.electrum_addr().unwrap());
 
     // Start syncing new blocks/transactions in the background
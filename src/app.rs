@@ -5,6 +5,8 @@ use std::{cell::Cell, net, thread};
 use bitcoincore_rpc::{self as rpc, Client as RpcClient, RpcApi};
 
 use crate::error::{BwtError, Result};
+use crate::indexer::IndexerConfig;
+use crate::store::{MemoryStore, Store};
 use crate::util::bitcoincore_ext::create_rpc_client;
 use crate::util::progress::Progress;
 use crate::util::{banner, fd_readiness_notification, on_oneshot_done, throttle_sender, RpcApiExt};
@@ -22,10 +24,10 @@ use crate::webhooks::WebHookNotifier;
 const THROTTLE_SEC: u64 = 2;
 const LT: &str = "bwt";
 
-pub struct App {
+pub struct App<S: Store = MemoryStore> {
     config: Config,
-    indexer: Arc<RwLock<Indexer>>,
-    query: Arc<Query>,
+    indexer: Arc<RwLock<Indexer<S>>>,
+    query: Arc<Query<S>>,
     access_token: Option<String>,
     sync_chan: (mpsc::Sender<()>, mpsc::Receiver<()>),
     next_prune: Cell<Option<Instant>>,
@@ -44,11 +46,42 @@ impl App {
     /// To abort during initialization, disconnect the progress_tx channel.
     /// To shutdown after initialization was completed, drop the App.
     pub fn boot(config: Config, progress_tx: Option<mpsc::Sender<Progress>>) -> Result<Self> {
+        Self::boot_with(config, progress_tx, Indexer::new)
+    }
+
+    pub fn test_rpc(config: &Config) -> Result<()> {
+        let rpc = RpcClient::new(&config.bitcoind_url(), config.bitcoind_auth()?)?;
+        rpc.get_wallet_info()?;
+        Ok(())
+    }
+}
+
+impl<S: Store> App<S> {
+    /// Like `boot()`, but with the index kept in a custom `Store` implementation
+    pub fn boot_with_store(
+        config: Config,
+        store: S,
+        progress_tx: Option<mpsc::Sender<Progress>>,
+    ) -> Result<Self> {
+        Self::boot_with(config, progress_tx, |config, rpc, watcher| {
+            Indexer::with_store(config, rpc, watcher, store)
+        })
+    }
+
+    fn boot_with(
+        config: Config,
+        progress_tx: Option<mpsc::Sender<Progress>>,
+        make_indexer: impl FnOnce(IndexerConfig, Arc<RpcClient>, WalletWatcher) -> Result<Indexer<S>>,
+    ) -> Result<Self> {
         debug!(target: LT, "{}", scrub_config(&config));
 
         let watcher = WalletWatcher::from_config(&config)?;
         let rpc = Arc::new(create_rpc_client(&config)?);
-        let indexer = Arc::new(RwLock::new(Indexer::new(rpc.clone(), watcher)?));
+        let indexer = Arc::new(RwLock::new(make_indexer(
+            (&config).into(),
+            rpc.clone(),
+            watcher,
+        )?));
         let query = Arc::new(Query::new((&config).into(), rpc.clone(), indexer.clone()));
 
         // wait for bitcoind to load up and initialize the wallet
@@ -83,14 +116,17 @@ impl App {
         let throttled_sync_tx = throttle_sender(sync_tx.clone(), THROTTLE_SEC);
 
         #[cfg(feature = "electrum")]
-        let electrum = config.electrum_addr().map(|addr| {
-            ElectrumServer::start(
-                addr,
-                iif!(config.electrum_socks_auth, access_token.clone(), None),
-                config.electrum_skip_merkle,
-                query.clone(),
-            )
-        });
+        let electrum = Some(config.electrum_listen()?)
+            .filter(|listen| !listen.is_empty())
+            .map(|listen| {
+                ElectrumServer::start(
+                    listen,
+                    iif!(config.electrum_socks_auth, access_token.clone(), None),
+                    config.electrum_skip_merkle,
+                    config.electrum_peers.clone(),
+                    query.clone(),
+                )
+            });
 
         #[cfg(feature = "http")]
         let http = config.http_addr().map(|addr| {
@@ -98,6 +134,12 @@ impl App {
                 addr,
                 access_token.clone(),
                 config.http_cors.clone(),
+                iif!(
+                    config.http_compression,
+                    Some(config.http_compression_min_size),
+                    None
+                ),
+                config.http_batch_interval.map(Duration::from_millis),
                 query.clone(),
                 throttled_sync_tx.clone(),
             )
@@ -111,7 +153,10 @@ impl App {
         }
 
         #[cfg(feature = "webhooks")]
-        let webhook = config.webhook_urls.clone().map(WebHookNotifier::start);
+        let webhook = config
+            .webhook_urls
+            .clone()
+            .map(|urls| WebHookNotifier::start(urls, config.amount_format));
 
         let next_prune = config.prune_until.map(|_| Instant::now());
 
@@ -155,6 +200,10 @@ impl App {
                 .map(|webhook| webhook.send_updates(&updates));
         }
 
+        // Send out the batched http updates at the end of every sync pass
+        #[cfg(feature = "http")]
+        self.http.as_ref().map(|http| http.flush_updates());
+
         // Try pruning the chain (when 'prune-until' is set)
         self.try_prune()?;
 
@@ -206,7 +255,7 @@ impl App {
     }
 
     /// Get the `Query` instance
-    pub fn query(&self) -> Arc<Query> {
+    pub fn query(&self) -> Arc<Query<S>> {
         self.query.clone()
     }
 
@@ -217,7 +266,7 @@ impl App {
 
     #[cfg(feature = "electrum")]
     pub fn electrum_addr(&self) -> Option<net::SocketAddr> {
-        Some(self.electrum.as_ref()?.addr())
+        self.electrum.as_ref()?.addr()
     }
 
     #[cfg(feature = "http")]
@@ -296,12 +345,6 @@ impl App {
     fn default_shutdown_signal(&self) -> Option<mpsc::Receiver<()>> {
         None
     }
-
-    pub fn test_rpc(config: &Config) -> Result<()> {
-        let rpc = RpcClient::new(&config.bitcoind_url(), config.bitcoind_auth()?)?;
-        rpc.get_wallet_info()?;
-        Ok(())
-    }
 }
 
 // Check if the wallet is loaded, try loading it if not, and create the wallet
@@ -355,7 +398,7 @@ fn init_bitcoind(
     if let Some(bitcoind_wallet) = &config.bitcoind_wallet {
         load_wallet(&rpc, bitcoind_wallet, config.create_wallet_if_missing)?;
     }
-    let walletinfo = wait_wallet_scan(&rpc, progress_tx, None, interval)?;
+    let walletinfo = wait_wallet_scan(&*rpc, progress_tx, None, interval)?;
 
     // Check that bitcoind is properly configured for use with the prune-until option
     if config.prune_until.is_some() && !(bcinfo.pruned && bcinfo.automatic_pruning == Some(false)) {
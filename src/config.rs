@@ -3,9 +3,15 @@ use std::{fs, io, net, path, time};
 use bitcoin::{Address, Network};
 use bitcoincore_rpc::Auth as RpcAuth;
 
+#[cfg(feature = "electrum-tls")]
+use crate::electrum::TlsIdentity;
+#[cfg(feature = "electrum")]
+use crate::electrum::{ElectrumPeer, Listen};
 use crate::error::{Context, OptionExt, Result};
+use crate::indexer::IndexerConfig;
 use crate::query::QueryConfig;
 use crate::types::RescanSince;
+use crate::util::amount::AmountFormat;
 use crate::util::auth::AuthMethod;
 use crate::util::descriptor::ExtendedDescriptor;
 use crate::util::xpub::XyzPubKey;
@@ -113,6 +119,13 @@ pub struct Config {
     #[serde(default, deserialize_with = "parse_duration_serde_opt")]
     pub bitcoind_timeout: Option<time::Duration>,
 
+    /// Limit the number of concurrent RPC calls in flight to bitcoind, queueing the rest (unlimited by default)
+    #[cfg_attr(
+        feature = "cli",
+        structopt(long, env, hide_env_values(true), display_order(37))
+    )]
+    pub bitcoind_max_concurrent: Option<usize>,
+
     /// Create the specified bitcoind wallet if it's missing [env: CREATE_WALLET_IF_MISSING]
     #[cfg_attr(feature = "cli", structopt(long, short = "W", display_order(1002)))]
     #[serde(default)]
@@ -149,6 +162,14 @@ pub struct Config {
     #[serde(default)]
     pub xpubs: Vec<XyzPubKey>,
 
+    /// File with descriptors/xpubs to track, as a JSON array or one per line (optionally followed by a custom gap limit)
+    #[cfg_attr(
+        feature = "cli",
+        structopt(long, env, hide_env_values(true), display_order(22))
+    )]
+    #[serde(default)]
+    pub descriptors_file: Option<path::PathBuf>,
+
     /// Add an address to track
     #[cfg_attr(
         feature = "cli",
@@ -173,6 +194,11 @@ pub struct Config {
     #[serde(default)]
     pub addresses_file: Option<path::PathBuf>,
 
+    /// Track all the addresses known to the bitcoind wallet, instead of importing descriptors/xpubs/addresses [env: WATCH_ALL]
+    #[cfg_attr(feature = "cli", structopt(long, display_order(1011)))]
+    #[serde(default)]
+    pub watch_all: bool,
+
     /// Start date for wallet history rescan. Accepts YYYY-MM-DD formatted strings, unix timestamps, or 'now' to watch for new transactions only. Defaults to rescanning from genesis.
     // (defaults to scanning from genesis for structopt/cli use, or to 'now' for direct library use)
     #[cfg_attr(
@@ -195,6 +221,15 @@ pub struct Config {
     #[serde(default)]
     pub force_rescan: bool,
 
+    /// Index wallet history by scanning blocks from the given height onwards, instead of importing
+    /// addresses into the bitcoind wallet
+    #[cfg_attr(
+        feature = "cli",
+        structopt(long, env, hide_env_values(true), display_order(1004))
+    )]
+    #[serde(default)]
+    pub block_scan_from: Option<u32>,
+
     /// Gap limit for importing child addresses
     #[cfg_attr(
         feature = "cli",
@@ -225,6 +260,35 @@ pub struct Config {
     #[serde(default = "default_initial_import_size")]
     pub initial_import_size: u32,
 
+    /// The maximum number of addresses to import in a single bitcoind RPC call
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            default_value = "1000",
+            env,
+            hide_env_values(true),
+            display_order(53)
+        )
+    )]
+    #[serde(default = "default_import_batch_size")]
+    pub import_batch_size: usize,
+
+    /// The maximum number of transactions to fetch in a single batch RPC call when resolving the
+    /// wallet outputs spent by outgoing transactions that weren't indexed yet (0 to disable)
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            default_value = "100",
+            env,
+            hide_env_values(true),
+            display_order(54)
+        )
+    )]
+    #[serde(default = "default_prevout_batch_size")]
+    pub prevout_batch_size: usize,
+
     /// Don't wait for bitcoind to finish syncing up before starting bwt (useful with pruning for
     /// importing/scanning before blocks get pruned) [env: NO_WAIT_SYNC]
     #[cfg_attr(feature = "cli", structopt(
@@ -243,6 +307,116 @@ pub struct Config {
     #[serde(default)]
     pub prune_until: Option<u64>,
 
+    /// Drop the details of spent wallet outputs buried deeper than the given number of blocks to
+    /// reduce memory usage (transactions will be reported with partial funding/spending information)
+    #[cfg_attr(
+        feature = "cli",
+        structopt(long, env, hide_env_values(true), display_order(1010))
+    )]
+    #[serde(default)]
+    pub max_reorg_depth: Option<u32>,
+
+    /// Skip indexing wallet outputs with an amount below the given number of satoshis
+    #[cfg_attr(
+        feature = "cli",
+        structopt(long, env, hide_env_values(true), display_order(1012))
+    )]
+    #[serde(default)]
+    pub dust_threshold: Option<u64>,
+
+    /// Purge unconfirmed transactions that were evicted from the mempool after the given number of hours
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            default_value = "336",
+            env,
+            hide_env_values(true),
+            display_order(1013)
+        )
+    )]
+    #[serde(default = "default_mempool_expiry")]
+    pub mempool_expiry: u64,
+
+    /// Assume that transactions confirmed below the given block height are final, and don't
+    /// re-sync them when a reorg is detected (reorgs reaching below it are not handled correctly)
+    #[cfg_attr(
+        feature = "cli",
+        structopt(long, env, hide_env_values(true), display_order(1014))
+    )]
+    #[serde(default)]
+    pub assume_confirmed_below: Option<u32>,
+
+    /// The number of confirmations after which received transactions are considered final (settled)
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            default_value = "6",
+            env,
+            hide_env_values(true),
+            display_order(1015)
+        )
+    )]
+    #[serde(default = "default_finality_confirmations")]
+    pub finality_confirmations: u32,
+
+    /// The confirmation counts at which the buckets for grouping unspent outputs start (comma-separated)
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            default_value = "1,6",
+            env,
+            hide_env_values(true),
+            use_delimiter(true),
+            display_order(1016)
+        )
+    )]
+    #[serde(default = "default_utxo_conf_buckets")]
+    pub utxo_conf_buckets: Vec<u32>,
+
+    /// The format for amounts in API responses and notifications, as integer satoshis ('sat') or BTC decimal strings ('btc')
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            default_value = "sat",
+            env,
+            hide_env_values(true),
+            display_order(1017)
+        )
+    )]
+    #[serde(default)]
+    pub amount_format: AmountFormat,
+
+    /// Keep the results of syncs during which the chain tip moved, instead of retrying until the tip is stable. Only applies to bitcoind versions older than v0.21, where `listsinceblock` is not atomic. The index may briefly include transactions from blocks above its synced tip, until the next sync reconciles it [env: TOLERATE_STALE_TIP]
+    #[cfg_attr(feature = "cli", structopt(long, display_order(1018)))]
+    #[serde(default)]
+    pub tolerate_stale_tip: bool,
+
+    /// The number of blocks the index may lag behind bitcoind's tip before the health check reports it as unhealthy
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            default_value = "2",
+            env,
+            hide_env_values(true),
+            display_order(1019)
+        )
+    )]
+    #[serde(default = "default_health_max_lag")]
+    pub health_max_lag: u32,
+
+    /// Keep conflicted (double-spent or evicted) transactions around for the given number of hours after they get purged from the index, to let clients learn what happened to them
+    #[cfg_attr(
+        feature = "cli",
+        structopt(long, env, hide_env_values(true), display_order(1020))
+    )]
+    #[serde(default)]
+    pub conflicted_retention: Option<u64>,
+
     //
     // Auth settings
     //
@@ -280,6 +454,38 @@ pub struct Config {
     )]
     pub electrum_addr: Option<net::SocketAddr>,
 
+    /// Path to bind the electrum rpc server unix socket (disables the default tcp server unless --electrum-addr is explicitly set)
+    #[cfg(all(feature = "electrum", unix))]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(long, env, hide_env_values(true), display_order(44))
+    )]
+    pub electrum_socket: Option<path::PathBuf>,
+
+    /// Address to bind the electrum rpc server over TLS (requires --electrum-tls-cert and --electrum-tls-key)
+    #[cfg(feature = "electrum-tls")]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(long, env, hide_env_values(true), display_order(47))
+    )]
+    pub electrum_tls_addr: Option<net::SocketAddr>,
+
+    /// Path to the PEM-encoded TLS certificate chain for the electrum rpc server
+    #[cfg(feature = "electrum-tls")]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(long, env, hide_env_values(true), display_order(48))
+    )]
+    pub electrum_tls_cert: Option<path::PathBuf>,
+
+    /// Path to the PEM-encoded TLS private key for the electrum rpc server
+    #[cfg(feature = "electrum-tls")]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(long, env, hide_env_values(true), display_order(49))
+    )]
+    pub electrum_tls_key: Option<path::PathBuf>,
+
     /// Skip generating merkle proofs. Reduces resource usage, requires running Electrum with --skipmerklecheck. [env: ELECTRUM_SKIP_MERKLE]
     #[cfg(feature = "electrum")]
     #[cfg_attr(feature = "cli", structopt(long, short = "M", display_order(1006)))]
@@ -293,6 +499,22 @@ pub struct Config {
     #[serde(default)]
     pub electrum_socks_auth: bool,
 
+    /// Peer server to advertise to clients via server.peers.subscribe, as <host>[:t<tcp-port>][:s<ssl-port>][:<feature>...] (none by default)
+    #[cfg(feature = "electrum")]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long = "electrum-peer",
+            env,
+            hide_env_values(true),
+            use_delimiter(true),
+            value_delimiter(";"),
+            display_order(50)
+        )
+    )]
+    #[serde(default)]
+    pub electrum_peers: Vec<ElectrumPeer>,
+
     //
     // HTTP options
     //
@@ -312,6 +534,36 @@ pub struct Config {
     )]
     pub http_cors: Option<String>,
 
+    /// Compress http responses with gzip/deflate when accepted by the client [env: HTTP_COMPRESSION]
+    #[cfg(feature = "http")]
+    #[cfg_attr(feature = "cli", structopt(long, display_order(1021)))]
+    #[serde(default)]
+    pub http_compression: bool,
+
+    /// Minimum response body size to compress (in bytes)
+    #[cfg(feature = "http")]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            default_value = "1024",
+            env,
+            hide_env_values(true),
+            display_order(1022)
+        )
+    )]
+    #[serde(default = "default_http_compression_min_size")]
+    pub http_compression_min_size: usize,
+
+    /// Coalesce the SSE/WebSocket updates sent within this interval (in milliseconds) into a
+    /// single BatchUpdate event (disabled by default)
+    #[cfg(feature = "http")]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(long, env, hide_env_values(true), display_order(1023))
+    )]
+    pub http_batch_interval: Option<u64>,
+
     //
     // Miscellaneous options
     //
@@ -461,6 +713,12 @@ impl Config {
 
     #[cfg(feature = "electrum")]
     pub fn electrum_addr(&self) -> Option<net::SocketAddr> {
+        // Don't bind the default tcp address if a unix socket was configured
+        #[cfg(unix)]
+        if self.electrum_addr.is_none() && self.electrum_socket.is_some() {
+            return None;
+        }
+
         self.electrum_addr.clone().or_else(|| {
             // Use a default value when used as CLI, require explicitly setting it for library use
             #[cfg(feature = "cli")]
@@ -478,6 +736,32 @@ impl Config {
         })
     }
 
+    #[cfg(feature = "electrum")]
+    pub fn electrum_listen(&self) -> Result<Vec<Listen>> {
+        let listen = self.electrum_addr().map(Listen::Tcp).into_iter();
+        #[cfg(unix)]
+        let listen = listen.chain(self.electrum_socket.clone().map(Listen::Unix));
+        #[cfg(feature = "electrum-tls")]
+        let listen = listen.chain(self.electrum_tls()?);
+        Ok(listen.collect())
+    }
+
+    #[cfg(feature = "electrum-tls")]
+    fn electrum_tls(&self) -> Result<Option<Listen>> {
+        let addr = some_or_ret!(self.electrum_tls_addr, Ok(None));
+        let identity = TlsIdentity {
+            cert_path: self
+                .electrum_tls_cert
+                .clone()
+                .or_err("--electrum-tls-addr requires --electrum-tls-cert")?,
+            key_path: self
+                .electrum_tls_key
+                .clone()
+                .or_err("--electrum-tls-addr requires --electrum-tls-key")?,
+        };
+        Ok(Some(Listen::Tls(addr, identity)))
+    }
+
     #[cfg(feature = "http")]
     pub fn http_addr(&self) -> Option<net::SocketAddr> {
         self.http_addr.clone().or_else(|| {
@@ -503,6 +787,9 @@ impl Config {
         if bool_env("FORCE_RESCAN") {
             config.force_rescan = true;
         }
+        if bool_env("WATCH_ALL") {
+            config.watch_all = true;
+        }
         if bool_env("CREATE_WALLET_IF_MISSING") {
             config.create_wallet_if_missing = true;
         }
@@ -521,6 +808,13 @@ impl Config {
         if bool_env("LOG_TIMESTAMP") {
             config.timestamp = true;
         }
+        if bool_env("TOLERATE_STALE_TIP") {
+            config.tolerate_stale_tip = true;
+        }
+        #[cfg(feature = "http")]
+        if bool_env("HTTP_COMPRESSION") {
+            config.http_compression = true;
+        }
         #[cfg(feature = "electrum")]
         if bool_env("ELECTRUM_SKIP_MERKLE") {
             config.electrum_skip_merkle = true;
@@ -748,11 +1042,34 @@ fn bitcoind_default_dir() -> Option<path::PathBuf> {
     None
 }
 
+impl From<&Config> for IndexerConfig {
+    fn from(config: &Config) -> IndexerConfig {
+        IndexerConfig {
+            max_reorg_depth: config.max_reorg_depth,
+            dust_threshold: config.dust_threshold,
+            mempool_expiry: time::Duration::from_secs(config.mempool_expiry * 3600),
+            assume_confirmed_below: config.assume_confirmed_below,
+            block_scan_from: config.block_scan_from,
+            prevout_batch_size: config.prevout_batch_size,
+            finality_confirmations: config.finality_confirmations,
+            tolerate_stale_tip: config.tolerate_stale_tip,
+            conflicted_retention: config
+                .conflicted_retention
+                .map(|hours| time::Duration::from_secs(hours * 3600)),
+        }
+    }
+}
+
 impl From<&Config> for QueryConfig {
     fn from(config: &Config) -> QueryConfig {
         QueryConfig {
             network: config.network,
             broadcast_cmd: config.broadcast_cmd.clone(),
+            dust_threshold: config.dust_threshold,
+            finality_confirmations: config.finality_confirmations,
+            utxo_conf_buckets: config.utxo_conf_buckets.clone(),
+            health_max_lag: config.health_max_lag,
+            amount_format: config.amount_format,
         }
     }
 }
@@ -762,15 +1079,23 @@ impl From<&Config> for QueryConfig {
 // Create a Default implementation
 defaultable!(Config,
   @default(
-    verbose, timestamp, broadcast_cmd, startup_banner, prune_until,
-    descriptors, xpubs, addresses, addresses_file, force_rescan,
-    bitcoind_wallet, bitcoind_dir, bitcoind_url, bitcoind_auth, bitcoind_cookie, bitcoind_timeout, create_wallet_if_missing,
+    verbose, timestamp, amount_format, broadcast_cmd, startup_banner, prune_until, max_reorg_depth, dust_threshold, assume_confirmed_below,
+    descriptors, xpubs, descriptors_file, addresses, addresses_file, watch_all, force_rescan, block_scan_from,
+    tolerate_stale_tip, conflicted_retention,
+    bitcoind_wallet, bitcoind_dir, bitcoind_url, bitcoind_auth, bitcoind_cookie, bitcoind_timeout, bitcoind_max_concurrent, create_wallet_if_missing,
     auth_cookie, auth_token, auth_ephemeral, print_token,
     #[cfg(feature = "electrum")] electrum_addr,
+    #[cfg(all(feature = "electrum", unix))] electrum_socket,
+    #[cfg(feature = "electrum-tls")] electrum_tls_addr,
+    #[cfg(feature = "electrum-tls")] electrum_tls_cert,
+    #[cfg(feature = "electrum-tls")] electrum_tls_key,
     #[cfg(feature = "electrum")] electrum_skip_merkle,
     #[cfg(feature = "electrum")] electrum_socks_auth,
+    #[cfg(feature = "electrum")] electrum_peers,
     #[cfg(feature = "http")] http_addr,
     #[cfg(feature = "http")] http_cors,
+    #[cfg(feature = "http")] http_compression,
+    #[cfg(feature = "http")] http_batch_interval,
     #[cfg(feature = "webhooks")] webhook_urls,
     #[cfg(feature = "proxy")] bitcoind_proxy,
     #[cfg(unix)] unix_listener_path,
@@ -780,7 +1105,14 @@ defaultable!(Config,
     rescan_since=RescanSince::Now,
     gap_limit=20,
     initial_import_size=350,
+    import_batch_size=1000,
+    prevout_batch_size=100,
     poll_interval=time::Duration::from_secs(5),
+    mempool_expiry=336,
+    finality_confirmations=6,
+    health_max_lag=2,
+    #[cfg(feature = "http")] http_compression_min_size=1024,
+    utxo_conf_buckets=vec![1, 6],
     wait_sync=true,
     require_addresses=true,
     setup_logger=true,
@@ -801,9 +1133,31 @@ fn default_gap_limit() -> u32 {
 fn default_initial_import_size() -> u32 {
     350
 }
+fn default_import_batch_size() -> usize {
+    1000
+}
+fn default_prevout_batch_size() -> usize {
+    100
+}
+fn default_finality_confirmations() -> u32 {
+    6
+}
+fn default_health_max_lag() -> u32 {
+    2
+}
+#[cfg(feature = "http")]
+fn default_http_compression_min_size() -> usize {
+    1024
+}
+fn default_utxo_conf_buckets() -> Vec<u32> {
+    vec![1, 6]
+}
 fn default_poll_interval() -> time::Duration {
     time::Duration::from_secs(5)
 }
+fn default_mempool_expiry() -> u64 {
+    336
+}
 fn default_true() -> bool {
     true
 }
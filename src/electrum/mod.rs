@@ -1,13 +1,74 @@
+use std::str::FromStr;
+
 use bitcoin::Txid;
-use bitcoin_hashes::{sha256d, Hash, HashEngine};
+use bitcoin_hashes::{sha256d, Hash};
+use serde::de;
 
-use crate::error::{OptionExt, Result};
+use crate::error::{Context, Error, OptionExt, Result};
 use crate::query::Query;
-use crate::types::{MempoolEntry, ScriptHash, StatusHash, TxStatus};
-use crate::util::BoolThen;
+use crate::store::Store;
+use crate::types::TxStatus;
 
 mod server;
+mod transport;
 pub use server::ElectrumServer;
+pub use transport::Listen;
+#[cfg(feature = "electrum-tls")]
+pub use transport::TlsIdentity;
+
+/// A peer server advertised to clients via `server.peers.subscribe`, specified as
+/// `<host>[:t<tcp-port>][:s<ssl-port>][:<feature>...]` (e.g. `electrum.example.com:t50001:s50002`).
+/// Other features use the protocol's notation, like `v1.4` for the protocol version or `p10000`
+/// for the pruning limit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ElectrumPeer {
+    pub host: String,
+    pub tcp_port: Option<u16>,
+    pub ssl_port: Option<u16>,
+    pub features: Vec<String>,
+}
+
+impl FromStr for ElectrumPeer {
+    type Err = Error;
+
+    fn from_str(inp: &str) -> Result<Self> {
+        let mut parts = inp.split(':');
+        let host = parts.next().filter(|host| !host.is_empty());
+        let mut peer = ElectrumPeer {
+            host: host.or_err("Missing electrum peer host")?.to_string(),
+            tcp_port: None,
+            ssl_port: None,
+            features: vec![],
+        };
+        let parse_port = |port: &str| port.parse().context("Invalid electrum peer port");
+        for part in parts {
+            if let Some(port) = part.strip_prefix('t') {
+                peer.tcp_port = Some(parse_port(port)?);
+            } else if let Some(port) = part.strip_prefix('s') {
+                peer.ssl_port = Some(parse_port(port)?);
+            } else {
+                ensure!(part.len() > 1, "Invalid electrum peer feature: {}", part);
+                peer.features.push(part.to_string());
+            }
+        }
+        ensure!(
+            peer.tcp_port.is_some() || peer.ssl_port.is_some(),
+            "Electrum peer {} requires a tcp or ssl port",
+            peer.host
+        );
+        Ok(peer)
+    }
+}
+
+impl<'de> de::Deserialize<'de> for ElectrumPeer {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        FromStr::from_str(&s).map_err(de::Error::custom)
+    }
+}
 
 pub fn electrum_height(status: TxStatus, has_unconfirmed_parents: Option<bool>) -> i32 {
     match status {
@@ -24,8 +85,6 @@ pub fn electrum_height(status: TxStatus, has_unconfirmed_parents: Option<bool>)
 }
 
 trait QueryExt {
-    fn get_status_hash(&self, scripthash: &ScriptHash) -> Option<StatusHash>;
-
     fn electrum_merkle_proof(
         &self,
         txid: &Txid,
@@ -46,29 +105,7 @@ trait QueryExt {
     ) -> Result<(Txid, Vec<sha256d::Hash>)>;
 }
 
-impl QueryExt for Query {
-    fn get_status_hash(&self, scripthash: &ScriptHash) -> Option<StatusHash> {
-        let mut engine = StatusHash::engine();
-        let has_history = self.for_each_history(scripthash, |hist| {
-            let has_unconfirmed_parents = hist.status.is_unconfirmed().and_then(|| {
-                self.with_mempool_entry(&hist.txid, MempoolEntry::has_unconfirmed_parents)
-            });
-            let p = format!(
-                "{}:{}:",
-                hist.txid,
-                electrum_height(hist.status, has_unconfirmed_parents)
-            );
-            engine.input(&p.into_bytes());
-        });
-
-        if has_history {
-            Some(StatusHash::from_engine(engine))
-        } else {
-            // empty history needs to be represented as a `null` in json
-            None
-        }
-    }
-
+impl<S: Store> QueryExt for Query<S> {
     fn electrum_merkle_proof(
         &self,
         txid: &Txid,
@@ -164,3 +201,25 @@ fn create_merkle_branch_and_root(
     }
     (merkle, hashes[0])
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_electrum_peer() {
+        let peer: ElectrumPeer = "electrum.example.com:t50001:s50002:p10000".parse().unwrap();
+        assert_eq!(peer.host, "electrum.example.com");
+        assert_eq!(peer.tcp_port, Some(50001));
+        assert_eq!(peer.ssl_port, Some(50002));
+        assert_eq!(peer.features, vec!["p10000"]);
+
+        let peer: ElectrumPeer = "example.onion:s50002".parse().unwrap();
+        assert_eq!((peer.tcp_port, peer.ssl_port), (None, Some(50002)));
+
+        assert!("example.com".parse::<ElectrumPeer>().is_err());
+        assert!(":t50001".parse::<ElectrumPeer>().is_err());
+        assert!("example.com:tfoo".parse::<ElectrumPeer>().is_err());
+        assert!("example.com:t50001:x".parse::<ElectrumPeer>().is_err());
+    }
+}
@@ -1,20 +1,23 @@
 use std::cmp;
 use std::collections::{HashMap, HashSet};
 use std::io::{BufRead, BufReader, Write};
-use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::net::{Shutdown, SocketAddr};
 use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender, TrySendError};
 use std::sync::{Arc, Mutex};
-use std::{thread, time};
+use std::thread;
 
 use bitcoin::Txid;
 use bitcoin_hashes::hex::ToHex;
+use bitcoincore_rpc::json::EstimateMode;
 use serde_json::{from_str, from_value, Value};
 
-use crate::electrum::{electrum_height, QueryExt};
+use crate::electrum::transport::{Listen, Stream};
+use crate::electrum::{electrum_height, ElectrumPeer, QueryExt};
 use crate::error::{fmt_error_chain, BwtError, Context, Result};
-use crate::indexer::IndexChange;
+use crate::indexer::{IndexChange, Subscription};
 use crate::query::Query;
-use crate::types::{BlockId, MempoolEntry, ScriptHash, StatusHash};
+use crate::store::Store;
+use crate::types::{BlockId, MempoolEntry, ScriptHash, SortOrder, StatusHash};
 use crate::util::auth::electrum_socks5_auth;
 use crate::util::{banner, BoolThen};
 
@@ -27,24 +30,28 @@ const MAX_HEADERS: u32 = 2016;
 
 const LT: &str = "bwt::electrum"; // log target name
 
-struct Connection {
-    query: Arc<Query>,
+struct Connection<S: Store> {
+    query: Arc<Query<S>>,
     skip_merkle: bool,
-    stream: TcpStream,
-    addr: SocketAddr,
+    stream: Stream,
+    addr: String,
+    listening: Arc<Vec<Listen>>,
+    peers: Arc<Vec<ElectrumPeer>>,
     chan: SyncChannel<Message>,
-    subman: Arc<Mutex<SubscriptionManager>>,
+    subman: Arc<Mutex<SubscriptionManager<S>>>,
     subscriber_id: usize,
 }
 
-impl Connection {
+impl<S: Store> Connection<S> {
     pub fn new(
-        query: Arc<Query>,
+        query: Arc<Query<S>>,
         skip_merkle: bool,
-        stream: TcpStream,
-        addr: SocketAddr,
-        subman: Arc<Mutex<SubscriptionManager>>,
-    ) -> Connection {
+        stream: Stream,
+        addr: String,
+        listening: Arc<Vec<Listen>>,
+        peers: Arc<Vec<ElectrumPeer>>,
+        subman: Arc<Mutex<SubscriptionManager<S>>>,
+    ) -> Self {
         let chan = SyncChannel::new(10);
         let subscriber_id = subman.lock().unwrap().register(chan.sender());
         Connection {
@@ -54,6 +61,8 @@ impl Connection {
             subscriber_id,
             stream,
             addr,
+            listening,
+            peers,
             chan,
         }
     }
@@ -82,8 +91,52 @@ impl Connection {
         Ok(json!("bc1qmuagsjvq0lh3admnafk0qnlql0vvxv08au9l2d"))
     }
 
+    fn server_features(&self) -> Result<Value> {
+        // advertise the ports of the tcp/tls listeners, grouped by the bound ip
+        let mut hosts = serde_json::Map::new();
+        for listen in self.listening.iter() {
+            let (addr, port_key) = match listen {
+                Listen::Tcp(addr) => (addr, "tcp_port"),
+                #[cfg(feature = "electrum-tls")]
+                Listen::Tls(addr, _) => (addr, "ssl_port"),
+                #[cfg(unix)]
+                Listen::Unix(_) => continue,
+            };
+            let host = hosts
+                .entry(addr.ip().to_string())
+                .or_insert_with(|| json!({ "tcp_port": null, "ssl_port": null }));
+            host[port_key] = json!(addr.port());
+        }
+
+        Ok(json!({
+            "genesis_hash": self.query.get_block_hash(0)?,
+            "hosts": hosts,
+            "protocol_max": PROTOCOL_VERSION,
+            "protocol_min": PROTOCOL_VERSION,
+            "pruning": null,
+            "server_version": format!("bwt v{}", BWT_VERSION),
+            "hash_function": "sha256",
+        }))
+    }
+
     fn server_peers_subscribe(&self) -> Result<Value> {
-        Ok(json!([]))
+        // advertise the configured peers as [ip, hostname, features] tuples. the hostname is used in
+        // place of the ip address, which isn't known for peers configured by their hostname.
+        let peers: Vec<Value> = self
+            .peers
+            .iter()
+            .map(|peer| {
+                let mut features = vec![];
+                if !peer.features.iter().any(|feature| feature.starts_with('v')) {
+                    features.push(format!("v{}", PROTOCOL_VERSION));
+                }
+                features.extend(peer.tcp_port.map(|port| format!("t{}", port)));
+                features.extend(peer.ssl_port.map(|port| format!("s{}", port)));
+                features.extend(peer.features.iter().cloned());
+                json!([peer.host, peer.host, features])
+            })
+            .collect();
+        Ok(json!(peers))
     }
 
     fn mempool_get_fee_histogram(&self) -> Result<Value> {
@@ -153,7 +206,7 @@ impl Connection {
 
     fn blockchain_estimatefee(&self, params: Value) -> Result<Value> {
         let (target,): (u16,) = from_value(params)?;
-        let fee_rate = self.query.estimate_fee(target)?;
+        let fee_rate = self.query.estimate_fee(target, EstimateMode::Unset)?;
 
         // format for electrum: from sat/b to BTC/kB, -1 to indicate no estimate is available
         Ok(json!(fee_rate.map_or(-1.0, |rate| rate / 100_000f64)))
@@ -180,43 +233,42 @@ impl Connection {
     fn blockchain_scripthash_get_balance(&self, params: Value) -> Result<Value> {
         let (script_hash,): (ScriptHash,) = from_value(params)?;
 
-        let (confirmed_balance, mempool_balance) = self.query.get_script_balance(&script_hash)?;
-
-        Ok(json!({
-            "confirmed": confirmed_balance,
-            "unconfirmed": mempool_balance,
-        }))
+        let balance = self.query.get_electrum_balance(&script_hash)?;
+        Ok(json!(balance))
     }
 
     fn blockchain_scripthash_get_history(&self, params: Value) -> Result<Value> {
         let (script_hash,): (ScriptHash,) = from_value(params)?;
 
-        let txs: Vec<Value> = self.query.map_history(&script_hash, |txhist| {
-            // unlike other electrum server implementations that return the direct fee paid by the tx itself, we
-            // return the "effective fee rate", which takes unconfirmed ancestor transactions into account.
-            let (effective_fee, has_unconfirmed_parents) = txhist
-                .status
-                .is_unconfirmed()
-                .and_then(|| {
-                    self.query
-                        .with_mempool_entry(&txhist.txid, |mempool_entry| {
-                            // report the fee as the effective feerate multiplied by the size, to get electrum to
-                            // display the effective feerate when it divides this back by the size.
-                            let effective_fee = (mempool_entry.effective_feerate()
-                                * mempool_entry.vsize as f64)
-                                as u64;
-                            let has_unconfirmed_parents = mempool_entry.has_unconfirmed_parents();
-                            (Some(effective_fee), Some(has_unconfirmed_parents))
+        let txs: Vec<Value> =
+            self.query
+                .map_history(&script_hash, SortOrder::OldestFirst, |txhist| {
+                    // unlike other electrum server implementations that return the direct fee paid by the tx itself, we
+                    // return the "effective fee rate", which takes unconfirmed ancestor transactions into account.
+                    let (effective_fee, has_unconfirmed_parents) = txhist
+                        .status
+                        .is_unconfirmed()
+                        .and_then(|| {
+                            self.query
+                                .with_mempool_entry(&txhist.txid, |mempool_entry| {
+                                    // report the fee as the effective feerate multiplied by the size, to get electrum to
+                                    // display the effective feerate when it divides this back by the size.
+                                    let effective_fee = (mempool_entry.effective_feerate()
+                                        * mempool_entry.vsize as f64)
+                                        as u64;
+                                    let has_unconfirmed_parents =
+                                        mempool_entry.has_unconfirmed_parents();
+                                    (Some(effective_fee), Some(has_unconfirmed_parents))
+                                })
                         })
-                })
-                .unwrap_or((None, None));
+                        .unwrap_or((None, None));
 
-            json!({
-                "height": electrum_height(txhist.status, has_unconfirmed_parents),
-                "tx_hash": txhist.txid,
-                "fee": effective_fee,
-            })
-        });
+                    json!({
+                        "height": electrum_height(txhist.status, has_unconfirmed_parents),
+                        "tx_hash": txhist.txid,
+                        "fee": effective_fee,
+                    })
+                });
         Ok(json!(txs))
     }
 
@@ -225,7 +277,7 @@ impl Connection {
 
         let utxos: Vec<Value> = self
             .query
-            .list_unspent(Some(&script_hash), 0, None)?
+            .list_unspent(Some(&script_hash), 0, None, false)?
             .into_iter()
             .map(|utxo| {
                 let has_unconfirmed_parents = utxo.status.is_unconfirmed().and_then(|| {
@@ -339,6 +391,7 @@ impl Connection {
             "mempool.get_fee_histogram" => self.mempool_get_fee_histogram(),
             "server.banner" => self.server_banner(),
             "server.donation_address" => self.server_donation_address(),
+            "server.features" => self.server_features(),
             "server.peers.subscribe" => self.server_peers_subscribe(),
             "server.ping" => Ok(Value::Null),
             "server.version" => self.server_version(),
@@ -411,7 +464,7 @@ impl Connection {
     }
 
     fn handle_requests(
-        mut stream: TcpStream,
+        mut stream: Stream,
         access_token: Option<&str>,
         tx: SyncSender<Message>,
     ) -> Result<()> {
@@ -453,9 +506,9 @@ impl Connection {
 
     pub fn run(mut self, access_token: Arc<Option<String>>) {
         let tx = self.chan.sender();
-        let stream = self.stream.try_clone().expect("failed to clone TcpStream");
+        let stream = self.stream.try_clone().expect("failed to clone stream");
         let child = spawn_thread("reader", move || {
-            Connection::handle_requests(stream, access_token.as_deref(), tx)
+            Self::handle_requests(stream, access_token.as_deref(), tx)
         });
         if let Err(e) = self.handle_replies() {
             warn!(target: LT, "[{}] handling failed: {}", self.addr, e,)
@@ -469,7 +522,7 @@ impl Connection {
     }
 }
 
-impl Drop for Connection {
+impl<S: Store> Drop for Connection<S> {
     fn drop(&mut self) {
         self.subman.lock().unwrap().remove(self.subscriber_id);
     }
@@ -499,15 +552,15 @@ pub enum Notification {
 
 pub struct ElectrumServer {
     notification: Sender<Notification>,
-    addr: SocketAddr,
+    listening: Arc<Vec<Listen>>,
     server: Option<thread::JoinHandle<()>>, // so we can join the server while dropping this ojbect
 }
 
 impl ElectrumServer {
-    fn start_notifier(
+    fn start_notifier<S: Store>(
         notification: Channel<Notification>,
-        subman: Arc<Mutex<SubscriptionManager>>,
-        acceptor: Sender<Option<(TcpStream, SocketAddr)>>,
+        subman: Arc<Mutex<SubscriptionManager<S>>>,
+        acceptor: Sender<Option<(Stream, String)>>,
     ) {
         spawn_thread("notification", move || {
             for msg in notification.receiver().iter() {
@@ -523,43 +576,48 @@ impl ElectrumServer {
         });
     }
 
-    fn start_acceptor(addr: SocketAddr) -> (SocketAddr, Channel<Option<(TcpStream, SocketAddr)>>) {
-        let listener = TcpListener::bind(addr)
-            .with_context(|| format!("bind({}) failed", addr))
-            .unwrap(); // TODO don't panic
-        let bound_addr = listener.local_addr().unwrap();
-        info!(
-            target: LT,
-            "Electrum RPC server running on {}:t (protocol {})", bound_addr, PROTOCOL_VERSION
-        );
-
+    // Start an acceptor thread for each of the listening transports, all feeding into the same channel
+    fn start_acceptors(listen: Vec<Listen>) -> (Vec<Listen>, Channel<Option<(Stream, String)>>) {
         let chan = Channel::unbounded();
-        let acceptor = chan.sender();
-        spawn_thread("acceptor", move || loop {
-            let (stream, addr) = listener.accept().expect("accept failed");
-            stream
-                .set_nonblocking(false)
-                .expect("failed to set connection as blocking");
-            if acceptor.send(Some((stream, addr))).is_err() {
-                trace!(target: LT, "acceptor shutting down");
-                break;
-            }
-        });
-        (bound_addr, chan)
+        let listening = listen
+            .into_iter()
+            .map(|listen| {
+                let listener = listen.bind().unwrap(); // TODO don't panic
+                let bound_addr = listener.local_addr();
+                info!(
+                    target: LT,
+                    "Electrum RPC server running on {} (protocol {})", bound_addr, PROTOCOL_VERSION
+                );
+
+                let acceptor = chan.sender();
+                spawn_thread("acceptor", move || loop {
+                    let (stream, addr) = listener.accept().expect("accept failed");
+                    if acceptor.send(Some((stream, addr))).is_err() {
+                        trace!(target: LT, "acceptor shutting down");
+                        break;
+                    }
+                });
+                bound_addr
+            })
+            .collect();
+        (listening, chan)
     }
 
-    pub fn start(
-        addr: SocketAddr,
+    pub fn start<S: Store>(
+        listen: Vec<Listen>,
         access_token: Option<String>,
         skip_merkle: bool,
-        query: Arc<Query>,
+        peers: Vec<ElectrumPeer>,
+        query: Arc<Query<S>>,
     ) -> Self {
         let notification = Channel::unbounded();
-        let (bound_addr, acceptor) = Self::start_acceptor(addr);
+        let (listening, acceptor) = Self::start_acceptors(listen);
+        let listening = Arc::new(listening);
+        let peers = Arc::new(peers);
         let access_token = Arc::new(access_token);
         Self {
             notification: notification.sender(),
-            addr: bound_addr,
+            listening: listening.clone(),
             server: Some(spawn_thread("rpc", move || {
                 let subman = Arc::new(Mutex::new(SubscriptionManager {
                     next_id: 0,
@@ -573,12 +631,22 @@ impl ElectrumServer {
                 while let Some((stream, addr)) = acceptor.receiver().recv().unwrap() {
                     let query = query.clone();
                     let subman = subman.clone();
+                    let listening = listening.clone();
+                    let peers = peers.clone();
                     let threads_ = threads.clone();
                     let access_token = access_token.clone();
 
                     let thandle = spawn_thread("peer", move || {
                         info!(target: LT, "[{}] connected peer", addr);
-                        let conn = Connection::new(query, skip_merkle, stream, addr, subman);
+                        let conn = Connection::new(
+                            query,
+                            skip_merkle,
+                            stream,
+                            addr.clone(),
+                            listening,
+                            peers,
+                            subman,
+                        );
                         conn.run(access_token);
                         info!(target: LT, "[{}] disconnected peer", addr);
                         threads_.lock().unwrap().remove(&thread::current().id());
@@ -636,8 +704,13 @@ impl ElectrumServer {
         }
     }
 
-    pub fn addr(&self) -> SocketAddr {
-        self.addr
+    /// Get the bound TCP address, if the server is listening on one
+    pub fn addr(&self) -> Option<SocketAddr> {
+        self.listening.iter().find_map(|listen| match listen {
+            Listen::Tcp(addr) => Some(*addr),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        })
     }
 }
 
@@ -649,28 +722,28 @@ impl Drop for ElectrumServer {
             handle.join().unwrap();
         }
         trace!(target: LT, "RPC server is stopped");
-        // Initiate one final connection to the Electrum server to make the acceptor thread notice the channel is closed and shut down.
+        // Initiate one final connection to the Electrum server to make the acceptor threads notice the channel is closed and shut down.
         // A hack, to be replaced with concurrent multi-channel signal processing (which cannot easily be done with the std lib)
-        TcpStream::connect_timeout(&self.addr, time::Duration::from_millis(200)).ok();
+        for listen in self.listening.iter() {
+            listen.wake();
+            listen.cleanup();
+        }
     }
 }
 
 // unite with the http server subscription implementation?
-struct SubscriptionManager {
+struct SubscriptionManager<S: Store> {
     next_id: usize,
     subscribers: HashMap<usize, Subscriber>,
-    query: Arc<Query>,
+    query: Arc<Query<S>>,
 }
 
 struct Subscriber {
     sender: SyncSender<Message>,
-    // wants new blocks
-    blocks: bool,
-    // wants updates for these scripthashes
-    scripthashes: HashSet<ScriptHash>,
+    subscription: Subscription,
 }
 
-impl SubscriptionManager {
+impl<S: Store> SubscriptionManager<S> {
     pub fn register(&mut self, sender: SyncSender<Message>) -> usize {
         let id = self.next_id;
         self.next_id += 1;
@@ -678,20 +751,19 @@ impl SubscriptionManager {
             id,
             Subscriber {
                 sender,
-                blocks: false,
-                scripthashes: HashSet::new(),
+                subscription: Subscription::default(),
             },
         );
         id
     }
     pub fn subscribe_blocks(&mut self, subscriber_id: usize) {
         if let Some(s) = self.subscribers.get_mut(&subscriber_id) {
-            s.blocks = true
+            s.subscription.subscribe_blocks()
         }
     }
     pub fn subscribe_scripthash(&mut self, subscriber_id: usize, scripthash: ScriptHash) {
         if let Some(s) = self.subscribers.get_mut(&subscriber_id) {
-            s.scripthashes.insert(scripthash);
+            s.subscription.subscribe_scripthash(scripthash);
         }
     }
     pub fn remove(&mut self, subscriber_id: usize) {
@@ -709,14 +781,13 @@ impl SubscriptionManager {
             self.subscribers.len()
         );
 
-        let mut scripthashes: HashMap<ScriptHash, Option<Option<StatusHash>>> =
-            HashMap::with_capacity(changelog.len());
+        let mut scripthashes: HashSet<ScriptHash> = HashSet::with_capacity(changelog.len());
         let mut tip_msgs: Vec<Message> = Vec::with_capacity(1); // typically only one, but account for the possibility of more
         for change in changelog {
             match change {
                 IndexChange::TxoFunded(_, scripthash, ..)
                 | IndexChange::TxoSpent(_, scripthash, ..) => {
-                    scripthashes.insert(scripthash, None);
+                    scripthashes.insert(scripthash);
                 }
                 IndexChange::ChainTip(BlockId(tip_height, tip_hash)) => {
                     let hex_header = self.query.get_header_hex(&tip_hash)?;
@@ -726,22 +797,28 @@ impl SubscriptionManager {
             }
         }
 
-        let query = self.query.clone();
+        // calculate the status hashes of subscribed scripthashes once, in a single batch
+        let subscribed = scripthashes
+            .into_iter()
+            .filter(|scripthash| {
+                self.subscribers
+                    .values()
+                    .any(|subscriber| subscriber.subscription.wants_scripthash(scripthash))
+            })
+            .collect::<Vec<_>>();
+        let status_hashes = self.query.get_status_hashes(&subscribed);
 
         self.subscribers.retain(|subscriber_id, subscriber| {
-            if subscriber.blocks {
+            if subscriber.subscription.wants_blocks() {
                 tip_msgs.clone().into_iter()
             } else {
                 vec![].into_iter()
             }
             .chain(
-                scripthashes
-                    .iter_mut()
-                    .filter(|(scripthash, _)| subscriber.scripthashes.contains(*scripthash))
+                status_hashes
+                    .iter()
+                    .filter(|(scripthash, _)| subscriber.subscription.wants_scripthash(scripthash))
                     .map(|(scripthash, status_hash)| {
-                        // calculate the status hash once per script hash and cache it
-                        let status_hash =
-                            status_hash.get_or_insert_with(|| query.get_status_hash(scripthash));
                         Message::HistoryChange(*scripthash, *status_hash)
                     }),
             )
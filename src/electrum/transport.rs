@@ -0,0 +1,431 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::{fmt, time};
+
+#[cfg(any(unix, feature = "electrum-tls"))]
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+#[cfg(unix)]
+use std::os::unix::{
+    fs::FileTypeExt,
+    net::{UnixListener, UnixStream},
+};
+
+#[cfg(feature = "electrum-tls")]
+use std::sync::{Arc, Mutex};
+
+use crate::error::{Context, Result};
+
+/// The transport the Electrum RPC server listens on
+#[derive(Clone, Debug)]
+pub enum Listen {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+    #[cfg(feature = "electrum-tls")]
+    Tls(SocketAddr, TlsIdentity),
+}
+
+/// Paths to the PEM-encoded certificate chain and private key used for TLS
+#[cfg(feature = "electrum-tls")]
+#[derive(Clone, Debug)]
+pub struct TlsIdentity {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Listen::Tcp(addr) => write!(f, "{}:t", addr),
+            #[cfg(unix)]
+            Listen::Unix(path) => write!(f, "unix:{}", path.display()),
+            #[cfg(feature = "electrum-tls")]
+            Listen::Tls(addr, _) => write!(f, "{}:s", addr),
+        }
+    }
+}
+
+impl Listen {
+    pub fn bind(&self) -> Result<Listener> {
+        Ok(match self {
+            Listen::Tcp(addr) => Listener::Tcp(
+                TcpListener::bind(addr).with_context(|| format!("bind({}) failed", addr))?,
+            ),
+            #[cfg(unix)]
+            Listen::Unix(path) => {
+                // cleanup socket file from previous run
+                remove_socket_file(path)?;
+                let listener = UnixListener::bind(path)
+                    .with_context(|| format!("bind({}) failed", path.display()))?;
+                Listener::Unix(listener, path.clone())
+            }
+            #[cfg(feature = "electrum-tls")]
+            Listen::Tls(addr, identity) => {
+                let tls_config = identity.load_config()?;
+                let listener =
+                    TcpListener::bind(addr).with_context(|| format!("bind({}) failed", addr))?;
+                Listener::Tls(listener, tls_config, identity.clone())
+            }
+        })
+    }
+
+    /// Initiate a connection to make the acceptor thread wake up from its blocking accept() call
+    pub fn wake(&self) {
+        match self {
+            #[cfg(feature = "electrum-tls")]
+            Listen::Tls(addr, _) => {
+                TcpStream::connect_timeout(addr, time::Duration::from_millis(200)).ok();
+            }
+            Listen::Tcp(addr) => {
+                TcpStream::connect_timeout(addr, time::Duration::from_millis(200)).ok();
+            }
+            #[cfg(unix)]
+            Listen::Unix(path) => {
+                UnixStream::connect(path).ok();
+            }
+        }
+    }
+
+    /// Remove the socket file (for unix sockets)
+    pub fn cleanup(&self) {
+        #[cfg(unix)]
+        if let Listen::Unix(path) = self {
+            remove_socket_file(path).ok();
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+    #[cfg(feature = "electrum-tls")]
+    Tls(TcpListener, Arc<rustls::ServerConfig>, TlsIdentity),
+}
+
+impl Listener {
+    /// Get the bound address (resolving the actual port when binding on port 0)
+    pub fn local_addr(&self) -> Listen {
+        match self {
+            Listener::Tcp(listener) => Listen::Tcp(listener.local_addr().unwrap()),
+            #[cfg(unix)]
+            Listener::Unix(_, path) => Listen::Unix(path.clone()),
+            #[cfg(feature = "electrum-tls")]
+            Listener::Tls(listener, _, identity) => {
+                Listen::Tls(listener.local_addr().unwrap(), identity.clone())
+            }
+        }
+    }
+
+    /// Accept a new connection, returning it along with a description of the peer
+    pub fn accept(&self) -> io::Result<(Stream, String)> {
+        Ok(match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept()?;
+                stream.set_nonblocking(false)?;
+                (Stream::Tcp(stream), addr.to_string())
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, path) => {
+                let (stream, _) = listener.accept()?;
+                (Stream::Unix(stream), format!("unix:{}", path.display()))
+            }
+            #[cfg(feature = "electrum-tls")]
+            Listener::Tls(listener, tls_config, _) => {
+                let (stream, addr) = listener.accept()?;
+                stream.set_nonblocking(false)?;
+                let stream = TlsStream::new(tls_config.clone(), stream)?;
+                (Stream::Tls(stream), format!("{} (tls)", addr))
+            }
+        })
+    }
+}
+
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    #[cfg(feature = "electrum-tls")]
+    Tls(TlsStream),
+}
+
+impl Stream {
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        Ok(match self {
+            Stream::Tcp(stream) => Stream::Tcp(stream.try_clone()?),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Stream::Unix(stream.try_clone()?),
+            #[cfg(feature = "electrum-tls")]
+            Stream::Tls(stream) => Stream::Tls(stream.try_clone()?),
+        })
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(how),
+            #[cfg(feature = "electrum-tls")]
+            Stream::Tls(stream) => stream.sock.shutdown(how),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+            #[cfg(feature = "electrum-tls")]
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+            #[cfg(feature = "electrum-tls")]
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+            #[cfg(feature = "electrum-tls")]
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+#[cfg(feature = "electrum-tls")]
+impl TlsIdentity {
+    fn load_config(&self) -> Result<Arc<rustls::ServerConfig>> {
+        use rustls_pemfile::Item;
+        let open = |path: &Path| -> Result<_> {
+            let file = fs::File::open(path)
+                .with_context(|| format!("failed opening {}", path.display()))?;
+            Ok(io::BufReader::new(file))
+        };
+
+        let certs = rustls_pemfile::certs(&mut open(&self.cert_path)?)?;
+        ensure!(
+            !certs.is_empty(),
+            "no certificates found in {:?}",
+            self.cert_path
+        );
+        let certs = certs.into_iter().map(rustls::Certificate).collect();
+
+        let key = rustls_pemfile::read_all(&mut open(&self.key_path)?)?
+            .into_iter()
+            .find_map(|item| match item {
+                Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(key),
+                _ => None,
+            })
+            .with_context(|| format!("no private key found in {:?}", self.key_path))?;
+
+        let config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, rustls::PrivateKey(key))
+            .context("invalid tls certificate or key")?;
+        Ok(Arc::new(config))
+    }
+}
+
+/// A TLS stream that can be cloned to be read from and written to by separate threads. The TLS
+/// session state is shared, but the socket is only read from without holding the lock.
+#[cfg(feature = "electrum-tls")]
+pub struct TlsStream {
+    conn: Arc<Mutex<rustls::ServerConnection>>,
+    sock: TcpStream,
+}
+
+#[cfg(feature = "electrum-tls")]
+impl TlsStream {
+    fn new(config: Arc<rustls::ServerConfig>, sock: TcpStream) -> io::Result<Self> {
+        let conn = rustls::ServerConnection::new(config)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(TlsStream {
+            conn: Arc::new(Mutex::new(conn)),
+            sock,
+        })
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(TlsStream {
+            conn: self.conn.clone(),
+            sock: self.sock.try_clone()?,
+        })
+    }
+
+    // write out any pending TLS records (handshake messages or encrypted data)
+    fn write_tls(conn: &mut rustls::ServerConnection, mut sock: &TcpStream) -> io::Result<()> {
+        while conn.wants_write() {
+            conn.write_tls(&mut sock)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "electrum-tls")]
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut tls_buf = [0u8; 4096];
+        loop {
+            {
+                let mut conn = self.conn.lock().unwrap();
+                match conn.reader().read(buf) {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                    res => return res,
+                }
+                Self::write_tls(&mut conn, &self.sock)?;
+            }
+
+            // wait for more data from the socket without holding the lock
+            let n = self.sock.read(&mut tls_buf)?;
+            if n == 0 {
+                return Ok(0);
+            }
+
+            let mut conn = self.conn.lock().unwrap();
+            let mut tls_data = &tls_buf[..n];
+            while !tls_data.is_empty() {
+                conn.read_tls(&mut tls_data)?;
+                conn.process_new_packets()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            }
+        }
+    }
+}
+
+#[cfg(feature = "electrum-tls")]
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        let n = conn.writer().write(buf)?;
+        Self::write_tls(&mut conn, &self.sock)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        conn.writer().flush()?;
+        Self::write_tls(&mut conn, &self.sock)
+    }
+}
+
+#[cfg(unix)]
+fn remove_socket_file(path: &Path) -> io::Result<()> {
+    if let Ok(meta) = fs::metadata(path) {
+        if meta.file_type().is_socket() {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+
+    #[test]
+    fn test_unix_socket() {
+        let path = std::env::temp_dir().join(format!("bwt-test-{}.sock", std::process::id()));
+        let listen = Listen::Unix(path.clone());
+        let listener = listen.bind().unwrap();
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        let (mut stream, peer) = listener.accept().unwrap();
+        assert_eq!(peer, format!("unix:{}", path.display()));
+
+        client.write_all(b"ping\n").unwrap();
+        let mut line = String::new();
+        BufReader::new(stream.try_clone().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        assert_eq!(line, "ping\n");
+        stream.write_all(b"pong\n").unwrap();
+        stream.shutdown(Shutdown::Both).unwrap();
+
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "pong\n");
+
+        listen.cleanup();
+        assert!(!path.exists());
+    }
+}
+
+#[cfg(all(test, feature = "electrum-tls"))]
+mod tls_tests {
+    use super::*;
+    use std::convert::TryInto;
+    use std::io::{BufRead, BufReader};
+    use std::thread;
+
+    #[test]
+    fn test_tls_handshake() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("test/tls");
+        let identity = TlsIdentity {
+            cert_path: fixtures.join("cert.pem"),
+            key_path: fixtures.join("key.pem"),
+        };
+        let listener = Listen::Tls("127.0.0.1:0".parse().unwrap(), identity)
+            .bind()
+            .unwrap();
+        let addr = match listener.local_addr() {
+            Listen::Tls(addr, _) => addr,
+            _ => unreachable!(),
+        };
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = String::new();
+            BufReader::new(stream.try_clone().unwrap())
+                .read_line(&mut request)
+                .unwrap();
+            assert_eq!(
+                request,
+                "{\"id\":1,\"method\":\"server.ping\",\"params\":[]}\n"
+            );
+            stream.write_all(b"{\"id\":1,\"result\":null}\n").unwrap();
+        });
+
+        // trust the self-signed test certificate
+        let mut roots = rustls::RootCertStore::empty();
+        let cert_pem = fs::read(fixtures.join("cert.pem")).unwrap();
+        for cert in rustls_pemfile::certs(&mut &cert_pem[..]).unwrap() {
+            roots.add(&rustls::Certificate(cert)).unwrap();
+        }
+        let client_config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let conn =
+            rustls::ClientConnection::new(Arc::new(client_config), "localhost".try_into().unwrap())
+                .unwrap();
+        let mut client = rustls::StreamOwned::new(conn, TcpStream::connect(addr).unwrap());
+
+        client
+            .write_all(b"{\"id\":1,\"method\":\"server.ping\",\"params\":[]}\n")
+            .unwrap();
+        let mut reply = String::new();
+        BufReader::new(&mut client).read_line(&mut reply).unwrap();
+        assert_eq!(reply, "{\"id\":1,\"result\":null}\n");
+
+        server.join().unwrap();
+    }
+}
@@ -2,7 +2,7 @@ use core::fmt::Display;
 
 pub use anyhow::{Context, Error, Result};
 
-use bitcoin::{BlockHash, Txid};
+use bitcoin::{Address, BlockHash, Network, Txid};
 use bitcoincore_rpc as rpc;
 
 use crate::types::ScriptHash;
@@ -30,9 +30,35 @@ pub enum BwtError {
     #[error("Custom broadcast command failed with {0}")]
     BroadcastCmdFailed(std::process::ExitStatus),
 
+    #[error("Cannot derive hardened child keys from an xpub without the xprv: {0}")]
+    HardenedDerivation(String),
+
+    #[error("Extended key {0} is for {1}, which does not match the configured network {2}")]
+    NetworkMismatch(String, Network, Network),
+
     #[error("Transaction rejected: {0}")]
     TxUnacceptable(String),
 
+    #[error(
+        "Transaction fee rate of {0:.2} sat/vB is below the minimum relay fee of {1:.2} sat/vB"
+    )]
+    FeeTooLow(f64, f64),
+
+    #[error("Private key not available in the bitcoind wallet for {0}")]
+    PrivateKeyUnavailable(Address),
+
+    #[error("This requires the `{0}` feature, which bwt was built without")]
+    FeatureNotEnabled(&'static str),
+
+    #[error("The index was not synced yet")]
+    NotYetSynced,
+
+    #[error("A reindex is already in progress")]
+    ReindexInProgress,
+
+    #[error("{0} is not supported with --block-scan-from, as blocks that were already scanned are not re-scanned")]
+    UnsupportedWithBlockScan(&'static str),
+
     #[error("Error communicating with the Bitcoin RPC: {0}")]
     RpcProtocol(rpc::Error),
 
@@ -48,6 +74,12 @@ impl BwtError {
             BwtError::PrunedBlocks => StatusCode::GONE,
             BwtError::TxNotFound(_) => StatusCode::NOT_FOUND,
             BwtError::ScriptHashNotFound(_) => StatusCode::NOT_FOUND,
+            BwtError::PrivateKeyUnavailable(_) => StatusCode::BAD_REQUEST,
+            BwtError::FeeTooLow(..) => StatusCode::BAD_REQUEST,
+            BwtError::NotYetSynced => StatusCode::SERVICE_UNAVAILABLE,
+            BwtError::ReindexInProgress => StatusCode::CONFLICT,
+            BwtError::FeatureNotEnabled(_) => StatusCode::NOT_IMPLEMENTED,
+            BwtError::UnsupportedWithBlockScan(_) => StatusCode::NOT_IMPLEMENTED,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
@@ -1,32 +1,43 @@
+use std::collections::HashSet;
+use std::str::FromStr;
 use std::sync::{mpsc, Arc, Mutex};
-use std::{convert, net, thread};
+use std::{convert, mem, net, thread, time};
 
+use futures_util::SinkExt;
 use serde::{Deserialize, Deserializer};
 use tokio::sync::{mpsc as tmpsc, oneshot};
 use tokio_stream::{wrappers::UnboundedReceiverStream, Stream, StreamExt};
 use warp::http::{header, StatusCode};
+use warp::ws::{Message, WebSocket, Ws};
 use warp::{self, reply, sse::Event, Filter, Reply};
 
 use bitcoin::{Address, BlockHash, OutPoint, Txid};
 use bitcoin_hashes::hex::{FromHex, ToHex};
+use bitcoincore_rpc::json::EstimateMode;
 
-use crate::error::{fmt_error_chain, BwtError, Error, OptionExt};
-use crate::types::{BlockId, ScriptHash};
+use crate::error::{fmt_error_chain, BwtError, Context, Error, OptionExt};
+use crate::indexer::Subscription;
+use crate::store::{self, Store};
+use crate::types::{BlockId, RescanSince, ScriptHash, SortOrder, TxDirection, TxStatus};
+use crate::util::amount::AmountFormat;
 use crate::util::auth::http_basic_auth;
-use crate::util::{banner, block_on_future, descriptor::Checksum, whitepaper};
-use crate::{store, IndexChange, Query};
+use crate::util::{banner, block_on_future, descriptor::Checksum, whitepaper, BoolThen};
+use crate::{IndexChange, Query};
 
 type SyncChanSender = Arc<Mutex<mpsc::Sender<()>>>;
 
-fn setup(
+fn setup<S: Store>(
     access_token: Option<String>,
     cors: Option<String>,
-    query: Arc<Query>,
+    compression_min_size: Option<usize>,
+    query: Arc<Query<S>>,
     sync_tx: SyncChanSender,
     listeners: Listeners,
 ) -> warp::Server<impl warp::Filter<Extract = impl warp::Reply> + Clone> {
+    let amount_format = query.amount_format();
     let query = warp::any().map(move || Arc::clone(&query));
     let sync_tx = warp::any().map(move || Arc::clone(&sync_tx));
+    let ws_listeners = Arc::clone(&listeners);
     let listeners = warp::any().map(move || Arc::clone(&listeners));
 
     let mut headers = header::HeaderMap::new();
@@ -34,24 +45,44 @@ fn setup(
         // allow using "any" as an alias for "*", avoiding expansion when passing "*" can be tricky
         let cors = if cors == "any" { "*".into() } else { cors };
         headers.insert("Access-Control-Allow-Origin", cors.parse().unwrap());
+        headers.insert(
+            "Access-Control-Expose-Headers",
+            "X-Next-Cursor".parse().unwrap(),
+        );
     }
 
     // GET /wallets
     let wallets_handler = warp::get()
         .and(warp::path!("wallets"))
         .and(query.clone())
-        .map(|query: Arc<Query>| {
+        .map(|query: Arc<Query<S>>| {
             let wallets = query.get_wallets();
-            reply::json(&wallets)
+            json_reply(&query, &wallets)
         });
 
+    // GET /wallets/gap-status
+    let wallets_gap_status_handler = warp::get()
+        .and(warp::path!("wallets" / "gap-status"))
+        .and(query.clone())
+        .map(|query: Arc<Query<S>>| json_reply(&query, &query.get_gap_statuses()));
+
+    // GET /wallets/verify-imports
+    let wallets_verify_handler = warp::get()
+        .and(warp::path!("wallets" / "verify-imports"))
+        .and(query.clone())
+        .map(|query: Arc<Query<S>>| {
+            let discrepancies = query.verify_imports()?;
+            Ok(json_reply(&query, &discrepancies))
+        })
+        .map(handle_error);
+
     // GET /wallet/:checksum
     let wallet_handler = warp::get()
         .and(warp::path!("wallet" / Checksum))
         .and(query.clone())
-        .map(|checksum: Checksum, query: Arc<Query>| {
+        .map(|checksum: Checksum, query: Arc<Query<S>>| {
             let wallet = query.get_wallet(&checksum).or_err(StatusCode::NOT_FOUND)?;
-            Ok(reply::json(&wallet))
+            Ok(json_reply(&query, &wallet))
         })
         .map(handle_error);
 
@@ -59,11 +90,40 @@ fn setup(
     let wallet_key_handler = warp::get()
         .and(warp::path!("wallet" / Checksum / u32))
         .and(query.clone())
-        .map(|checksum: Checksum, index: u32, query: Arc<Query>| {
+        .map(|checksum: Checksum, index: u32, query: Arc<Query<S>>| {
             let script_info = query
                 .get_wallet_script_info(&checksum, index)
                 .or_err(StatusCode::NOT_FOUND)?;
-            Ok(reply::json(&script_info))
+            Ok(json_reply(&query, &script_info))
+        })
+        .map(handle_error);
+
+    // GET /wallet/:checksum/:index/bip21
+    let wallet_key_bip21_handler = warp::get()
+        .and(warp::path!("wallet" / Checksum / u32 / "bip21"))
+        .and(warp::query::<Bip21Options>())
+        .and(query.clone())
+        .map(
+            |checksum: Checksum, index: u32, options: Bip21Options, query: Arc<Query<S>>| {
+                let script_info = query
+                    .get_wallet_script_info(&checksum, index)
+                    .or_err(StatusCode::NOT_FOUND)?;
+                script_info
+                    .to_bip21(options.amount, options.label.as_deref())
+                    .or_err(StatusCode::BAD_REQUEST)
+            },
+        )
+        .map(handle_error);
+
+    // GET /wallet/:checksum/balance
+    let wallet_balance_handler = warp::get()
+        .and(warp::path!("wallet" / Checksum / "balance"))
+        .and(query.clone())
+        .map(|checksum: Checksum, query: Arc<Query<S>>| {
+            let balance = query
+                .get_wallet_balance(&checksum)
+                .or_err(StatusCode::NOT_FOUND)?;
+            Ok(json_reply(&query, &balance))
         })
         .map(handle_error);
 
@@ -71,11 +131,116 @@ fn setup(
     let wallet_gap_handler = warp::get()
         .and(warp::path!("wallet" / Checksum / "gap"))
         .and(query.clone())
-        .map(|checksum: Checksum, query: Arc<Query>| {
+        .map(|checksum: Checksum, query: Arc<Query<S>>| {
             let gap = query
                 .find_wallet_gap(&checksum)
                 .or_err(StatusCode::NOT_FOUND)?;
-            Ok(reply::json(&gap))
+            Ok(json_reply(&query, &gap))
+        })
+        .map(handle_error);
+
+    // GET /wallet/:checksum/gap-status
+    let wallet_gap_status_handler = warp::get()
+        .and(warp::path!("wallet" / Checksum / "gap-status"))
+        .and(query.clone())
+        .map(|checksum: Checksum, query: Arc<Query<S>>| {
+            let status = query
+                .get_gap_status(&checksum)
+                .or_err(StatusCode::NOT_FOUND)?;
+            Ok(json_reply(&query, &status))
+        })
+        .map(handle_error);
+
+    // GET /wallet/:checksum/import-state
+    let wallet_import_state_handler = warp::get()
+        .and(warp::path!("wallet" / Checksum / "import-state"))
+        .and(query.clone())
+        .map(|checksum: Checksum, query: Arc<Query<S>>| {
+            let state = query
+                .get_import_state(&checksum)
+                .or_err(StatusCode::NOT_FOUND)?;
+            Ok(json_reply(&query, &state))
+        })
+        .map(handle_error);
+
+    // GET /wallet/:checksum/addresses?start=<index>&count=<count>
+    let wallet_addresses_handler = warp::get()
+        .and(warp::path!("wallet" / Checksum / "addresses"))
+        .and(warp::query::<AddressRangeOptions>())
+        .and(query.clone())
+        .map(
+            |checksum: Checksum, options: AddressRangeOptions, query: Arc<Query<S>>| {
+                query.get_wallet(&checksum).or_err(StatusCode::NOT_FOUND)?;
+                let script_infos =
+                    query.get_wallet_script_range(&checksum, options.start, options.count)?;
+                Ok(json_reply(&query, &script_infos))
+            },
+        )
+        .map(handle_error);
+
+    // POST /wallet/:checksum/gap-limit/:gap_limit
+    let wallet_gap_limit_handler = warp::post()
+        .and(warp::path!("wallet" / Checksum / "gap-limit" / u32))
+        .and(query.clone())
+        .and(sync_tx.clone())
+        .map(
+            |checksum: Checksum, gap_limit: u32, query: Arc<Query<S>>, sync_tx: SyncChanSender| {
+                query.get_wallet(&checksum).or_err(StatusCode::NOT_FOUND)?;
+                // the rescan can take a while, run it in the background and sync right after it
+                query.set_gap_limit_background(
+                    &checksum,
+                    gap_limit,
+                    move |result| match result {
+                        Ok(()) => {
+                            sync_tx.lock().unwrap().send(()).ok();
+                        }
+                        Err(e) => warn!("gap limit rescan failed: {:?}", e),
+                    },
+                )?;
+                Ok(reply::with_status(
+                    "gap limit updated",
+                    StatusCode::ACCEPTED,
+                ))
+            },
+        )
+        .map(handle_error);
+
+    // POST /address/:address/track
+    let address_track_handler = warp::post()
+        .and(warp::path!("address" / Address / "track"))
+        .and(warp::body::json())
+        .and(query.clone())
+        .and(sync_tx.clone())
+        .map(
+            |address: Address,
+             body: TrackAddressBody,
+             query: Arc<Query<S>>,
+             sync_tx: SyncChanSender| {
+                query
+                    .track_address(address, body.rescan_since)
+                    .context(StatusCode::BAD_REQUEST)?;
+                // sync right away to import the address and pick up its history
+                sync_tx.lock().unwrap().send(())?;
+                Ok(reply::with_status("address tracked", StatusCode::ACCEPTED))
+            },
+        )
+        .map(handle_error);
+
+    // GET /wallets/next-change
+    let wallets_next_change_handler = warp::get()
+        .and(warp::path!("wallets" / "next-change"))
+        .and(warp::query::<ChangeAddressOptions>())
+        .and(query.clone())
+        .map(|options: ChangeAddressOptions, query: Arc<Query<S>>| {
+            let checksum = options
+                .wallet
+                .map(|checksum| Checksum::from_str(&checksum))
+                .transpose()
+                .context(StatusCode::BAD_REQUEST)?;
+            let script_info = query
+                .get_change_address(checksum.as_ref())
+                .context(StatusCode::BAD_REQUEST)?;
+            Ok(json_reply(&query, &script_info))
         })
         .map(handle_error);
 
@@ -83,7 +248,7 @@ fn setup(
     let wallet_next_handler = warp::get()
         .and(warp::path!("wallet" / Checksum / "next"))
         .and(query.clone())
-        .map(|checksum: Checksum, query: Arc<Query>| {
+        .map(|checksum: Checksum, query: Arc<Query<S>>| {
             let wallet = query.get_wallet(&checksum).or_err(StatusCode::NOT_FOUND)?;
             let next_index = wallet.get_next_index();
             let uri = format!("/wallet/{}/{}", checksum, next_index);
@@ -107,7 +272,7 @@ fn setup(
     // GET /wallet/:checksum/:index/*
     let wallet_key_route = warp::path!("wallet" / Checksum / u32 / ..)
         .and(query.clone())
-        .map(|checksum: Checksum, index: u32, query: Arc<Query>| {
+        .map(|checksum: Checksum, index: u32, query: Arc<Query<S>>| {
             let script_info = query
                 .get_wallet_script_info(&checksum, index)
                 .or_err(StatusCode::NOT_FOUND)?;
@@ -128,11 +293,26 @@ fn setup(
         .and(spk_route.clone())
         .and(warp::path::end())
         .and(query.clone())
-        .map(|scripthash, query: Arc<Query>| {
+        .map(|scripthash, query: Arc<Query<S>>| {
             let script_info = query
                 .get_script_info(&scripthash)
                 .or_err(StatusCode::NOT_FOUND)?;
-            Ok(reply::json(&script_info))
+            Ok(json_reply(&query, &script_info))
+        })
+        .map(handle_error);
+
+    // GET /wallet/:checksum/:index/descriptor
+    // GET /address/:address/descriptor
+    // GET /scripthash/:scripthash/descriptor
+    let spk_descriptor_handler = warp::get()
+        .and(spk_route.clone())
+        .and(warp::path!("descriptor"))
+        .and(query.clone())
+        .map(|scripthash, query: Arc<Query<S>>| {
+            let desc = query
+                .get_descriptor(&scripthash)?
+                .or_err(StatusCode::NOT_FOUND)?;
+            Ok(desc)
         })
         .map(handle_error);
 
@@ -143,11 +323,11 @@ fn setup(
         .and(spk_route.clone())
         .and(warp::path!("stats"))
         .and(query.clone())
-        .map(|scripthash, query: Arc<Query>| {
+        .map(|scripthash, query: Arc<Query<S>>| {
             let script_stats = query
                 .get_script_stats(&scripthash)?
                 .or_err(StatusCode::NOT_FOUND)?;
-            Ok(reply::json(&script_stats))
+            Ok(json_reply(&query, &script_stats))
         })
         .map(handle_error);
 
@@ -159,26 +339,98 @@ fn setup(
         .and(warp::path!("utxos"))
         .and(warp::query::<UtxoOptions>())
         .and(query.clone())
-        .map(|scripthash, options: UtxoOptions, query: Arc<Query>| {
-            let utxos =
-                query.list_unspent(Some(&scripthash), options.min_conf, options.include_unsafe)?;
-            Ok(reply::json(&utxos))
+        .map(|scripthash, options: UtxoOptions, query: Arc<Query<S>>| {
+            let utxos = query.list_unspent(
+                Some(&scripthash),
+                options.min_conf,
+                options.include_unsafe,
+                options.include_dust,
+            )?;
+            Ok(json_reply(&query, &utxos))
         })
         .map(handle_error);
 
+    // POST /wallet/:checksum/:index/sign-message
+    // POST /address/:address/sign-message
+    // POST /scripthash/:scripthash/sign-message
+    let spk_sign_message_handler = warp::post()
+        .and(spk_route.clone())
+        .and(warp::path!("sign-message"))
+        .and(warp::body::json())
+        .and(query.clone())
+        .map(|scripthash, body: SignMessageBody, query: Arc<Query<S>>| {
+            let signature = query.sign_message(&scripthash, &body.message)?;
+            Ok(signature)
+        })
+        .map(handle_error);
+
+    // POST /wallet/:checksum/:index/alert
+    // POST /address/:address/alert
+    // POST /scripthash/:scripthash/alert
+    let spk_alert_handler = warp::post()
+        .and(spk_route.clone())
+        .and(warp::path!("alert"))
+        .and(warp::body::json())
+        .and(query.clone())
+        .map(|scripthash, body: AmountAlertBody, query: Arc<Query<S>>| {
+            query.register_amount_alert(scripthash, body.min_amount, body.one_shot);
+            reply::with_status("alert registered", StatusCode::CREATED)
+        });
+
+    // DELETE /wallet/:checksum/:index/alert
+    // DELETE /address/:address/alert
+    // DELETE /scripthash/:scripthash/alert
+    let spk_alert_delete_handler = warp::delete()
+        .and(spk_route.clone())
+        .and(warp::path!("alert"))
+        .and(query.clone())
+        .map(|scripthash, query: Arc<Query<S>>| {
+            query
+                .remove_amount_alert(&scripthash)
+                .do_then(|| "alert removed")
+                .or_err(StatusCode::NOT_FOUND)
+        })
+        .map(handle_error);
+
+    // GET /alerts
+    let alerts_handler = warp::get()
+        .and(warp::path!("alerts"))
+        .and(query.clone())
+        .map(|query: Arc<Query<S>>| json_reply(&query, &query.get_amount_alerts()));
+
     // GET /wallet/:checksum/:index/txs
     // GET /address/:address/txs
     // GET /scripthash/:scripthash/txs
     let spk_txs_handler = warp::get()
         .and(spk_route.clone())
         .and(warp::path!("txs"))
+        .and(warp::query::<HistoryOptions>())
         .and(query.clone())
-        .map(|scripthash, query: Arc<Query>| {
-            let txs = query.map_history(&scripthash, |txhist| {
-                query.get_tx_detail(&txhist.txid).unwrap()
-            });
-            Ok(reply::json(&txs))
-        })
+        .map(
+            |scripthash, options: HistoryOptions, query: Arc<Query<S>>| {
+                let limit = options.limit.unwrap_or(usize::MAX);
+                if options.compact {
+                    let (txs, next_cursor) = query.map_history_after_filtered(
+                        &scripthash,
+                        options.order,
+                        options.after,
+                        limit,
+                        options.direction,
+                        CompactHistory::new,
+                    );
+                    return Ok(history_page_reply(&query, &txs, next_cursor));
+                }
+                let (txs, next_cursor) = query.map_history_after_filtered(
+                    &scripthash,
+                    options.order,
+                    options.after,
+                    limit,
+                    options.direction,
+                    |txhist| query.get_tx_detail(&txhist.txid).unwrap(),
+                );
+                Ok(history_page_reply(&query, &txs, next_cursor))
+            },
+        )
         .map(handle_error);
 
     // GET /wallet/:checksum/:index/txs/compact
@@ -187,10 +439,47 @@ fn setup(
     let spk_txs_compact_handler = warp::get()
         .and(spk_route.clone())
         .and(warp::path!("txs" / "compact"))
+        .and(warp::query::<HistoryOptions>())
         .and(query.clone())
-        .map(|scripthash, query: Arc<Query>| {
-            let txs = query.map_history(&scripthash, compact_history);
-            Ok(reply::json(&txs))
+        .map(
+            |scripthash, options: HistoryOptions, query: Arc<Query<S>>| {
+                let (txs, next_cursor) = query.map_history_after_filtered(
+                    &scripthash,
+                    options.order,
+                    options.after,
+                    options.limit.unwrap_or(usize::MAX),
+                    options.direction,
+                    CompactHistory::new,
+                );
+                Ok(history_page_reply(&query, &txs, next_cursor))
+            },
+        )
+        .map(handle_error);
+
+    // GET /wallet/:checksum/:index/txs/csv
+    // GET /address/:address/txs/csv
+    // GET /scripthash/:scripthash/txs/csv
+    let spk_txs_csv_handler = warp::get()
+        .and(spk_route.clone())
+        .and(warp::path!("txs" / "csv"))
+        .and(query.clone())
+        .map(|scripthash, query: Arc<Query<S>>| {
+            let mut csv = vec![];
+            query.export_history_csv(&scripthash, &mut csv)?;
+            Ok(reply::with_header(csv, header::CONTENT_TYPE, "text/csv"))
+        })
+        .map(handle_error);
+
+    // GET /wallet/:checksum/:index/txs/mempool
+    // GET /address/:address/txs/mempool
+    // GET /scripthash/:scripthash/txs/mempool
+    let spk_txs_mempool_handler = warp::get()
+        .and(spk_route.clone())
+        .and(warp::path!("txs" / "mempool"))
+        .and(query.clone())
+        .map(|scripthash, query: Arc<Query<S>>| {
+            let txs = query.get_mempool_history(&scripthash);
+            Ok(json_reply(&query, &txs))
         })
         .map(handle_error);
 
@@ -202,20 +491,40 @@ fn setup(
         .and(tx_route)
         .and(warp::path::end())
         .and(query.clone())
-        .map(|txid: Txid, query: Arc<Query>| {
-            let tx_info = query.get_tx_detail(&txid).or_err(StatusCode::NOT_FOUND)?;
-            Ok(reply::json(&tx_info))
+        .map(|txid: Txid, query: Arc<Query<S>>| {
+            let tx_info = query
+                .get_tx_detail(&txid)
+                .ok_or(BwtError::TxNotFound(txid))?;
+            Ok(json_reply(&query, &tx_info))
         })
         .map(handle_error);
 
+    // POST /tx/:txid/refresh
+    let tx_refresh_handler = warp::post()
+        .and(tx_route)
+        .and(warp::path!("refresh"))
+        .and(query.clone())
+        .and(sync_tx.clone())
+        .map(
+            |txid: Txid, query: Arc<Query<S>>, sync_tx: SyncChanSender| {
+                let status = query.refresh_tx(&txid)?.ok_or(BwtError::TxNotFound(txid))?;
+                // sync right away to emit the changes
+                sync_tx.lock().unwrap().send(())?;
+                Ok(reply::json(
+                    &json!({ "txid": txid, "block_height": status }),
+                ))
+            },
+        )
+        .map(handle_error);
+
     // GET /tx/:txid/verbose
     let tx_verbose_handler = warp::get()
         .and(tx_route)
         .and(warp::path!("verbose"))
         .and(query.clone())
-        .map(|txid: Txid, query: Arc<Query>| {
+        .map(|txid: Txid, query: Arc<Query<S>>| {
             let tx_json = query.get_tx_json(&txid)?;
-            Ok(reply::json(&tx_json))
+            Ok(json_reply(&query, &tx_json))
         })
         .map(handle_error);
 
@@ -224,18 +533,45 @@ fn setup(
         .and(tx_route)
         .and(warp::path!("hex"))
         .and(query.clone())
-        .map(|txid: Txid, query: Arc<Query>| {
+        .map(|txid: Txid, query: Arc<Query<S>>| {
             let tx_raw = query.get_tx_raw(&txid)?;
             Ok(tx_raw.to_hex())
         })
         .map(handle_error);
 
+    // GET /tx/:txid/replacements
+    let tx_replacements_handler = warp::get()
+        .and(tx_route)
+        .and(warp::path!("replacements"))
+        .and(query.clone())
+        .map(|txid: Txid, query: Arc<Query<S>>| {
+            let chain = query
+                .get_replacement_chain(&txid)
+                .ok_or(BwtError::TxNotFound(txid))?;
+            let latest = chain.last().copied();
+            Ok(reply::json(&json!({ "chain": chain, "latest": latest })))
+        })
+        .map(handle_error);
+
+    // GET /tx/:txid/scripthashes
+    let tx_scripthashes_handler = warp::get()
+        .and(tx_route)
+        .and(warp::path!("scripthashes"))
+        .and(query.clone())
+        .map(|txid: Txid, query: Arc<Query<S>>| {
+            let scripts = query
+                .get_tx_scripts(&txid)
+                .ok_or(BwtError::TxNotFound(txid))?;
+            Ok(json_reply(&query, &scripts))
+        })
+        .map(handle_error);
+
     // GET /tx/:txid/proof
     let tx_proof_handler = warp::get()
         .and(tx_route)
         .and(warp::path!("proof"))
         .and(query.clone())
-        .map(|txid: Txid, query: Arc<Query>| {
+        .map(|txid: Txid, query: Arc<Query<S>>| {
             let proof = query.get_tx_proof(&txid)?;
             Ok(proof.to_hex())
         })
@@ -250,28 +586,46 @@ fn setup(
                 .or(warp::path::end().map(|| 0))
                 .unify(),
         )
+        .and(warp::query::<HistorySinceOptions>())
         .and(query.clone())
-        .map(|min_block_height: u32, query: Arc<Query>| {
-            let txs = query.map_history_since(min_block_height, |txhist| {
-                query.get_tx_detail(&txhist.txid).unwrap()
-            });
-            reply::json(&txs)
-        });
+        .map(
+            |min_block_height: u32, options: HistorySinceOptions, query: Arc<Query<S>>| {
+                let txs = query.map_history_since_snapshot(
+                    min_block_height,
+                    &options.known_mempool,
+                    |txhist| query.get_tx_detail(&txhist.txid).unwrap(),
+                );
+                json_reply(&query, &txs)
+            },
+        );
+
+    // GET /txs/conflicted
+    let txs_conflicted_handler = warp::get()
+        .and(warp::path!("txs" / "conflicted"))
+        .and(query.clone())
+        .map(|query: Arc<Query<S>>| json_reply(&query, &query.get_conflicted_txs()));
 
     // GET /txs/since/:block_height/compact
     let txs_since_compact_handler = warp::get()
         .and(warp::path!("txs" / "since" / u32 / "compact"))
+        .and(warp::query::<HistorySinceOptions>())
         .and(query.clone())
-        .map(|min_block_height: u32, query: Arc<Query>| {
-            let txs = query.map_history_since(min_block_height, compact_history);
-            reply::json(&txs)
-        });
+        .map(
+            |min_block_height: u32, options: HistorySinceOptions, query: Arc<Query<S>>| {
+                let txs = query.map_history_since_snapshot(
+                    min_block_height,
+                    &options.known_mempool,
+                    CompactHistory::new,
+                );
+                json_reply(&query, &txs)
+            },
+        );
 
     // POST /tx
     let tx_broadcast_handler = warp::post()
         .and(warp::body::json())
         .and(query.clone())
-        .map(|body: BroadcastBody, query: Arc<Query>| {
+        .map(|body: BroadcastBody, query: Arc<Query<S>>| {
             let txid = query.broadcast(&body.tx_hex)?;
             Ok(txid.to_string())
         })
@@ -281,11 +635,23 @@ fn setup(
     let txo_handler = warp::get()
         .and(warp::path!("txo" / Txid / u32))
         .and(query.clone())
-        .map(|txid: Txid, vout: u32, query: Arc<Query>| {
+        .map(|txid: Txid, vout: u32, query: Arc<Query<S>>| {
             let txo = query
                 .lookup_txo(&OutPoint::new(txid, vout))
                 .or_err(StatusCode::NOT_FOUND)?;
-            Ok(reply::json(&txo))
+            Ok(json_reply(&query, &txo))
+        })
+        .map(handle_error);
+
+    // GET /txo/:txid/:vout/spend
+    let txo_spend_handler = warp::get()
+        .and(warp::path!("txo" / Txid / u32 / "spend"))
+        .and(query.clone())
+        .map(|txid: Txid, vout: u32, query: Arc<Query<S>>| {
+            let status = query
+                .get_txo_spend_status(&OutPoint::new(txid, vout))?
+                .or_err(StatusCode::NOT_FOUND)?;
+            Ok(json_reply(&query, &status))
         })
         .map(handle_error);
 
@@ -294,9 +660,35 @@ fn setup(
         .and(warp::path!("utxos"))
         .and(warp::query::<UtxoOptions>())
         .and(query.clone())
-        .map(|options: UtxoOptions, query: Arc<Query>| {
-            let utxos = query.list_unspent(None, options.min_conf, options.include_unsafe)?;
-            Ok(reply::json(&utxos))
+        .map(|options: UtxoOptions, query: Arc<Query<S>>| {
+            let utxos = query.list_unspent(
+                None,
+                options.min_conf,
+                options.include_unsafe,
+                options.include_dust,
+            )?;
+            Ok(json_reply(&query, &utxos))
+        })
+        .map(handle_error);
+
+    // GET /utxos/grouped
+    let utxos_grouped_handler = warp::get()
+        .and(warp::path!("utxos" / "grouped"))
+        .and(query.clone())
+        .map(|query: Arc<Query<S>>| {
+            let grouped = query.list_unspent_grouped(None)?;
+            Ok(json_reply(&query, &grouped))
+        })
+        .map(handle_error);
+
+    // GET /utxos/spendable
+    let spendable_inputs_handler = warp::get()
+        .and(warp::path!("utxos" / "spendable"))
+        .and(warp::query::<UtxoOptions>())
+        .and(query.clone())
+        .map(|options: UtxoOptions, query: Arc<Query<S>>| {
+            let inputs = query.get_spendable_inputs(options.min_conf)?;
+            Ok(json_reply(&query, &inputs))
         })
         .map(handle_error);
 
@@ -307,7 +699,7 @@ fn setup(
         .and(listeners.clone())
         .and(query.clone())
         .map(
-            |filter: ChangelogFilter, listeners: Listeners, query: Arc<Query>| {
+            |filter: ChangelogFilter, listeners: Listeners, query: Arc<Query<S>>| {
                 let stream = make_sse_stream(filter, listeners, &query)?;
                 Ok(warp::sse::reply(warp::sse::keep_alive().stream(stream)))
             },
@@ -321,13 +713,13 @@ fn setup(
         .and(spk_route)
         .and(warp::path!("stream"))
         .and(ChangelogFilter::param())
-        .and(listeners)
+        .and(listeners.clone())
         .and(query.clone())
         .map(
             |scripthash: ScriptHash,
              mut filter: ChangelogFilter,
              listeners: Listeners,
-             query: Arc<Query>| {
+             query: Arc<Query<S>>| {
                 filter.scripthash = Some(scripthash);
                 let stream = make_sse_stream(filter, listeners, &query)?;
                 Ok(warp::sse::reply(warp::sse::keep_alive().stream(stream)))
@@ -335,24 +727,49 @@ fn setup(
         )
         .map(handle_error);
 
+    // GET /ws
+    let ws_handler = ws_route(ws_listeners, amount_format);
+
+    // GET /chain-info
+    let chain_info_handler = warp::get()
+        .and(warp::path!("chain-info"))
+        .and(query.clone())
+        .map(|query: Arc<Query<S>>| {
+            let chain_info = query.get_chain_info()?;
+            Ok(json_reply(&query, &chain_info))
+        })
+        .map(handle_error);
+
     // GET /block/tip
     let block_tip_handler = warp::get()
         .and(warp::path!("block" / "tip"))
         .and(query.clone())
-        .map(|query: Arc<Query>| {
-            // XXX currently returns the tip reported by bitcoind, return the indexer tip as well?
+        .map(|query: Arc<Query<S>>| {
             let BlockId(height, blockhash) = query.get_tip()?;
             Ok(reply::json(&json!({ "hash": blockhash, "height": height })))
         })
         .map(handle_error);
 
+    // GET /block/synced-tip
+    let block_synced_tip_handler = warp::get()
+        .and(warp::path!("block" / "synced-tip"))
+        .and(query.clone())
+        .map(|query: Arc<Query<S>>| {
+            let BlockId(height, blockhash) = query.get_synced_tip()?;
+            let stable = query.is_synced_tip_stable();
+            Ok(reply::json(
+                &json!({ "hash": blockhash, "height": height, "stable": stable }),
+            ))
+        })
+        .map(handle_error);
+
     // GET /block/:hash
     let block_header_handler = warp::get()
         .and(warp::path!("block" / BlockHash))
         .and(query.clone())
-        .map(|blockhash: BlockHash, query: Arc<Query>| {
+        .map(|blockhash: BlockHash, query: Arc<Query<S>>| {
             let header_info = query.get_header_info(&blockhash)?;
-            Ok(reply::json(&header_info))
+            Ok(json_reply(&query, &header_info))
         })
         .map(handle_error);
 
@@ -360,7 +777,7 @@ fn setup(
     let block_hex_handler = warp::get()
         .and(warp::path!("block" / BlockHash / "hex"))
         .and(query.clone())
-        .map(|blockhash: BlockHash, query: Arc<Query>| {
+        .map(|blockhash: BlockHash, query: Arc<Query<S>>| {
             let header_hex = query.get_header_hex(&blockhash)?;
             Ok(header_hex)
         })
@@ -370,7 +787,7 @@ fn setup(
     let block_height_handler = warp::get()
         .and(warp::path!("block" / u32))
         .and(query.clone())
-        .map(|height: u32, query: Arc<Query>| {
+        .map(|height: u32, query: Arc<Query<S>>| {
             let blockhash = query.get_block_hash(height)?;
             let uri = format!("/block/{}", blockhash);
             // issue a 307 redirect to the block hash uri, and also include the hash in the body
@@ -382,60 +799,114 @@ fn setup(
         })
         .map(handle_error);
 
+    // GET /mempool/txs
+    let mempool_txs_handler = warp::get()
+        .and(warp::path!("mempool" / "txs"))
+        .and(query.clone())
+        .map(|query: Arc<Query<S>>| json_reply(&query, &query.get_mempool_txs()));
+
+    // GET /mempool/spends
+    let mempool_spends_handler = warp::get()
+        .and(warp::path!("mempool" / "spends"))
+        .and(query.clone())
+        .map(|query: Arc<Query<S>>| {
+            let spends = query.get_pending_spends()?;
+            Ok(json_reply(&query, &spends))
+        })
+        .map(handle_error);
+
     // GET /mempool/histogram
     let mempool_histogram_handler = warp::get()
         .and(warp::path!("mempool" / "histogram"))
         .and(query.clone())
-        .map(|query: Arc<Query>| {
+        .map(|query: Arc<Query<S>>| {
             let histogram = query.fee_histogram()?;
-            Ok(reply::json(&histogram))
+            Ok(json_reply(&query, &histogram))
         })
         .map(handle_error);
 
     // GET /fee-estimate/:confirmation-target
     let fee_estimate_handler = warp::get()
         .and(warp::path!("fee-estimate" / u16))
+        .and(warp::query::<FeeEstimateOptions>())
         .and(query.clone())
-        .map(|confirmation_target: u16, query: Arc<Query>| {
-            let feerate = query.estimate_fee(confirmation_target)?;
-            Ok(reply::json(&feerate))
-        })
+        .map(
+            |confirmation_target: u16, options: FeeEstimateOptions, query: Arc<Query<S>>| {
+                let mode = options.mode.unwrap_or(EstimateMode::Unset);
+                let feerate = query.estimate_fee(confirmation_target, mode)?;
+                Ok(json_reply(&query, &feerate))
+            },
+        )
         .map(handle_error);
 
     // GET /dump
     let dump_handler = warp::get()
         .and(warp::path!("dump"))
         .and(query.clone())
-        .map(|query: Arc<Query>| reply::json(&query.dump_index()));
+        .map(|query: Arc<Query<S>>| json_reply(&query, &query.dump_index()));
 
     // GET /debug
     let debug_handler = warp::get()
         .and(warp::path!("debug"))
         .and(query.clone())
-        .map(|query: Arc<Query>| query.debug_index());
+        .map(|query: Arc<Query<S>>| query.debug_index());
+
+    // GET /health
+    let health_handler = warp::get()
+        .and(warp::path!("health"))
+        .and(query.clone())
+        .map(|query: Arc<Query<S>>| {
+            let health = query
+                .get_health()
+                .context(StatusCode::SERVICE_UNAVAILABLE)?;
+            let status = iif!(
+                health.healthy,
+                StatusCode::OK,
+                StatusCode::SERVICE_UNAVAILABLE
+            );
+            Ok(reply::with_status(json_reply(&query, &health), status))
+        })
+        .map(handle_error);
 
     // GET /banner.txt
     let banner_handler = warp::get()
         .and(warp::path!("banner.txt"))
         .and(query.clone())
-        .map(|query: Arc<Query>| banner::get_welcome_banner(&query, true))
+        .map(|query: Arc<Query<S>>| banner::get_welcome_banner(&query, true))
         .map(handle_error);
 
     // POST /sync
     let sync_handler = warp::post()
         .and(warp::path!("sync"))
-        .and(sync_tx)
+        .and(sync_tx.clone())
         .map(|sync_tx: SyncChanSender| {
             sync_tx.lock().unwrap().send(())?;
             Ok(reply::with_status("syncing queued", StatusCode::ACCEPTED))
         })
         .map(handle_error);
 
+    // POST /reindex
+    let reindex_handler = warp::post()
+        .and(warp::path!("reindex"))
+        .and(query.clone())
+        .and(sync_tx.clone())
+        .map(|query: Arc<Query<S>>, sync_tx: SyncChanSender| {
+            // the reindex can take a while, run it in the background and sync right after it
+            query.reindex_background(move |result| match result {
+                Ok(()) => {
+                    sync_tx.lock().unwrap().send(()).ok();
+                }
+                Err(e) => warn!("reindex failed: {:?}", e),
+            })?;
+            Ok(reply::with_status("reindex started", StatusCode::ACCEPTED))
+        })
+        .map(handle_error);
+
     // GET /bitcoin.pdf
     let whitepaper_handler = warp::get()
         .and(warp::path!("bitcoin.pdf"))
         .and(query)
-        .map(|query: Arc<Query>| {
+        .map(|query: Arc<Query<S>>| {
             let pdf_blob = whitepaper::get_whitepaper_pdf(query.rpc())?;
             Ok(reply::with_header(
                 pdf_blob,
@@ -447,41 +918,75 @@ fn setup(
 
     let handler = balanced_or_tree!(
         wallets_handler,
+        wallets_verify_handler,
+        wallets_gap_status_handler,
+        wallets_next_change_handler,
         wallet_handler,
         wallet_key_handler, // needs to be before spk_handler to work with keys that don't have any indexed history
+        wallet_key_bip21_handler,
         wallet_gap_handler,
+        wallet_gap_status_handler,
+        wallet_import_state_handler,
+        wallet_balance_handler,
+        wallet_gap_limit_handler,
+        address_track_handler,
         wallet_next_handler,
+        wallet_addresses_handler,
         spk_handler,
         spk_utxo_handler,
         spk_stats_handler,
+        spk_descriptor_handler,
+        spk_sign_message_handler,
+        spk_alert_handler,
+        spk_alert_delete_handler,
+        alerts_handler,
         spk_txs_handler,
         spk_txs_compact_handler,
+        spk_txs_csv_handler,
+        spk_txs_mempool_handler,
         tx_handler,
+        tx_refresh_handler,
         tx_verbose_handler,
         tx_hex_handler,
+        tx_replacements_handler,
+        tx_scripthashes_handler,
         tx_proof_handler,
         txs_since_handler,
         txs_since_compact_handler,
+        txs_conflicted_handler,
         tx_broadcast_handler,
         txo_handler,
+        txo_spend_handler,
         utxos_handler,
+        utxos_grouped_handler,
+        spendable_inputs_handler,
         sse_handler,
         spk_sse_handler,
+        ws_handler,
+        chain_info_handler,
         block_tip_handler,
+        block_synced_tip_handler,
         block_header_handler,
         block_hex_handler,
         block_height_handler,
         mempool_histogram_handler,
+        mempool_txs_handler,
+        mempool_spends_handler,
         fee_estimate_handler,
         dump_handler,
         debug_handler,
+        health_handler,
         banner_handler,
         sync_handler,
+        reindex_handler,
         whitepaper_handler,
         warp::any().map(|| StatusCode::NOT_FOUND)
     )
     .with(warp::reply::with::headers(headers));
 
+    // Compress large responses, if enabled and accepted by the client
+    let handler = with_compression(handler, compression_min_size);
+
     // Wrap handler with (optional) authentication, logging and rejection handling
     let handler = http_basic_auth(access_token)
         .and_then(reject_error)
@@ -516,21 +1021,37 @@ async fn spawn<S>(
 pub struct HttpServer {
     addr: net::SocketAddr,
     listeners: Listeners,
+    batch_tx: Option<mpsc::Sender<BatchMsg>>,
     shutdown_tx: Option<oneshot::Sender<()>>,
     thread: Option<thread::JoinHandle<()>>,
 }
 
 impl HttpServer {
-    pub fn start(
+    pub fn start<S: Store>(
         addr: net::SocketAddr,
         access_token: Option<String>,
         cors: Option<String>,
-        query: Arc<Query>,
+        compression_min_size: Option<usize>,
+        batch_interval: Option<time::Duration>,
+        query: Arc<Query<S>>,
         sync_tx: mpsc::Sender<()>,
     ) -> Self {
         let listeners = Arc::new(Mutex::new(Vec::new()));
+        let batch_tx = batch_interval.map(|interval| {
+            let (batch_tx, batch_rx) = mpsc::channel();
+            let listeners = listeners.clone();
+            thread::spawn(move || batch_updates(listeners, interval, batch_rx));
+            batch_tx
+        });
         let sync_tx = Arc::new(Mutex::new(sync_tx));
-        let warp_server = setup(access_token, cors, query, sync_tx, listeners.clone());
+        let warp_server = setup(
+            access_token,
+            cors,
+            compression_min_size,
+            query,
+            sync_tx,
+            listeners.clone(),
+        );
 
         let (shutdown_tx, shutdown_rx) = oneshot::channel();
         let (addr_tx, addr_rx) = oneshot::channel();
@@ -544,6 +1065,7 @@ impl HttpServer {
 
         HttpServer {
             listeners,
+            batch_tx,
             addr: bound_addr,
             shutdown_tx: Some(shutdown_tx),
             thread: Some(thread),
@@ -551,22 +1073,20 @@ impl HttpServer {
     }
 
     pub fn send_updates(&self, changelog: &[IndexChange]) {
-        let mut listeners = self.listeners.lock().unwrap();
-        if listeners.is_empty() {
-            return;
+        match &self.batch_tx {
+            Some(batch_tx) => batch_tx
+                .send(BatchMsg::Updates(changelog.to_vec()))
+                .unwrap(),
+            None => dispatch_updates(&self.listeners, changelog),
+        }
+    }
+
+    /// Send out the pending batched updates without waiting for the batch interval to elapse.
+    /// A no-op if batching is disabled.
+    pub fn flush_updates(&self) {
+        if let Some(batch_tx) = &self.batch_tx {
+            batch_tx.send(BatchMsg::Flush).unwrap();
         }
-        info!(
-            "sending {} update(s) to {} sse client(s)",
-            changelog.len(),
-            listeners.len()
-        );
-        // send updates while dropping unresponsive listeners
-        listeners.retain(|listener| {
-            changelog
-                .iter()
-                .filter(|change| listener.filter.matches(change))
-                .all(|change| listener.tx.send(change.clone()).is_ok())
-        })
     }
 
     pub fn addr(&self) -> net::SocketAddr {
@@ -584,17 +1104,140 @@ impl Drop for HttpServer {
 
 type Listeners = Arc<Mutex<Vec<Listener>>>;
 
+fn dispatch_updates(listeners: &Listeners, changelog: &[IndexChange]) {
+    let mut listeners = listeners.lock().unwrap();
+    if listeners.is_empty() {
+        return;
+    }
+    info!(
+        "sending {} update(s) to {} sse/ws client(s)",
+        changelog.len(),
+        listeners.len()
+    );
+    // send updates while dropping unresponsive listeners
+    listeners.retain(|listener| {
+        changelog
+            .iter()
+            .filter(|change| listener.filter.matches(change))
+            .all(|change| listener.tx.send(change.clone()).is_ok())
+    })
+}
+
 struct Listener {
     tx: tmpsc::UnboundedSender<IndexChange>,
     filter: ChangelogFilter,
 }
 
+enum BatchMsg {
+    Updates(Vec<IndexChange>),
+    Flush,
+}
+
+// Collect the updates sent within `interval` of the first pending one (or until an explicit
+// flush) and send them out to the listeners as a single coalesced `BatchUpdate` event.
+// Reorgs and replaced transactions cannot be represented in a batch and are sent out as-is,
+// following the batch of the updates that preceded them.
+fn batch_updates(listeners: Listeners, interval: time::Duration, rx: mpsc::Receiver<BatchMsg>) {
+    let mut pending = vec![];
+    let mut deadline: Option<time::Instant> = None;
+    loop {
+        let msg = match deadline {
+            Some(deadline) => {
+                rx.recv_timeout(deadline.saturating_duration_since(time::Instant::now()))
+            }
+            None => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+        let shutdown = match msg {
+            Ok(BatchMsg::Updates(changelog)) => {
+                for change in changelog {
+                    if is_batchable(&change) {
+                        deadline.get_or_insert_with(|| time::Instant::now() + interval);
+                        pending.push(change);
+                    } else {
+                        if !pending.is_empty() {
+                            dispatch_batch(&listeners, &mem::take(&mut pending));
+                            deadline = None;
+                        }
+                        dispatch_updates(&listeners, &[change]);
+                    }
+                }
+                continue;
+            }
+            Ok(BatchMsg::Flush) | Err(mpsc::RecvTimeoutError::Timeout) => false,
+            Err(mpsc::RecvTimeoutError::Disconnected) => true,
+        };
+        if !pending.is_empty() {
+            dispatch_batch(&listeners, &mem::take(&mut pending));
+        }
+        deadline = None;
+        if shutdown {
+            break;
+        }
+    }
+}
+
+// Reorgs and replaced transactions invalidate previously sent state, which a BatchUpdate has no
+// way to express
+fn is_batchable(change: &IndexChange) -> bool {
+    !matches!(
+        change,
+        IndexChange::Reorg(..) | IndexChange::TransactionReplaced(_)
+    )
+}
+
+fn dispatch_batch(listeners: &Listeners, changelog: &[IndexChange]) {
+    let mut listeners = listeners.lock().unwrap();
+    if listeners.is_empty() {
+        return;
+    }
+    info!(
+        "sending a batch of {} update(s) to {} sse/ws client(s)",
+        changelog.len(),
+        listeners.len()
+    );
+    // coalesce the changes matching each listener's filter, dropping unresponsive listeners
+    listeners.retain(|listener| {
+        let changes = changelog
+            .iter()
+            .filter(|change| listener.filter.matches(change));
+        IndexChange::batch(changes).is_none_or(|batch| listener.tx.send(batch).is_ok())
+    })
+}
+
+// Get the change if the subscription is interested in it. `BatchUpdate`s are narrowed down to
+// the subscribed scripthashes and chain tip.
+fn subscribed_change(subscription: &Subscription, change: &IndexChange) -> Option<IndexChange> {
+    match change {
+        IndexChange::BatchUpdate {
+            changed_scripthashes,
+            new_tip,
+            ..
+        } => {
+            let changed_scripthashes: Vec<ScriptHash> = changed_scripthashes
+                .iter()
+                .filter(|scripthash| subscription.wants_scripthash(scripthash))
+                .copied()
+                .collect();
+            let new_tip = new_tip.filter(|_| subscription.wants_blocks());
+            // subscriptions are scripthash-based and don't receive transaction events
+            (!changed_scripthashes.is_empty() || new_tip.is_some()).do_then(|| {
+                IndexChange::BatchUpdate {
+                    changed_scripthashes,
+                    changed_txids: vec![],
+                    new_tip,
+                }
+            })
+        }
+        _ => subscription.matches(change).do_then(|| change.clone()),
+    }
+}
+
 // Create a stream of real-time changelog events matching `filter`, optionally also including
 // historical events occuring after `synced-tip`
-fn make_sse_stream(
+fn make_sse_stream<S: Store>(
     filter: ChangelogFilter,
     listeners: Listeners,
-    query: &Query,
+    query: &Query<S>,
 ) -> Result<impl Stream<Item = Result<Event, warp::Error>>, Error> {
     debug!("subscribing sse client with {:?}", filter);
 
@@ -614,27 +1257,127 @@ fn make_sse_stream(
     .filter(move |change| filter.matches(change));
     // TODO don't produce unwanted events to begin with instead of filtering them
 
+    let amount_format = query.amount_format();
     Ok(tokio_stream::iter(changelog)
         .chain(rx)
-        .map(make_sse_msg)
+        .map(move |change| make_sse_msg(change, amount_format))
         .map(Ok))
 }
 
-fn make_sse_msg(change: IndexChange) -> Event {
+fn make_sse_msg(change: IndexChange, amount_format: AmountFormat) -> Event {
     match &change {
-        IndexChange::ChainTip(blockid) => {
+        IndexChange::ChainTip(blockid)
+        | IndexChange::BatchUpdate {
+            new_tip: Some(blockid),
+            ..
+        } => {
             // set the synced tip as the sse identifier field, so the client will send it back to
             // us on reconnection via the Last-Event-Id header.
             Event::default()
                 .id(blockid.to_string())
-                .json_data(change)
+                .json_data(amount_format.wrap(&change))
                 .unwrap()
         }
-        _ => Event::default().json_data(change).unwrap(),
+        _ => Event::default()
+            .json_data(amount_format.wrap(&change))
+            .unwrap(),
+    }
+}
+
+fn ws_route(
+    listeners: Listeners,
+    amount_format: AmountFormat,
+) -> impl Filter<Extract = (impl Reply,), Error = warp::Rejection> + Clone {
+    let listeners = warp::any().map(move || Arc::clone(&listeners));
+    warp::path!("ws")
+        .and(warp::ws())
+        .and(listeners)
+        .map(move |ws: Ws, listeners: Listeners| {
+            ws.on_upgrade(move |socket| handle_ws_client(socket, listeners, amount_format))
+        })
+}
+
+// Stream the changelog events matching the client's subscriptions over the WebSocket, while
+// handling its subscription requests. Chain tip events are always sent.
+async fn handle_ws_client(socket: WebSocket, listeners: Listeners, amount_format: AmountFormat) {
+    let (mut ws_tx, mut ws_rx) = futures_util::StreamExt::split(socket);
+
+    // register with a match-all filter, the events are filtered by the client's subscription below
+    let (tx, rx) = tmpsc::unbounded_channel();
+    let mut rx = UnboundedReceiverStream::new(rx);
+    listeners.lock().unwrap().push(Listener {
+        tx,
+        filter: ChangelogFilter::default(),
+    });
+
+    let mut subscription = Subscription::default();
+    subscription.subscribe_blocks();
+
+    debug!("ws client connected");
+
+    loop {
+        let reply = tokio::select! {
+            msg = ws_rx.next() => match msg {
+                Some(Ok(msg)) if msg.is_text() => {
+                    handle_ws_request(&mut subscription, msg.to_str().unwrap())
+                }
+                Some(Ok(msg)) if msg.is_close() => break,
+                // pings are answered automatically, binary messages are ignored
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => {
+                    debug!("ws client error: {}", e);
+                    break;
+                }
+                None => break,
+            },
+            change = rx.next() => match change {
+                Some(change) => match subscribed_change(&subscription, &change) {
+                    Some(change) => serde_json::to_value(amount_format.wrap(&change)).unwrap(),
+                    None => continue,
+                },
+                None => break,
+            },
+        };
+        if ws_tx.send(Message::text(reply.to_string())).await.is_err() {
+            break;
+        }
+    }
+
+    debug!("ws client disconnected");
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+enum WsRequest {
+    Subscribe(Vec<ScriptHash>),
+    Unsubscribe(Vec<ScriptHash>),
+    Ping,
+}
+
+fn handle_ws_request(subscription: &mut Subscription, msg: &str) -> serde_json::Value {
+    let request = match serde_json::from_str::<WsRequest>(msg) {
+        Ok(request) => request,
+        Err(e) => return json!({ "error": e.to_string() }),
+    };
+    trace!("ws request: {:?}", request);
+    match request {
+        WsRequest::Subscribe(scripthashes) => {
+            for scripthash in &scripthashes {
+                subscription.subscribe_scripthash(*scripthash);
+            }
+            json!({ "subscribed": scripthashes })
+        }
+        WsRequest::Unsubscribe(scripthashes) => {
+            for scripthash in &scripthashes {
+                subscription.unsubscribe_scripthash(scripthash);
+            }
+            json!({ "unsubscribed": scripthashes })
+        }
+        WsRequest::Ping => json!("pong"),
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, Default)]
 #[serde(rename_all = "kebab-case")]
 struct ChangelogFilter {
     #[serde(default, deserialize_with = "deser_synced_tip")]
@@ -717,6 +1460,137 @@ struct UtxoOptions {
     #[serde(default)]
     min_conf: usize,
     include_unsafe: Option<bool>,
+    #[serde(default)]
+    include_dust: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct HistoryOptions {
+    #[serde(default)]
+    order: SortOrder,
+    limit: Option<usize>,
+    #[serde(default, deserialize_with = "deser_history_cursor")]
+    after: Option<(TxStatus, Txid)>,
+    #[serde(default)]
+    compact: bool,
+    // only include transactions funding (`incoming`) or spending from (`outgoing`) the script
+    direction: Option<TxDirection>,
+}
+
+// History cursors are formatted as `<block_height>:<txid>`, or `mempool:<txid>` for unconfirmed
+fn parse_history_cursor(s: &str) -> Result<(TxStatus, Txid), Error> {
+    let mut parts = s.splitn(2, ':');
+    let status = match parts.next().required()? {
+        "mempool" => TxStatus::Unconfirmed,
+        height => TxStatus::Confirmed(height.parse()?),
+    };
+    let txid = Txid::from_hex(parts.next().or_err("missing cursor txid")?)?;
+    Ok((status, txid))
+}
+
+fn fmt_history_cursor((status, txid): (TxStatus, Txid)) -> String {
+    match status {
+        TxStatus::Confirmed(height) => format!("{}:{}", height, txid),
+        TxStatus::Unconfirmed | TxStatus::Conflicted => format!("mempool:{}", txid),
+    }
+}
+
+fn deser_history_cursor<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Option<(TxStatus, Txid)>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    let cursor =
+        parse_history_cursor(&s).map_err(|err| serde::de::Error::custom(err.to_string()))?;
+    Ok(Some(cursor))
+}
+
+// Reply with `value` as JSON, serializing its amounts using the configured `AmountFormat`
+fn json_reply<S: Store, T: serde::Serialize + ?Sized>(query: &Query<S>, value: &T) -> reply::Json {
+    reply::json(&query.amount_format().wrap(value))
+}
+
+// Reply with the history page, setting the X-Next-Cursor header if there are more entries
+fn history_page_reply<S: Store, T: serde::Serialize>(
+    query: &Query<S>,
+    entries: &[T],
+    next_cursor: Option<(TxStatus, Txid)>,
+) -> warp::reply::Response {
+    let mut resp = json_reply(query, entries).into_response();
+    if let Some(next_cursor) = next_cursor {
+        let cursor = fmt_history_cursor(next_cursor).parse().unwrap();
+        resp.headers_mut().insert("X-Next-Cursor", cursor);
+    }
+    resp
+}
+
+#[derive(Deserialize, Debug)]
+struct HistorySinceOptions {
+    // comma-separated list of the unconfirmed txids the client already has
+    #[serde(default, deserialize_with = "deser_txid_set")]
+    known_mempool: HashSet<Txid>,
+}
+
+fn deser_txid_set<'de, D>(deserializer: D) -> std::result::Result<HashSet<Txid>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    s.split(',')
+        .filter(|txid| !txid.is_empty())
+        .map(|txid| Txid::from_str(txid).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+#[derive(Deserialize, Debug)]
+struct ChangeAddressOptions {
+    wallet: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Bip21Options {
+    amount: Option<u64>,
+    label: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct AddressRangeOptions {
+    #[serde(default)]
+    start: u32,
+    #[serde(default = "default_address_range_count")]
+    count: u32,
+}
+
+fn default_address_range_count() -> u32 {
+    20
+}
+
+#[derive(Deserialize, Debug)]
+struct FeeEstimateOptions {
+    #[serde(default, deserialize_with = "deser_estimate_mode")]
+    mode: Option<EstimateMode>,
+}
+
+fn deser_estimate_mode<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Option<EstimateMode>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    Ok(Some(match s.to_lowercase().as_str() {
+        "unset" => EstimateMode::Unset,
+        "economical" => EstimateMode::Economical,
+        "conservative" => EstimateMode::Conservative,
+        _ => {
+            return Err(serde::de::Error::custom(format!(
+                "invalid estimate mode {}",
+                s
+            )))
+        }
+    }))
 }
 
 #[derive(Deserialize, Debug)]
@@ -724,8 +1598,174 @@ struct BroadcastBody {
     tx_hex: String,
 }
 
-fn compact_history(tx_hist: &store::HistoryEntry) -> serde_json::Value {
-    json!([tx_hist.txid, tx_hist.status])
+#[derive(Deserialize, Debug)]
+struct AmountAlertBody {
+    min_amount: u64,
+    #[serde(default)]
+    one_shot: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct TrackAddressBody {
+    #[serde(default = "default_rescan_since")]
+    rescan_since: RescanSince,
+}
+
+fn default_rescan_since() -> RescanSince {
+    RescanSince::Now
+}
+
+#[derive(Deserialize, Debug)]
+struct SignMessageBody {
+    message: String,
+}
+
+// A compact history entry, serialized as a `[txid, block_height]` tuple
+struct CompactHistory(Txid, TxStatus);
+
+impl CompactHistory {
+    fn new(tx_hist: &store::HistoryEntry) -> Self {
+        CompactHistory(tx_hist.txid, tx_hist.status)
+    }
+}
+
+impl serde::Serialize for CompactHistory {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeTuple;
+        let mut tuple = serializer.serialize_tuple(2)?;
+        tuple.serialize_element(&self.0)?;
+        tuple.serialize_element(&self.1)?;
+        tuple.end()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ContentEncoding {
+    Gzip,
+    Deflate,
+}
+
+impl ContentEncoding {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Deflate => "deflate",
+        }
+    }
+
+    // Pick the preferred supported encoding from an Accept-Encoding header value,
+    // favouring gzip when both are equally acceptable
+    fn negotiate(accept_encoding: &str) -> Option<Self> {
+        let (mut gzip_q, mut deflate_q, mut wildcard_q) = (None, None, None);
+        for item in accept_encoding.split(',') {
+            let mut parts = item.split(';').map(str::trim);
+            let coding = parts.next().unwrap_or("").to_lowercase();
+            let q = parts
+                .find_map(|param| param.strip_prefix("q="))
+                .map_or(Some(1.0), |q| f32::from_str(q).ok());
+            match coding.as_str() {
+                "gzip" | "x-gzip" => gzip_q = q,
+                "deflate" => deflate_q = q,
+                "*" => wildcard_q = q,
+                _ => (),
+            }
+        }
+        let gzip_q = gzip_q.or(wildcard_q).unwrap_or(0.0);
+        let deflate_q = deflate_q.or(wildcard_q).unwrap_or(0.0);
+        if gzip_q > 0.0 && gzip_q >= deflate_q {
+            Some(ContentEncoding::Gzip)
+        } else if deflate_q > 0.0 {
+            Some(ContentEncoding::Deflate)
+        } else {
+            None
+        }
+    }
+
+    fn encode(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        use flate2::{write, Compression};
+        use std::io::Write;
+        match self {
+            ContentEncoding::Gzip => {
+                let mut encoder = write::GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            // HTTP's "deflate" is the zlib format
+            ContentEncoding::Deflate => {
+                let mut encoder = write::ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+// Wrap the handler to compress responses of at least `min_size` bytes (when set)
+fn with_compression<F, R>(
+    handler: F,
+    min_size: Option<usize>,
+) -> impl Filter<Extract = (reply::Response,), Error = warp::Rejection> + Clone
+where
+    F: Filter<Extract = (R,), Error = warp::Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    warp::header::optional::<String>("accept-encoding")
+        .and(handler)
+        .and_then(move |accept_encoding: Option<String>, reply: R| {
+            compress_reply(reply.into_response(), accept_encoding, min_size)
+        })
+}
+
+async fn compress_reply(
+    resp: reply::Response,
+    accept_encoding: Option<String>,
+    min_size: Option<usize>,
+) -> Result<reply::Response, convert::Infallible> {
+    use warp::hyper::body::{self, HttpBody};
+
+    let min_size = some_or_ret!(min_size, Ok(resp));
+    // Streamed responses (like SSE) have no exact size and are never compressed
+    let size = some_or_ret!(HttpBody::size_hint(resp.body()).exact(), Ok(resp));
+    if size < min_size as u64 || resp.headers().contains_key(header::CONTENT_ENCODING) {
+        return Ok(resp);
+    }
+
+    let (mut parts, body) = resp.into_parts();
+    parts.headers.append(
+        header::VARY,
+        header::HeaderValue::from_static("accept-encoding"),
+    );
+    let encoding = accept_encoding
+        .as_deref()
+        .and_then(ContentEncoding::negotiate);
+    let encoding = some_or_ret!(encoding, Ok(reply::Response::from_parts(parts, body)));
+
+    let data = match body::to_bytes(body).await {
+        Ok(data) => data,
+        Err(e) => {
+            warn!("failed reading response body: {:?}", e);
+            let resp = reply::with_status("", StatusCode::INTERNAL_SERVER_ERROR);
+            return Ok(resp.into_response());
+        }
+    };
+    let body = match encoding.encode(&data) {
+        Ok(compressed) => {
+            parts.headers.remove(header::CONTENT_LENGTH);
+            parts.headers.insert(
+                header::CONTENT_ENCODING,
+                header::HeaderValue::from_static(encoding.as_str()),
+            );
+            compressed.into()
+        }
+        Err(e) => {
+            warn!("failed compressing response: {:?}", e);
+            data.into()
+        }
+    };
+    Ok(reply::Response::from_parts(parts, body))
 }
 
 // Handle errors produced by route handlers
@@ -778,3 +1818,200 @@ fn get_error_status(e: &Error) -> StatusCode {
 #[derive(Debug)]
 struct WarpError(Error);
 impl warp::reject::Reject for WarpError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::TxStatus;
+
+    #[tokio::test]
+    async fn test_ws_subscription() {
+        let listeners: Listeners = Arc::new(Mutex::new(Vec::new()));
+        let mut client = warp::test::ws()
+            .path("/ws")
+            .handshake(ws_route(listeners.clone(), AmountFormat::Sat))
+            .await
+            .unwrap();
+
+        let (scripthash, other_scripthash) =
+            (ScriptHash::default(), "11".repeat(32).parse().unwrap());
+        let recv_json = |msg: Message| -> serde_json::Value {
+            serde_json::from_str(msg.to_str().unwrap()).unwrap()
+        };
+
+        client.send_text(r#""ping""#).await;
+        assert_eq!(recv_json(client.recv().await.unwrap()), json!("pong"));
+
+        client
+            .send_text(json!({ "subscribe": [scripthash] }).to_string())
+            .await;
+        let reply = recv_json(client.recv().await.unwrap());
+        assert_eq!(reply, json!({ "subscribed": [scripthash] }));
+
+        let make_change = |scripthash, vout| {
+            let outpoint = OutPoint::new(Txid::default(), vout);
+            IndexChange::TxoFunded(outpoint, scripthash, 1000, TxStatus::Unconfirmed)
+        };
+        let changelog = vec![make_change(other_scripthash, 0), make_change(scripthash, 1)];
+        dispatch_updates(&listeners, &changelog);
+
+        // only the event for the subscribed scripthash is received
+        let event = recv_json(client.recv().await.unwrap());
+        assert_eq!(event, serde_json::to_value(&changelog[1]).unwrap());
+    }
+
+    #[test]
+    fn test_batch_updates() {
+        let listeners: Listeners = Arc::new(Mutex::new(Vec::new()));
+        let (tx, mut rx) = tmpsc::unbounded_channel();
+        listeners.lock().unwrap().push(Listener {
+            tx,
+            filter: ChangelogFilter::default(),
+        });
+
+        let (batch_tx, batch_rx) = mpsc::channel();
+        let batch_listeners = listeners.clone();
+        let interval = time::Duration::from_secs(60);
+        let batcher = thread::spawn(move || batch_updates(batch_listeners, interval, batch_rx));
+
+        let (scripthash, other_scripthash): (ScriptHash, ScriptHash) =
+            (ScriptHash::default(), "11".repeat(32).parse().unwrap());
+        let txid = Txid::default();
+        let make_change = |scripthash, vout| {
+            let outpoint = OutPoint::new(txid, vout);
+            IndexChange::TxoFunded(outpoint, scripthash, 1000, TxStatus::Unconfirmed)
+        };
+        let tip = BlockId(100, BlockHash::default());
+        let send = |changelog| batch_tx.send(BatchMsg::Updates(changelog)).unwrap();
+
+        // updates are coalesced until the interval elapses or the end of the sync pass
+        send(vec![
+            IndexChange::Transaction(txid, TxStatus::Unconfirmed),
+            make_change(other_scripthash, 0),
+            make_change(scripthash, 1),
+        ]);
+        send(vec![make_change(scripthash, 2), IndexChange::ChainTip(tip)]);
+        batch_tx.send(BatchMsg::Flush).unwrap();
+
+        let batch = rx.blocking_recv().unwrap();
+        assert_eq!(
+            serde_json::to_value(&batch).unwrap(),
+            json!({
+                "category": "BatchUpdate",
+                "params": {
+                    "changed_scripthashes": [scripthash, other_scripthash],
+                    "changed_txids": [txid],
+                    "new_tip": tip,
+                }
+            })
+        );
+        assert!(rx.try_recv().is_err());
+
+        // reorgs and replaced transactions are sent out as-is, following the pending batch
+        let reorg = IndexChange::Reorg(100, BlockHash::default(), BlockHash::default());
+        let replaced = IndexChange::TransactionReplaced(txid);
+        send(vec![
+            make_change(scripthash, 3),
+            reorg.clone(),
+            replaced.clone(),
+        ]);
+        drop(batch_tx);
+        batcher.join().unwrap();
+        let changes: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|change| serde_json::to_value(&change).unwrap())
+            .collect();
+        assert_eq!(changes.len(), 3);
+        assert_eq!(changes[0]["category"], "BatchUpdate");
+        assert_eq!(changes[1], serde_json::to_value(&reorg).unwrap());
+        assert_eq!(changes[2], serde_json::to_value(&replaced).unwrap());
+
+        // websocket clients only get the subscribed parts of the batch
+        let mut subscription = Subscription::default();
+        assert!(subscribed_change(&subscription, &batch).is_none());
+        subscription.subscribe_scripthash(other_scripthash);
+        assert!(matches!(
+            subscribed_change(&subscription, &batch),
+            Some(IndexChange::BatchUpdate { changed_scripthashes, new_tip: None, .. })
+                if changed_scripthashes == vec![other_scripthash]
+        ));
+    }
+
+    #[test]
+    fn test_negotiate_encoding() {
+        use ContentEncoding::{Deflate, Gzip};
+        assert_eq!(ContentEncoding::negotiate("gzip, deflate, br"), Some(Gzip));
+        assert_eq!(ContentEncoding::negotiate("deflate"), Some(Deflate));
+        assert_eq!(
+            ContentEncoding::negotiate("gzip;q=0.5, deflate"),
+            Some(Deflate)
+        );
+        assert_eq!(ContentEncoding::negotiate("gzip;q=0, *"), Some(Deflate));
+        assert_eq!(ContentEncoding::negotiate("*"), Some(Gzip));
+        assert_eq!(ContentEncoding::negotiate("br, identity"), None);
+        assert_eq!(ContentEncoding::negotiate(""), None);
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let body = "x".repeat(2000);
+        let route = {
+            let body = body.clone();
+            warp::path!("big")
+                .map(move || body.clone())
+                .or(warp::path!("small").map(|| "small".to_string()))
+                .unify()
+        };
+        let route = with_compression(route, Some(1024));
+
+        let resp = warp::test::request()
+            .path("/big")
+            .header("accept-encoding", "gzip")
+            .reply(&route)
+            .await;
+        assert_eq!(resp.headers()[header::CONTENT_ENCODING], "gzip");
+        assert!(resp.body().len() < body.len());
+        let mut decoded = String::new();
+        GzDecoder::new(&resp.body()[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, body);
+
+        // small payloads, clients that don't accept compression or a disabled
+        // compression option all get the original body
+        let resp = warp::test::request()
+            .path("/small")
+            .header("accept-encoding", "gzip")
+            .reply(&route)
+            .await;
+        assert!(!resp.headers().contains_key(header::CONTENT_ENCODING));
+        assert_eq!(resp.body(), "small");
+
+        let resp = warp::test::request().path("/big").reply(&route).await;
+        assert!(!resp.headers().contains_key(header::CONTENT_ENCODING));
+        assert_eq!(resp.body().len(), body.len());
+
+        let route = with_compression(warp::path!("big").map(move || body.clone()), None);
+        let resp = warp::test::request()
+            .path("/big")
+            .header("accept-encoding", "gzip")
+            .reply(&route)
+            .await;
+        assert!(!resp.headers().contains_key(header::CONTENT_ENCODING));
+    }
+
+    #[test]
+    fn test_compact_history() {
+        let txid = Txid::default();
+        let entries = vec![
+            CompactHistory(txid, TxStatus::Confirmed(105)),
+            CompactHistory(txid, TxStatus::Unconfirmed),
+        ];
+        assert_eq!(
+            serde_json::to_value(&entries).unwrap(),
+            json!([[txid, 105], [txid, null]])
+        );
+    }
+}
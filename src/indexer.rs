@@ -1,43 +1,208 @@
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+#[cfg(feature = "http")]
+use std::sync::RwLock;
 use std::sync::{mpsc, Arc};
-use std::{fmt, thread, time};
+use std::{fmt, mem, thread, time};
 
 use serde::Serialize;
 
-use bitcoin::{Address, BlockHash, OutPoint, Txid};
-use bitcoincore_rpc::json::GetTransactionResultDetailCategory as TxCategory;
-use bitcoincore_rpc::{Client as RpcClient, RpcApi};
+use bitcoin::{Address, BlockHash, OutPoint, Script, Transaction, Txid};
 
-use crate::error::Result;
-use crate::store::{FundingInfo, MemoryStore, SpendingInfo, TxEntry};
-use crate::types::{BlockId, InPoint, RescanSince, ScriptHash, TxStatus};
-use crate::util::bitcoincore_ext::{ListTransactionResult, RpcApiExt};
-use crate::util::progress::Progress;
-use crate::wallet::{KeyOrigin, WalletWatcher};
+#[cfg(feature = "op-return")]
+use bitcoin::blockdata::script::Instruction;
+use bitcoincore_rpc::json::{
+    GetTransactionResult, GetTransactionResultDetail,
+    GetTransactionResultDetailCategory as TxCategory, Utxo as ScanTxOutUtxo,
+};
+use bitcoincore_rpc::{self as rpc, Client as RpcClient, Result as RpcResult};
 
-pub struct Indexer {
-    rpc: Arc<RpcClient>,
+use crate::error::{fmt_error_chain, BwtError, Result};
+use crate::store::{self, FundingInfo, MemoryStore, SpendingInfo, Store, TxEntry, TxFee};
+use crate::types::{BlockId, InPoint, RescanSince, ScriptHash, TxStatus};
+use crate::util::bitcoincore_ext::{
+    ListSinceBlockResult, ListTransactionResult, NodeRpc, RPC_INVALID_ADDRESS_OR_KEY,
+};
+use crate::util::descriptor::Checksum;
+use crate::util::progress::{IndexProgress, Progress};
+use crate::util::{amount, BoolThen};
+use crate::wallet::{GapRescan, KeyOrigin, WalletWatcher};
+
+pub struct Indexer<S: Store = MemoryStore, R: NodeRpc = RpcClient> {
+    config: IndexerConfig,
+    rpc: Arc<R>,
     watcher: WalletWatcher,
-    store: MemoryStore,
+    store: S,
     tip: Option<BlockId>,
     fixed_listsinceblock: bool,
+    // fingerprint of the last processed `listsinceblock` result, used to skip no-op syncs
+    last_listing: Option<u64>,
+    // unconfirmed transactions purged by the mempool expiry sweep, which bitcoind's wallet will
+    // keep listing. they are ignored unless they confirm.
+    swept_txids: HashSet<Txid>,
+    // the scriptPubKeys watched for when scanning blocks directly (with `block_scan_from`)
+    scan_targets: HashMap<Script, (Address, KeyOrigin)>,
+    // the mempool transactions seen during the last raw mempool scan
+    scanned_mempool: HashSet<Txid>,
+    // changes collected by syncs that completed after their caller went away (see `sync_async()`)
+    // or by single transaction refreshes (see `refresh_tx()`), to be returned by the next sync
+    unclaimed_changes: Vec<IndexChange>,
+    // alerts for incoming payments, checked against the changes found by each sync
+    amount_alerts: AmountAlerts,
+    // whether the last sync completed without the chain tip moving under it (see `tolerate_stale_tip`)
+    tip_stable: bool,
+    // the outcome of the last regular sync, for health reporting
+    last_sync: Option<SyncReport>,
+}
+
+/// The outcome of a regular `sync()` run
+#[derive(Debug, Clone, Serialize)]
+pub struct SyncReport {
+    /// When the sync finished, as a unix timestamp
+    pub time: u64,
+    /// How long the sync took, in milliseconds
+    pub duration_ms: u64,
+    /// The error the sync failed with, if any
+    pub error: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct IndexerConfig {
+    pub max_reorg_depth: Option<u32>,
+    pub dust_threshold: Option<u64>,
+    pub mempool_expiry: time::Duration,
+    pub assume_confirmed_below: Option<u32>,
+    pub block_scan_from: Option<u32>,
+    pub prevout_batch_size: usize,
+    pub finality_confirmations: u32,
+    pub tolerate_stale_tip: bool,
+    pub conflicted_retention: Option<time::Duration>,
+}
+
+impl<R: NodeRpc> Indexer<MemoryStore, R> {
+    pub fn new(config: IndexerConfig, rpc: Arc<R>, watcher: WalletWatcher) -> Result<Self> {
+        let store = MemoryStore::with_dust_threshold(config.dust_threshold)
+            .with_conflicted_retention(config.conflicted_retention);
+        Self::with_store(config, rpc, watcher, store)
+    }
+}
+
+impl<S: Store, R: NodeRpc> Indexer<S, R> {
+    /// Create a new indexer with an empty store that tracks the same wallets, to rebuild the
+    /// index from scratch without blocking this one. See `reindex()` and `replace_with()`.
+    pub fn fork_empty(&self) -> Self {
+        Indexer {
+            config: self.config.clone(),
+            rpc: self.rpc.clone(),
+            watcher: self.watcher.clone(),
+            store: self.store.fork_empty(),
+            tip: None,
+            fixed_listsinceblock: self.fixed_listsinceblock,
+            last_listing: None,
+            swept_txids: self.swept_txids.clone(),
+            scan_targets: self.scan_targets.clone(),
+            scanned_mempool: HashSet::new(),
+            unclaimed_changes: vec![],
+            amount_alerts: AmountAlerts::default(),
+            tip_stable: true,
+            last_sync: None,
+        }
+    }
+
+    /// Sync the full wallet history into the store, without importing addresses. Meant to be used
+    /// on an indexer created with `fork_empty()`.
+    pub fn reindex(&mut self) -> Result<()> {
+        let timer = time::Instant::now();
+        let synced_tip = self.sync_history(true, &mut Changelog::new(false), None)?;
+        self.sync_mempool(/*force_refresh=*/ true);
+        self.sync_mempool_descendants();
+        self.tip = Some(synced_tip);
+        self.prune_deep_history(synced_tip.height());
+
+        let stats = self.store.stats();
+        info!(
+            "completed reindex in {:?} up to height {} (total {} transactions, {} addresses and {} unspent outputs)",
+            timer.elapsed(),
+            synced_tip.0,
+            stats.transaction_count,
+            stats.scripthash_count,
+            stats.utxo_count,
+        );
+        Ok(())
+    }
+
+    /// Swap in the store rebuilt by a forked indexer, along with the tip it was synced to and the
+    /// mempool transactions it scanned. Changes that happened since will be picked up by the next sync.
+    pub fn replace_with(&mut self, rebuilt: Self) {
+        self.store = rebuilt.store;
+        self.tip = rebuilt.tip;
+        self.scanned_mempool = rebuilt.scanned_mempool;
+        // keep the targets added at runtime while the rebuilt indexer was syncing
+        self.scan_targets.extend(rebuilt.scan_targets);
+        self.last_listing = None;
+    }
+}
+
+#[cfg(feature = "http")]
+impl<S: Store, R: NodeRpc> Indexer<S, R> {
+    /// Run a regular `sync()` on tokio's blocking thread pool, for use within async runtimes.
+    ///
+    /// This is cancel-safe: dropping the returned future does not interrupt the sync, which keeps
+    /// running to completion in the background while holding the indexer lock. Its changes are
+    /// kept and returned by the next sync, so that no updates are lost.
+    pub async fn sync_async(indexer: Arc<RwLock<Self>>) -> Result<Vec<IndexChange>> {
+        let (result_tx, result_rx) = tokio::sync::oneshot::channel();
+        // the indexer lock is only ever taken on the blocking thread pool, never on the async runtime
+        tokio::task::spawn_blocking(move || {
+            let mut indexer = indexer.write().unwrap();
+            let result = indexer.sync();
+            if let Err(Ok(changes)) = result_tx.send(result) {
+                // the future was dropped, keep the changes for the next sync
+                indexer.unclaimed_changes = changes;
+            }
+        });
+        result_rx.await?
+    }
 }
 
-impl Indexer {
-    pub fn new(rpc: Arc<RpcClient>, watcher: WalletWatcher) -> Result<Self> {
+impl<S: Store, R: NodeRpc> Indexer<S, R> {
+    /// Create an indexer backed by a custom `Store` implementation
+    pub fn with_store(
+        config: IndexerConfig,
+        rpc: Arc<R>,
+        mut watcher: WalletWatcher,
+        store: S,
+    ) -> Result<Self> {
         // listsinceblock was racey in Bitcoin Core < 0.21: https://github.com/bitcoin/bitcoin/issues/19338
         let fixed_listsinceblock = rpc.version()? >= 210000;
 
+        let scan_targets = if config.block_scan_from.is_some() {
+            make_scan_targets(watcher.derive_scan_targets()).collect()
+        } else {
+            HashMap::new()
+        };
+
         Ok(Indexer {
+            config,
             rpc,
             watcher,
-            store: MemoryStore::new(),
+            store,
             tip: None,
             fixed_listsinceblock,
+            last_listing: None,
+            swept_txids: HashSet::new(),
+            scan_targets,
+            scanned_mempool: HashSet::new(),
+            unclaimed_changes: vec![],
+            amount_alerts: AmountAlerts::default(),
+            tip_stable: true,
+            last_sync: None,
         })
     }
 
-    pub fn store(&self) -> &MemoryStore {
+    pub fn store(&self) -> &S {
         &self.store
     }
 
@@ -49,42 +214,100 @@ impl Indexer {
         self.tip
     }
 
+    /// Whether the last sync completed against a stable chain tip. This is only false when
+    /// `tolerate_stale_tip` is enabled and the tip moved during the sync, in which case the index
+    /// may include some transactions from blocks above its synced tip until the next sync.
+    pub fn tip_stable(&self) -> bool {
+        self.tip_stable
+    }
+
+    /// The outcome of the last regular `sync()`, or None if none ran yet
+    pub fn last_sync(&self) -> Option<&SyncReport> {
+        self.last_sync.as_ref()
+    }
+
+    /// Register an alert for incoming payments to `scripthash` of at least `min_amount` satoshis,
+    /// replacing any existing alert for it. One-shot alerts are removed after firing once.
+    pub fn register_amount_alert(
+        &mut self,
+        scripthash: ScriptHash,
+        min_amount: u64,
+        one_shot: bool,
+    ) {
+        self.amount_alerts
+            .register(scripthash, min_amount, one_shot)
+    }
+
+    /// Remove the alert for `scripthash`, returning whether there was one
+    pub fn remove_amount_alert(&mut self, scripthash: &ScriptHash) -> bool {
+        self.amount_alerts.remove(scripthash)
+    }
+
+    pub fn amount_alerts(&self) -> &AmountAlerts {
+        &self.amount_alerts
+    }
+
+    /// Whether the initial sync completed. Until then, the index may be missing wallet history.
+    pub fn is_synced(&self) -> bool {
+        self.tip.is_some()
+    }
+
     // continue to sync transactions and import addresses (with rescan) until no more new addresses
     // need to be imported. the initial sync does not collect the Changelog and does not emit updates.
     pub fn initial_sync(&mut self, progress_tx: Option<mpsc::Sender<Progress>>) -> Result<()> {
         let timer = time::Instant::now();
 
         debug!("starting initial sync");
-        self.watcher.check_imports(&self.rpc)?;
+        if self.config.block_scan_from.is_none() {
+            self.watcher.check_imports(&*self.rpc)?;
+        }
 
         let mut changelog = Changelog::new(false);
         let mut synced_tip;
 
-        let shutdown_progress_thread = spawn_send_progress_thread(self.rpc.clone(), progress_tx);
+        let shutdown_progress_thread =
+            spawn_send_progress_thread(self.rpc.clone(), progress_tx.clone());
 
         while {
-            synced_tip = self.sync_transactions(true, &mut changelog)?;
-            self.watcher.do_imports(&self.rpc, /*rescan=*/ true)?
+            let progress = IndexProgress::new(progress_tx.clone());
+            synced_tip = self.sync_history(true, &mut changelog, Some(progress))?;
+            self.do_imports(/*rescan=*/ true)?
         } { /* do while */ }
 
         shutdown_progress_thread.send(()).ok();
 
         self.sync_mempool(/*force_refresh=*/ true);
+        self.sync_mempool_descendants();
 
         let stats = self.store.stats();
         info!(
-            "completed initial sync in {:?} up to height {} (total {} transactions and {} addresses)",
+            "completed initial sync in {:?} up to height {} (total {} transactions, {} addresses and {} unspent outputs)",
             timer.elapsed(),
             synced_tip.0,
             stats.transaction_count,
             stats.scripthash_count,
+            stats.utxo_count,
         );
         self.tip = Some(synced_tip);
+        self.prune_deep_history(synced_tip.height());
         Ok(())
     }
 
     // initiate a regular sync to catch up with updates and import new addresses (no rescan)
     pub fn sync(&mut self) -> Result<Vec<IndexChange>> {
+        let started = time::Instant::now();
+        let result = self.do_sync();
+        self.last_sync = Some(SyncReport {
+            time: time::SystemTime::now()
+                .duration_since(time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            duration_ms: started.elapsed().as_millis() as u64,
+            error: result.as_ref().err().map(fmt_error_chain),
+        });
+        result
+    }
+
+    fn do_sync(&mut self) -> Result<Vec<IndexChange>> {
         let mut changelog = Changelog::new(self.tip.is_some());
 
         // detect reorgs and sync the whole history from scratch when they happen
@@ -98,21 +321,52 @@ impl Indexer {
                 );
 
                 // notify clients about the reorg, but don't collect additional events (apart from
-                // ChainTip, added below)
+                // ChainTip and AmountAlert, added below). the funded outputs are still collected
+                // to fire the amount alerts for payments first seen during the re-sync.
                 changelog.push(|| IndexChange::Reorg(tip_height, *tip_hash, best_chain_hash));
-                changelog.track = false;
-
-                // XXX start syncing from N blocks backs instead of from the beginning?
-                self.tip = None;
+                changelog.untrack(/*keep_funded=*/ !self.amount_alerts.is_empty());
+
+                // re-sync from the height below which transactions are assumed to be immutable if
+                // configured, or from the beginning otherwise. listsinceblock lists the transactions
+                // confirmed after the provided block, so start from the one preceding it.
+                self.tip = match self.config.assume_confirmed_below {
+                    Some(height) if height > 0 && height <= tip_height => {
+                        let since_height = height - 1;
+                        let since_hash = self.rpc.get_block_hash(since_height as u64)?;
+                        Some(BlockId(since_height, since_hash))
+                    }
+                    _ => None,
+                };
+
+                // without listsinceblock reporting the removed transactions, purge everything
+                // confirmed above the height the blocks are re-scanned from
+                if let Some(scan_from) = self.config.block_scan_from {
+                    let rescan_height = self.tip.map_or(scan_from, |tip| tip.height() + 1);
+                    self.purge_confirmed_since(rescan_height, &mut changelog);
+                }
             }
         }
 
-        let synced_tip = self.sync_transactions(false, &mut changelog)?;
+        let synced_tip = self.sync_history(false, &mut changelog, None)?;
         let tip_updated = self.tip != Some(synced_tip);
         self.sync_mempool(/*force_refresh=*/ tip_updated);
-        self.watcher.do_imports(&self.rpc, /*rescan=*/ false)?;
+        self.sync_mempool_descendants();
+        self.sweep_stale_mempool(&mut changelog);
+        self.store.expire_conflicted();
+        self.do_imports(/*rescan=*/ false)?;
 
-        let mut changelog = changelog.into_vec();
+        if let (true, Some(prev_tip)) = (tip_updated, &self.tip) {
+            self.push_finalized(prev_tip.height(), synced_tip.height(), &mut changelog);
+        }
+
+        let (mut changelog, untracked_funded) = changelog.into_parts();
+        let alerts = self
+            .amount_alerts
+            .check(changelog.iter().chain(&untracked_funded));
+        changelog.extend(alerts);
+        if !self.unclaimed_changes.is_empty() {
+            changelog.splice(0..0, mem::take(&mut self.unclaimed_changes));
+        }
 
         if tip_updated {
             info!(
@@ -123,6 +377,12 @@ impl Indexer {
 
             changelog.push(IndexChange::ChainTip(synced_tip));
             self.tip = Some(synced_tip);
+            self.prune_deep_history(synced_tip.height());
+            self.amount_alerts.prune(
+                &self.store,
+                synced_tip.height(),
+                self.config.finality_confirmations.max(1),
+            );
         }
 
         if !changelog.is_empty() && log_enabled!(log::Level::Debug) {
@@ -134,10 +394,92 @@ impl Indexer {
         Ok(changelog)
     }
 
+    // emit TransactionFinal events for the transactions that reached the configured number of
+    // finality confirmations as the tip advanced from `prev_tip_height` to `tip_height`
+    fn push_finalized(&self, prev_tip_height: u32, tip_height: u32, changelog: &mut Changelog) {
+        // transactions confirmed at height h become final once the tip reaches h+threshold-1
+        let threshold = self.config.finality_confirmations.max(1);
+        let min_height = (prev_tip_height + 2).saturating_sub(threshold);
+        let max_height = (tip_height + 1).saturating_sub(threshold);
+        if !changelog.track || max_height < min_height {
+            return;
+        }
+        let store = &self.store;
+        changelog.with(|changes| {
+            changes.extend(
+                store
+                    .get_history_since(min_height)
+                    .into_iter()
+                    .filter_map(|hist| match hist.status {
+                        TxStatus::Confirmed(height) if height <= max_height => {
+                            Some(IndexChange::TransactionFinal(hist.txid, height))
+                        }
+                        _ => None,
+                    }),
+            )
+        });
+    }
+
+    // drop the details of spent outputs buried deeper than the configured max reorg depth
+    fn prune_deep_history(&mut self, tip_height: u32) {
+        if let Some(max_reorg_depth) = self.config.max_reorg_depth {
+            if let Some(max_height) = tip_height.checked_sub(max_reorg_depth) {
+                self.store.prune_below(max_height);
+            }
+        }
+    }
+
+    // purge unconfirmed transactions that linger in the index after being evicted from the mempool
+    fn sweep_stale_mempool(&mut self, changelog: &mut Changelog) {
+        let rpc = &self.rpc;
+        let swept_txids =
+            store::sweep_stale_mempool(&mut self.store, self.config.mempool_expiry, |txid| {
+                matches!(rpc.get_mempool_entry_opt(txid), Ok(None))
+            });
+        for txid in swept_txids {
+            info!("purged expired mempool transaction {}", txid);
+            changelog.push(|| IndexChange::TransactionReplaced(txid));
+            self.swept_txids.insert(txid);
+        }
+    }
+
+    // sync the wallet history using the bitcoind wallet, or by scanning blocks directly if enabled
+    fn sync_history(
+        &mut self,
+        refresh_outgoing: bool,
+        changelog: &mut Changelog,
+        progress: Option<IndexProgress>,
+    ) -> Result<BlockId> {
+        if let Some(scan_from) = self.config.block_scan_from {
+            let synced_tip = self.sync_blocks(scan_from, changelog, progress)?;
+            self.scan_mempool(changelog)?;
+            Ok(synced_tip)
+        } else {
+            self.sync_transactions(refresh_outgoing, changelog, progress)
+        }
+    }
+
+    // import new addresses into the bitcoind wallet, or start watching for them when scanning
+    // blocks. returns whether a rescan is needed to pick up their history.
+    fn do_imports(&mut self, rescan: bool) -> Result<bool> {
+        if self.config.block_scan_from.is_some() {
+            let new_targets = self.watcher.derive_scan_targets();
+            self.scan_targets.extend(make_scan_targets(new_targets));
+            // scanning picks up new addresses as it goes, no need to rescan
+            Ok(false)
+        } else {
+            self.watcher.do_imports(&*self.rpc, rescan)
+        }
+    }
+
+    // sync the wallet transactions that changed since the last indexed tip using `listsinceblock`,
+    // purging the ones it reports as `removed` by reorgs. progress is only reported during the
+    // initial sync.
     fn sync_transactions(
         &mut self,
         refresh_outgoing: bool,
         changelog: &mut Changelog,
+        mut progress: Option<IndexProgress>,
     ) -> Result<BlockId> {
         // To deal with listsinceblock's non-atomicity in bitcoin < 0.21, we need to record the
         // tip before and make sure it didn't move during the listsinceblock rpc call.
@@ -151,10 +493,17 @@ impl Indexer {
         let prev_indexed_tip = self.tip.as_ref().map(|tip| tip.hash());
         let result = self.rpc.list_since_block_(prev_indexed_tip)?;
 
-        // Ensure consistent results for `listsinceblock` in < 0.21.
+        // Ensure consistent results for `listsinceblock` in < 0.21. With `tolerate_stale_tip`, keep
+        // the results and sync against the tip captured before the call instead of retrying. The
+        // transactions from the newer blocks will be listed again by the next sync.
+        self.tip_stable = true;
         if tip_sanity_check.map_or(false, |tip| result.lastblock != *tip.hash()) {
-            warn!("chain tip moved while reading listsinceblock, retrying...");
-            return self.sync_transactions(refresh_outgoing, changelog);
+            if !self.config.tolerate_stale_tip {
+                warn!("chain tip moved while reading listsinceblock, retrying...");
+                return self.sync_transactions(refresh_outgoing, changelog, progress);
+            }
+            warn!("chain tip moved while reading listsinceblock, keeping the potentially stale results");
+            self.tip_stable = false;
         }
 
         // When `listsinceblock` is atomic in >=0.21, use its returned 'lastblock' as the tip.
@@ -177,6 +526,22 @@ impl Indexer {
             tip.height()
         );
 
+        // Skip processing entirely if the listing is identical to the one seen in the previous
+        // sync, unless it contains transactions with a mempool-dependent conflict status
+        let listing_marker = listing_fingerprint(&result);
+        if !refresh_outgoing
+            && self.last_listing == Some(listing_marker)
+            && result.removed.is_empty()
+            && !result
+                .transactions
+                .iter()
+                .any(|ltx| ltx.info.confirmations == 0 && !ltx.info.wallet_conflicts.is_empty())
+        {
+            trace!("no changes since the last sync, skipping processing");
+            return Ok(tip);
+        }
+        self.last_listing = None;
+
         for ltx in result.removed {
             // transactions that were re-added in the active chain will appear in `removed`
             // but with a positive confirmation count, ignore these.
@@ -186,14 +551,34 @@ impl Indexer {
         }
 
         let mut buffered_outgoing: HashMap<Txid, i32> = HashMap::new();
+        let mut buffered_incoming: Vec<Vec<ListTransactionResult>> = vec![];
         let mut cached_conflicted = HashMap::new();
+        #[cfg(feature = "op-return")]
+        let mut incoming_txids = HashSet::new();
+
+        if let Some(progress) = &mut progress {
+            progress.add_to_total(result.transactions.len());
+        }
 
         for ltx in result.transactions {
+            if let Some(progress) = &mut progress {
+                progress.tick()?;
+            }
+
+            if ltx.info.confirmations == 0 && self.swept_txids.contains(&ltx.info.txid) {
+                continue;
+            }
+
             if self.is_conflicted(&ltx, &mut cached_conflicted)? {
-                self.purge_tx(&ltx.info.txid, changelog);
+                self.purge_conflicted_tx(&ltx.info.txid, changelog);
                 continue;
             }
 
+            // the transactions conflicting with an active one were replaced by it
+            for conflicted_txid in &ltx.info.wallet_conflicts {
+                self.store.mark_replaced(conflicted_txid, &ltx.info.txid);
+            }
+
             // "listtransactions"/"listsinceblock" in fact lists transaction outputs and not transactions.
             // for "receive" txs, it returns one entry per wallet-owned output in the tx.
             // for "send" txs, it returns one entry for every output in the tx, owned or not.
@@ -202,7 +587,14 @@ impl Indexer {
                     // incoming txouts are easy: bitcoind tells us the associated
                     // address and label, giving us all the information we need in
                     // order to save the txo to the index.
-                    self.process_incoming_txo(ltx, tip.height(), changelog);
+                    #[cfg(feature = "op-return")]
+                    incoming_txids.insert(ltx.info.txid);
+                    // the outputs of the same transaction are listed consecutively, buffer them
+                    // to get indexed together
+                    match buffered_incoming.last_mut() {
+                        Some(ltxs) if ltxs[0].info.txid == ltx.info.txid => ltxs.push(ltx),
+                        _ => buffered_incoming.push(vec![ltx]),
+                    }
                 }
                 TxCategory::Send => {
                     // indexing outgoing txs require fetching the list of spent prevouts and
@@ -217,16 +609,218 @@ impl Indexer {
             };
         }
 
+        // the incoming outputs of outgoing txs are deferred to get indexed together with their
+        // spent inputs, so that the transaction is never left indexed with partial details
+        let mut deferred_incoming: HashMap<Txid, Vec<ListTransactionResult>> = HashMap::new();
+        for ltxs in buffered_incoming {
+            let txid = ltxs[0].info.txid;
+            if buffered_outgoing.contains_key(&txid) {
+                deferred_incoming.entry(txid).or_default().extend(ltxs);
+            } else {
+                self.process_incoming_txos(ltxs, tip.height(), changelog);
+            }
+        }
+
+        // fetching the outgoing transactions is the slower part, account for it in the progress too
+        if let Some(progress) = &mut progress {
+            progress.add_to_total(buffered_outgoing.len());
+        }
+
+        let mut had_failures = false;
+        let mut outgoing_txs = Vec::with_capacity(buffered_outgoing.len());
         for (txid, confirmations) in buffered_outgoing {
+            if let Some(progress) = &mut progress {
+                progress.tick()?;
+            }
             let status = TxStatus::from_confirmations(confirmations, tip.height());
-            self.process_outgoing_tx(txid, status, refresh_outgoing, changelog)
-                .map_err(|err| warn!("failed processing outgoing payment: {:?}", err))
-                .ok();
+            match self.fetch_outgoing_tx(txid, status, refresh_outgoing, changelog) {
+                Ok(Some(tx)) => {
+                    outgoing_txs.push((tx, status));
+                    continue;
+                }
+                Ok(None) => (),
+                Err(err) => {
+                    warn!("failed processing outgoing payment: {:?}", err);
+                    had_failures = true;
+                }
+            }
+            // the spends are already indexed or couldn't be fetched, index the incoming outputs alone
+            if let Some(ltxs) = deferred_incoming.remove(&txid) {
+                self.process_incoming_txos(ltxs, tip.height(), changelog);
+            }
+        }
+
+        // the spent prevouts are expected to be indexed by now, but may not be if the funding
+        // transaction wasn't listed (yet). look them up to avoid under-attributing the spends.
+        let txs = outgoing_txs.iter().map(|(tx, _)| tx);
+        let pending_txids = deferred_incoming.keys().copied().collect();
+        self.resolve_prevouts(txs, &pending_txids, tip.height(), changelog)
+            .map_err(|err| {
+                warn!("failed resolving spent prevouts: {:?}", err);
+                had_failures = true;
+            })
+            .ok();
+
+        // index parents before their children, so that spends of in-batch outputs get picked up
+        for (tx, status) in sort_by_dependencies(outgoing_txs, |(tx, _)| tx) {
+            let txid = tx.txid();
+            trace!("processing outgoing tx txid={} status={:?}", txid, status);
+            let ltxs = deferred_incoming.remove(&txid).unwrap_or_default();
+            let outputs = resolve_incoming_txos(&self.watcher, ltxs.iter().map(|ltx| &ltx.detail));
+            index_wallet_tx(
+                &mut self.store,
+                &mut self.watcher,
+                &txid,
+                status,
+                outputs,
+                Some(&tx),
+                refresh_outgoing,
+                changelog,
+            );
+        }
+
+        // the raw transaction is not available for incoming txs, fetch it to scan for OP_RETURNs.
+        // outgoing txs are scanned while processing them.
+        #[cfg(feature = "op-return")]
+        for txid in incoming_txids {
+            if needs_op_return_scan(&self.store, &txid) {
+                self.rpc
+                    .get_transaction(&txid, Some(true))
+                    .and_then(|gtx| gtx.transaction().map_err(Into::into))
+                    .map(|tx| index_op_return(&mut self.store, &tx))
+                    .map_err(|err| {
+                        warn!("failed scanning OP_RETURN outputs: {:?}", err);
+                        had_failures = true;
+                    })
+                    .ok();
+            }
+        }
+
+        // only remember the listing if it was fully processed, so that failures get retried
+        if !had_failures {
+            self.last_listing = Some(listing_marker);
         }
 
         Ok(tip)
     }
 
+    // scan the blocks following the synced tip (or starting at `scan_from` for the initial sync)
+    // for outputs funding the watched addresses and inputs spending known wallet outputs
+    fn sync_blocks(
+        &mut self,
+        scan_from: u32,
+        changelog: &mut Changelog,
+        mut progress: Option<IndexProgress>,
+    ) -> Result<BlockId> {
+        let start_height = self.tip.map_or(scan_from, |tip| tip.height() + 1);
+        let best_height = self.rpc.get_block_count()? as u32;
+        ensure!(
+            self.tip.is_some() || start_height <= best_height,
+            "Cannot scan blocks from height {}, the chain tip is at {}",
+            start_height,
+            best_height
+        );
+
+        if let Some(progress) = &mut progress {
+            progress.add_to_total((best_height + 1).saturating_sub(start_height) as usize);
+        }
+
+        let mut synced_tip = self.tip;
+        for height in start_height..=best_height {
+            let blockhash = self.rpc.get_block_hash(height as u64)?;
+            let block = self.rpc.get_block(&blockhash)?;
+
+            // the chain was reorged while scanning, stop here and let the next sync detect it
+            if matches!(synced_tip, Some(tip) if block.header.prev_blockhash != *tip.hash()) {
+                warn!(
+                    "chain tip moved while scanning blocks, stopping at {}",
+                    height
+                );
+                break;
+            }
+
+            let status = TxStatus::Confirmed(height);
+            let mut found_funding = false;
+            for tx in &block.txdata {
+                found_funding |= self.scan_tx(tx, status, changelog);
+            }
+            // the gap past newly funded addresses needs to be watched before scanning further
+            if found_funding {
+                self.do_imports(/*rescan=*/ false)?;
+            }
+
+            synced_tip = Some(BlockId(height, blockhash));
+            if let Some(progress) = &mut progress {
+                progress.tick()?;
+            }
+        }
+
+        Ok(synced_tip.unwrap()) // cannot fail, at least one block is scanned when there's no tip
+    }
+
+    // scan the transactions that entered the mempool since the last scan, and purge the indexed
+    // unconfirmed transactions that left it without getting confirmed
+    fn scan_mempool(&mut self, changelog: &mut Changelog) -> Result<()> {
+        let mempool_txids: HashSet<Txid> = self.rpc.get_raw_mempool()?.into_iter().collect();
+
+        let mut new_txs = vec![];
+        for txid in mempool_txids.difference(&self.scanned_mempool) {
+            // transactions may get evicted between the two rpc calls
+            match self.rpc.get_raw_transaction(txid, None) {
+                Ok(tx) => new_txs.push(tx),
+                Err(e) => debug!("failed fetching mempool transaction {}: {}", txid, e),
+            }
+        }
+
+        // mempool transactions are unordered, process the parents of unconfirmed chains of wallet
+        // transactions first so that their outputs are known when indexing the children spends
+        for tx in sort_by_dependencies(new_txs, |tx| tx) {
+            if self.scan_tx(&tx, TxStatus::Unconfirmed, changelog) {
+                self.do_imports(/*rescan=*/ false)?;
+            }
+        }
+
+        for txid in self.store.get_mempool_txids(false) {
+            if !mempool_txids.contains(&txid) {
+                debug!(
+                    "transaction {} left the mempool unconfirmed, purging it",
+                    txid
+                );
+                self.purge_conflicted_tx(&txid, changelog);
+            }
+        }
+
+        self.scanned_mempool = mempool_txids;
+        Ok(())
+    }
+
+    // index the transaction's outputs funding watched addresses and its inputs spending wallet
+    // outputs. returns whether new descriptor addresses were funded.
+    fn scan_tx(&mut self, tx: &Transaction, status: TxStatus, changelog: &mut Changelog) -> bool {
+        scan_tx(
+            &mut self.store,
+            &mut self.watcher,
+            &self.scan_targets,
+            tx,
+            status,
+            changelog,
+        )
+    }
+
+    // purge the transactions confirmed at or after `min_height`, to re-scan them following a reorg
+    fn purge_confirmed_since(&mut self, min_height: u32, changelog: &mut Changelog) {
+        let txids: Vec<Txid> = self
+            .store
+            .get_history_since(min_height)
+            .into_iter()
+            .filter(|txhist| txhist.status.is_confirmed())
+            .map(|txhist| txhist.txid)
+            .collect();
+        for txid in txids {
+            self.purge_tx(&txid, changelog);
+        }
+    }
+
     /// Check if the given wallet transaction is conflicted
     fn is_conflicted(
         &self,
@@ -253,15 +847,8 @@ impl Indexer {
         }
     }
 
-    // upsert the transaction while collecting the changelog
     fn upsert_tx(&mut self, txid: &Txid, status: TxStatus, changelog: &mut Changelog) {
-        let tx_updated = self.store.upsert_tx(txid, status);
-        if tx_updated {
-            changelog.with(|changelog| {
-                let tx_entry = self.store.get_tx_entry(txid).unwrap();
-                changelog.extend(IndexChange::from_tx(txid, tx_entry));
-            });
-        }
+        upsert_tx(&mut self.store, txid, status, changelog)
     }
 
     fn purge_tx(&mut self, txid: &Txid, changelog: &mut Changelog) {
@@ -271,111 +858,184 @@ impl Indexer {
         }
     }
 
-    fn process_incoming_txo(
+    // like purge_tx(), but retains the transaction if `conflicted_retention` is set
+    fn purge_conflicted_tx(&mut self, txid: &Txid, changelog: &mut Changelog) {
+        let tx_deleted = self.store.purge_conflicted_tx(txid);
+        if tx_deleted {
+            changelog.push(|| IndexChange::TransactionReplaced(*txid));
+        }
+    }
+
+    // index the incoming txos listed for a single transaction at once
+    fn process_incoming_txos(
         &mut self,
-        ltx: ListTransactionResult,
+        ltxs: Vec<ListTransactionResult>,
         tip_height: u32,
         changelog: &mut Changelog,
     ) {
-        let label = ltx.detail.label.as_ref();
-        let origin = some_or_ret!(label.and_then(|l| KeyOrigin::from_label(l)));
-        let address = some_or_ret!(ltx.detail.address);
-
-        // XXX we assume that any address with a "bwt/..." label is ours, this may not necessarily be true.
-
-        let txid = ltx.info.txid;
-        let vout = ltx.detail.vout;
-        let scripthash = ScriptHash::from(&address);
+        let ltx = some_or_ret!(ltxs.first());
         let status = TxStatus::from_confirmations(ltx.info.confirmations, tip_height);
-        let amount = ltx.detail.amount.to_unsigned().unwrap().as_sat(); // safe to unwrap, incoming payments cannot have negative amounts
-
-        trace!(
-            "processing incoming txout {}:{} scripthash={} address={} origin={:?} status={:?} amount={}",
-            txid, vout, scripthash, address, origin, status, amount
+        index_incoming_txos(
+            &mut self.store,
+            &mut self.watcher,
+            &ltx.info.txid,
+            status,
+            ltxs.iter().map(|ltx| &ltx.detail),
+            changelog,
         );
-
-        self.upsert_tx(&txid, status, changelog);
-
-        self.store.index_scripthash(&scripthash, &origin, &address);
-
-        let txo_added =
-            self.store
-                .index_tx_output_funding(&txid, vout, FundingInfo(scripthash, amount));
-
-        if txo_added {
-            changelog.push(|| {
-                IndexChange::TxoFunded(OutPoint::new(txid, vout), scripthash, amount, status)
-            });
-            self.watcher.mark_funded(&origin);
-        }
     }
 
-    fn process_outgoing_tx(
+    // fetch an outgoing transaction for indexing its spent inputs, unless they're already indexed
+    fn fetch_outgoing_tx(
         &mut self,
         txid: Txid,
         status: TxStatus,
         refresh: bool,
         changelog: &mut Changelog,
-    ) -> Result<()> {
-        trace!("processing outgoing tx txid={} status={:?}", txid, status);
-
-        let has_spends = |tx_entry: &TxEntry| !tx_entry.spending.is_empty();
-
-        if !refresh && self.store.get_tx_entry(&txid).map_or(false, has_spends) {
+    ) -> Result<Option<Transaction>> {
+        if !refresh && has_indexed_spends(&self.store, &txid) {
             // skip indexing spent inputs, but keep the status which might be more recent
             self.upsert_tx(&txid, status, changelog);
             trace!("skipping outgoing tx {}, already indexed", txid);
-            return Ok(());
+            return Ok(None);
         }
 
         // TODO use batch rpc to fetch all buffered outgoing txs
         let tx = self.rpc.get_transaction(&txid, Some(true))?.transaction()?;
+        Ok(Some(tx))
+    }
 
-        let spending: HashMap<u32, SpendingInfo> = tx
-            .input
-            .iter()
-            .enumerate()
-            .filter_map(|(vin, input)| {
-                let inpoint = InPoint::new(txid, vin as u32);
-                let prevout = input.previous_output;
-                let FundingInfo(scripthash, amount) = self.store.lookup_txo_fund(&prevout)?;
-
-                #[cfg(feature = "track-spends")]
-                self.store.index_txo_spend(prevout, inpoint);
-
-                changelog.push(|| IndexChange::TxoSpent(inpoint, scripthash, prevout, status));
-
-                // we could keep just the previous_output and lookup the scripthash and amount
-                // from the corresponding FundingInfo, but we keep it here anyway for quick access
-                let spending_info = SpendingInfo(scripthash, prevout, amount);
-                Some((vin as u32, spending_info))
-            })
-            .collect();
+    // index the wallet outputs spent by the given transactions that are missing from the index,
+    // by fetching the funding transactions from the bitcoind wallet in batches. the outputs of
+    // `pending_txids` are skipped, as they're about to get indexed.
+    fn resolve_prevouts<'a>(
+        &mut self,
+        txs: impl Iterator<Item = &'a Transaction>,
+        pending_txids: &HashSet<Txid>,
+        tip_height: u32,
+        changelog: &mut Changelog,
+    ) -> Result<()> {
+        if self.config.prevout_batch_size == 0 {
+            return Ok(());
+        }
 
-        if !spending.is_empty() {
-            self.upsert_tx(&txid, status, changelog);
-            self.store
-                .index_tx_inputs_spending(&txid, spending, refresh);
+        let mut missing: HashMap<Txid, Vec<u32>> = HashMap::new();
+        for input in txs.flat_map(|tx| &tx.input) {
+            let prevout = input.previous_output;
+            if !prevout.is_null()
+                && !pending_txids.contains(&prevout.txid)
+                && self.store.lookup_txo_fund(&prevout).is_none()
+            {
+                missing.entry(prevout.txid).or_default().push(prevout.vout);
+            }
+        }
+        if missing.is_empty() {
+            return Ok(());
         }
+        trace!(
+            "resolving spent prevouts from {} transactions",
+            missing.len()
+        );
 
+        let network = self.watcher.network();
+        let missing: Vec<_> = missing.into_iter().collect();
+        for batch in missing.chunks(self.config.prevout_batch_size) {
+            let params: Vec<_> = batch
+                .iter()
+                .map(|(txid, _)| vec![json!(txid), json!(true)])
+                .collect();
+            let results: Vec<RpcResult<GetTransactionResult>> =
+                self.rpc.batch_call_each("gettransaction", &params)?;
+
+            for ((txid, vouts), result) in batch.iter().zip(results) {
+                // transactions unknown to the bitcoind wallet cannot be funding wallet outputs
+                let gtx = match result {
+                    Ok(gtx) => gtx,
+                    Err(_) => continue,
+                };
+                let tx = gtx.transaction()?;
+                let status = TxStatus::from_confirmations(gtx.info.confirmations, tip_height);
+                let prevouts = vouts.iter().filter_map(|vout| {
+                    let txout = tx.output.get(*vout as usize)?;
+                    let address = Address::from_script(&txout.script_pubkey, network)?;
+                    Some((OutPoint::new(*txid, *vout), address, txout.value))
+                });
+                for (outpoint, address, amount) in prevouts {
+                    let origin = self
+                        .store
+                        .get_script_info(&ScriptHash::from(&address))
+                        .map(|script_info| script_info.origin)
+                        .or_else(|| self.watcher.find_origin(&address));
+                    if let Some(origin) = origin {
+                        debug!("resolved spent wallet output {} of {}", outpoint, address);
+                        index_txo(
+                            &mut self.store,
+                            &mut self.watcher,
+                            outpoint,
+                            &address,
+                            origin,
+                            amount,
+                            status,
+                            changelog,
+                        );
+                    }
+                }
+            }
+        }
         Ok(())
     }
 
     /// Update missing/outdated mempool entries for unconfirmed mempool transactions (or all mempool
     /// entries when force_refresh is set, during the initial sync or following a chain tip update)
     fn sync_mempool(&mut self, force_refresh: bool) {
-        let mempool = self.store.mempool_mut();
+        let mut txids = self.store.get_mempool_txids(!force_refresh);
+        if force_refresh {
+            // prioritize populating entries that are still missing
+            txids.sort_by_key(|txid| store::get_mempool_entry(&self.store, txid).is_some());
+        }
 
-        for (txid, opt_entry) in mempool.iter_mut() {
-            if force_refresh || opt_entry.is_none() {
-                match self.rpc.get_mempool_entry(txid) {
-                    Ok(rpc_entry) => *opt_entry = Some(rpc_entry.into()),
-                    Err(e) => warn!("failed fetching mempool entry for {}: {}", txid, e),
-                }
+        // TODO use batch rpc
+        for txid in txids {
+            match self.rpc.get_mempool_entry(&txid) {
+                Ok(rpc_entry) => self.store.set_mempool_entry(&txid, rpc_entry.into()),
+                Err(e) => warn!("failed fetching mempool entry for {}: {}", txid, e),
             }
         }
+    }
 
-        // TODO use batch rpc
+    /// Refresh the in-mempool descendants of all mempool transactions. Descendants can come and go
+    /// without the transaction itself changing, so this is done on every sync using a single batch.
+    fn sync_mempool_descendants(&mut self) {
+        let txids: Vec<Txid> = self
+            .store
+            .mempool_transactions()
+            .into_iter()
+            .filter(|(_, opt_entry)| opt_entry.is_some())
+            .map(|(txid, _)| txid)
+            .collect();
+        let params: Vec<_> = txids.iter().map(|txid| vec![json!(txid)]).collect();
+        let results: Vec<RpcResult<Vec<Txid>>> =
+            match self.rpc.batch_call_each("getmempooldescendants", &params) {
+                Ok(results) => results,
+                Err(e) => {
+                    warn!("failed fetching mempool descendants: {}", e);
+                    return;
+                }
+            };
+
+        for (txid, result) in txids.iter().zip(results) {
+            match result {
+                Ok(descendants) => self.store.set_mempool_descendants(txid, descendants),
+                // the transaction was mined (or dropped) since the mempool was last synced, its
+                // remaining package members are picked up by the next sync
+                Err(rpc::Error::JsonRpc(rpc::jsonrpc::Error::Rpc(e)))
+                    if e.code == RPC_INVALID_ADDRESS_OR_KEY =>
+                {
+                    self.store.set_mempool_descendants(txid, vec![])
+                }
+                Err(e) => warn!("failed fetching mempool descendants for {}: {}", txid, e),
+            }
+        }
     }
 
     /// Get historical events that happened at or after `min_block_height`, including unconfirmed,
@@ -396,49 +1056,760 @@ impl Indexer {
             .collect()
     }
 
-    pub fn track_address(&mut self, address: Address, rescan_since: RescanSince) -> Result<()> {
-        self.watcher.track_address(address, rescan_since)
+    /// Widen the gap limit of a descriptor wallet and import the additional addresses right away.
+    /// Their history will be picked up on the next sync.
+    /// Re-fetch a single wallet transaction from bitcoind and update its indexed status, without
+    /// waiting for a full sync. Returns the updated status, or None if the bitcoind wallet does
+    /// not know the transaction. The resulting changes are emitted by the next sync.
+    pub fn refresh_tx(&mut self, txid: &Txid) -> Result<Option<TxStatus>> {
+        let gtx = some_or_ret!(self.rpc.get_transaction_opt(txid)?, Ok(None));
+        let confirmations = gtx.info.confirmations;
+
+        // derive the tip the confirmations were counted against from the confirming block height,
+        // which may be ahead of the synced tip
+        let tip_height = match (confirmations > 0, gtx.info.blockheight) {
+            (true, Some(block_height)) => block_height + confirmations as u32 - 1,
+            (true, None) => self.rpc.get_block_count()? as u32,
+            (false, _) => self.tip.map_or(0, |tip| tip.height()),
+        };
+        let status = TxStatus::from_confirmations(confirmations, tip_height);
+        trace!("refreshing tx {} with status {:?}", txid, status);
+
+        let mut changelog =
+            bulk_process(&mut self.store, &mut self.watcher, vec![gtx], tip_height)?;
+        if self.tip.is_some() {
+            let alerts = self.amount_alerts.check(&changelog);
+            changelog.extend(alerts);
+            self.unclaimed_changes.append(&mut changelog);
+        }
+
+        Ok(Some(status))
     }
-}
 
-#[derive(Clone, Serialize, Debug)]
-#[serde(tag = "category", content = "params")]
-pub enum IndexChange {
-    ChainTip(BlockId),
-    Reorg(u32, BlockHash, BlockHash),
+    // the additional addresses are returned for importing with a rescan, which may take awhile and
+    // shouldn't hold up the caller (see `GapRescan`). not supported when scanning blocks, where the
+    // history of the additional addresses in the blocks that were already scanned would be missed.
+    pub fn set_gap_limit(
+        &mut self,
+        checksum: &Checksum,
+        gap_limit: u32,
+    ) -> Result<Option<GapRescan>> {
+        ensure!(
+            self.config.block_scan_from.is_none(),
+            BwtError::UnsupportedWithBlockScan("Widening the gap limit")
+        );
+        self.watcher.set_gap_limit(checksum, gap_limit)
+    }
 
-    Transaction(Txid, TxStatus),
-    TransactionReplaced(Txid),
+    /// Pick up the history of the addresses imported by a completed `GapRescan`. Returns the next
+    /// addresses to import with a rescan, if the gap moved forward following the discovered history.
+    ///
+    /// The changes are returned by the next sync.
+    pub fn gap_rescanned(&mut self, rescan: &GapRescan) -> Result<Option<GapRescan>> {
+        // the rescanned transactions predate the synced tip and won't get listed by syncing from it,
+        // list the full wallet history instead. the tip is left as-is for the next sync to advance.
+        let tip = self.tip.take();
+        let mut changelog = Changelog::new(tip.is_some());
+        let result = self.sync_transactions(false, &mut changelog, None);
+        self.tip = tip;
+        result?;
 
-    TxoFunded(OutPoint, ScriptHash, u64, TxStatus),
-    TxoSpent(InPoint, ScriptHash, OutPoint, TxStatus),
-}
+        let mut changelog = changelog.into_vec();
+        let alerts = self.amount_alerts.check(&changelog);
+        changelog.extend(alerts);
+        self.unclaimed_changes.extend(changelog);
+        Ok(self.watcher.gap_rescanned(rescan))
+    }
 
-struct Changelog {
-    track: bool,
-    changes: Vec<IndexChange>,
-}
+    /// Index the unspent outputs found by scanning the UTXO set, providing their balance without
+    /// the transaction history (which gets filled in by later syncs). Outputs are attributed to
+    /// the tracked descriptor wallets where possible, or to standalone addresses otherwise.
+    pub fn index_scanned_utxos(&mut self, utxos: &[ScanTxOutUtxo]) -> Vec<OutPoint> {
+        let network = self.watcher.network();
+        let mut changelog = Changelog::new(false);
+        utxos
+            .iter()
+            .filter_map(|utxo| {
+                let address = Address::from_script(&utxo.script_pub_key, network)?;
+                let origin = self
+                    .watcher
+                    .find_origin(&address)
+                    .unwrap_or(KeyOrigin::Standalone);
+                let outpoint = OutPoint::new(utxo.txid, utxo.vout);
+                let status = TxStatus::Confirmed(utxo.height as u32);
+                let amount = utxo.amount.as_sat();
+                index_txo(
+                    &mut self.store,
+                    &mut self.watcher,
+                    outpoint,
+                    &address,
+                    origin,
+                    amount,
+                    status,
+                    &mut changelog,
+                );
+                Some(outpoint)
+            })
+            .collect()
+    }
 
-impl Changelog {
-    fn new(track: bool) -> Self {
-        Changelog {
-            track,
-            changes: vec![],
+    pub fn track_address(&mut self, address: Address, rescan_since: RescanSince) -> Result<()> {
+        let block_scan = self.config.block_scan_from.is_some();
+        // scan targets only match the blocks scanned from now on
+        ensure!(
+            !block_scan || rescan_since == RescanSince::Now,
+            BwtError::UnsupportedWithBlockScan("Tracking addresses with a rescan")
+        );
+        self.watcher.track_address(address, rescan_since)?;
+        if block_scan {
+            self.do_imports(/*rescan=*/ false)?;
         }
+        Ok(())
+    }
+}
+
+/// Process a list of wallet transactions (as returned by `gettransaction`) directly into the store,
+/// without fetching anything from bitcoind. This can be used for importing from backups and for
+/// exercising the indexing logic without a live node.
+///
+/// Transactions are processed in height order (unconfirmed last), with the incoming-only ones indexed
+/// before the outgoing ones. Outgoing transactions are indexed with their funded outputs and spent
+/// inputs at once, parents first. Processing is idempotent: feeding the same set of transactions
+/// again yields no changes.
+pub fn bulk_process<S: Store>(
+    store: &mut S,
+    watcher: &mut WalletWatcher,
+    mut txs: Vec<GetTransactionResult>,
+    tip_height: u32,
+) -> Result<Vec<IndexChange>> {
+    let mut changelog = Changelog::new(true);
+
+    // more confirmations means lower height, with unconfirmed and conflicted ones last
+    txs.sort_by_key(|gtx| Reverse(gtx.info.confirmations));
+
+    let mut outgoing = vec![];
+
+    for gtx in &txs {
+        let txid = gtx.info.txid;
+        if gtx.info.confirmations < 0 {
+            if store.purge_tx(&txid) {
+                changelog.push(|| IndexChange::TransactionReplaced(txid));
+            }
+            continue;
+        }
+
+        // the active transaction among unconfirmed conflicts can't be determined without the mempool
+        if gtx.info.confirmations > 0 {
+            for conflicted_txid in &gtx.info.wallet_conflicts {
+                store.mark_replaced(conflicted_txid, &txid);
+            }
+        }
+
+        let status = TxStatus::from_confirmations(gtx.info.confirmations, tip_height);
+        let incoming = gtx
+            .details
+            .iter()
+            .filter(|detail| detail.category == TxCategory::Receive);
+
+        // outgoing txs get their incoming outputs indexed together with their spent inputs
+        if !gtx.details.iter().any(|d| d.category == TxCategory::Send) {
+            index_incoming_txos(store, watcher, &txid, status, incoming, &mut changelog);
+        } else if has_indexed_spends(store, &txid) {
+            upsert_tx(store, &txid, status, &mut changelog);
+            index_incoming_txos(store, watcher, &txid, status, incoming, &mut changelog);
+        } else {
+            outgoing.push((gtx, gtx.transaction()?, status));
+        }
+    }
+
+    for (gtx, tx, status) in sort_by_dependencies(outgoing, |(_, tx, _)| tx) {
+        let incoming = gtx
+            .details
+            .iter()
+            .filter(|detail| detail.category == TxCategory::Receive);
+        let outputs = resolve_incoming_txos(watcher, incoming);
+        let txid = gtx.info.txid;
+        index_wallet_tx(
+            store,
+            watcher,
+            &txid,
+            status,
+            outputs,
+            Some(&tx),
+            false,
+            &mut changelog,
+        );
+    }
+
+    #[cfg(feature = "op-return")]
+    for gtx in &txs {
+        if needs_op_return_scan(store, &gtx.info.txid) {
+            index_op_return(store, &gtx.transaction()?);
+        }
+    }
+
+    Ok(changelog.into_vec())
+}
+
+// upsert the transaction while collecting the changelog
+fn upsert_tx<S: Store>(store: &mut S, txid: &Txid, status: TxStatus, changelog: &mut Changelog) {
+    let tx_updated = store.upsert_tx(txid, status);
+    if tx_updated {
+        changelog.with(|changelog| {
+            let tx_entry = store.get_tx_entry(txid).unwrap();
+            changelog.extend(IndexChange::from_tx(txid, tx_entry));
+        });
+    }
+}
+
+// index the wallet outputs funded by a transaction, given its "receive" details
+fn index_incoming_txos<'a, S: Store>(
+    store: &mut S,
+    watcher: &mut WalletWatcher,
+    txid: &Txid,
+    status: TxStatus,
+    details: impl IntoIterator<Item = &'a GetTransactionResultDetail>,
+    changelog: &mut Changelog,
+) {
+    let outputs = resolve_incoming_txos(watcher, details);
+    index_txos(store, watcher, txid, status, outputs, changelog);
+}
+
+// resolve the wallet outputs funded by a transaction from its "receive" details, as a list of
+// (vout, address, origin, amount) tuples
+fn resolve_incoming_txos<'a>(
+    watcher: &WalletWatcher,
+    details: impl IntoIterator<Item = &'a GetTransactionResultDetail>,
+) -> Vec<(u32, &'a Address, KeyOrigin, u64)> {
+    details
+        .into_iter()
+        .filter_map(|detail| {
+            let address = detail.address.as_ref()?;
+            let origin = watcher.resolve_origin(address, detail.label.as_deref());
+            // when tracking the whole bitcoind wallet, addresses that weren't imported by bwt are considered standalone
+            let origin =
+                origin.or_else(|| watcher.watches_all().do_then(|| KeyOrigin::Standalone))?;
+
+            // XXX we assume that any address with a "bwt/..." label is ours, this may not necessarily be true.
+
+            let amount = detail.amount.to_unsigned().unwrap().as_sat(); // safe to unwrap, incoming payments cannot have negative amounts
+
+            Some((detail.vout, address, origin, amount))
+        })
+        .collect()
+}
+
+// index a wallet output funding a known address. returns whether it was newly added.
+#[allow(clippy::too_many_arguments)]
+fn index_txo<S: Store>(
+    store: &mut S,
+    watcher: &mut WalletWatcher,
+    outpoint: OutPoint,
+    address: &Address,
+    origin: KeyOrigin,
+    amount: u64,
+    status: TxStatus,
+    changelog: &mut Changelog,
+) -> bool {
+    let outputs = vec![(outpoint.vout, address, origin, amount)];
+    !index_txos(store, watcher, &outpoint.txid, status, outputs, changelog).is_empty()
+}
+
+// index multiple wallet outputs funded by the same transaction at once, given as a list of
+// (vout, address, origin, amount) tuples. returns the origins of the newly added outputs.
+fn index_txos<S: Store>(
+    store: &mut S,
+    watcher: &mut WalletWatcher,
+    txid: &Txid,
+    status: TxStatus,
+    outputs: Vec<(u32, &Address, KeyOrigin, u64)>,
+    changelog: &mut Changelog,
+) -> Vec<KeyOrigin> {
+    index_wallet_tx(
+        store, watcher, txid, status, outputs, None, false, changelog,
+    )
+}
+
+// index a transaction's wallet outputs along with its inputs spending wallet outputs (when the
+// raw `tx` is provided), marking the funded addresses as used. returns the origins of the newly
+// added outputs.
+#[allow(clippy::too_many_arguments)]
+fn index_wallet_tx<S: Store>(
+    store: &mut S,
+    watcher: &mut WalletWatcher,
+    txid: &Txid,
+    status: TxStatus,
+    outputs: Vec<(u32, &Address, KeyOrigin, u64)>,
+    tx: Option<&Transaction>,
+    refresh: bool,
+    changelog: &mut Changelog,
+) -> Vec<KeyOrigin> {
+    let added_origins = index_tx(store, txid, status, outputs, tx, refresh, changelog);
+    for origin in &added_origins {
+        watcher.mark_funded(origin);
+    }
+    added_origins
+}
+
+// index a transaction with the full set of its funded wallet outputs and its inputs spending
+// (already indexed) wallet outputs, applied to the store as a single update so that it never
+// holds the transaction with partial details. returns the origins of the newly added outputs.
+fn index_tx<S: Store>(
+    store: &mut S,
+    txid: &Txid,
+    status: TxStatus,
+    outputs: Vec<(u32, &Address, KeyOrigin, u64)>,
+    tx: Option<&Transaction>,
+    refresh: bool,
+    changelog: &mut Changelog,
+) -> Vec<KeyOrigin> {
+    // skip dust entirely, so that transactions that only send dust to the wallet don't get indexed
+    let outputs: Vec<_> = outputs
+        .into_iter()
+        .filter(|(vout, _, _, amount)| {
+            let is_dust = store.is_dust(*amount);
+            if is_dust {
+                trace!("skipping dust txout {}:{} amount={}", txid, vout, amount);
+            }
+            !is_dust
+        })
+        .collect();
+    let spending = tx
+        .map(|tx| resolve_spending(store, tx))
+        .filter(|(spending, _)| !spending.is_empty());
+    if outputs.is_empty() && spending.is_none() {
+        return vec![];
+    }
+
+    let mut funding = Vec::with_capacity(outputs.len());
+    let mut funding_origins = HashMap::with_capacity(outputs.len());
+    for (vout, address, origin, amount) in outputs {
+        let scripthash = ScriptHash::from(address);
+        trace!(
+            "processing incoming txout {}:{} scripthash={} address={} origin={:?} status={:?} amount={}",
+            txid, vout, scripthash, address, origin, status, amount
+        );
+        store.index_scripthash(&scripthash, &origin, address);
+        funding.push((vout, FundingInfo(scripthash, amount)));
+        funding_origins.insert(vout, (scripthash, amount, origin));
+    }
+
+    let spent: Vec<_> = spending.as_ref().map_or_else(Vec::new, |(spending, _)| {
+        spending
+            .iter()
+            .map(|(vin, SpendingInfo(scripthash, prevout, _))| {
+                (InPoint::new(*txid, *vin), *scripthash, *prevout)
+            })
+            .collect()
+    });
+
+    let (tx_updated, added_vouts) = store.index_tx(txid, status, funding, spending, refresh);
+
+    if tx_updated {
+        // the transaction events include all of its funded outputs and spent inputs
+        changelog.with(|changelog| {
+            let tx_entry = store.get_tx_entry(txid).unwrap();
+            changelog.extend(IndexChange::from_tx(txid, tx_entry));
+        });
+    } else {
+        for &vout in &added_vouts {
+            let (scripthash, amount, _) = funding_origins[&vout];
+            let outpoint = OutPoint::new(*txid, vout);
+            changelog.push(|| IndexChange::TxoFunded(outpoint, scripthash, amount, status));
+        }
+        for &(inpoint, scripthash, prevout) in &spent {
+            changelog.push(|| IndexChange::TxoSpent(inpoint, scripthash, prevout, status));
+        }
+    }
+
+    #[cfg(feature = "op-return")]
+    if let (Some(tx), false) = (tx, spent.is_empty()) {
+        index_op_return(store, tx);
+    }
+
+    added_vouts
+        .into_iter()
+        .map(|vout| funding_origins.remove(&vout).unwrap().2)
+        .collect()
+}
+
+// resolve the transaction inputs spending wallet outputs (which are expected to be indexed
+// already), along with the transaction fee if it can be determined
+//
+// The fee is always derived from the indexed prevout amounts. The `fee` field reported by
+// `listtransactions`/`gettransaction` is ignored: it is negative for `send` entries, missing for
+// `receive` entries, and computed by bitcoind from the wallet's own inputs only, which makes it
+// wrong (and possibly positive) for transactions that also spend foreign inputs. The mempool
+// `fees` reported by `getmempoolentry` are unsigned and are used as-is for `MempoolEntry`.
+fn resolve_spending<S: Store>(
+    store: &S,
+    tx: &Transaction,
+) -> (HashMap<u32, SpendingInfo>, Option<TxFee>) {
+    let spending: HashMap<u32, SpendingInfo> = tx
+        .input
+        .iter()
+        .enumerate()
+        .filter_map(|(vin, input)| {
+            let prevout = input.previous_output;
+            let FundingInfo(scripthash, amount) = store.lookup_txo_fund(&prevout)?;
+
+            // we could keep just the previous_output and lookup the scripthash and amount
+            // from the corresponding FundingInfo, but we keep it here anyway for quick access
+            let spending_info = SpendingInfo(scripthash, prevout, amount);
+            Some((vin as u32, spending_info))
+        })
+        .collect();
+
+    // the fee can only be determined when all of the inputs are spending wallet outputs
+    let fee = (spending.len() == tx.input.len()).and_then(|| {
+        let spent_amount: u64 = spending
+            .values()
+            .map(|SpendingInfo(_, _, amount)| amount)
+            .sum();
+        let output_amount: u64 = tx.output.iter().map(|txout| txout.value).sum();
+        Some(TxFee {
+            fee: spent_amount.checked_sub(output_amount)?,
+            vsize: (tx.get_weight() as u64).div_ceil(4),
+        })
+    });
+
+    (spending, fee)
+}
+
+// index a scanned transaction's outputs funding one of the watched addresses along with its
+// inputs spending wallet outputs, or just update its status if the spends were already indexed.
+// returns whether new descriptor addresses were funded, which requires watching for additional ones.
+fn scan_tx<S: Store>(
+    store: &mut S,
+    watcher: &mut WalletWatcher,
+    scan_targets: &HashMap<Script, (Address, KeyOrigin)>,
+    tx: &Transaction,
+    status: TxStatus,
+    changelog: &mut Changelog,
+) -> bool {
+    let txid = tx.txid();
+    let outputs: Vec<_> = tx
+        .output
+        .iter()
+        .enumerate()
+        .filter_map(|(vout, txout)| {
+            let (address, origin) = scan_targets.get(&txout.script_pubkey)?;
+            Some((vout as u32, address, origin.clone(), txout.value))
+        })
+        .collect();
+
+    let tx = if has_indexed_spends(store, &txid) {
+        upsert_tx(store, &txid, status, changelog);
+        None
+    } else {
+        Some(tx)
+    };
+
+    let added_origins =
+        index_wallet_tx(store, watcher, &txid, status, outputs, tx, false, changelog);
+    added_origins
+        .iter()
+        .any(|origin| !KeyOrigin::is_standalone(origin))
+}
+
+fn make_scan_targets(
+    targets: Vec<(Address, KeyOrigin)>,
+) -> impl Iterator<Item = (Script, (Address, KeyOrigin))> {
+    targets
+        .into_iter()
+        .map(|(address, origin)| (address.script_pubkey(), (address, origin)))
+}
+
+fn has_indexed_spends<S: Store>(store: &S, txid: &Txid) -> bool {
+    store
+        .get_tx_entry(txid)
+        .is_some_and(|tx_entry| !tx_entry.spending.is_empty())
+}
+
+// order the transactions so that the ones funding the inputs of others come before them, keeping
+// the original order otherwise
+fn sort_by_dependencies<T>(items: Vec<T>, get_tx: impl Fn(&T) -> &Transaction) -> Vec<T> {
+    let positions: HashMap<Txid, usize> = items
+        .iter()
+        .enumerate()
+        .map(|(i, item)| (get_tx(item).txid(), i))
+        .collect();
+
+    // an iterative post-order traversal, to avoid deep recursion with long unconfirmed chains
+    let mut visited = vec![false; items.len()];
+    let mut order = Vec::with_capacity(items.len());
+    for root in 0..items.len() {
+        let mut stack = vec![(root, false)];
+        while let Some((i, parents_done)) = stack.pop() {
+            if parents_done {
+                order.push(i);
+                continue;
+            }
+            if visited[i] {
+                continue;
+            }
+            visited[i] = true;
+            stack.push((i, true));
+            for input in get_tx(&items[i]).input.iter().rev() {
+                if let Some(&parent) = positions.get(&input.previous_output.txid) {
+                    if !visited[parent] {
+                        stack.push((parent, false));
+                    }
+                }
+            }
+        }
+    }
+
+    let mut items: Vec<Option<T>> = items.into_iter().map(Some).collect();
+    order
+        .into_iter()
+        .map(|i| items[i].take().unwrap())
+        .collect()
+}
+
+// index the data payloads of the tx's OP_RETURN outputs, if the tx is indexed
+#[cfg(feature = "op-return")]
+fn index_op_return<S: Store>(store: &mut S, tx: &Transaction) {
+    let txid = tx.txid();
+    if store.get_tx_entry(&txid).is_none() {
+        return;
+    }
+    let op_return = tx
+        .output
+        .iter()
+        .filter(|txout| txout.script_pubkey.is_op_return())
+        .map(|txout| {
+            // the data pushes following the OP_RETURN opcode, concatenated
+            txout
+                .script_pubkey
+                .instructions()
+                .skip(1)
+                .filter_map(|ins| match ins {
+                    Ok(Instruction::PushBytes(data)) => Some(data.to_vec()),
+                    _ => None,
+                })
+                .flatten()
+                .collect()
+        })
+        .collect();
+    store.set_tx_op_return(&txid, op_return);
+}
+
+#[cfg(feature = "op-return")]
+fn needs_op_return_scan<S: Store>(store: &S, txid: &Txid) -> bool {
+    matches!(store.get_tx_entry(txid), Some(tx_entry) if tx_entry.op_return.is_none())
+}
+
+#[derive(Clone, Serialize, Debug)]
+#[serde(tag = "category", content = "params")]
+pub enum IndexChange {
+    ChainTip(BlockId),
+    Reorg(u32, BlockHash, BlockHash),
+
+    Transaction(Txid, TxStatus),
+    TransactionReplaced(Txid),
+    TransactionFinal(Txid, u32),
+
+    TxoFunded(
+        OutPoint,
+        ScriptHash,
+        #[serde(serialize_with = "amount::serialize")] u64,
+        TxStatus,
+    ),
+    TxoSpent(InPoint, ScriptHash, OutPoint, TxStatus),
+
+    AmountAlert(
+        OutPoint,
+        ScriptHash,
+        #[serde(serialize_with = "amount::serialize")] u64,
+        TxStatus,
+    ),
+
+    // coalesced notification for a batch of changes, see `IndexChange::batch()`
+    BatchUpdate {
+        changed_scripthashes: Vec<ScriptHash>,
+        changed_txids: Vec<Txid>,
+        new_tip: Option<BlockId>,
+    },
+}
+
+/// A subscription to chain tip and scripthash events, shared by the Electrum and WebSocket servers
+#[derive(Debug, Default)]
+pub struct Subscription {
+    // wants new blocks
+    blocks: bool,
+    // wants updates for these scripthashes
+    scripthashes: HashSet<ScriptHash>,
+}
+
+impl Subscription {
+    pub fn subscribe_blocks(&mut self) {
+        self.blocks = true;
+    }
+    pub fn subscribe_scripthash(&mut self, scripthash: ScriptHash) {
+        self.scripthashes.insert(scripthash);
+    }
+    pub fn unsubscribe_scripthash(&mut self, scripthash: &ScriptHash) -> bool {
+        self.scripthashes.remove(scripthash)
+    }
+    pub fn wants_blocks(&self) -> bool {
+        self.blocks
+    }
+    pub fn wants_scripthash(&self, scripthash: &ScriptHash) -> bool {
+        self.scripthashes.contains(scripthash)
+    }
+    pub fn matches(&self, change: &IndexChange) -> bool {
+        match change {
+            IndexChange::ChainTip(_) | IndexChange::Reorg(..) => self.blocks,
+            _ => {
+                matches!(change.scripthash(), Some(scripthash) if self.wants_scripthash(scripthash))
+            }
+        }
+    }
+}
+
+/// A registry of alerts for incoming payments of at least a minimum amount, fired as
+/// `IndexChange::AmountAlert` events alongside the `TxoFunded` events that triggered them
+#[derive(Debug, Default)]
+pub struct AmountAlerts(HashMap<ScriptHash, AmountAlert>);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AmountAlert {
+    #[serde(serialize_with = "amount::serialize")]
+    pub min_amount: u64,
+    pub one_shot: bool,
+    // the funding outputs that already fired the alert, to avoid firing again when they confirm or
+    // get re-synced following a reorg. pruned once spent or final.
+    #[serde(skip)]
+    fired: HashSet<OutPoint>,
+}
+
+impl AmountAlerts {
+    fn register(&mut self, scripthash: ScriptHash, min_amount: u64, one_shot: bool) {
+        let alert = AmountAlert {
+            min_amount,
+            one_shot,
+            fired: HashSet::new(),
+        };
+        self.0.insert(scripthash, alert);
+    }
+
+    fn remove(&mut self, scripthash: &ScriptHash) -> bool {
+        self.0.remove(scripthash).is_some()
+    }
+
+    pub fn get(&self, scripthash: &ScriptHash) -> Option<&AmountAlert> {
+        self.0.get(scripthash)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&ScriptHash, &AmountAlert)> {
+        self.0.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // the AmountAlert events for the new funding outputs that meet their scripthash's alert
+    fn check<'a>(
+        &mut self,
+        changes: impl IntoIterator<Item = &'a IndexChange>,
+    ) -> Vec<IndexChange> {
+        let mut alerts = vec![];
+        if self.0.is_empty() {
+            return alerts;
+        }
+        for change in changes {
+            let (outpoint, scripthash, amount, status) = match change {
+                IndexChange::TxoFunded(outpoint, scripthash, amount, status) => {
+                    (outpoint, scripthash, *amount, *status)
+                }
+                _ => continue,
+            };
+            if let Some(alert) = self.0.get_mut(scripthash) {
+                if amount >= alert.min_amount && status.is_viable() && alert.fired.insert(*outpoint)
+                {
+                    alerts.push(IndexChange::AmountAlert(
+                        *outpoint,
+                        *scripthash,
+                        amount,
+                        status,
+                    ));
+                    if alert.one_shot {
+                        self.0.remove(scripthash);
+                    }
+                }
+            }
+        }
+        alerts
+    }
+
+    // forget the fired outputs that were spent or reached `finality_confirmations`, which are not
+    // expected to be reported as funded again
+    fn prune<S: Store>(&mut self, store: &S, tip_height: u32, finality_confirmations: u32) {
+        for alert in self.0.values_mut() {
+            alert.fired.retain(|outpoint| {
+                store::get_confirmations(store, &outpoint.txid, tip_height)
+                    .is_some_and(|confirmations| confirmations < finality_confirmations)
+            });
+            #[cfg(feature = "track-spends")]
+            alert
+                .fired
+                .retain(|outpoint| store.lookup_txo_spend(outpoint).is_none());
+        }
+    }
+}
+
+struct Changelog {
+    track: bool,
+    changes: Vec<IndexChange>,
+    // the TxoFunded changes collected while not tracking, if enabled by `untrack()`
+    untracked_funded: Option<Vec<IndexChange>>,
+}
+
+impl Changelog {
+    fn new(track: bool) -> Self {
+        Changelog {
+            track,
+            changes: vec![],
+            untracked_funded: None,
+        }
+    }
+    // stop tracking changes, optionally still collecting the TxoFunded ones separately
+    fn untrack(&mut self, keep_funded: bool) {
+        self.track = false;
+        self.untracked_funded = keep_funded.do_then(Vec::new);
     }
     fn push(&mut self, make_update: impl Fn() -> IndexChange) {
         if self.track {
             self.changes.push(make_update());
+        } else if let Some(untracked_funded) = &mut self.untracked_funded {
+            let update = make_update();
+            if let IndexChange::TxoFunded(..) = update {
+                untracked_funded.push(update);
+            }
         }
     }
     fn with(&mut self, closure: impl Fn(&mut Vec<IndexChange>)) {
         if self.track {
             closure(&mut self.changes)
+        } else if let Some(untracked_funded) = &mut self.untracked_funded {
+            let mut updates = vec![];
+            closure(&mut updates);
+            untracked_funded.extend(
+                updates
+                    .into_iter()
+                    .filter(|update| matches!(update, IndexChange::TxoFunded(..))),
+            );
         }
     }
     fn into_vec(self) -> Vec<IndexChange> {
         self.changes
     }
+    // the tracked changes, and the TxoFunded ones collected while not tracking
+    fn into_parts(self) -> (Vec<IndexChange>, Vec<IndexChange>) {
+        (self.changes, self.untracked_funded.unwrap_or_default())
+    }
 }
 impl IndexChange {
     // the scripthash affected by the update, if any
@@ -446,6 +1817,7 @@ impl IndexChange {
         match self {
             IndexChange::TxoFunded(_, ref scripthash, ..) => Some(scripthash),
             IndexChange::TxoSpent(_, ref scripthash, ..) => Some(scripthash),
+            IndexChange::AmountAlert(_, ref scripthash, ..) => Some(scripthash),
             _ => None,
         }
     }
@@ -455,6 +1827,7 @@ impl IndexChange {
         match self {
             IndexChange::TxoFunded(ref outpoint, ..) => Some(outpoint),
             IndexChange::TxoSpent(_, _, ref outpoint, _) => Some(outpoint),
+            IndexChange::AmountAlert(ref outpoint, ..) => Some(outpoint),
             _ => None,
         }
     }
@@ -466,12 +1839,53 @@ impl IndexChange {
 
             Self::Transaction(..) => "Transaction",
             Self::TransactionReplaced(..) => "TransactionReplaced",
+            Self::TransactionFinal(..) => "TransactionFinal",
 
             Self::TxoFunded(..) => "TxoFunded",
             Self::TxoSpent(..) => "TxoSpent",
+
+            Self::AmountAlert(..) => "AmountAlert",
+
+            Self::BatchUpdate { .. } => "BatchUpdate",
         }
     }
 
+    /// Coalesce a batch of changes into a single `BatchUpdate` with the affected scripthashes and
+    /// transactions, and the most recent chain tip. Returns None if there are no changes.
+    ///
+    /// Reorgs and replaced transactions cannot be represented in a batch and should be sent
+    /// separately.
+    pub fn batch<'a>(changes: impl IntoIterator<Item = &'a IndexChange>) -> Option<Self> {
+        let mut changed_scripthashes = BTreeSet::new();
+        let mut changed_txids = BTreeSet::new();
+        let mut new_tip = None;
+        let mut has_changes = false;
+        for change in changes {
+            match change {
+                IndexChange::ChainTip(tip) => new_tip = Some(*tip),
+                IndexChange::Transaction(txid, _) | IndexChange::TransactionFinal(txid, _) => {
+                    changed_txids.insert(*txid);
+                }
+                IndexChange::BatchUpdate {
+                    changed_scripthashes: scripthashes,
+                    changed_txids: txids,
+                    new_tip: tip,
+                } => {
+                    changed_scripthashes.extend(scripthashes);
+                    changed_txids.extend(txids);
+                    new_tip = tip.or(new_tip);
+                }
+                _ => changed_scripthashes.extend(change.scripthash()),
+            }
+            has_changes = true;
+        }
+        has_changes.do_then(|| IndexChange::BatchUpdate {
+            changed_scripthashes: changed_scripthashes.into_iter().collect(),
+            changed_txids: changed_txids.into_iter().collect(),
+            new_tip,
+        })
+    }
+
     // create all the changelog events inflicted by the transaction
     fn from_tx(txid: &Txid, tx_entry: &TxEntry) -> Vec<Self> {
         let mut changes = vec![IndexChange::Transaction(*txid, tx_entry.status)];
@@ -498,9 +1912,30 @@ impl fmt::Display for IndexChange {
     }
 }
 
+// Hash the listing fields that affect the index. Note that the confirmation count of
+// all transactions changes whenever the tip moves, which changes the fingerprint too.
+// The listing has one entry per wallet-related transaction output, with the same txid repeated for
+// transactions involving multiple outputs (and twice for the same output with both a "send" and a
+// "receive" entry for self-transfers). The full entry identity is hashed to tell these apart.
+fn listing_fingerprint(result: &ListSinceBlockResult) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    result.lastblock.hash(&mut hasher);
+    result.transactions.len().hash(&mut hasher);
+    for ltx in &result.transactions {
+        ltx.info.txid.hash(&mut hasher);
+        ltx.info.confirmations.hash(&mut hasher);
+        std::mem::discriminant(&ltx.detail.category).hash(&mut hasher);
+        ltx.detail.vout.hash(&mut hasher);
+        ltx.detail.address.hash(&mut hasher);
+        ltx.detail.label.hash(&mut hasher);
+        ltx.detail.amount.as_sat().hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
 // Spawn a thread to poll getwalletinfo, log progress and send progress updates via mpsc
-fn spawn_send_progress_thread(
-    rpc: Arc<RpcClient>,
+fn spawn_send_progress_thread<R: NodeRpc>(
+    rpc: Arc<R>,
     progress_tx: Option<mpsc::Sender<Progress>>,
 ) -> mpsc::SyncSender<()> {
     use crate::util::progress::wait_wallet_scan;
@@ -520,10 +1955,1147 @@ fn spawn_send_progress_thread(
         if shutdown_rx.try_recv() != Err(mpsc::TryRecvError::Empty) {
             return;
         }
-        if let Err(e) = wait_wallet_scan(&rpc, progress_tx, Some(shutdown_rx), interval) {
+        if let Err(e) = wait_wallet_scan(&*rpc, progress_tx, Some(shutdown_rx), interval) {
             trace!("progress thread aborted: {:?}", e);
         }
     });
 
     shutdown_tx
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::script::Builder;
+    use bitcoin::{Network, Script, TxIn, TxOut};
+    use serde_json::Value;
+    use std::str::FromStr;
+
+    fn make_gtx(tx: &Transaction, confirmations: i32, details: Vec<Value>) -> GetTransactionResult {
+        serde_json::from_value(gtx_json(tx, confirmations, details)).unwrap()
+    }
+
+    // The gettransaction result for the wallet transaction, as returned by bitcoind
+    fn gtx_json(tx: &Transaction, confirmations: i32, details: Vec<Value>) -> Value {
+        json!({
+            "txid": tx.txid(),
+            "confirmations": confirmations,
+            "time": 0,
+            "timereceived": 0,
+            "bip125-replaceable": "no",
+            "walletconflicts": [],
+            "amount": 0,
+            "details": details,
+            "hex": bitcoin::consensus::encode::serialize_hex(tx),
+        })
+    }
+
+    // Make a transaction spending `prevouts` (or a single null prevout if there are none)
+    fn make_tx(prevouts: &[OutPoint], outputs: &[(u64, Script)]) -> Transaction {
+        let input = if prevouts.is_empty() {
+            vec![TxIn::default()]
+        } else {
+            prevouts
+                .iter()
+                .map(|prevout| TxIn {
+                    previous_output: *prevout,
+                    ..Default::default()
+                })
+                .collect()
+        };
+        let output = outputs
+            .iter()
+            .map(|(value, script_pubkey)| TxOut {
+                value: *value,
+                script_pubkey: script_pubkey.clone(),
+            })
+            .collect();
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input,
+            output,
+        }
+    }
+
+    // The wallet transaction details for receiving and sending, as listed by bitcoind
+    fn receive_detail(address: &Address, amount: f64, vout: u32) -> Value {
+        json!({ "address": address, "category": "receive", "amount": amount, "vout": vout })
+    }
+    fn send_detail(amount: f64, vout: u32, fee: f64) -> Value {
+        json!({ "category": "send", "amount": amount, "vout": vout, "fee": fee })
+    }
+
+    // A chain of block hashes up to height 101, forking off at height 100 for non-zero `fork`s
+    fn make_chain(fork: u8) -> Vec<BlockHash> {
+        (0..=101u32)
+            .map(|height| {
+                let fork = iif!(height >= 100, fork, 0);
+                bitcoin::hashes::Hash::hash(&[&height.to_le_bytes()[..], &[fork]].concat())
+            })
+            .collect()
+    }
+
+    // A mock node serving the `chain` blocks (with the last one as the tip), the wallet
+    // transactions in `txs` and the listsinceblock entries produced by `listing` for the chain
+    fn make_mock_node(
+        chain: Arc<std::sync::Mutex<Vec<BlockHash>>>,
+        txs: Vec<Transaction>,
+        listing: impl Fn(&[BlockHash]) -> Vec<Value> + Send + 'static,
+    ) -> crate::util::mock_node::MockNode {
+        let node = crate::util::mock_node::MockNode::new();
+        // below 0.21, to sync using the tip captured before listsinceblock
+        node.respond("getnetworkinfo", json!({ "version": 200000 }));
+        let chain_ = chain.clone();
+        node.respond_with("getblockcount", move |_| {
+            Ok(json!(chain_.lock().unwrap().len() - 1))
+        });
+        let chain_ = chain.clone();
+        node.respond_with("getblockhash", move |args| {
+            let height = args[0].as_u64().unwrap() as usize;
+            Ok(json!(chain_.lock().unwrap()[height]))
+        });
+        node.respond_with("listsinceblock", move |_| {
+            let chain = chain.lock().unwrap();
+            Ok(json!({ "transactions": listing(&chain), "lastblock": chain.last().unwrap() }))
+        });
+        // fetched for scanning OP_RETURN outputs
+        node.respond_with("gettransaction", move |args| {
+            let txid: Txid = serde_json::from_value(args[0].clone()).unwrap();
+            let tx = txs.iter().find(|tx| tx.txid() == txid).unwrap();
+            Ok(gtx_json(tx, 2, vec![]))
+        });
+        node
+    }
+
+    // A listsinceblock entry for the wallet transaction detail, confirmed at `height` on the chain
+    fn listing_entry(
+        chain: &[BlockHash],
+        txid: &Txid,
+        height: Option<u32>,
+        detail: Value,
+    ) -> Value {
+        let tip_height = chain.len() as u32 - 1;
+        let mut entry = json!({
+            "txid": txid, "confirmations": height.map_or(0, |height| tip_height - height + 1),
+            "blockheight": height, "blockhash": height.map(|h| chain[h as usize]),
+            "time": 0, "timereceived": 0, "bip125-replaceable": "no", "walletconflicts": [],
+        });
+        entry
+            .as_object_mut()
+            .unwrap()
+            .extend(detail.as_object().unwrap().clone());
+        entry
+    }
+
+    fn test_config() -> IndexerConfig {
+        IndexerConfig {
+            max_reorg_depth: None,
+            dust_threshold: None,
+            mempool_expiry: time::Duration::from_secs(3600),
+            assume_confirmed_below: None,
+            block_scan_from: None,
+            prevout_batch_size: 100,
+            finality_confirmations: 6,
+            tolerate_stale_tip: false,
+            conflicted_retention: None,
+        }
+    }
+
+    #[test]
+    fn test_block_scan_runtime_targets() {
+        use crate::util::descriptor::{DescriptorExt, ExtendedDescriptor};
+        use crate::wallet::Wallet;
+
+        const XPUB: &str = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+        let node = crate::util::mock_node::MockNode::new();
+        node.respond("getnetworkinfo", json!({ "version": 210000 }));
+        let desc = ExtendedDescriptor::from_str(&format!("wpkh({}/0/*)", XPUB)).unwrap();
+        let checksum = desc.checksum();
+        let wallet =
+            Wallet::from_descriptor(desc, Network::Bitcoin, 5, 5, RescanSince::Now).unwrap();
+        let watcher = WalletWatcher::new(Network::Bitcoin, vec![wallet], vec![], false).unwrap();
+        let config = IndexerConfig {
+            block_scan_from: Some(0),
+            ..test_config()
+        };
+        let mut indexer = Indexer::new(config, Arc::new(node), watcher).unwrap();
+
+        // blocks that were already scanned would have to be re-scanned for the new addresses
+        let err = indexer.set_gap_limit(&checksum, 10).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<BwtError>(),
+            Some(BwtError::UnsupportedWithBlockScan(_))
+        ));
+        let address = Address::p2wsh(&Script::new(), Network::Bitcoin);
+        let err = indexer
+            .track_address(address.clone(), RescanSince::Timestamp(0))
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<BwtError>(),
+            Some(BwtError::UnsupportedWithBlockScan(_))
+        ));
+        assert!(!indexer.scan_targets.contains_key(&address.script_pubkey()));
+
+        // tracking new activity only is fine
+        indexer
+            .track_address(address.clone(), RescanSince::Now)
+            .unwrap();
+        assert!(indexer.scan_targets.contains_key(&address.script_pubkey()));
+    }
+
+    #[test]
+    fn test_sync_with_mock_node() {
+        use crate::util::mock_node::rpc_error;
+        use std::sync::Mutex;
+
+        let address = Address::p2wsh(&Script::new(), Network::Regtest);
+        let funding_tx = make_tx(&[], &[(50000, address.script_pubkey())]);
+        let txid = funding_tx.txid();
+
+        let chain = Arc::new(Mutex::new(make_chain(0)));
+        // the wallet transaction as listed by listsinceblock, confirmed at height 100 (or None if unconfirmed)
+        let tx_height = Arc::new(Mutex::new(Some(100u32)));
+        let tx_height_ = tx_height.clone();
+        let node = make_mock_node(chain.clone(), vec![funding_tx], move |chain| {
+            let detail = receive_detail(&address, 0.0005, 0);
+            vec![listing_entry(
+                chain,
+                &txid,
+                *tx_height_.lock().unwrap(),
+                detail,
+            )]
+        });
+
+        let watcher = WalletWatcher::watch_all(Network::Regtest);
+        let node = Arc::new(node);
+        let mut indexer = Indexer::new(test_config(), node.clone(), watcher).unwrap();
+
+        indexer.initial_sync(None).unwrap();
+        let tip_hash = make_chain(0)[101];
+        assert_eq!(indexer.tip(), Some(BlockId(101, tip_hash)));
+        assert_eq!(
+            indexer.store().get_tx_status(&txid),
+            Some(TxStatus::Confirmed(100))
+        );
+
+        // syncing again with no changes is a no-op
+        let changes = indexer.sync().unwrap();
+        assert!(changes.is_empty(), "{:?}", changes);
+
+        // reorg the blocks at heights 100-101, moving the transaction back to the mempool
+        *chain.lock().unwrap() = make_chain(1);
+        *tx_height.lock().unwrap() = None;
+        let changes = indexer.sync().unwrap();
+        let new_tip_hash = make_chain(1)[101];
+        assert!(matches!(changes.first(),
+            Some(IndexChange::Reorg(101, old, new)) if *old == tip_hash && *new == new_tip_hash));
+        assert!(matches!(changes.last(),
+            Some(IndexChange::ChainTip(tip)) if *tip == BlockId(101, new_tip_hash)));
+        assert_eq!(indexer.tip(), Some(BlockId(101, new_tip_hash)));
+        assert_eq!(
+            indexer.store().get_tx_status(&txid),
+            Some(TxStatus::Unconfirmed)
+        );
+        assert_eq!(indexer.store().audit(), Ok(()));
+
+        // the in-mempool descendants are refreshed on every sync
+        let child_txid = Txid::from_str(&"11".repeat(32)).unwrap();
+        node.respond(
+            "getmempoolentry",
+            json!({
+                "vsize": 100, "time": 0, "height": 101, "descendantcount": 2, "descendantsize": 200,
+                "ancestorcount": 1, "ancestorsize": 100, "wtxid": txid, "depends": [],
+                "spentby": [child_txid], "bip125-replaceable": false,
+                "fees": { "base": 0.00001, "modified": 0.00001, "ancestor": 0.00001, "descendant": 0.00005 },
+            }),
+        );
+        node.respond("getmempooldescendants", json!([child_txid]));
+        indexer.sync().unwrap();
+        assert_eq!(
+            store::mempool_descendants(indexer.store(), &txid),
+            vec![child_txid]
+        );
+
+        // transactions that left the mempool since have no descendants
+        node.respond_with("getmempooldescendants", |_| {
+            Err(rpc_error(-5, "Transaction not in mempool"))
+        });
+        indexer.sync().unwrap();
+        assert!(store::mempool_descendants(indexer.store(), &txid).is_empty());
+    }
+
+    #[test]
+    fn test_replace_with_rebuilt() {
+        let node = crate::util::mock_node::MockNode::new();
+        node.respond("getnetworkinfo", json!({ "version": 210000 }));
+        let watcher = WalletWatcher::new(Network::Regtest, vec![], vec![], false).unwrap();
+        let config = IndexerConfig {
+            block_scan_from: Some(0),
+            ..test_config()
+        };
+        let mut indexer = Indexer::new(config, Arc::new(node), watcher).unwrap();
+        let stale_txid = Txid::from_str(&"11".repeat(32)).unwrap();
+        indexer.scanned_mempool.insert(stale_txid);
+
+        let mut rebuilt = indexer.fork_empty();
+        let (address, rebuilt_address) = (
+            Address::p2wsh(&Script::new(), Network::Regtest),
+            Address::p2wsh(&Builder::new().push_int(1).into_script(), Network::Regtest),
+        );
+        indexer
+            .track_address(address.clone(), RescanSince::Now)
+            .unwrap();
+        let scanned_txid = Txid::from_str(&"22".repeat(32)).unwrap();
+        rebuilt.scanned_mempool.insert(scanned_txid);
+        let target = (rebuilt_address.clone(), KeyOrigin::Standalone);
+        rebuilt
+            .scan_targets
+            .insert(rebuilt_address.script_pubkey(), target);
+
+        // the mempool scan state follows the rebuilt store, while keeping all the scan targets
+        indexer.replace_with(rebuilt);
+        assert_eq!(
+            indexer.scanned_mempool,
+            vec![scanned_txid].into_iter().collect()
+        );
+        assert!(indexer.scan_targets.contains_key(&address.script_pubkey()));
+        assert!(indexer
+            .scan_targets
+            .contains_key(&rebuilt_address.script_pubkey()));
+    }
+
+    #[test]
+    fn test_gap_rescan() {
+        use crate::util::descriptor::{DescriptorExt, ExtendedDescriptor};
+        use crate::wallet::Wallet;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Mutex;
+
+        const XPUB: &str = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+        let desc = ExtendedDescriptor::from_str(&format!("wpkh({}/0/*)", XPUB)).unwrap();
+        let checksum = desc.checksum();
+        let wallet =
+            Wallet::from_descriptor(desc, Network::Bitcoin, 5, 5, RescanSince::Timestamp(0))
+                .unwrap();
+        // an address past the gap limit, which received funds in an old block
+        let address = wallet.derive_address(15);
+        let label = KeyOrigin::Descriptor(checksum.clone(), 15).to_label();
+        let funding_tx = make_tx(&[], &[(50000, address.script_pubkey())]);
+        let txid = funding_tx.txid();
+
+        // the transaction gets listed following a rescan, but only when listing the full history
+        let rescanned = Arc::new(AtomicBool::new(false));
+        let chain = make_chain(0);
+        let node = make_mock_node(
+            Arc::new(Mutex::new(chain.clone())),
+            vec![funding_tx],
+            |_| vec![],
+        );
+        let rescanned_ = rescanned.clone();
+        node.respond_with("listsinceblock", move |args| {
+            let mut detail = receive_detail(&address, 0.0005, 0);
+            detail["label"] = json!(label);
+            let transactions = iif!(
+                args[0].is_null() && rescanned_.load(Ordering::SeqCst),
+                vec![listing_entry(&chain, &txid, Some(50), detail)],
+                vec![]
+            );
+            Ok(json!({ "transactions": transactions, "lastblock": chain.last().unwrap() }))
+        });
+        let rescanned_ = rescanned.clone();
+        node.respond_with("importmulti", move |args| {
+            if args[1]["rescan"] == json!(true) {
+                rescanned_.store(true, Ordering::SeqCst);
+            }
+            let results = args[0].as_array().unwrap().iter();
+            Ok(json!(results
+                .map(|_| json!({ "success": true }))
+                .collect::<Vec<_>>()))
+        });
+        let node = Arc::new(node);
+
+        let watcher = WalletWatcher::new(Network::Bitcoin, vec![wallet], vec![], false).unwrap();
+        let mut indexer = Indexer::new(test_config(), node.clone(), watcher).unwrap();
+        indexer.sync().unwrap();
+        let tip = indexer.tip();
+
+        let rescan = indexer.set_gap_limit(&checksum, 20).unwrap().unwrap();
+        rescan.import(&*node).unwrap();
+        // listing the wallet history since the synced tip doesn't pick up the rescanned transaction
+        assert!(indexer.sync().unwrap().is_empty());
+        assert!(indexer.store().get_tx_entry(&txid).is_none());
+
+        // the full history is listed once the rescan completes. the gap moved forward past the
+        // newly funded address, which requires another rescan.
+        let rescan = indexer.gap_rescanned(&rescan).unwrap().unwrap();
+        assert_eq!(indexer.tip(), tip);
+        assert_eq!(
+            indexer.store().get_tx_status(&txid),
+            Some(TxStatus::Confirmed(50))
+        );
+        rescan.import(&*node).unwrap();
+        assert!(indexer.gap_rescanned(&rescan).unwrap().is_none());
+
+        // the changes get returned by the next sync
+        let changes = indexer.sync().unwrap();
+        assert!(changes
+            .iter()
+            .any(|change| matches!(change, IndexChange::Transaction(t, _) if *t == txid)));
+        assert!(indexer.sync().unwrap().is_empty());
+        assert_eq!(indexer.store().audit(), Ok(()));
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "http")]
+    async fn test_sync_async_canceled() {
+        use futures_util::FutureExt;
+        use std::sync::Mutex;
+
+        let address = Address::p2wsh(&Script::new(), Network::Regtest);
+        let funding_tx = make_tx(&[], &[(50000, address.script_pubkey())]);
+        let txid = funding_tx.txid();
+
+        // the wallet transaction shows up in the listing after the initial sync
+        let listed = Arc::new(Mutex::new(false));
+        let listed_ = listed.clone();
+        let chain = Arc::new(Mutex::new(make_chain(0)));
+        let node = make_mock_node(chain, vec![funding_tx], move |chain| {
+            let detail = receive_detail(&address, 0.0005, 0);
+            let entry = listing_entry(chain, &txid, None, detail);
+            iif!(*listed_.lock().unwrap(), vec![entry], vec![])
+        });
+        let node = Arc::new(node);
+        let watcher = WalletWatcher::watch_all(Network::Regtest);
+        let mut indexer = Indexer::new(test_config(), node.clone(), watcher).unwrap();
+        indexer.initial_sync(None).unwrap();
+        let indexer = Arc::new(RwLock::new(indexer));
+        *listed.lock().unwrap() = true;
+
+        // start syncing while the indexer is locked, then drop the future before it completes
+        let lock = indexer.write().unwrap();
+        let listings = node.calls("listsinceblock").len();
+        let sync = Indexer::sync_async(indexer.clone());
+        assert!(sync.now_or_never().is_none());
+        drop(lock);
+
+        // wait for the background sync to start, the lock is held until it completes
+        while node.calls("listsinceblock").len() == listings {
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        let mut indexer = indexer.write().unwrap();
+        assert_eq!(
+            indexer.store().get_tx_status(&txid),
+            Some(TxStatus::Unconfirmed)
+        );
+
+        // the changes of the canceled sync are returned by the next one
+        let changes = indexer.sync().unwrap();
+        assert!(matches!(changes[..], [
+            IndexChange::Transaction(t, TxStatus::Unconfirmed),
+            IndexChange::TxoFunded(outpoint, ..),
+        ] if t == txid && outpoint.txid == txid));
+        assert!(indexer.sync().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_bulk_process() {
+        let address = Address::p2wsh(&Script::new(), Network::Regtest);
+        let scripthash = ScriptHash::from(&address);
+
+        let funding_tx = make_tx(&[], &[(50000, address.script_pubkey())]);
+        let spending_tx = make_tx(
+            &[OutPoint::new(funding_tx.txid(), 0)],
+            &[(49000, Script::new())],
+        );
+
+        let txs = vec![
+            // provided out of order, the funding tx must get processed first regardless
+            make_gtx(&spending_tx, 0, vec![send_detail(-0.00049, 0, -0.00001)]),
+            make_gtx(&funding_tx, 3, vec![receive_detail(&address, 0.0005, 0)]),
+        ];
+
+        let mut store = MemoryStore::new();
+        let mut watcher = WalletWatcher::watch_all(Network::Regtest);
+
+        let changes = bulk_process(&mut store, &mut watcher, txs.clone(), 100).unwrap();
+        assert_eq!(changes.len(), 4); // 2x Transaction, TxoFunded and TxoSpent
+        assert_eq!(store.get_tx_count(&scripthash), 2);
+        assert_eq!(
+            store
+                .get_tx_entry(&spending_tx.txid())
+                .unwrap()
+                .fee
+                .unwrap()
+                .fee,
+            1000
+        );
+        assert_eq!(store.audit(), Ok(()));
+
+        // reprocessing is a no-op and doesn't introduce duplicate history entries
+        let changes = bulk_process(&mut store, &mut watcher, txs, 100).unwrap();
+        assert!(changes.is_empty());
+        assert_eq!(store.get_tx_count(&scripthash), 2);
+        assert_eq!(store.audit(), Ok(()));
+    }
+
+    #[test]
+    fn test_self_transfer_balance() {
+        use crate::types::TxDirection;
+        use crate::util::descriptor::{DescriptorExt, ExtendedDescriptor};
+        use crate::wallet::Wallet;
+
+        const XPUB: &str = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+        let (wallets, checksums): (Vec<_>, Vec<_>) = ["0/*", "1/*"]
+            .iter()
+            .map(|path| {
+                let desc =
+                    ExtendedDescriptor::from_str(&format!("wpkh({}/{})", XPUB, path)).unwrap();
+                let checksum = desc.checksum();
+                let wallet =
+                    Wallet::from_descriptor(desc, Network::Bitcoin, 5, 5, RescanSince::Now);
+                (wallet.unwrap(), checksum)
+            })
+            .unzip();
+        let (receive, change) = (wallets[0].clone(), wallets[1].clone());
+        let mut watcher = WalletWatcher::new(Network::Bitcoin, wallets, vec![], false).unwrap();
+
+        let receive_detail = |wallet: &Wallet, index: u32, vout: u32, amount: f64| {
+            let checksum = iif!(wallet.is_internal(), &checksums[1], &checksums[0]);
+            let origin = KeyOrigin::Descriptor(checksum.clone(), index);
+            json!({ "address": wallet.derive_address(index), "label": origin.to_label(),
+                    "category": "receive", "amount": amount, "vout": vout })
+        };
+        let funding_tx = make_tx(&[], &[(60000, receive.derive_address(0).script_pubkey())]);
+        // consolidate into another receive address and a change address, paying a 1000 sat fee
+        let consolidation_tx = make_tx(
+            &[OutPoint::new(funding_tx.txid(), 0)],
+            &[
+                (25000, receive.derive_address(1).script_pubkey()),
+                (34000, change.derive_address(0).script_pubkey()),
+            ],
+        );
+        let make_txs = |consolidation_confs| {
+            vec![
+                make_gtx(&funding_tx, 2, vec![receive_detail(&receive, 0, 0, 0.0006)]),
+                make_gtx(
+                    &consolidation_tx,
+                    consolidation_confs,
+                    vec![
+                        json!({ "category": "send", "amount": -0.00025, "vout": 0, "fee": -0.00001 }),
+                        json!({ "category": "send", "amount": -0.00034, "vout": 1, "fee": -0.00001 }),
+                        receive_detail(&receive, 1, 0, 0.00025),
+                        receive_detail(&change, 0, 1, 0.00034),
+                    ],
+                ),
+            ]
+        };
+
+        let mut store = MemoryStore::new();
+        bulk_process(&mut store, &mut watcher, make_txs(0), 100).unwrap();
+
+        // the spent value leaves the receive address and arrives at the other wallet addresses,
+        // with only the fee actually leaving the wallet
+        let tx_entry = store.get_tx_entry(&consolidation_tx.txid()).unwrap();
+        assert_eq!(tx_entry.net_amount(), -1000);
+        assert_eq!(tx_entry.fee.unwrap().fee, 1000);
+        assert_eq!(tx_entry.direction(), TxDirection::SelfTransfer);
+        assert_eq!(
+            store::get_descriptor_balance(&store, &checksums[0]),
+            (60000, 25000)
+        );
+        assert_eq!(
+            store::get_descriptor_balance(&store, &checksums[1]),
+            (0, 34000)
+        );
+        assert_eq!(store.audit(), Ok(()));
+
+        // the balances add up to the same total once confirmed
+        bulk_process(&mut store, &mut watcher, make_txs(1), 101).unwrap();
+        assert_eq!(
+            store::get_descriptor_balance(&store, &checksums[0]),
+            (25000, 25000)
+        );
+        assert_eq!(
+            store::get_descriptor_balance(&store, &checksums[1]),
+            (34000, 34000)
+        );
+        assert_eq!(
+            store.get_tx_status(&consolidation_tx.txid()),
+            Some(TxStatus::Confirmed(101))
+        );
+        assert_eq!(store.audit(), Ok(()));
+    }
+
+    #[test]
+    fn test_fee_sources() {
+        let address = Address::p2wsh(&Script::new(), Network::Regtest);
+        let funding_tx = make_tx(
+            &[],
+            &[
+                (50000, address.script_pubkey()),
+                (50000, address.script_pubkey()),
+            ],
+        );
+        let own_spend = make_tx(
+            &[OutPoint::new(funding_tx.txid(), 0)],
+            &[(49000, Script::new())],
+        );
+        let mixed_spend = make_tx(
+            &[OutPoint::new(funding_tx.txid(), 1), OutPoint::default()],
+            &[(49000, Script::new())],
+        );
+
+        let txs = vec![
+            // receive entries don't have a fee
+            make_gtx(
+                &funding_tx,
+                3,
+                vec![
+                    receive_detail(&address, 0.0005, 0),
+                    receive_detail(&address, 0.0005, 1),
+                ],
+            ),
+            // the reported fee is ignored in favor of the one derived from the prevouts
+            make_gtx(&own_spend, 0, vec![send_detail(-0.00049, 0, -0.00002)]),
+            // bitcoind reports a bogus positive fee when foreign inputs are involved
+            make_gtx(&mixed_spend, 0, vec![send_detail(-0.00049, 0, 0.00001)]),
+        ];
+
+        let mut store = MemoryStore::new();
+        let mut watcher = WalletWatcher::watch_all(Network::Regtest);
+        bulk_process(&mut store, &mut watcher, txs, 100).unwrap();
+
+        let get_fee = |txid| store.get_tx_entry(&txid).unwrap().fee.map(|f| f.fee);
+        assert_eq!(get_fee(funding_tx.txid()), None);
+        assert_eq!(get_fee(own_spend.txid()), Some(1000));
+        assert_eq!(get_fee(mixed_spend.txid()), None);
+    }
+
+    #[test]
+    fn test_amount_alerts() {
+        let scripthash = ScriptHash::from(&Address::p2wsh(&Script::new(), Network::Regtest));
+        let funded = |txid: &str, amount, status| {
+            let outpoint = OutPoint::new(Txid::from_str(&txid.repeat(32)).unwrap(), 0);
+            IndexChange::TxoFunded(outpoint, scripthash, amount, status)
+        };
+        let count_alerts = |changes: &[IndexChange]| {
+            changes
+                .iter()
+                .filter(|c| matches!(c, IndexChange::AmountAlert(..)))
+                .count()
+        };
+
+        let mut alerts = AmountAlerts::default();
+        alerts.register(scripthash, 10000, false);
+
+        // only outputs meeting the threshold fire, and only once per output
+        let changes = alerts.check(&[
+            funded("11", 9999, TxStatus::Unconfirmed),
+            funded("22", 10000, TxStatus::Unconfirmed),
+        ]);
+        assert_eq!(count_alerts(&changes), 1);
+        assert!(matches!(
+            changes[0],
+            IndexChange::AmountAlert(_, _, 10000, TxStatus::Unconfirmed)
+        ));
+
+        let changes = alerts.check(&[funded("22", 10000, TxStatus::Confirmed(10))]);
+        assert_eq!(count_alerts(&changes), 0);
+
+        // one-shot alerts are removed after firing
+        alerts.register(scripthash, 500, true);
+        let changes = alerts.check(&[
+            funded("33", 600, TxStatus::Unconfirmed),
+            funded("44", 700, TxStatus::Unconfirmed),
+        ]);
+        assert_eq!(count_alerts(&changes), 1);
+        assert!(alerts.get(&scripthash).is_none());
+    }
+
+    #[test]
+    fn test_amount_alerts_sync() {
+        use std::sync::Mutex;
+
+        let address = Address::p2wsh(&Script::new(), Network::Regtest);
+        let scripthash = ScriptHash::from(&address);
+        let old_tx = make_tx(&[], &[(50000, address.script_pubkey())]);
+        let new_tx = make_tx(&[], &[(60000, address.script_pubkey())]);
+        let new_outpoint = OutPoint::new(new_tx.txid(), 0);
+
+        let chain = Arc::new(Mutex::new(make_chain(0)));
+        // the wallet transactions listed by listsinceblock, with the height they confirmed at
+        let listed = Arc::new(Mutex::new(vec![(old_tx.txid(), Some(100u32), 0.0005)]));
+        let listed_ = listed.clone();
+        let node = make_mock_node(chain.clone(), vec![old_tx, new_tx.clone()], move |chain| {
+            let listed = listed_.lock().unwrap();
+            let entry = |(txid, height, amount)| {
+                listing_entry(chain, &txid, height, receive_detail(&address, amount, 0))
+            };
+            listed.iter().copied().map(entry).collect()
+        });
+
+        let config = IndexerConfig {
+            finality_confirmations: 2,
+            ..test_config()
+        };
+        let watcher = WalletWatcher::watch_all(Network::Regtest);
+        let mut indexer = Indexer::new(config, Arc::new(node), watcher).unwrap();
+        indexer.initial_sync(None).unwrap();
+        indexer.register_amount_alert(scripthash, 10000, false);
+
+        let alerted = |changes: &[IndexChange]| -> Vec<OutPoint> {
+            changes
+                .iter()
+                .filter_map(|change| match change {
+                    IndexChange::AmountAlert(outpoint, ..) => Some(*outpoint),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        // payments first seen while re-syncing following a reorg fire alerts, without emitting
+        // the regular events
+        *chain.lock().unwrap() = make_chain(1);
+        listed
+            .lock()
+            .unwrap()
+            .push((new_outpoint.txid, None, 0.0006));
+        let changes = indexer.sync().unwrap();
+        assert!(matches!(changes[0], IndexChange::Reorg(..)));
+        assert_eq!(alerted(&changes), vec![new_outpoint]);
+        assert!(!changes
+            .iter()
+            .any(|change| matches!(change, IndexChange::TxoFunded(..))));
+
+        // confirming doesn't fire again
+        listed.lock().unwrap()[1].1 = Some(101);
+        let changes = indexer.sync().unwrap();
+        assert!(alerted(&changes).is_empty());
+        let fired = |indexer: &Indexer<MemoryStore, _>| {
+            indexer
+                .amount_alerts()
+                .get(&scripthash)
+                .unwrap()
+                .fired
+                .clone()
+        };
+        assert!(fired(&indexer).contains(&new_outpoint));
+
+        // fired outputs are forgotten once final
+        let final_tip = bitcoin::hashes::Hash::hash(b"102");
+        chain.lock().unwrap().push(final_tip);
+        indexer.sync().unwrap();
+        assert!(fired(&indexer).is_empty());
+    }
+
+    #[test]
+    fn test_bulk_process_unconfirmed_chain() {
+        let address = Address::p2wsh(&Script::new(), Network::Regtest);
+        let change_address =
+            Address::p2wsh(&Builder::new().push_int(1).into_script(), Network::Regtest);
+        let funding_tx = make_tx(&[], &[(50000, address.script_pubkey())]);
+        // spends the funding tx with a payment and a change output
+        let first_tx = make_tx(
+            &[OutPoint::new(funding_tx.txid(), 0)],
+            &[
+                (30000, Script::new()),
+                (19000, change_address.script_pubkey()),
+            ],
+        );
+        // spends the change output of the first tx
+        let second_tx = make_tx(
+            &[OutPoint::new(first_tx.txid(), 1)],
+            &[(18000, Script::new())],
+        );
+
+        let txs = vec![
+            // the unconfirmed children are listed before their parents
+            make_gtx(&second_tx, 0, vec![send_detail(-0.00018, 0, -0.00001)]),
+            make_gtx(
+                &first_tx,
+                0,
+                vec![
+                    send_detail(-0.0003, 0, -0.00001),
+                    receive_detail(&change_address, 0.00019, 1),
+                ],
+            ),
+            make_gtx(&funding_tx, 1, vec![receive_detail(&address, 0.0005, 0)]),
+        ];
+
+        let mut store = MemoryStore::new();
+        let mut watcher = WalletWatcher::watch_all(Network::Regtest);
+        bulk_process(&mut store, &mut watcher, txs, 100).unwrap();
+
+        // the middle tx is indexed with both its change output and its spent input
+        let first_entry = store.get_tx_entry(&first_tx.txid()).unwrap();
+        assert_eq!(first_entry.funding.len(), 1);
+        assert_eq!(first_entry.spending.len(), 1);
+        assert_eq!(first_entry.fee.unwrap().fee, 1000);
+
+        let second_entry = store.get_tx_entry(&second_tx.txid()).unwrap();
+        assert_eq!(second_entry.spending.len(), 1);
+        assert_eq!(second_entry.fee.unwrap().fee, 1000);
+
+        assert_eq!(store.get_tx_count(&ScriptHash::from(&address)), 2);
+        assert_eq!(store.get_tx_count(&ScriptHash::from(&change_address)), 2);
+        assert_eq!(store.audit(), Ok(()));
+    }
+
+    #[test]
+    fn test_scan_tx() {
+        let address = Address::p2wsh(&Script::new(), Network::Regtest);
+        let scripthash = ScriptHash::from(&address);
+
+        let mut watcher = WalletWatcher::new(
+            Network::Regtest,
+            vec![],
+            vec![(address.clone(), RescanSince::Now)],
+            false,
+        )
+        .unwrap();
+        let scan_targets: HashMap<_, _> =
+            make_scan_targets(watcher.derive_scan_targets()).collect();
+        assert_eq!(scan_targets.len(), 1);
+        assert!(watcher.derive_scan_targets().is_empty());
+
+        let funding_tx = make_tx(
+            &[],
+            &[(50000, address.script_pubkey()), (10000, Script::new())],
+        );
+        let spending_tx = make_tx(
+            &[OutPoint::new(funding_tx.txid(), 0)],
+            &[(49000, Script::new())],
+        );
+        let unrelated_tx = make_tx(&[OutPoint::new(funding_tx.txid(), 1)], &[]);
+
+        let mut store = MemoryStore::new();
+        let mut changelog = Changelog::new(true);
+
+        // scanned as unconfirmed mempool transactions, listed in reverse order
+        let txs = vec![&unrelated_tx, &spending_tx, &funding_tx];
+        let txs = sort_by_dependencies(txs, |tx| tx);
+        assert_eq!(txs, vec![&funding_tx, &unrelated_tx, &spending_tx]);
+        for tx in txs {
+            let status = TxStatus::Unconfirmed;
+            scan_tx(
+                &mut store,
+                &mut watcher,
+                &scan_targets,
+                tx,
+                status,
+                &mut changelog,
+            );
+        }
+        assert_eq!(store.get_tx_count(&scripthash), 2);
+        assert!(store.get_tx_entry(&unrelated_tx.txid()).is_none());
+        assert_eq!(
+            store
+                .get_tx_entry(&spending_tx.txid())
+                .unwrap()
+                .fee
+                .unwrap()
+                .fee,
+            1000
+        );
+
+        // confirming them updates their status without indexing them again
+        let mut changelog = Changelog::new(true);
+        for tx in &[&funding_tx, &spending_tx] {
+            let status = TxStatus::Confirmed(100);
+            scan_tx(
+                &mut store,
+                &mut watcher,
+                &scan_targets,
+                tx,
+                status,
+                &mut changelog,
+            );
+        }
+        for tx in &[&funding_tx, &spending_tx] {
+            let tx_entry = store.get_tx_entry(&tx.txid()).unwrap();
+            assert_eq!(tx_entry.status, TxStatus::Confirmed(100));
+        }
+        assert_eq!(store.get_tx_count(&scripthash), 2);
+        assert_eq!(store.audit(), Ok(()));
+    }
+
+    #[test]
+    fn test_resume_interrupted_sync() {
+        let address = Address::p2wsh(&Script::new(), Network::Regtest);
+        let scripthash = ScriptHash::from(&address);
+
+        let funding_tx = make_tx(&[], &[(50000, address.script_pubkey())]);
+        let spending_tx = make_tx(
+            &[OutPoint::new(funding_tx.txid(), 0)],
+            &[(30000, Script::new()), (19000, address.script_pubkey())],
+        );
+        let txs = vec![
+            make_gtx(&funding_tx, 3, vec![receive_detail(&address, 0.0005, 0)]),
+            make_gtx(
+                &spending_tx,
+                1,
+                vec![
+                    send_detail(-0.0003, 0, -0.00001),
+                    receive_detail(&address, 0.00019, 1),
+                ],
+            ),
+        ];
+
+        let mut watcher = WalletWatcher::watch_all(Network::Regtest);
+        let mut fresh_store = MemoryStore::new();
+        bulk_process(&mut fresh_store, &mut watcher, txs.clone(), 100).unwrap();
+
+        // a sync interrupted after indexing the funded outputs, but before the spending inputs
+        let mut store = MemoryStore::new();
+        let mut changelog = Changelog::new(false);
+        for gtx in &txs {
+            let status = TxStatus::from_confirmations(gtx.info.confirmations, 100);
+            let incoming = gtx
+                .details
+                .iter()
+                .filter(|d| d.category == TxCategory::Receive);
+            index_incoming_txos(
+                &mut store,
+                &mut watcher,
+                &gtx.info.txid,
+                status,
+                incoming,
+                &mut changelog,
+            );
+        }
+        assert_eq!(store.audit(), Ok(()));
+        assert!(store
+            .get_tx_entry(&spending_tx.txid())
+            .unwrap()
+            .spending
+            .is_empty());
+        assert_eq!(
+            store::get_tx_unspent_outputs(&store, &funding_tx.txid()).len(),
+            1
+        );
+
+        // the next sync resumes cleanly, arriving at the same state as an uninterrupted one
+        let changes = bulk_process(&mut store, &mut watcher, txs, 100).unwrap();
+        assert_eq!(changes.len(), 1); // TxoSpent
+        assert_eq!(store.audit(), Ok(()));
+        #[cfg(feature = "track-spends")]
+        assert!(store::get_tx_unspent_outputs(&store, &funding_tx.txid()).is_empty());
+        assert_eq!(store.utxo_count(), 1);
+        assert_eq!(store.utxo_count(), fresh_store.utxo_count());
+        assert_eq!(
+            store.get_history(&scripthash),
+            fresh_store.get_history(&scripthash)
+        );
+        assert_eq!(
+            store::get_tx_unspent_outputs(&store, &spending_tx.txid()).len(),
+            store::get_tx_unspent_outputs(&fresh_store, &spending_tx.txid()).len()
+        );
+    }
+
+    #[test]
+    fn test_change_detection() {
+        use crate::util::descriptor::ExtendedDescriptor;
+        use crate::wallet::Wallet;
+        use std::str::FromStr;
+
+        const XPUB: &str = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+        let wallets = ["0/*", "1/*"]
+            .iter()
+            .map(|path| {
+                let desc = ExtendedDescriptor::from_str(&format!("wpkh({}/{})", XPUB, path));
+                Wallet::from_descriptor(desc.unwrap(), Network::Bitcoin, 5, 5, RescanSince::Now)
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let (receive_address, change_address) =
+            (wallets[0].derive_address(0), wallets[1].derive_address(0));
+
+        let standalone_address = Address::p2wsh(&Script::new(), Network::Bitcoin);
+        let standalone = vec![(standalone_address.clone(), RescanSince::Now)];
+        let mut watcher = WalletWatcher::new(Network::Bitcoin, wallets, standalone, false).unwrap();
+        let scan_targets: HashMap<_, _> =
+            make_scan_targets(watcher.derive_scan_targets()).collect();
+
+        let funding_tx = make_tx(
+            &[],
+            &[
+                (50000, receive_address.script_pubkey()),
+                (10000, standalone_address.script_pubkey()),
+            ],
+        );
+        // spend with a payment to a non-wallet address and a change output
+        let payment_address =
+            Address::p2wsh(&Builder::new().push_int(1).into_script(), Network::Bitcoin);
+        let spending_tx = make_tx(
+            &[OutPoint::new(funding_tx.txid(), 0)],
+            &[
+                (30000, payment_address.script_pubkey()),
+                (19000, change_address.script_pubkey()),
+            ],
+        );
+
+        let mut store = MemoryStore::new();
+        let mut changelog = Changelog::new(false);
+        for tx in &[&funding_tx, &spending_tx] {
+            let status = TxStatus::Confirmed(100);
+            scan_tx(
+                &mut store,
+                &mut watcher,
+                &scan_targets,
+                tx,
+                status,
+                &mut changelog,
+            );
+        }
+
+        let is_change = |txid: &Txid, vout: u32| {
+            let FundingInfo(scripthash, _) = store.lookup_txo_fund(&OutPoint::new(*txid, vout))?;
+            watcher.is_change(&store.get_script_info(&scripthash)?.origin)
+        };
+        assert_eq!(is_change(&funding_tx.txid(), 0), Some(false));
+        // unknown for standalone addresses
+        assert_eq!(is_change(&funding_tx.txid(), 1), None);
+        // the payment output is not a wallet output, the change output is
+        assert!(store
+            .lookup_txo_fund(&OutPoint::new(spending_tx.txid(), 0))
+            .is_none());
+        assert_eq!(is_change(&spending_tx.txid(), 1), Some(true));
+        assert_eq!(
+            store
+                .get_tx_entry(&spending_tx.txid())
+                .unwrap()
+                .fee
+                .unwrap()
+                .fee,
+            1000
+        );
+    }
+
+    #[test]
+    fn test_listing_fingerprint() {
+        let address1 = Address::p2wsh(&Script::new(), Network::Regtest);
+        let address2 = Address::p2wsh(&Builder::new().push_int(1).into_script(), Network::Regtest);
+        let txid = Txid::default();
+
+        let entry = |category: &str, vout: u32, address: &Address, amount: f64| {
+            json!({
+                "txid": txid,
+                "confirmations": 1,
+                "time": 0,
+                "timereceived": 0,
+                "bip125-replaceable": "no",
+                "walletconflicts": [],
+                "category": category,
+                "vout": vout,
+                "address": address,
+                "label": "bwt",
+                "amount": amount,
+            })
+        };
+        let fingerprint = |entries: Vec<Value>| {
+            let result: ListSinceBlockResult = serde_json::from_value(json!({
+                "transactions": entries,
+                "lastblock": BlockHash::default(),
+            }))
+            .unwrap();
+            listing_fingerprint(&result)
+        };
+
+        // a single transaction paying to several wallet addresses is listed once per output
+        let listing = vec![
+            entry("receive", 0, &address1, 0.1),
+            entry("receive", 1, &address2, 0.2),
+        ];
+        assert_eq!(fingerprint(listing.clone()), fingerprint(listing));
+
+        // entries for the same txid that differ by their address, vout or category are told apart
+        assert_ne!(
+            fingerprint(vec![
+                entry("receive", 0, &address1, 0.1),
+                entry("receive", 1, &address1, 0.2)
+            ]),
+            fingerprint(vec![
+                entry("receive", 0, &address1, 0.1),
+                entry("receive", 1, &address2, 0.2)
+            ]),
+        );
+        assert_ne!(
+            fingerprint(vec![entry("receive", 0, &address1, 0.1)]),
+            fingerprint(vec![entry("receive", 1, &address1, 0.1)]),
+        );
+        assert_ne!(
+            fingerprint(vec![entry("receive", 0, &address1, 0.1)]),
+            fingerprint(vec![entry("generate", 0, &address1, 0.1)]),
+        );
+        // duplicated entries are not collapsed
+        assert_ne!(
+            fingerprint(vec![entry("receive", 0, &address1, 0.1)]),
+            fingerprint(vec![
+                entry("receive", 0, &address1, 0.1),
+                entry("receive", 0, &address1, 0.1)
+            ]),
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "op-return")]
+    fn test_op_return() {
+        let address = Address::p2wsh(&Script::new(), Network::Regtest);
+        let op_return_script = Builder::new()
+            .push_opcode(bitcoin::blockdata::opcodes::all::OP_RETURN)
+            .push_slice(b"bwt")
+            .push_slice(b"!")
+            .into_script();
+        let tx = make_tx(
+            &[],
+            &[(50000, address.script_pubkey()), (0, op_return_script)],
+        );
+        let gtx = make_gtx(
+            &tx,
+            1,
+            vec![
+                json!({ "address": address, "category": "receive", "amount": 0.0005, "vout": 0, "label": "bwt" }),
+            ],
+        );
+
+        let mut store = MemoryStore::new();
+        let mut watcher = WalletWatcher::watch_all(Network::Regtest);
+        bulk_process(&mut store, &mut watcher, vec![gtx], 100).unwrap();
+
+        let tx_entry = store.get_tx_entry(&tx.txid()).unwrap();
+        assert_eq!(tx_entry.op_return, Some(vec![b"bwt!".to_vec()]));
+    }
+
+    #[test]
+    fn test_rebuild_reproduces_state() {
+        let address = Address::p2wsh(&Script::new(), Network::Regtest);
+        let funding_tx = make_tx(&[], &[(50000, address.script_pubkey())]);
+        let spending_tx = make_tx(
+            &[OutPoint::new(funding_tx.txid(), 0)],
+            &[(20000, address.script_pubkey())],
+        );
+        let receive = |amount: f64, vout: u32| json!({ "address": address, "category": "receive", "amount": amount, "vout": vout, "label": "bwt" });
+        let send = json!({ "address": address, "category": "send", "amount": -0.0002, "vout": 0, "fee": -0.0003 });
+        let txs = vec![
+            make_gtx(&funding_tx, 2, vec![receive(0.0005, 0)]),
+            make_gtx(&spending_tx, 1, vec![receive(0.0002, 0), send]),
+        ];
+        let mut watcher = WalletWatcher::watch_all(Network::Regtest);
+
+        // index incrementally, like the live index does as transactions arrive
+        let mut live_store = MemoryStore::new();
+        bulk_process(&mut live_store, &mut watcher, vec![txs[0].clone()], 100).unwrap();
+        bulk_process(&mut live_store, &mut watcher, txs.clone(), 101).unwrap();
+
+        // then rebuild from scratch in one go
+        let mut rebuilt_store = MemoryStore::new();
+        bulk_process(&mut rebuilt_store, &mut watcher, txs, 101).unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&live_store).unwrap(),
+            serde_json::to_value(&rebuilt_store).unwrap()
+        );
+        assert!(rebuilt_store.audit().is_ok());
+    }
+}
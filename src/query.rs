@@ -1,55 +1,92 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
 use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
+use std::thread;
 use std::time::{Duration, Instant};
 
 use serde::Serialize;
 use serde_json::Value;
 
-use bitcoin::{BlockHash, BlockHeader, Network, OutPoint, Transaction, Txid};
+use bitcoin::{Address, BlockHash, BlockHeader, Network, OutPoint, Script, Transaction, Txid};
 use bitcoin_hashes::hex::FromHex;
-use bitcoincore_rpc::{json as rpcjson, Client as RpcClient, RpcApi};
-
-use crate::error::{BwtError, Context, OptionExt, Result};
-use crate::indexer::{IndexChange, Indexer};
-use crate::store::{FundingInfo, HistoryEntry, ScriptInfo, SpendingInfo, TxEntry};
-use crate::types::{BlockId, MempoolEntry, ScriptHash, TxStatus};
-use crate::util::descriptor::Checksum;
-use crate::util::{make_fee_histogram, BoolThen};
-use crate::wallet::{KeyOrigin, Wallet};
-
-#[cfg(feature = "track-spends")]
-use crate::types::InPoint;
+#[cfg(feature = "op-return")]
+use bitcoin_hashes::hex::ToHex;
+use bitcoincore_rpc::json::{self as rpcjson, EstimateMode};
+use bitcoincore_rpc::{self as rpc, Client as RpcClient, RpcApi};
+
+use crate::error::{BwtError, Context, Error, OptionExt, Result};
+use crate::indexer::{AmountAlert, IndexChange, Indexer};
+use crate::store::{
+    self, FundingInfo, HistoryEntry, MemoryStore, ScriptInfo, Store, TxEntry, TxFee,
+};
+use crate::types::{
+    BlockId, ConfBucket, InPoint, MempoolEntry, OutputSpendStatus, RescanSince, ScriptHash,
+    SortOrder, TxDirection, TxStatus,
+};
+use crate::util::amount::AmountFormat;
+use crate::util::bitcoincore_ext::{batch_call, RPC_WALLET_ERROR};
+use crate::util::descriptor::{self, Checksum, ExtendedDescriptor};
+use crate::util::progress::scan_txout_set;
+use crate::util::xpub::Bip32Origin;
+use crate::util::{amount, fmt_datetime, make_fee_histogram, BoolThen};
+use crate::wallet::{GapRescan, GapStatus, ImportDiscrepancy, ImportState, KeyOrigin, Wallet};
+
+#[cfg(feature = "electrum")]
+use crate::types::{ElectrumBalance, StatusHash};
 
 const FEE_HISTOGRAM_TTL: Duration = Duration::from_secs(120);
 const FEE_ESTIMATES_TTL: Duration = Duration::from_secs(120);
-
-pub struct Query {
+const MIN_RELAY_FEE_TTL: Duration = Duration::from_secs(60);
+const CHAIN_INFO_TTL: Duration = Duration::from_secs(10);
+const UTXO_SCAN_INTERVAL: Duration = Duration::from_secs(5);
+const UTXO_SCAN_TIMEOUT: Duration = Duration::from_secs(600);
+const BLOCK_TIME_BATCH_SIZE: usize = 100;
+// block times are only cached once they're buried deep enough to be unlikely to get reorged
+const BLOCK_TIME_CACHE_DEPTH: u32 = 6;
+
+pub struct Query<S: Store = MemoryStore> {
     config: QueryConfig,
     rpc: Arc<RpcClient>,
-    indexer: Arc<RwLock<Indexer>>,
+    indexer: Arc<RwLock<Indexer<S>>>,
 
     cached_relayfee: RwLock<Option<f64>>,
+    cached_min_relay_fee: RwLock<Option<(f64, Instant)>>,
     cached_histogram: RwLock<Option<(FeeHistogram, Instant)>>,
-    cached_estimates: RwLock<HashMap<u16, (Option<f64>, Instant)>>,
+    cached_estimates: RwLock<HashMap<(u16, EstimateMode), (FeeEstimate, Instant)>>,
+    cached_block_times: RwLock<HashMap<u32, u64>>,
+    cached_chain_info: RwLock<Option<(ChainInfo, Instant)>>,
+    // set while a reindex is running, to reject concurrent ones
+    reindexing: AtomicBool,
 }
 
 pub struct QueryConfig {
     pub network: Network,
     pub broadcast_cmd: Option<String>,
+    pub dust_threshold: Option<u64>,
+    pub finality_confirmations: u32,
+    pub utxo_conf_buckets: Vec<u32>,
+    pub health_max_lag: u32,
+    pub amount_format: AmountFormat,
 }
 
 type FeeHistogram = Vec<(f32, u32)>;
+type FeeEstimate = Option<f64>;
 
-impl Query {
-    pub fn new(config: QueryConfig, rpc: Arc<RpcClient>, indexer: Arc<RwLock<Indexer>>) -> Self {
+impl<S: Store> Query<S> {
+    pub fn new(config: QueryConfig, rpc: Arc<RpcClient>, indexer: Arc<RwLock<Indexer<S>>>) -> Self {
         Query {
             config,
             rpc,
             indexer,
             cached_relayfee: RwLock::new(None),
+            cached_min_relay_fee: RwLock::new(None),
             cached_histogram: RwLock::new(None),
             cached_estimates: RwLock::new(HashMap::new()),
+            cached_block_times: RwLock::new(HashMap::new()),
+            cached_chain_info: RwLock::new(None),
+            reindexing: AtomicBool::new(false),
         }
     }
 
@@ -57,12 +94,74 @@ impl Query {
         &self.rpc
     }
 
+    /// The format used for serializing amounts in API responses
+    pub fn amount_format(&self) -> AmountFormat {
+        self.config.amount_format
+    }
+
     pub fn debug_index(&self) -> String {
         format!("{:#?}", self.indexer.read().unwrap().store())
     }
 
+    /// Rebuild the index from scratch, dropping any state that diverged from the bitcoind wallet.
+    ///
+    /// The new index is built in the background while the current one continues serving queries,
+    /// and is swapped in once ready. Changes that happened in the meanwhile are picked up by the
+    /// next sync.
+    ///
+    /// Fails with `BwtError::ReindexInProgress` if another reindex is already running.
+    pub fn reindex(&self) -> Result<()> {
+        self.claim_reindex()?;
+        let result = self.do_reindex();
+        self.reindexing.store(false, Ordering::SeqCst);
+        result
+    }
+
+    /// Like `reindex()`, but runs in a background thread and reports the result to `done`.
+    /// Fails right away if another reindex is already running.
+    pub fn reindex_background(
+        self: &Arc<Self>,
+        done: impl FnOnce(Result<()>) + Send + 'static,
+    ) -> Result<()> {
+        self.claim_reindex()?;
+        let query = Arc::clone(self);
+        thread::spawn(move || {
+            let result = query.do_reindex();
+            query.reindexing.store(false, Ordering::SeqCst);
+            done(result)
+        });
+        Ok(())
+    }
+
+    fn claim_reindex(&self) -> Result<()> {
+        let claimed =
+            self.reindexing
+                .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst);
+        ensure!(claimed.is_ok(), BwtError::ReindexInProgress);
+        Ok(())
+    }
+
+    fn do_reindex(&self) -> Result<()> {
+        let mut rebuilt = self.indexer.read().unwrap().fork_empty();
+        rebuilt.reindex()?;
+        self.indexer.write().unwrap().replace_with(rebuilt);
+        Ok(())
+    }
+
     pub fn dump_index(&self) -> Value {
-        json!(self.indexer.read().unwrap().store())
+        let indexer = self.indexer.read().unwrap();
+        json!(self.amount_format().wrap(indexer.store()))
+    }
+
+    /// Run `f` against a read-consistent view of the index, holding a single read lock for all the
+    /// queries made through it. The other `Query` methods acquire the lock separately for each
+    /// call, which lets the index change between them.
+    ///
+    /// Syncing is blocked until `f` returns, so it should be kept short and avoid calling bitcoind.
+    /// It must not call back into `Query`, as re-acquiring the read lock while a sync is waiting
+    /// for the write lock may deadlock.
+    pub fn with_snapshot<T>(&self, f: impl FnOnce(&Indexer<S>) -> T) -> T {
+        f(&self.indexer.read().unwrap())
     }
 
     //
@@ -79,6 +178,73 @@ impl Query {
         Ok(self.rpc.get_block_count()? as u32)
     }
 
+    /// Get a summary of bitcoind's chain state and sync progress. Cached for a short duration.
+    pub fn get_chain_info(&self) -> Result<ChainInfo> {
+        ttl_cache!(
+            self.cached_chain_info,
+            CHAIN_INFO_TTL,
+            || -> Result<ChainInfo> {
+                let info = self.rpc.get_blockchain_info()?;
+                Ok(ChainInfo {
+                    chain: info.chain,
+                    blocks: info.blocks as u32,
+                    headers: info.headers as u32,
+                    verification_progress: info.verification_progress,
+                    initial_block_download: info.initial_block_download,
+                    pruned: info.pruned,
+                })
+            }
+        );
+    }
+
+    /// Get the tip that the index was last synced against. Unlike `get_tip()`, which returns the
+    /// current bitcoind tip, this corresponds to the index state returned by the other queries.
+    /// Returns a `NotYetSynced` error if the initial sync did not complete yet.
+    pub fn get_synced_tip(&self) -> Result<BlockId> {
+        Ok(self
+            .indexer
+            .read()
+            .unwrap()
+            .tip()
+            .ok_or(BwtError::NotYetSynced)?)
+    }
+
+    /// Check the index health, based on how far it lags behind bitcoind's tip and on the outcome
+    /// of the last sync
+    pub fn get_health(&self) -> Result<HealthStatus> {
+        let core_tip_height = self.get_tip_height()?;
+        let indexer = self.indexer.read().unwrap();
+        let tip_height = indexer.tip().map(|tip| tip.height());
+        let lag_blocks = tip_height.map(|height| core_tip_height.saturating_sub(height));
+        let last_sync = indexer.last_sync();
+        let last_sync_error = last_sync.and_then(|report| report.error.clone());
+
+        Ok(HealthStatus {
+            healthy: lag_blocks.is_some_and(|lag| lag <= self.config.health_max_lag)
+                && last_sync_error.is_none(),
+            synced: lag_blocks == Some(0),
+            tip_height,
+            core_tip_height,
+            lag_blocks,
+            last_sync_duration_ms: last_sync.map(|report| report.duration_ms),
+            last_sync_error,
+        })
+    }
+
+    /// Whether the last sync completed against a stable chain tip (see `Indexer::tip_stable()`)
+    pub fn is_synced_tip_stable(&self) -> bool {
+        self.indexer.read().unwrap().tip_stable()
+    }
+
+    // Fail with `NotYetSynced` instead of returning results based on a partial index
+    fn ensure_synced(&self) -> Result<()> {
+        ensure!(
+            self.indexer.read().unwrap().is_synced(),
+            BwtError::NotYetSynced
+        );
+        Ok(())
+    }
+
     pub fn get_header(&self, blockhash: &BlockHash) -> Result<BlockHeader> {
         Ok(self.rpc.get_block_header(blockhash)?)
     }
@@ -110,7 +276,9 @@ impl Query {
         Ok(self.rpc.call("getrawmempool", &[json!(true)])?)
     }
 
-    pub fn estimate_fee(&self, target: u16) -> Result<Option<f64>> {
+    /// Get the feerate estimate (in sat/vB) for confirming within `target` blocks, or None if
+    /// bitcoind doesn't have enough data to provide an estimate. Cached per target/mode.
+    pub fn estimate_fee(&self, target: u16, mode: EstimateMode) -> Result<Option<f64>> {
         ensure!(target < 1024, "target out of range");
 
         // regtest typically doesn't have fee estimates, just use the relay fee instead.
@@ -125,13 +293,16 @@ impl Query {
             || -> Result<Option<f64>> {
                 let feerate = self
                     .rpc
-                    .estimate_smart_fee(target, None)?
+                    .estimate_smart_fee(
+                        target,
+                        iif!(mode == EstimateMode::Unset, None, Some(mode)),
+                    )?
                     .fee_rate
                     // from sat/kB to sat/b
                     .map(|rate| rate.as_sat() as f64 / 1000f64);
                 Ok(feerate)
             },
-            target
+            (target, mode)
         );
     }
 
@@ -146,6 +317,25 @@ impl Query {
         });
     }
 
+    /// Get the minimum fee rate (in sat/vB) required for transactions to get accepted into the
+    /// bitcoind mempool. This is the higher of `minrelaytxfee` and the dynamic `mempoolminfee`,
+    /// which goes up when the mempool is full. Cached for a short duration.
+    pub fn min_relay_fee(&self) -> Result<f64> {
+        ttl_cache!(
+            self.cached_min_relay_fee,
+            MIN_RELAY_FEE_TTL,
+            || -> Result<f64> {
+                let info = self.rpc.call::<Value>("getmempoolinfo", &[])?;
+                let get_rate =
+                    |key: &str| info[key].as_f64().or_err("invalid getmempoolinfo reply");
+                let feerate = get_rate("minrelaytxfee")?.max(get_rate("mempoolminfee")?);
+
+                // from BTC/kB to sat/b
+                Ok(feerate * 100_000f64)
+            }
+        );
+    }
+
     pub fn fee_histogram(&self) -> Result<FeeHistogram> {
         ttl_cache!(
             self.cached_histogram,
@@ -159,7 +349,7 @@ impl Query {
 
     pub fn get_mempool_entry<T>(&self, txid: &Txid) -> Option<MempoolEntry> {
         let indexer = self.indexer.read().unwrap();
-        indexer.store().get_mempool_entry(txid).cloned()
+        store::get_mempool_entry(indexer.store(), txid).cloned()
     }
 
     pub fn with_mempool_entry<T>(
@@ -168,7 +358,7 @@ impl Query {
         f: impl FnOnce(&MempoolEntry) -> T,
     ) -> Option<T> {
         let indexer = self.indexer.read().unwrap();
-        indexer.store().get_mempool_entry(txid).map(f)
+        store::get_mempool_entry(indexer.store(), txid).map(f)
     }
 
     //
@@ -204,9 +394,11 @@ impl Query {
     }
 
     pub fn broadcast(&self, tx_hex: &str) -> Result<Txid> {
+        // deserialize the tx to ensure validity (preventing potential code injection) and to determine the txid
+        let tx: Transaction = bitcoin::consensus::deserialize(&Vec::from_hex(tx_hex)?)?;
+        self.check_relay(&tx)?;
+
         if let Some(broadcast_cmd) = &self.config.broadcast_cmd {
-            // deserialize the tx to ensure validity (preventing potential code injection) and to determine the txid
-            let tx: Transaction = bitcoin::consensus::deserialize(&Vec::from_hex(tx_hex)?)?;
             self.check_tx(&tx)?;
             let cmd = broadcast_cmd.replacen("{tx_hex}", tx_hex, 1);
             debug!("broadcasting tx with cmd {}", broadcast_cmd);
@@ -218,6 +410,62 @@ impl Query {
         }
     }
 
+    /// Check that the transaction pays enough fees to get relayed, failing with a `FeeTooLow` error
+    /// that specifies the required minimum otherwise. The check is skipped if the previous outputs
+    /// cannot be resolved (from the index or the utxo set), leaving it to bitcoind to decide.
+    pub fn check_relay(&self, tx: &Transaction) -> Result<()> {
+        let mut input_amount = 0;
+        for input in &tx.input {
+            input_amount += some_or_ret!(self.get_prevout_amount(&input.previous_output)?, Ok(()));
+        }
+        let output_amount: u64 = tx.output.iter().map(|txout| txout.value).sum();
+        let fee = input_amount.saturating_sub(output_amount);
+        let vsize = (tx.get_weight() as f64 / 4f64).ceil();
+        let feerate = fee as f64 / vsize;
+
+        let min_feerate = self.min_relay_fee()?;
+        ensure!(
+            feerate >= min_feerate,
+            BwtError::FeeTooLow(feerate, min_feerate)
+        );
+        Ok(())
+    }
+
+    fn get_prevout_amount(&self, outpoint: &OutPoint) -> Result<Option<u64>> {
+        let indexer = self.indexer.read().unwrap();
+        if let Some(FundingInfo(_, amount)) = indexer.store().lookup_txo_fund(outpoint) {
+            return Ok(Some(amount));
+        }
+        drop(indexer);
+        Ok(self
+            .rpc
+            .get_tx_out(&outpoint.txid, outpoint.vout, Some(true))?
+            .map(|txout| txout.value.as_sat()))
+    }
+
+    /// Sign a message with the private key of a wallet address. Requires the key to be available in
+    /// the bitcoind wallet, which is typically not the case for watch-only wallets.
+    pub fn sign_message(&self, scripthash: &ScriptHash, message: &str) -> Result<String> {
+        let address = self
+            .indexer
+            .read()
+            .unwrap()
+            .store()
+            .get_script_address(scripthash)
+            .or_err(BwtError::ScriptHashNotFound(*scripthash))?;
+
+        self.rpc
+            .call("signmessage", &[json!(address), json!(message)])
+            .map_err(|err| match err {
+                rpc::Error::JsonRpc(rpc::jsonrpc::Error::Rpc(ref e))
+                    if e.code == RPC_WALLET_ERROR =>
+                {
+                    BwtError::PrivateKeyUnavailable(address).into()
+                }
+                err => Error::from(BwtError::from(err)),
+            })
+    }
+
     pub fn check_tx(&self, tx: &Transaction) -> Result<()> {
         let mut res = self.rpc.test_mempool_accept(&[tx])?;
         ensure!(res.len() == 1, "invalid response");
@@ -251,30 +499,345 @@ impl Query {
         indexer.store().get_tx_entry(txid).map(f)
     }
 
+    /// The number of confirmations after which transactions are considered final
+    pub fn finality_confirmations(&self) -> u32 {
+        self.config.finality_confirmations
+    }
+
+    /// Get the number of confirmations for a wallet transaction, relative to the last synced tip
+    /// (without querying bitcoind). Returns `Some(0)` for unconfirmed and `None` for unknown txs.
+    pub fn get_confirmations(&self, txid: &Txid) -> Result<Option<u32>> {
+        let indexer = self.indexer.read().unwrap();
+        let status = some_or_ret!(indexer.store().get_tx_status(txid), Ok(None));
+        let tip = indexer.tip().or_err("the index is not synced yet")?;
+        Ok(status.confirmations(tip.height()))
+    }
+
+    /// Get the block height a wallet transaction confirmed at. Returns None for unconfirmed,
+    /// conflicted and unknown txs.
+    pub fn get_confirmed_height(&self, txid: &Txid) -> Option<u32> {
+        let indexer = self.indexer.read().unwrap();
+        store::get_confirmed_height(indexer.store(), txid)
+    }
+
     pub fn get_tx_detail(&self, txid: &Txid) -> Option<TxDetail> {
         TxDetail::make(txid, &self)
     }
 
+    /// Get the wallet scripts funded or spent by the transaction. Returns None for transactions
+    /// that are not known.
+    pub fn get_tx_scripts(&self, txid: &Txid) -> Option<Vec<ScriptInfo>> {
+        let indexer = self.indexer.read().unwrap();
+        let store = indexer.store();
+        Some(
+            store::get_tx_scripthashes(store, txid)?
+                .iter()
+                .filter_map(|scripthash| {
+                    let mut script_info = store.get_script_info(scripthash)?;
+                    attach_wallet_info(&mut script_info, &indexer);
+                    Some(script_info)
+                })
+                .collect(),
+        )
+    }
+
+    /// Get the conflicted transactions that were purged from the index but retained for the
+    /// configured `conflicted_retention` window, with the most recently conflicted first
+    pub fn get_conflicted_txs(&self) -> Vec<ConflictedTx> {
+        let indexer = self.indexer.read().unwrap();
+        let store = indexer.store();
+        store
+            .get_conflicted_txs()
+            .into_iter()
+            .map(|(txid, tx_entry, conflicted_at)| {
+                let mut scripthashes: Vec<ScriptHash> =
+                    tx_entry.scripthashes().into_iter().copied().collect();
+                scripthashes.sort();
+                ConflictedTx {
+                    txid,
+                    replaced_by: store::replacement_chain(store, &txid)
+                        .last()
+                        .copied()
+                        .filter(|latest| *latest != txid),
+                    conflicted_at,
+                    net_amount: tx_entry.net_amount(),
+                    scripthashes,
+                }
+            })
+            .collect()
+    }
+
+    /// Get the chain of replacements that the transaction is a part of, ordered from the original
+    /// transaction to the latest version. Returns None for transactions that are not known.
+    pub fn get_replacement_chain(&self, txid: &Txid) -> Option<Vec<Txid>> {
+        let indexer = self.indexer.read().unwrap();
+        let store = indexer.store();
+        let chain = store::replacement_chain(store, txid);
+        (chain.len() > 1 || store.get_tx_entry(txid).is_some()).do_then(|| chain)
+    }
+
     //
     // History
     //
 
-    /// Get a copy of the scripthash history, ordered with oldest first.
-    pub fn get_history(&self, scripthash: &ScriptHash) -> Vec<HistoryEntry> {
-        self.map_history(scripthash, Clone::clone)
+    /// Get a copy of the scripthash history, ordered according to `order` (see `SortOrder`).
+    pub fn get_history(&self, scripthash: &ScriptHash, order: SortOrder) -> Vec<HistoryEntry> {
+        self.map_history(scripthash, order, Clone::clone)
+    }
+
+    /// Like `get_history()`, but returns a `NotYetSynced` error before the initial sync
+    /// completes instead of a possibly empty history.
+    pub fn try_get_history(
+        &self,
+        scripthash: &ScriptHash,
+        order: SortOrder,
+    ) -> Result<Vec<HistoryEntry>> {
+        self.ensure_synced()?;
+        Ok(self.get_history(scripthash, order))
     }
 
-    /// Map the scripthash history as refs through `f`, ordered with oldest first.
+    /// Get the scripthash history along with the block time of confirmed transactions, or the
+    /// time unconfirmed ones were first seen in the mempool.
+    pub fn get_history_with_time(
+        &self,
+        scripthash: &ScriptHash,
+        order: SortOrder,
+    ) -> Result<Vec<TimedHistoryEntry>> {
+        let history = self.get_history(scripthash, order);
+        let first_seen: HashMap<Txid, u64> = {
+            let indexer = self.indexer.read().unwrap();
+            history
+                .iter()
+                .filter_map(|entry| {
+                    let mempool_entry = store::get_mempool_entry(indexer.store(), &entry.txid)?;
+                    Some((entry.txid, mempool_entry.first_seen))
+                })
+                .collect()
+        };
+
+        let mut heights: Vec<u32> = history
+            .iter()
+            .filter_map(|entry| entry.status.height())
+            .collect();
+        heights.dedup();
+        let block_times = self.get_block_times(&heights)?;
+
+        Ok(history
+            .into_iter()
+            .map(|entry| {
+                let time = match entry.status {
+                    TxStatus::Confirmed(height) => block_times.get(&height).copied(),
+                    TxStatus::Unconfirmed => first_seen.get(&entry.txid).copied(),
+                    TxStatus::Conflicted => None,
+                };
+                TimedHistoryEntry { entry, time }
+            })
+            .collect())
+    }
+
+    /// Export the scripthash history as CSV for accounting, oldest first, with the columns
+    /// `Date,TxID,Type,Amount,Balance,Confirmations`. Amounts are in BTC and dates are the UTC
+    /// block time. Unconfirmed transactions have a blank date and conflicted ones are skipped.
+    pub fn export_history_csv(
+        &self,
+        scripthash: &ScriptHash,
+        mut writer: impl io::Write,
+    ) -> Result<()> {
+        self.ensure_synced()?;
+        let history = self.get_history_with_time(scripthash, SortOrder::OldestFirst)?;
+        let indexer = self.indexer.read().unwrap();
+        let tip_height = indexer
+            .tip()
+            .or_err("the index is not synced yet")?
+            .height();
+
+        writeln!(writer, "Date,TxID,Type,Amount,Balance,Confirmations")?;
+        let mut balance = 0i64;
+        for TimedHistoryEntry { entry, time } in history {
+            let tx_entry = match indexer.store().get_tx_entry(&entry.txid) {
+                Some(tx_entry) if entry.status.is_viable() => tx_entry,
+                _ => continue,
+            };
+            let amounts = tx_entry.script_amounts(scripthash);
+            let tx_type = if amounts.sent == 0 {
+                "receive"
+            } else if amounts.received > 0 && tx_entry.direction() == TxDirection::SelfTransfer {
+                "self"
+            } else {
+                "send"
+            };
+            let amount = amounts.received as i64 - amounts.sent as i64;
+            balance += amount;
+
+            let date = match (entry.status, time) {
+                (TxStatus::Confirmed(_), Some(time)) => fmt_datetime(time),
+                _ => String::new(),
+            };
+            writeln!(
+                writer,
+                "{},{},{},{},{},{}",
+                date,
+                entry.txid,
+                tx_type,
+                amount::format_btc_signed(amount),
+                amount::format_btc_signed(balance),
+                entry.status.confirmations(tip_height).unwrap_or(0),
+            )?;
+        }
+        Ok(())
+    }
+
+    // get the block times at the given heights, fetching the uncached ones in batches
+    fn get_block_times(&self, heights: &[u32]) -> Result<HashMap<u32, u64>> {
+        let mut block_times = HashMap::new();
+        let mut missing = vec![];
+        {
+            let cache = self.cached_block_times.read().unwrap();
+            for height in heights {
+                match cache.get(height) {
+                    Some(time) => {
+                        block_times.insert(*height, *time);
+                    }
+                    None => missing.push(*height),
+                }
+            }
+        }
+
+        let tip_height = self.get_tip_height()?;
+        for heights in missing.chunks(BLOCK_TIME_BATCH_SIZE) {
+            let params: Vec<_> = heights.iter().map(|height| vec![json!(height)]).collect();
+            let blockhashes: Vec<BlockHash> = batch_call(&self.rpc, "getblockhash", &params)?;
+            let params: Vec<_> = blockhashes.iter().map(|hash| vec![json!(hash)]).collect();
+            let headers: Vec<rpcjson::GetBlockHeaderResult> =
+                batch_call(&self.rpc, "getblockheader", &params)?;
+
+            let mut cache = self.cached_block_times.write().unwrap();
+            for (height, header) in heights.iter().zip(headers) {
+                let time = header.time as u64;
+                block_times.insert(*height, time);
+                if tip_height.saturating_sub(*height) + 1 >= BLOCK_TIME_CACHE_DEPTH {
+                    cache.insert(*height, time);
+                }
+            }
+        }
+
+        Ok(block_times)
+    }
+
+    /// Map the scripthash history as refs through `f`, ordered according to `order`.
     pub fn map_history<T>(
         &self,
         scripthash: &ScriptHash,
+        order: SortOrder,
         f: impl Fn(&HistoryEntry) -> T,
     ) -> Vec<T> {
         let indexer = self.indexer.read().unwrap();
         indexer
             .store()
             .get_history(scripthash)
-            .map_or_else(Vec::new, |history| history.iter().map(f).collect())
+            .map_or_else(Vec::new, |history| match order {
+                SortOrder::OldestFirst => history.iter().map(f).collect(),
+                SortOrder::NewestFirst => history.iter().rev().map(f).collect(),
+            })
+    }
+
+    /// Map up to `limit` scripthash history entries following the `after` cursor through `f`,
+    /// ordered according to `order`. Returns the cursor for the next page along with the results.
+    pub fn map_history_after<T>(
+        &self,
+        scripthash: &ScriptHash,
+        order: SortOrder,
+        after: Option<(TxStatus, Txid)>,
+        limit: usize,
+        f: impl Fn(&HistoryEntry) -> T,
+    ) -> (Vec<T>, Option<(TxStatus, Txid)>) {
+        self.map_history_after_filtered(scripthash, order, after, limit, None, f)
+    }
+
+    /// Like `map_history_after()`, but only including the transactions moving funds in
+    /// `direction` relative to the scripthash (see `Store::get_script_history_filtered()`)
+    pub fn map_history_after_filtered<T>(
+        &self,
+        scripthash: &ScriptHash,
+        order: SortOrder,
+        after: Option<(TxStatus, Txid)>,
+        limit: usize,
+        direction: Option<TxDirection>,
+        f: impl Fn(&HistoryEntry) -> T,
+    ) -> (Vec<T>, Option<(TxStatus, Txid)>) {
+        let indexer = self.indexer.read().unwrap();
+        let (entries, next_cursor) = store::get_history_after_filtered(
+            indexer.store(),
+            scripthash,
+            after,
+            limit,
+            order,
+            direction,
+        );
+        (entries.into_iter().map(f).collect(), next_cursor)
+    }
+
+    /// Get the unconfirmed transactions in the scripthash history along with their mempool
+    /// information, including whether they are BIP 125 replaceable. Ordered by txid.
+    pub fn get_mempool_history(&self, scripthash: &ScriptHash) -> Vec<MempoolHistoryEntry> {
+        let indexer = self.indexer.read().unwrap();
+        let store = indexer.store();
+        let history = some_or_ret!(store.get_history(scripthash), vec![]);
+
+        // unconfirmed transactions are always ordered last
+        let mut entries = history
+            .iter()
+            .rev()
+            .take_while(|hist| hist.status.is_unconfirmed())
+            .map(|hist| MempoolHistoryEntry {
+                txid: hist.txid,
+                mempool_info: store::get_mempool_entry(store, &hist.txid).map(Into::into),
+            })
+            .collect::<Vec<_>>();
+        entries.reverse();
+        entries
+    }
+
+    /// Get all the unconfirmed mempool transactions affecting the wallet, ordered by the time
+    /// they entered the mempool
+    pub fn get_mempool_txs(&self) -> Vec<MempoolHistoryEntry> {
+        let indexer = self.indexer.read().unwrap();
+        indexer
+            .store()
+            .mempool_transactions()
+            .into_iter()
+            .map(|(txid, mempool_entry)| MempoolHistoryEntry {
+                txid,
+                mempool_info: mempool_entry.map(Into::into),
+            })
+            .collect()
+    }
+
+    /// Get the wallet outputs that are being spent by unconfirmed transactions
+    #[cfg(feature = "track-spends")]
+    pub fn get_pending_spends(&self) -> Result<Vec<PendingSpend>> {
+        let indexer = self.indexer.read().unwrap();
+        let store = indexer.store();
+        Ok(store
+            .pending_spends()
+            .into_iter()
+            .filter_map(|(outpoint, spent_by)| {
+                let FundingInfo(scripthash, amount) = store.lookup_txo_fund(&outpoint)?;
+                Some(PendingSpend {
+                    outpoint,
+                    spent_by,
+                    scripthash,
+                    address: store.get_script_address(&scripthash),
+                    amount,
+                })
+            })
+            .collect())
+    }
+
+    /// Spends are not indexed without `track-spends`
+    #[cfg(not(feature = "track-spends"))]
+    pub fn get_pending_spends(&self) -> Result<Vec<PendingSpend>> {
+        bail!(BwtError::FeatureNotEnabled("track-spends"))
     }
 
     /// Call `f` with each history iterm as ref
@@ -288,6 +851,32 @@ impl Query {
         }
     }
 
+    /// Get the Electrum status hash of the scripthash history, or None if it has no history
+    #[cfg(feature = "electrum")]
+    pub fn get_status_hash(&self, scripthash: &ScriptHash) -> Option<StatusHash> {
+        let indexer = self.indexer.read().unwrap();
+        store::status_hash(indexer.store(), scripthash)
+    }
+
+    /// Get the Electrum balance of the scripthash. Unlike `get_script_balance()`, the unconfirmed
+    /// balance accounts for unconfirmed spends and may be negative.
+    #[cfg(feature = "electrum")]
+    pub fn get_electrum_balance(&self, scripthash: &ScriptHash) -> Result<ElectrumBalance> {
+        self.ensure_synced()?;
+        let indexer = self.indexer.read().unwrap();
+        Ok(store::electrum_balance(indexer.store(), scripthash))
+    }
+
+    /// Get the Electrum status hashes of multiple scripthashes, computed under a single lock
+    #[cfg(feature = "electrum")]
+    pub fn get_status_hashes(
+        &self,
+        scripthashes: &[ScriptHash],
+    ) -> Vec<(ScriptHash, Option<StatusHash>)> {
+        let indexer = self.indexer.read().unwrap();
+        store::status_hashes(indexer.store(), scripthashes)
+    }
+
     /// Get a copy of all history entries for all scripthashes since `min_block_height` (inclusive,
     /// including all unconfirmed), ordered with oldest first.
     pub fn get_history_since(&self, min_block_height: u32) -> Vec<HistoryEntry> {
@@ -300,9 +889,22 @@ impl Query {
         &self,
         min_block_height: u32,
         f: impl Fn(&HistoryEntry) -> T,
+    ) -> Vec<T> {
+        self.map_history_since_snapshot(min_block_height, &HashSet::new(), f)
+    }
+
+    /// Map the history entries since `min_block_height` through `f` like `map_history_since()`,
+    /// but skip the unconfirmed transactions in `known_mempool` that the caller already has.
+    /// Only newly arrived unconfirmed transactions and ones that confirmed since are returned.
+    pub fn map_history_since_snapshot<T>(
+        &self,
+        min_block_height: u32,
+        known_mempool: &HashSet<Txid>,
+        f: impl Fn(&HistoryEntry) -> T,
     ) -> Vec<T> {
         let indexer = self.indexer.read().unwrap();
-        let entries = indexer.store().get_history_since(min_block_height);
+        let entries =
+            store::get_history_since_snapshot(indexer.store(), min_block_height, known_mempool);
         entries.into_iter().map(f).collect()
     }
 
@@ -337,22 +939,28 @@ impl Query {
         scripthash: Option<&ScriptHash>,
         min_conf: usize,
         include_unsafe: Option<bool>,
+        include_dust: bool,
     ) -> Result<Vec<Txo>> {
         let (BlockId(tip_height, _), req_script_info, unspents) = some_or_ret!(
             self.list_unspent_raw(scripthash, min_conf, include_unsafe)?,
             Ok(vec![])
         );
 
+        let dust_threshold = iif!(include_dust, None, self.config.dust_threshold);
+
         let indexer = self.indexer.read().unwrap();
         Ok(unspents
             .into_iter()
+            .filter(|unspent| !matches!(dust_threshold, Some(t) if unspent.amount.as_sat() < t))
             .filter_map(|unspent| {
                 // XXX we assume that any unspent output with a "bwt/..." label is ours, this may not necessarily be true.
                 let script_info = req_script_info.clone().or_else(|| {
                     let address = unspent.address.clone()?;
-                    let label = unspent.label.as_ref()?;
-                    let origin = KeyOrigin::from_label(label)?;
+                    let origin = indexer
+                        .watcher()
+                        .resolve_origin(&address, unspent.label.as_deref())?;
                     let mut script_info = ScriptInfo::from_address(address, origin);
+                    script_info.reused = store::is_reused(indexer.store(), &script_info.scripthash);
                     attach_wallet_info(&mut script_info, &indexer);
                     Some(script_info)
                 })?;
@@ -361,6 +969,65 @@ impl Query {
             .collect())
     }
 
+    /// Get the unspent wallet outputs grouped into buckets by their confirmation count, according
+    /// to the configured `utxo_conf_buckets` boundaries. Confirmations are counted relative to the
+    /// last synced tip. Dust outputs are excluded.
+    pub fn list_unspent_grouped(
+        &self,
+        scripthash: Option<&ScriptHash>,
+    ) -> Result<BTreeMap<ConfBucket, Vec<Txo>>> {
+        let tip_height = self.get_synced_tip()?.height();
+        let utxos = self.list_unspent(scripthash, 0, None, false)?;
+
+        let mut grouped: BTreeMap<ConfBucket, Vec<Txo>> = BTreeMap::new();
+        for utxo in utxos {
+            // unspent outputs listed by bitcoind are never conflicted
+            let confirmations = utxo.status.confirmations(tip_height).unwrap_or(0);
+            let bucket = ConfBucket::classify(confirmations, &self.config.utxo_conf_buckets);
+            grouped.entry(bucket).or_default().push(utxo);
+        }
+        Ok(grouped)
+    }
+
+    /// Get the wallet outputs that are safe to spend, annotated with the information needed to
+    /// construct PSBT inputs. Immature coinbase outputs, locked outputs and unconfirmed outputs
+    /// that are unsafe to spend are excluded (by bitcoind), as well as dust outputs.
+    pub fn get_spendable_inputs(&self, min_conf: usize) -> Result<Vec<SpendableInput>> {
+        let utxos = self.list_unspent(None, min_conf, Some(false), false)?;
+        Ok(utxos.into_iter().map(SpendableInput::from).collect())
+    }
+
+    /// Get a snapshot of the unspent outputs of the given descriptors by scanning bitcoind's UTXO
+    /// set with `scantxoutset`, without importing them or rescanning the wallet history.
+    ///
+    /// The outputs are added to the index, giving an immediate balance but no history.
+    pub fn snapshot_utxos_via_scan(&self, descriptors: &[String]) -> Result<Vec<Txo>> {
+        let requests = descriptors
+            .iter()
+            .cloned()
+            .map(rpcjson::ScanTxOutRequest::Single)
+            .collect();
+        let result = scan_txout_set(
+            self.rpc.clone(),
+            requests,
+            UTXO_SCAN_INTERVAL,
+            UTXO_SCAN_TIMEOUT,
+        )?;
+        ensure!(result.success != Some(false), "UTXO set scan failed");
+
+        let outpoints = self
+            .indexer
+            .write()
+            .unwrap()
+            .index_scanned_utxos(&result.unspents);
+
+        // outputs below the dust threshold are not indexed
+        Ok(outpoints
+            .iter()
+            .filter_map(|outpoint| self.lookup_txo(outpoint))
+            .collect())
+    }
+
     #[allow(clippy::type_complexity)]
     fn list_unspent_raw(
         &self,
@@ -383,8 +1050,12 @@ impl Query {
             }
         };
 
-        // an empty array indicates not to filter by the address
-        let addresses = script_info.as_ref().map_or(vec![], |i| vec![&i.address]);
+        // an empty array indicates not to filter by the address. scripts without an address form
+        // cannot be looked up in the bitcoind wallet.
+        let addresses = match &script_info {
+            None => vec![],
+            Some(script_info) => vec![some_or_ret!(script_info.address.as_ref(), Ok(None))],
+        };
 
         loop {
             let tip_height = self.rpc.get_block_count()? as u32;
@@ -426,24 +1097,95 @@ impl Query {
         })
     }
 
+    /// Get the spend status of a wallet output, or None if the outpoint is not a known wallet output
+    #[cfg(feature = "track-spends")]
+    pub fn get_txo_spend_status(&self, outpoint: &OutPoint) -> Result<Option<OutputSpendStatus>> {
+        let indexer = self.indexer.read().unwrap();
+        let store = indexer.store();
+        Ok(store
+            .lookup_txo_fund(outpoint)
+            .map(|_| store::get_output_spend_status(store, outpoint)))
+    }
+
+    /// Spends are not indexed without `track-spends`. Fail explicitly instead of reporting every
+    /// output as unspent.
+    #[cfg(not(feature = "track-spends"))]
+    pub fn get_txo_spend_status(&self, _outpoint: &OutPoint) -> Result<Option<OutputSpendStatus>> {
+        bail!(BwtError::FeatureNotEnabled("track-spends"))
+    }
+
     //
     // Scripthashes
     //
 
+    /// Get the script info of an indexed scripthash. Falls back to deriving it from the tracked
+    /// descriptors (within their gap limit) for wallet addresses that have no history yet.
     pub fn get_script_info(&self, scripthash: &ScriptHash) -> Option<ScriptInfo> {
         let indexer = self.indexer.read().unwrap();
-        let mut script_info = indexer.store().get_script_info(scripthash)?;
-        attach_wallet_info(&mut script_info, &indexer);
+        if let Some(mut script_info) = indexer.store().get_script_info(scripthash) {
+            attach_wallet_info(&mut script_info, &indexer);
+            return Some(script_info);
+        }
+
+        indexer.watcher().derive_script_info(scripthash)
+    }
 
-        Some(script_info)
+    /// Get the single-key output descriptor of the scripthash, including its checksum.
+    ///
+    /// This is the derived child descriptor for wallet keys (e.g. `wpkh([origin]xpub/0/5)`), or
+    /// an `addr()` descriptor for standalone addresses. Returns `None` if the key belongs to a
+    /// descriptor that is no longer tracked.
+    pub fn get_descriptor(&self, scripthash: &ScriptHash) -> Result<Option<String>> {
+        let indexer = self.indexer.read().unwrap();
+        let script_info = indexer
+            .store()
+            .get_script_info(scripthash)
+            .or_err(BwtError::ScriptHashNotFound(*scripthash))?;
+
+        Ok(match script_info.origin {
+            KeyOrigin::Descriptor(checksum, index) => indexer
+                .watcher()
+                .get(&checksum)
+                .map(|wallet| wallet.derive_desc(index).to_string()),
+            KeyOrigin::Standalone => Some(match &script_info.address {
+                Some(address) => descriptor::addr_descriptor(address),
+                None => descriptor::raw_descriptor(
+                    &indexer.store().get_script_pubkey(scripthash).unwrap(),
+                ),
+            }),
+        })
     }
 
     // returns a tuple of (confirmed_balance, unconfirmed_balance)
+    // fails with `NotYetSynced` before the initial sync completes, to avoid reporting a zero balance
     pub fn get_script_balance(&self, scripthash: &ScriptHash) -> Result<(u64, u64)> {
+        let (confirmed, unconfirmed, _) = self.get_script_balances(scripthash, None)?;
+        Ok((confirmed, unconfirmed))
+    }
+
+    /// Get the balance of outputs that are final, having at least `finality_confirmations`
+    /// confirmations (or the configured `--finality-confirmations` if not provided).
+    pub fn get_script_final_balance(
+        &self,
+        scripthash: &ScriptHash,
+        finality_confirmations: Option<u32>,
+    ) -> Result<u64> {
+        let (_, _, final_balance) = self.get_script_balances(scripthash, finality_confirmations)?;
+        Ok(final_balance)
+    }
+
+    // returns a tuple of (confirmed_balance, unconfirmed_balance, final_balance)
+    fn get_script_balances(
+        &self,
+        scripthash: &ScriptHash,
+        finality_confirmations: Option<u32>,
+    ) -> Result<(u64, u64, u64)> {
+        self.ensure_synced()?;
         let (_, _, unspents) = some_or_ret!(
             self.list_unspent_raw(Some(scripthash), 0, None)?,
-            Ok((0, 0))
+            Ok((0, 0, 0))
         );
+        let threshold = finality_confirmations.unwrap_or(self.config.finality_confirmations);
 
         let (confirmed, unconfirmed): (Vec<_>, Vec<_>) = unspents
             .into_iter()
@@ -452,6 +1194,11 @@ impl Query {
         Ok((
             confirmed.iter().map(|u| u.amount.as_sat()).sum(),
             unconfirmed.iter().map(|u| u.amount.as_sat()).sum(),
+            confirmed
+                .iter()
+                .filter(|u| u.confirmations >= threshold)
+                .map(|u| u.amount.as_sat())
+                .sum(),
         ))
     }
 
@@ -461,13 +1208,18 @@ impl Query {
         let script_info = some_or_ret!(self.get_script_info(scripthash), Ok(None));
 
         let tx_count = store.get_tx_count(scripthash);
-        let (confirmed_balance, unconfirmed_balance) = self.get_script_balance(scripthash)?;
+        let (confirmed_balance, unconfirmed_balance, final_balance) =
+            self.get_script_balances(scripthash, None)?;
+        let (total_received, total_sent) = store::get_script_totals(store, scripthash, false);
 
         Ok(Some(ScriptStats {
             script_info,
             tx_count,
             confirmed_balance,
             unconfirmed_balance,
+            final_balance,
+            total_received,
+            total_sent,
         }))
     }
 
@@ -494,17 +1246,165 @@ impl Query {
         let wallet = indexer.watcher().get(checksum)?;
 
         if wallet.is_valid_index(index) {
-            Some(ScriptInfo::from_desc(
-                KeyOrigin::Descriptor(checksum.clone(), index),
-                wallet.derive_address(index),
-                wallet.derive_desc(index),
-                wallet.bip32_origins(index),
-            ))
+            let mut script_info = wallet.script_info(index);
+            script_info.reused = store::is_reused(indexer.store(), &script_info.scripthash);
+            Some(script_info)
         } else {
             None
         }
     }
 
+    /// Derive `count` addresses of the `checksum` wallet starting at index `start`, without
+    /// importing them. Useful for inspecting addresses past the gap limit.
+    pub fn get_wallet_script_range(
+        &self,
+        checksum: &Checksum,
+        start: u32,
+        count: u32,
+    ) -> Result<Vec<ScriptInfo>> {
+        let indexer = self.indexer.read().unwrap();
+        let mut script_infos = indexer.watcher().derive_addresses(checksum, start, count)?;
+        for script_info in &mut script_infos {
+            script_info.reused = store::is_reused(indexer.store(), &script_info.scripthash);
+        }
+        Ok(script_infos)
+    }
+
+    /// Widen the gap limit of the `checksum` wallet, importing the additional addresses with a
+    /// rescan. The index keeps serving queries and syncing while the rescan runs. The history
+    /// it discovers becomes available following the next sync.
+    pub fn set_gap_limit(&self, checksum: &Checksum, gap_limit: u32) -> Result<()> {
+        let rescan = self
+            .indexer
+            .write()
+            .unwrap()
+            .set_gap_limit(checksum, gap_limit)?;
+        rescan.map_or(Ok(()), |rescan| self.do_gap_rescan(rescan))
+    }
+
+    /// Like `set_gap_limit()`, but runs the rescan in a background thread and reports the result
+    /// to `done`. Fails right away if the gap limit cannot be widened.
+    pub fn set_gap_limit_background(
+        self: &Arc<Self>,
+        checksum: &Checksum,
+        gap_limit: u32,
+        done: impl FnOnce(Result<()>) + Send + 'static,
+    ) -> Result<()> {
+        let rescan = self
+            .indexer
+            .write()
+            .unwrap()
+            .set_gap_limit(checksum, gap_limit)?;
+        let query = Arc::clone(self);
+        thread::spawn(move || done(rescan.map_or(Ok(()), |rescan| query.do_gap_rescan(rescan))));
+        Ok(())
+    }
+
+    // the rescan runs without holding the indexer lock, which is only taken to pick up the
+    // discovered history once it completes
+    fn do_gap_rescan(&self, mut rescan: GapRescan) -> Result<()> {
+        loop {
+            rescan.import(&*self.rpc)?;
+            match self.indexer.write().unwrap().gap_rescanned(&rescan)? {
+                Some(next_rescan) => rescan = next_rescan,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Start tracking a standalone address. Its history is rescanned from `rescan_since` (use a
+    /// timestamp before the address first received funds to bound the rescan, or `RescanSince::Now`
+    /// to track new transactions only), and will become available following the next sync.
+    pub fn track_address(&self, address: Address, rescan_since: RescanSince) -> Result<()> {
+        self.indexer
+            .write()
+            .unwrap()
+            .track_address(address, rescan_since)
+    }
+
+    /// Refresh the status of a single wallet transaction from bitcoind without waiting for the
+    /// next sync, see `Indexer::refresh_tx()`
+    pub fn refresh_tx(&self, txid: &Txid) -> Result<Option<TxStatus>> {
+        self.indexer.write().unwrap().refresh_tx(txid)
+    }
+
+    /// Compare the tracked addresses against the addresses actually imported into the bitcoind wallet
+    pub fn verify_imports(&self) -> Result<Vec<ImportDiscrepancy>> {
+        let indexer = self.indexer.read().unwrap();
+        indexer.watcher().verify_imports(&*self.rpc)
+    }
+
+    /// Register an alert for incoming payments to `scripthash` of at least `min_amount`, emitted as
+    /// an `AmountAlert` change. One-shot alerts are removed after firing once.
+    pub fn register_amount_alert(&self, scripthash: ScriptHash, min_amount: u64, one_shot: bool) {
+        self.indexer
+            .write()
+            .unwrap()
+            .register_amount_alert(scripthash, min_amount, one_shot)
+    }
+
+    pub fn remove_amount_alert(&self, scripthash: &ScriptHash) -> bool {
+        self.indexer
+            .write()
+            .unwrap()
+            .remove_amount_alert(scripthash)
+    }
+
+    pub fn get_amount_alerts(&self) -> HashMap<ScriptHash, AmountAlert> {
+        let indexer = self.indexer.read().unwrap();
+        indexer
+            .amount_alerts()
+            .iter()
+            .map(|(scripthash, alert)| (*scripthash, alert.clone()))
+            .collect()
+    }
+
+    /// Get the balance aggregated over all the addresses of the `checksum` wallet
+    pub fn get_wallet_balance(&self, checksum: &Checksum) -> Option<WalletBalance> {
+        let indexer = self.indexer.read().unwrap();
+        indexer.watcher().get(checksum)?;
+        let (confirmed, total) = store::get_descriptor_balance(indexer.store(), checksum);
+        Some(WalletBalance {
+            confirmed,
+            unconfirmed: total as i64 - confirmed as i64,
+            total,
+        })
+    }
+
+    /// Get the next unused change address, for use as the change output of a spend. Uses the
+    /// `checksum` change wallet if specified, or the only tracked change (internal) wallet if not.
+    ///
+    /// Addresses that were funded by any indexed transaction, including unconfirmed ones, are
+    /// skipped. Like the receive `get_next_index()`, the address is not reserved and will keep
+    /// being returned until it receives funds.
+    pub fn get_change_address(&self, checksum: Option<&Checksum>) -> Result<ScriptInfo> {
+        let indexer = self.indexer.read().unwrap();
+        indexer
+            .watcher()
+            .next_change_address(checksum, indexer.store())
+    }
+
+    pub fn get_import_state(&self, checksum: &Checksum) -> Option<ImportState> {
+        let indexer = self.indexer.read().unwrap();
+        indexer.watcher().import_state(checksum)
+    }
+
+    pub fn get_gap_status(&self, checksum: &Checksum) -> Option<GapStatus> {
+        let indexer = self.indexer.read().unwrap();
+        Some(indexer.watcher().get(checksum)?.gap_status())
+    }
+
+    /// Get the look-ahead window status of all the descriptor wallets
+    pub fn get_gap_statuses(&self) -> HashMap<Checksum, GapStatus> {
+        let indexer = self.indexer.read().unwrap();
+        indexer
+            .watcher()
+            .wallets()
+            .iter()
+            .map(|(checksum, wallet)| (checksum.clone(), wallet.gap_status()))
+            .collect()
+    }
+
     pub fn find_wallet_gap(&self, checksum: &Checksum) -> Option<usize> {
         let indexer = self.indexer.read().unwrap();
         let wallet = indexer.watcher().get(checksum)?;
@@ -513,7 +1413,7 @@ impl Query {
 }
 
 // Attach descriptor and bip32 origin information when available
-fn attach_wallet_info(script_info: &mut ScriptInfo, indexer: &Indexer) {
+fn attach_wallet_info<S: Store>(script_info: &mut ScriptInfo, indexer: &Indexer<S>) {
     if let KeyOrigin::Descriptor(ref checksum, index) = script_info.origin {
         if let Some(wallet) = indexer.watcher().get(checksum) {
             script_info.desc = Some(wallet.derive_desc(index));
@@ -522,10 +1422,20 @@ fn attach_wallet_info(script_info: &mut ScriptInfo, indexer: &Indexer) {
     }
 }
 
+#[derive(Debug, Serialize)]
+pub struct TimedHistoryEntry {
+    #[serde(flatten)]
+    pub entry: HistoryEntry,
+    /// The block time for confirmed transactions, or the time first seen in the mempool for
+    /// unconfirmed ones (if known)
+    pub time: Option<u64>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct Txo {
     pub txid: Txid,
     pub vout: u32,
+    #[serde(serialize_with = "amount::serialize")]
     pub amount: u64,
     #[serde(flatten)]
     pub script_info: ScriptInfo,
@@ -553,6 +1463,41 @@ impl Txo {
     }
 }
 
+/// An unspent wallet output with the information needed to spend it as a PSBT input.
+///
+/// The `value` and `script_pubkey` make up the `witness_utxo` for segwit inputs. Non-segwit
+/// inputs additionally require the full previous transaction as the `non_witness_utxo`.
+#[derive(Debug, Serialize)]
+pub struct SpendableInput {
+    pub outpoint: OutPoint,
+    #[serde(serialize_with = "amount::serialize")]
+    pub value: u64,
+    pub script_pubkey: Script,
+    pub scripthash: ScriptHash,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub desc: Option<ExtendedDescriptor>,
+    /// The key derivation paths, for the PSBT `bip32_derivation` field
+    pub bip32_origins: Vec<Bip32Origin>,
+    #[serde(rename = "block_height")]
+    pub status: TxStatus,
+}
+
+impl From<Txo> for SpendableInput {
+    fn from(txo: Txo) -> Self {
+        let script_info = txo.script_info;
+        SpendableInput {
+            outpoint: OutPoint::new(txo.txid, txo.vout),
+            value: txo.amount,
+            // unspent outputs listed by the bitcoind wallet always have an address
+            script_pubkey: script_info.address.as_ref().unwrap().script_pubkey(),
+            scripthash: script_info.scripthash,
+            desc: script_info.desc,
+            bip32_origins: script_info.bip32_origins.unwrap_or_default(),
+            status: txo.status,
+        }
+    }
+}
+
 #[derive(Serialize, Debug)]
 pub struct TxDetail {
     txid: Txid,
@@ -560,13 +1505,23 @@ pub struct TxDetail {
     status: TxStatus,
     funding: Vec<TxDetailFunding>,
     spending: Vec<TxDetailSpending>,
+    #[serde(serialize_with = "amount::serialize_signed")]
     balance_change: i64,
+    direction: TxDirection,
+    /// Whether the transaction has the configured number of finality confirmations
+    is_final: bool,
+    #[serde(serialize_with = "amount::serialize_opt")]
+    fee: Option<u64>,
+    fee_rate: Option<f64>,
+    #[cfg(feature = "op-return")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    op_return: Option<Vec<String>>,
     #[serde(flatten)]
     mempool_info: Option<TxDetailMempool>,
 }
 
 impl TxDetail {
-    fn make(txid: &Txid, query: &Query) -> Option<Self> {
+    fn make<S: Store>(txid: &Txid, query: &Query<S>) -> Option<Self> {
         let indexer = query.indexer.read().unwrap();
         let store = indexer.store();
         let tx_entry = store.get_tx_entry(txid)?;
@@ -574,45 +1529,66 @@ impl TxDetail {
         let mempool_entry = tx_entry
             .status
             .is_unconfirmed()
-            .and_then(|| store.get_mempool_entry(txid));
+            .and_then(|| store::get_mempool_entry(store, txid));
 
         let funding = tx_entry
             .funding
             .iter()
             .map(|(vout, FundingInfo(scripthash, amount))| {
+                let script_info = query.get_script_info(scripthash).unwrap(); // must exists
                 TxDetailFunding {
                     vout: *vout,
-                    script_info: query.get_script_info(scripthash).unwrap(), // must exists
+                    is_change: indexer.watcher().is_change(&script_info.origin),
+                    script_info,
+                    script_pubkey: store.get_script_pubkey(scripthash).unwrap(),
                     amount: *amount,
                     #[cfg(feature = "track-spends")]
                     spent_by: store.lookup_txo_spend(&OutPoint::new(*txid, *vout)),
+                    #[cfg(feature = "track-spends")]
+                    spend_status: store::get_output_spend_status(
+                        store,
+                        &OutPoint::new(*txid, *vout),
+                    ),
                 }
             })
             .collect::<Vec<TxDetailFunding>>();
 
-        let spending = tx_entry
-            .spending
-            .iter()
-            .map(|(vin, SpendingInfo(scripthash, prevout, amount))| {
+        let spending = store::resolve_spending(store, txid)
+            .into_iter()
+            .map(|input| {
                 TxDetailSpending {
-                    vin: *vin,
-                    script_info: query.get_script_info(scripthash).unwrap(), // must exists
-                    amount: *amount,
-                    prevout: *prevout,
+                    vin: input.vin,
+                    script_info: query.get_script_info(&input.scripthash).unwrap(), // must exists
+                    amount: input.amount,
+                    prevout: input.prevout,
                 }
             })
             .collect::<Vec<TxDetailSpending>>();
 
-        let funding_sum = funding.iter().map(|f| f.amount).sum::<u64>();
-        let spending_sum = spending.iter().map(|s| s.amount).sum::<u64>();
-        let balance_change = funding_sum as i64 - spending_sum as i64;
+        // use the fee determined while indexing, or from the mempool entry for unconfirmed txs
+        let fee = tx_entry.fee.or_else(|| {
+            mempool_entry.map(|entry| TxFee {
+                fee: entry.fee,
+                vsize: entry.vsize,
+            })
+        });
 
         Some(TxDetail {
             txid: *txid,
             status: tx_entry.status,
             funding,
             spending,
-            balance_change,
+            balance_change: tx_entry.net_amount(),
+            direction: tx_entry.direction(),
+            is_final: matches!(indexer.tip(), Some(tip)
+                if tx_entry.status.is_final(tip.height(), query.config.finality_confirmations)),
+            fee: fee.map(|fee| fee.fee),
+            fee_rate: fee.map(|fee| fee.feerate()),
+            #[cfg(feature = "op-return")]
+            op_return: tx_entry
+                .op_return
+                .as_ref()
+                .map(|op_return| op_return.iter().map(|data| data.to_hex()).collect()),
             mempool_info: mempool_entry.map(Into::into),
         })
     }
@@ -623,9 +1599,14 @@ struct TxDetailFunding {
     vout: u32,
     #[serde(flatten)]
     script_info: ScriptInfo,
+    script_pubkey: Script,
+    #[serde(serialize_with = "amount::serialize")]
     amount: u64,
+    is_change: Option<bool>,
     #[cfg(feature = "track-spends")]
     spent_by: Option<InPoint>,
+    #[cfg(feature = "track-spends")]
+    spend_status: OutputSpendStatus,
 }
 
 #[derive(Serialize, Debug)]
@@ -633,6 +1614,7 @@ struct TxDetailSpending {
     vin: u32,
     #[serde(flatten)]
     script_info: ScriptInfo,
+    #[serde(serialize_with = "amount::serialize")]
     amount: u64,
     prevout: OutPoint,
 }
@@ -643,6 +1625,7 @@ struct TxDetailMempool {
     effective_feerate: f64,
     bip125_replaceable: bool,
     has_unconfirmed_parents: bool,
+    descendants: Vec<Txid>,
 }
 
 impl From<&MempoolEntry> for TxDetailMempool {
@@ -652,15 +1635,94 @@ impl From<&MempoolEntry> for TxDetailMempool {
             effective_feerate: entry.effective_feerate(),
             bip125_replaceable: entry.bip125_replaceable,
             has_unconfirmed_parents: entry.has_unconfirmed_parents(),
+            descendants: entry.descendants.clone(),
         }
     }
 }
 
+#[derive(Serialize, Debug)]
+pub struct MempoolHistoryEntry {
+    txid: Txid,
+    // may be temporarily unavailable, see the note in the README
+    #[serde(flatten)]
+    mempool_info: Option<TxDetailMempool>,
+}
+
+/// A conflicted transaction retained after being purged from the index
+#[derive(Serialize, Debug)]
+pub struct ConflictedTx {
+    pub txid: Txid,
+    /// The latest version of the transaction's replacement chain, if it was replaced
+    pub replaced_by: Option<Txid>,
+    /// The time the transaction was purged at, as a unix timestamp
+    pub conflicted_at: u64,
+    #[serde(serialize_with = "amount::serialize_signed")]
+    pub net_amount: i64,
+    pub scripthashes: Vec<ScriptHash>,
+}
+
+/// A wallet output being spent by an unconfirmed transaction
+#[derive(Serialize, Debug)]
+pub struct PendingSpend {
+    pub outpoint: OutPoint,
+    pub spent_by: InPoint,
+    pub scripthash: ScriptHash,
+    pub address: Option<Address>,
+    #[serde(serialize_with = "amount::serialize")]
+    pub amount: u64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ChainInfo {
+    /// The network name as reported by bitcoind (`main`, `test`, `signet` or `regtest`)
+    pub chain: String,
+    /// The height of the best validated block
+    pub blocks: u32,
+    /// The height of the best known header, which may be ahead of `blocks` while syncing
+    pub headers: u32,
+    /// Estimated block chain verification progress, between 0 and 1
+    pub verification_progress: f64,
+    pub initial_block_download: bool,
+    pub pruned: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct HealthStatus {
+    pub healthy: bool,
+    /// Whether the index caught up with bitcoind's current tip
+    pub synced: bool,
+    /// The tip the index was last synced against, or None if the initial sync did not complete yet
+    pub tip_height: Option<u32>,
+    pub core_tip_height: u32,
+    pub lag_blocks: Option<u32>,
+    pub last_sync_duration_ms: Option<u64>,
+    pub last_sync_error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct WalletBalance {
+    #[serde(serialize_with = "amount::serialize")]
+    pub confirmed: u64,
+    /// The net change from unconfirmed transactions, which may be negative
+    #[serde(serialize_with = "amount::serialize_signed")]
+    pub unconfirmed: i64,
+    #[serde(serialize_with = "amount::serialize")]
+    pub total: u64,
+}
+
 #[derive(Serialize, Debug)]
 pub struct ScriptStats {
     #[serde(flatten)]
     script_info: ScriptInfo,
     tx_count: usize,
+    #[serde(serialize_with = "amount::serialize")]
     confirmed_balance: u64,
+    #[serde(serialize_with = "amount::serialize")]
     unconfirmed_balance: u64,
+    #[serde(serialize_with = "amount::serialize")]
+    final_balance: u64,
+    #[serde(serialize_with = "amount::serialize")]
+    total_received: u64,
+    #[serde(serialize_with = "amount::serialize")]
+    total_sent: u64,
 }
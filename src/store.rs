@@ -1,16 +1,35 @@
-use std::cmp::Ordering;
-use std::collections::{BTreeSet, HashMap, HashSet};
+use std::cmp::{self, Ordering, Reverse};
+use std::collections::{hash_map, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::ops::Bound::{Excluded, Unbounded};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use serde::Serialize;
 
-use bitcoin::{Address, OutPoint, Txid};
+use bitcoin::{Address, OutPoint, Script, Txid};
 
-use crate::types::{MempoolEntry, ScriptHash, TxStatus};
-use crate::util::{descriptor::ExtendedDescriptor, remove_if, xpub::Bip32Origin};
+use crate::types::{MempoolEntry, ScriptHash, SortOrder, TxDirection, TxStatus};
+use crate::util::descriptor::{Checksum, ExtendedDescriptor};
+use crate::util::{amount, remove_if, xpub::Bip32Origin, BoolThen};
 use crate::wallet::KeyOrigin;
 
 #[cfg(feature = "track-spends")]
-use crate::types::InPoint;
+use crate::types::{InPoint, OutputSpendStatus};
+
+#[cfg(feature = "electrum")]
+use {
+    crate::{
+        electrum::electrum_height,
+        types::{ElectrumBalance, StatusHash},
+    },
+    bitcoin_hashes::{Hash, HashEngine},
+    rayon::prelude::*,
+};
+
+// the minimum number of scripthashes to compute status hashes for on a single thread, to avoid
+// the overhead of splitting up small batches
+#[cfg(feature = "electrum")]
+const STATUS_HASH_MIN_CHUNK: usize = 16;
 
 #[derive(Debug, Serialize, Default)]
 pub struct MemoryStore {
@@ -19,11 +38,26 @@ pub struct MemoryStore {
     mempool: HashMap<Txid, Option<MempoolEntry>>,
     #[cfg(feature = "track-spends")]
     txo_spends: HashMap<OutPoint, InPoint>,
+    // maps replaced (conflicted) transactions to the transaction that replaced them, kept after
+    // the replaced transactions get purged
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    replaced_by: HashMap<Txid, Txid>,
+    // conflicted transactions retained after being purged, along with the time they were purged
+    // at. only kept when a conflicted retention window is set.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    conflicted: HashMap<Txid, (TxEntry, u64)>,
+    #[serde(skip)]
+    conflicted_retention: Option<Duration>,
+    #[serde(skip)]
+    dust_threshold: Option<u64>,
 }
 
 #[derive(Debug, Serialize)]
 struct ScriptEntry {
-    address: Address,
+    // scripts without a standard address form keep their raw scriptPubKey instead
+    address: Option<Address>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    raw_script: Option<Script>,
     origin: KeyOrigin,
     history: BTreeSet<HistoryEntry>,
 }
@@ -46,6 +80,17 @@ pub struct TxEntry {
     pub status: TxStatus,
     pub funding: HashMap<u32, FundingInfo>,
     pub spending: HashMap<u32, SpendingInfo>,
+    // a per-scripthash summary of the funding/spending entries that were dropped by `prune_below()`,
+    // kept to allow maintaining the history index for the tx and to keep the totals accurate
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub pruned: HashMap<ScriptHash, PrunedAmounts>,
+    // only known for transactions where all inputs are spending wallet outputs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fee: Option<TxFee>,
+    // the data payloads of the tx's OP_RETURN outputs, or None if the tx was not scanned yet
+    #[cfg(feature = "op-return")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub op_return: Option<Vec<Vec<u8>>>,
 }
 
 impl TxEntry {
@@ -54,61 +99,1026 @@ impl TxEntry {
             status,
             funding: HashMap::new(),
             spending: HashMap::new(),
+            pruned: HashMap::new(),
+            fee: None,
+            #[cfg(feature = "op-return")]
+            op_return: None,
+        }
+    }
+    /// The net change to the wallet balance inflicted by this transaction
+    pub fn net_amount(&self) -> i64 {
+        self.funding_sum() as i64 - self.spending_sum() as i64
+    }
+
+    pub fn direction(&self) -> TxDirection {
+        if self.spending_sum() == 0 {
+            TxDirection::Incoming
+        } else if matches!(self.fee, Some(TxFee { fee, .. }) if self.funding_sum() + fee == self.spending_sum())
+        {
+            // the fee is only known when all of the inputs are spending wallet outputs, and it
+            // accounts for all of the spent amount that didn't go back to the wallet. the same
+            // scripthash may appear as both funded and spent (i.e. consolidations).
+            TxDirection::SelfTransfer
+        } else {
+            TxDirection::Outgoing
         }
     }
+
+    fn funding_sum(&self) -> u64 {
+        let pruned_sum = self.pruned.values().map(|p| p.received).sum::<u64>();
+        self.funding
+            .values()
+            .map(|FundingInfo(_, amount)| amount)
+            .sum::<u64>()
+            + pruned_sum
+    }
+
+    fn spending_sum(&self) -> u64 {
+        let pruned_sum = self.pruned.values().map(|p| p.sent).sum::<u64>();
+        self.spending
+            .values()
+            .map(|SpendingInfo(_, _, amount)| amount)
+            .sum::<u64>()
+            + pruned_sum
+    }
+
+    /// The amounts received and sent by the scripthash in this transaction, including pruned entries
+    pub fn script_amounts(&self, scripthash: &ScriptHash) -> PrunedAmounts {
+        let mut amounts = self.pruned.get(scripthash).copied().unwrap_or_default();
+        amounts.received += self
+            .funding
+            .values()
+            .filter(|FundingInfo(sh, _)| sh == scripthash)
+            .map(|FundingInfo(_, amount)| amount)
+            .sum::<u64>();
+        amounts.sent += self
+            .spending
+            .values()
+            .filter(|SpendingInfo(sh, _, _)| sh == scripthash)
+            .map(|SpendingInfo(_, _, amount)| amount)
+            .sum::<u64>();
+        amounts
+    }
+
     pub fn scripthashes(&self) -> HashSet<&ScriptHash> {
         let funding_scripthashes = self.funding.iter().map(|(_, f)| &f.0);
         let spending_scripthashes = self.spending.iter().map(|(_, s)| &s.0);
-        funding_scripthashes.chain(spending_scripthashes).collect()
+        funding_scripthashes
+            .chain(spending_scripthashes)
+            .chain(self.pruned.keys())
+            .collect()
+    }
+}
+
+/// The amounts received and sent by a scripthash in funding/spending entries that were pruned
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct PrunedAmounts {
+    #[serde(serialize_with = "amount::serialize")]
+    pub received: u64,
+    #[serde(serialize_with = "amount::serialize")]
+    pub sent: u64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct TxFee {
+    #[serde(serialize_with = "amount::serialize")]
+    pub fee: u64,
+    pub vsize: u64,
+}
+
+impl TxFee {
+    /// The fee rate in sat/vB
+    pub fn feerate(&self) -> f64 {
+        self.fee as f64 / self.vsize as f64
     }
 }
 
+/// The mempool membership status of a transaction
+#[derive(Debug, Clone, Copy)]
+pub enum MempoolStatus<'a> {
+    /// The transaction is not in the mempool (or is unknown)
+    NotInMempool,
+    /// The transaction is in the mempool, but its MempoolEntry data was not fetched yet
+    InMempoolPending,
+    /// The transaction is in the mempool, with its MempoolEntry data available
+    InMempool(&'a MempoolEntry),
+}
+
 #[derive(Debug, Clone, Serialize)]
-pub struct FundingInfo(pub ScriptHash, pub u64);
+pub struct FundingInfo(
+    pub ScriptHash,
+    #[serde(serialize_with = "amount::serialize")] pub u64,
+);
 
 #[derive(Debug, Clone, Serialize)]
-pub struct SpendingInfo(pub ScriptHash, pub OutPoint, pub u64);
+pub struct SpendingInfo(
+    pub ScriptHash,
+    pub OutPoint,
+    #[serde(serialize_with = "amount::serialize")] pub u64,
+);
+
+/// The storage backend used by the `Indexer` to keep the wallet history index.
+///
+/// `MemoryStore` is the default implementation. The `Indexer` relies on the store to keep the
+/// cross-references between its scripthash history, transaction and mempool indexes consistent
+/// (see `MemoryStore` for the expected semantics).
+pub trait Store: Send + Sync + fmt::Debug + Serialize + 'static {
+    /// Create a new empty store with the same settings, to rebuild the index from scratch
+    fn fork_empty(&self) -> Self
+    where
+        Self: Sized;
+
+    /// Add a new scriptPubKey to the index, along with its address if it has a standard address
+    /// form. Returns true if it was newly added.
+    fn index_script(
+        &mut self,
+        scripthash: &ScriptHash,
+        origin: &KeyOrigin,
+        script_pubkey: &Script,
+        address: Option<&Address>,
+    ) -> bool;
+
+    /// Add a new scripthash to the index. Returns true if it was newly added.
+    fn index_scripthash(
+        &mut self,
+        scripthash: &ScriptHash,
+        origin: &KeyOrigin,
+        address: &Address,
+    ) -> bool {
+        self.index_script(scripthash, origin, &address.script_pubkey(), Some(address))
+    }
+
+    /// Insert a transaction or update its status. Returns true if it was added or updated.
+    fn upsert_tx(&mut self, txid: &Txid, status: TxStatus) -> bool;
+
+    /// Index a single txo received by the wallet (there may be more txos from the same tx coming).
+    /// Returns true if it was newly added. Outputs below the dust threshold are not indexed.
+    fn index_tx_output_funding(
+        &mut self,
+        txid: &Txid,
+        vout: u32,
+        funding_info: FundingInfo,
+    ) -> bool {
+        !self
+            .index_tx_outputs_funding(txid, vec![(vout, funding_info)])
+            .is_empty()
+    }
+
+    /// Index multiple txos received by the wallet in the same transaction at once, adding a single
+    /// history entry for each of the funded scripthashes. Returns the vouts that were newly added.
+    /// Outputs below the dust threshold are not indexed.
+    fn index_tx_outputs_funding(
+        &mut self,
+        txid: &Txid,
+        outputs: Vec<(u32, FundingInfo)>,
+    ) -> Vec<u32>;
+
+    /// Index the full set of spending inputs for this transaction
+    fn index_tx_inputs_spending(
+        &mut self,
+        txid: &Txid,
+        spending: HashMap<u32, SpendingInfo>,
+        allow_overwrite: bool,
+    );
+
+    fn set_tx_fee(&mut self, txid: &Txid, fee: TxFee);
+
+    /// Insert or update a transaction along with the full set of its wallet outputs and spending
+    /// inputs (and its fee, if known) as a single unit, so that the transaction is never observed
+    /// with partial details. Returns whether the transaction was added or had its status updated,
+    /// along with the vouts that were newly added.
+    ///
+    /// The default implementation applies the individual updates in sequence, which is atomic for
+    /// stores that are only accessed under an exclusive lock. Stores persisting their data should
+    /// override it to commit the updates together.
+    fn index_tx(
+        &mut self,
+        txid: &Txid,
+        status: TxStatus,
+        funding: Vec<(u32, FundingInfo)>,
+        spending: Option<(HashMap<u32, SpendingInfo>, Option<TxFee>)>,
+        allow_overwrite: bool,
+    ) -> (bool, Vec<u32>) {
+        let tx_updated = self.upsert_tx(txid, status);
+        let added_vouts = iif!(
+            funding.is_empty(),
+            vec![],
+            self.index_tx_outputs_funding(txid, funding)
+        );
+        if let Some((spending, fee)) = spending {
+            #[cfg(feature = "track-spends")]
+            for (vin, SpendingInfo(_, prevout, _)) in &spending {
+                self.index_txo_spend(*prevout, InPoint::new(*txid, *vin));
+            }
+            self.index_tx_inputs_spending(txid, spending, allow_overwrite);
+            if let Some(fee) = fee {
+                self.set_tx_fee(txid, fee);
+            }
+        }
+        (tx_updated, added_vouts)
+    }
+
+    /// Set the data payloads of the transaction's OP_RETURN outputs
+    #[cfg(feature = "op-return")]
+    fn set_tx_op_return(&mut self, txid: &Txid, op_return: Vec<Vec<u8>>);
+
+    /// Index the spending edge of a wallet output. Returns true if it was previously unspent.
+    #[cfg(feature = "track-spends")]
+    fn index_txo_spend(&mut self, spent_prevout: OutPoint, spending_input: InPoint) -> bool;
+
+    /// Remove a transaction entirely, along with its history entries. Returns true if it existed.
+    fn purge_tx(&mut self, txid: &Txid) -> bool;
+
+    /// Purge a transaction that was conflicted (double-spent or evicted from the mempool). Stores
+    /// that support it retain its entry for a while, see `get_conflicted_txs()`.
+    fn purge_conflicted_tx(&mut self, txid: &Txid) -> bool {
+        self.purge_tx(txid)
+    }
+
+    /// Get the conflicted transactions retained after being purged, along with the time they were
+    /// purged at, ordered with the most recent first
+    fn get_conflicted_txs(&self) -> Vec<(Txid, &TxEntry, u64)> {
+        vec![]
+    }
+
+    /// Drop the retained conflicted transactions that are past their retention window. Returns
+    /// the number of dropped transactions.
+    fn expire_conflicted(&mut self) -> usize {
+        0
+    }
+
+    /// Record that `txid` was replaced by the conflicting `replaced_by` transaction
+    fn mark_replaced(&mut self, txid: &Txid, replaced_by: &Txid);
+
+    /// Get the transaction that directly replaced `txid`, if it was replaced
+    fn get_replaced_by(&self, txid: &Txid) -> Option<Txid>;
+
+    /// Get the transaction that `txid` directly replaced, if any. If it replaced multiple
+    /// transactions, one of them is returned.
+    fn get_replaces(&self, txid: &Txid) -> Option<Txid>;
+
+    /// Prune the details of spent outputs that were spent by transactions confirmed at or below
+    /// `max_height`, dropping both the spending input and the spent output's funding entry.
+    ///
+    /// Unspent outputs are kept, as well as the history entries and the confirmation status of all
+    /// transactions. Pruned transactions will be reported with partial funding/spending details.
+    fn prune_below(&mut self, max_height: u32) -> usize;
+
+    /// Get the txids of the indexed mempool transactions, optionally only the ones that don't have
+    /// their MempoolEntry data populated yet.
+    fn get_mempool_txids(&self, missing_entry_only: bool) -> Vec<Txid>;
+
+    /// Get all the indexed mempool transactions along with their MempoolEntry data (if populated),
+    /// ordered by the time they entered the mempool. Transactions without the data are ordered last.
+    fn mempool_transactions(&self) -> Vec<(Txid, Option<&MempoolEntry>)>;
+
+    /// Set the MempoolEntry data of an indexed mempool transaction
+    fn set_mempool_entry(&mut self, txid: &Txid, entry: MempoolEntry);
+
+    /// Set the in-mempool descendants of an indexed mempool transaction. A no-op for transactions
+    /// that don't have their MempoolEntry data populated yet.
+    fn set_mempool_descendants(&mut self, txid: &Txid, descendants: Vec<Txid>);
+
+    /// Get the mempool membership status of a transaction, distinguishing between mempool
+    /// transactions that have their MempoolEntry data populated and ones that don't yet.
+    fn mempool_status(&self, txid: &Txid) -> MempoolStatus<'_>;
+
+    fn lookup_txo_fund(&self, outpoint: &OutPoint) -> Option<FundingInfo>;
+
+    #[cfg(feature = "track-spends")]
+    fn lookup_txo_spend(&self, outpoint: &OutPoint) -> Option<InPoint>;
+
+    /// Get the wallet outputs being spent by unconfirmed transactions, paired with the spending
+    /// input and ordered by the outpoint
+    #[cfg(feature = "track-spends")]
+    fn pending_spends(&self) -> Vec<(OutPoint, InPoint)>;
+
+    /// Whether spends of wallet outputs are being indexed. This is determined by the `track-spends`
+    /// feature, and methods that depend on the spend status return inexact results without it.
+    fn tracks_spends(&self) -> bool {
+        cfg!(feature = "track-spends")
+    }
+
+    /// Get the scripthash history, ordered with oldest first.
+    fn get_history(&self, scripthash: &ScriptHash) -> Option<&BTreeSet<HistoryEntry>>;
+
+    fn has_history(&self, scripthash: &ScriptHash) -> bool;
+
+    fn get_tx_count(&self, scripthash: &ScriptHash) -> usize;
+
+    fn get_tx_entry(&self, txid: &Txid) -> Option<&TxEntry>;
+
+    fn get_tx_status(&self, txid: &Txid) -> Option<TxStatus>;
+
+    fn get_script_info(&self, scripthash: &ScriptHash) -> Option<ScriptInfo>;
+
+    fn get_script_address(&self, scripthash: &ScriptHash) -> Option<Address>;
+
+    /// List the indexed scripthashes derived from the `checksum` descriptor, as (derivation index,
+    /// scripthash) tuples ordered by the index
+    fn list_descriptor_scripts(&self, checksum: &Checksum) -> Vec<(u32, ScriptHash)>;
+
+    /// Get the raw scriptPubKey of the scripthash
+    fn get_script_pubkey(&self, scripthash: &ScriptHash) -> Option<Script>;
+
+    /// Get all history entries for all scripthashes since `min_block_height` (including
+    /// unconfirmed transactions) as refs, ordered with oldest first. Entries at the same height
+    /// are ordered by txid, and transactions touching multiple scripthashes are listed once.
+    fn get_history_since(&self, min_block_height: u32) -> Vec<&HistoryEntry>;
+
+    fn stats(&self) -> StoreStats;
+
+    /// Check whether the amount is below the configured dust threshold
+    fn is_dust(&self, amount: u64) -> bool;
+}
+
+//
+// Queries derived from the `Store` primitives, available for all implementations
+//
+
+/// Get up to `limit` scripthash history entries following the `after` cursor (exclusive),
+/// ordered according to `order`. Returns the entries along with the cursor to resume from
+/// for the next page, or `None` if there are no more entries.
+///
+/// Unlike offset-based pagination, the `(status, txid)` cursor remains stable while new
+/// entries get added. The cursor status must not be `Conflicted`.
+pub fn get_history_after<'a, S: Store>(
+    store: &'a S,
+    scripthash: &ScriptHash,
+    after: Option<(TxStatus, Txid)>,
+    limit: usize,
+    order: SortOrder,
+) -> (Vec<&'a HistoryEntry>, Option<(TxStatus, Txid)>) {
+    get_history_after_filtered(store, scripthash, after, limit, order, None)
+}
+
+/// Like `get_history_after()`, but only including the entries moving funds in `direction`
+/// relative to the scripthash (see `get_script_history_filtered()`)
+pub fn get_history_after_filtered<'a, S: Store>(
+    store: &'a S,
+    scripthash: &ScriptHash,
+    after: Option<(TxStatus, Txid)>,
+    limit: usize,
+    order: SortOrder,
+    direction: Option<TxDirection>,
+) -> (Vec<&'a HistoryEntry>, Option<(TxStatus, Txid)>) {
+    let history = some_or_ret!(store.get_history(scripthash), (vec![], None));
+    let cursor = after.map(|(status, txid)| HistoryEntry::new(txid, status));
+
+    let mut iter: Box<dyn Iterator<Item = &HistoryEntry>> = match (order, cursor) {
+        (SortOrder::OldestFirst, None) => Box::new(history.iter()),
+        (SortOrder::OldestFirst, Some(cursor)) => {
+            Box::new(history.range((Excluded(cursor), Unbounded)))
+        }
+        (SortOrder::NewestFirst, None) => Box::new(history.iter().rev()),
+        (SortOrder::NewestFirst, Some(cursor)) => Box::new(history.range(..cursor).rev()),
+    };
+
+    if let Some(direction) = direction {
+        iter =
+            Box::new(iter.filter(move |hist| {
+                has_script_direction(store, scripthash, &hist.txid, direction)
+            }));
+    }
+
+    let entries = iter.by_ref().take(limit).collect::<Vec<_>>();
+    let next_cursor = match (entries.last(), iter.next()) {
+        (Some(last), Some(_)) => Some((last.status, last.txid)),
+        _ => None,
+    };
+    (entries, next_cursor)
+}
+
+/// Get the scripthash history entries of the transactions moving funds in `direction`
+/// relative to the scripthash, or the full history if no direction is given. Ordered with
+/// oldest first.
+///
+/// `Incoming` matches transactions funding the scripthash and `Outgoing` transactions spending
+/// from it, with transactions doing both matching either. `SelfTransfer` only matches these.
+pub fn get_script_history_filtered<'a, S: Store>(
+    store: &'a S,
+    scripthash: &ScriptHash,
+    direction: Option<TxDirection>,
+) -> Vec<&'a HistoryEntry> {
+    let history = some_or_ret!(store.get_history(scripthash), vec![]);
+    history
+        .iter()
+        .filter(|hist| {
+            direction.is_none_or(|direction| {
+                has_script_direction(store, scripthash, &hist.txid, direction)
+            })
+        })
+        .collect()
+}
+
+/// Check whether the transaction moves funds in `direction` relative to the scripthash,
+/// according to its funding and spending entries (see `get_script_history_filtered()`)
+pub fn has_script_direction<S: Store>(
+    store: &S,
+    scripthash: &ScriptHash,
+    txid: &Txid,
+    direction: TxDirection,
+) -> bool {
+    let amounts = some_or_ret!(store.get_tx_entry(txid), false).script_amounts(scripthash);
+    let (funds, spends) = (amounts.received > 0, amounts.sent > 0);
+    match direction {
+        TxDirection::Incoming => funds,
+        TxDirection::Outgoing => spends,
+        TxDirection::SelfTransfer => funds && spends,
+    }
+}
+
+/// Get the height the transaction confirmed at. Returns None for unconfirmed, conflicted and
+/// unknown transactions.
+pub fn get_confirmed_height<S: Store>(store: &S, txid: &Txid) -> Option<u32> {
+    store.get_tx_status(txid)?.height()
+}
+
+/// Get the number of confirmations given the current tip height. Returns `Some(0)` for
+/// unconfirmed transactions and None for conflicted and unknown ones.
+pub fn get_confirmations<S: Store>(store: &S, txid: &Txid, tip_height: u32) -> Option<u32> {
+    store.get_tx_status(txid)?.confirmations(tip_height)
+}
+
+/// Get the wallet scripthashes funded or spent by the transaction, sorted. Returns None for
+/// unknown transactions.
+pub fn get_tx_scripthashes<S: Store>(store: &S, txid: &Txid) -> Option<Vec<ScriptHash>> {
+    let mut scripthashes: Vec<ScriptHash> = store
+        .get_tx_entry(txid)?
+        .scripthashes()
+        .into_iter()
+        .copied()
+        .collect();
+    scripthashes.sort();
+    Some(scripthashes)
+}
+
+/// Get the wallet addresses funded or spent by the transaction, in the same order as
+/// `get_tx_scripthashes()`. Returns None for unknown transactions.
+pub fn get_tx_addresses<S: Store>(store: &S, txid: &Txid) -> Option<Vec<Address>> {
+    Some(
+        get_tx_scripthashes(store, txid)?
+            .iter()
+            // scripthashes of indexed transactions are expected to exist in the index
+            .filter_map(|scripthash| store.get_script_address(scripthash))
+            .collect(),
+    )
+}
+
+/// Get the balance aggregated over all the scripthashes derived from the `checksum` descriptor,
+/// as a (confirmed, total) tuple with the total including unconfirmed transactions.
+pub fn get_descriptor_balance<S: Store>(store: &S, checksum: &Checksum) -> (u64, u64) {
+    let (mut confirmed, mut total) = (0i64, 0i64);
+    for (_, scripthash) in store.list_descriptor_scripts(checksum) {
+        let history = store.get_history(&scripthash).into_iter().flatten();
+        for hist in history.filter(|hist| hist.status.is_viable()) {
+            let amounts = match store.get_tx_entry(&hist.txid) {
+                Some(tx_entry) => tx_entry.script_amounts(&scripthash),
+                None => continue,
+            };
+            let net = amounts.received as i64 - amounts.sent as i64;
+            total += net;
+            if hist.status.is_confirmed() {
+                confirmed += net;
+            }
+        }
+    }
+    // spends of outputs skipped as dust may take it below zero
+    (confirmed.max(0) as u64, total.max(0) as u64)
+}
+
+/// Like `get_history_since()`, but excluding the unconfirmed transactions in `known_mempool`,
+/// for clients that already have them from a previous poll. Transactions that confirmed since
+/// are still included (if confirmed at or after `min_block_height`).
+pub fn get_history_since_snapshot<'a, S: Store>(
+    store: &'a S,
+    min_block_height: u32,
+    known_mempool: &HashSet<Txid>,
+) -> Vec<&'a HistoryEntry> {
+    let mut entries = store.get_history_since(min_block_height);
+    entries
+        .retain(|hist| hist.status != TxStatus::Unconfirmed || !known_mempool.contains(&hist.txid));
+    entries
+}
+
+/// Get the full chain of replacements that `txid` is a part of, ordered from the original
+/// transaction to the latest version. Returns just `txid` if it was never replaced.
+pub fn replacement_chain<S: Store>(store: &S, txid: &Txid) -> Vec<Txid> {
+    let mut chain = vec![*txid];
+    let mut seen: HashSet<Txid> = chain.iter().copied().collect();
+    let mut prev = *txid;
+    while let Some(replaces) = store.get_replaces(&prev) {
+        if !seen.insert(replaces) {
+            break; // guard against cycles
+        }
+        chain.push(replaces);
+        prev = replaces;
+    }
+    chain.reverse();
+    let mut next = *txid;
+    while let Some(replaced_by) = store.get_replaced_by(&next) {
+        if !seen.insert(replaced_by) {
+            break;
+        }
+        chain.push(replaced_by);
+        next = replaced_by;
+    }
+    chain
+}
+
+/// Get a mempool entry. Returns `None` for non-mempool transactions, as well as for
+/// mempool transactions that don't have the MempoolEntry data populated yet.
+pub fn get_mempool_entry<'a, S: Store>(store: &'a S, txid: &Txid) -> Option<&'a MempoolEntry> {
+    match store.mempool_status(txid) {
+        MempoolStatus::InMempool(entry) => Some(entry),
+        MempoolStatus::InMempoolPending | MempoolStatus::NotInMempool => None,
+    }
+}
+
+/// Get the in-mempool descendants of a mempool transaction, as of the last sync. Empty for
+/// non-mempool transactions (including ones that got mined along with part of their package).
+pub fn mempool_descendants<S: Store>(store: &S, txid: &Txid) -> Vec<Txid> {
+    get_mempool_entry(store, txid).map_or_else(Vec::new, |entry| entry.descendants.clone())
+}
+
+/// Get the spend status of a wallet output, including the spending transaction and its confirmation status.
+#[cfg(feature = "track-spends")]
+pub fn get_output_spend_status<S: Store>(store: &S, outpoint: &OutPoint) -> OutputSpendStatus {
+    let spending_input = some_or_ret!(store.lookup_txo_spend(outpoint), OutputSpendStatus::Unspent);
+    match store.get_tx_status(&spending_input.txid) {
+        Some(TxStatus::Confirmed(height)) => {
+            OutputSpendStatus::SpentConfirmed(spending_input.txid, height)
+        }
+        Some(TxStatus::Unconfirmed) => OutputSpendStatus::SpentUnconfirmed(spending_input.txid),
+        // spending transactions are expected to exist in the index, and conflicted ones get purged
+        Some(TxStatus::Conflicted) | None => OutputSpendStatus::Unspent,
+    }
+}
+
+/// Get the wallet outputs of the transaction that are still unspent, ordered by vout.
+///
+/// Without `track-spends`, the spend status is unknown and all of the transaction's wallet
+/// outputs are returned.
+pub fn get_tx_unspent_outputs<S: Store>(store: &S, txid: &Txid) -> Vec<(u32, FundingInfo)> {
+    let tx_entry = some_or_ret!(store.get_tx_entry(txid), vec![]);
+    let mut outputs: Vec<_> = tx_entry
+        .funding
+        .iter()
+        .map(|(vout, funding_info)| (*vout, funding_info.clone()))
+        .collect();
+    #[cfg(feature = "track-spends")]
+    outputs.retain(|(vout, _)| {
+        store
+            .lookup_txo_spend(&OutPoint::new(*txid, *vout))
+            .is_none()
+    });
+    outputs.sort_by_key(|(vout, _)| *vout);
+    outputs
+}
+
+/// Get the wallet inputs of the transaction paired with the address and amount of the
+/// previous output they spend, ordered by vin.
+pub fn resolve_spending<S: Store>(store: &S, txid: &Txid) -> Vec<ResolvedInput> {
+    let tx_entry = some_or_ret!(store.get_tx_entry(txid), vec![]);
+    let mut inputs: Vec<_> = tx_entry
+        .spending
+        .iter()
+        .map(
+            |(vin, SpendingInfo(scripthash, prevout, amount))| ResolvedInput {
+                vin: *vin,
+                prevout: *prevout,
+                scripthash: *scripthash,
+                // spent scripthashes are expected to exist in the index
+                address: store.get_script_address(scripthash),
+                amount: *amount,
+            },
+        )
+        .collect();
+    inputs.sort_by_key(|input| input.vin);
+    inputs
+}
+
+/// Get the unspent outputs of the scripthash that don't have `min_conf` confirmations yet, as
+/// a list of (amount, blocks_until_mature) tuples sorted by the soonest to mature. Unconfirmed
+/// outputs are assumed to confirm in the next block.
+///
+/// Without `track-spends`, the spend status is unknown and spent outputs are included too.
+pub fn pending_maturity<S: Store>(
+    store: &S,
+    scripthash: &ScriptHash,
+    tip_height: u32,
+    min_conf: u32,
+) -> Vec<(u64, u32)> {
+    let history = some_or_ret!(store.get_history(scripthash), vec![]);
+    let mut pending: Vec<(u64, u32)> = history
+        .iter()
+        .filter_map(|hist| {
+            let confirmations = match hist.status {
+                TxStatus::Confirmed(height) => (tip_height + 1).saturating_sub(height),
+                TxStatus::Unconfirmed => 0,
+                TxStatus::Conflicted => return None,
+            };
+            (confirmations < min_conf).do_then(|| (hist, min_conf - confirmations))
+        })
+        .flat_map(|(hist, blocks_left)| {
+            get_tx_unspent_outputs(store, &hist.txid)
+                .into_iter()
+                .filter(|(_, FundingInfo(sh, _))| sh == scripthash)
+                .map(move |(_, FundingInfo(_, amount))| (amount, blocks_left))
+        })
+        .collect();
+    pending.sort_by_key(|(_, blocks_left)| *blocks_left);
+    pending
+}
+
+/// Purge unconfirmed transactions that entered the mempool more than `max_age` ago, once
+/// `is_gone` confirms that they are no longer in the mempool. Returns the purged txids.
+///
+/// Transactions that don't have their MempoolEntry data populated have an unknown age and are kept.
+pub fn sweep_stale_mempool<S: Store>(
+    store: &mut S,
+    max_age: Duration,
+    is_gone: impl Fn(&Txid) -> bool,
+) -> Vec<Txid> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let stale_txids: Vec<Txid> = store
+        .get_mempool_txids(false)
+        .into_iter()
+        .filter(|txid| {
+            matches!(get_mempool_entry(store, txid),
+                Some(entry) if now.saturating_sub(entry.first_seen) > max_age.as_secs())
+        })
+        .collect();
+
+    let mut purged = vec![];
+    for txid in stale_txids {
+        if is_gone(&txid) && store.purge_tx(&txid) {
+            purged.push(txid);
+        }
+    }
+    purged
+}
+
+/// Check whether the scripthash was funded by more than one distinct transaction
+pub fn is_reused<S: Store>(store: &S, scripthash: &ScriptHash) -> bool {
+    let history = some_or_ret!(store.get_history(scripthash), false);
+    history
+        .iter()
+        .filter_map(|hist| store.get_tx_entry(&hist.txid))
+        .filter(|tx_entry| tx_entry.funding.values().any(|f| f.0 == *scripthash))
+        .nth(1)
+        .is_some()
+}
+
+/// Get the lifetime totals of the scripthash as a tuple of (total_received, total_sent),
+/// regardless of the current spent status. With `confirmed_only`, unconfirmed transactions
+/// are not included.
+///
+/// Outputs pruned by `prune_below()` are not accounted for.
+pub fn get_script_totals<S: Store>(
+    store: &S,
+    scripthash: &ScriptHash,
+    confirmed_only: bool,
+) -> (u64, u64) {
+    let history = some_or_ret!(store.get_history(scripthash), (0, 0));
+    history
+        .iter()
+        .filter(|hist| !confirmed_only || hist.status.is_confirmed())
+        .filter_map(|hist| store.get_tx_entry(&hist.txid))
+        .fold((0, 0), |(received, sent), tx_entry| {
+            let tx_received = tx_entry
+                .funding
+                .values()
+                .filter(|FundingInfo(sh, _)| sh == scripthash)
+                .map(|FundingInfo(_, amount)| amount)
+                .sum::<u64>();
+            let tx_sent = tx_entry
+                .spending
+                .values()
+                .filter(|SpendingInfo(sh, ..)| sh == scripthash)
+                .map(|SpendingInfo(_, _, amount)| amount)
+                .sum::<u64>();
+            let pruned = tx_entry.pruned.get(scripthash).copied().unwrap_or_default();
+            (
+                received + tx_received + pruned.received,
+                sent + tx_sent + pruned.sent,
+            )
+        })
+}
+
+/// Get the Electrum status hash of the scripthash history, or None if it has no history
+#[cfg(feature = "electrum")]
+pub fn status_hash<S: Store>(store: &S, scripthash: &ScriptHash) -> Option<StatusHash> {
+    let history = store.get_history(scripthash)?;
+    let mut engine = StatusHash::engine();
+    for hist in history {
+        let has_unconfirmed_parents = hist.status.is_unconfirmed().and_then(|| {
+            get_mempool_entry(store, &hist.txid).map(MempoolEntry::has_unconfirmed_parents)
+        });
+        let p = format!(
+            "{}:{}:",
+            hist.txid,
+            electrum_height(hist.status, has_unconfirmed_parents)
+        );
+        engine.input(&p.into_bytes());
+    }
+    Some(StatusHash::from_engine(engine))
+}
+
+/// Get the Electrum balance of the scripthash, with the confirmed balance based on confirmed
+/// transactions only and the (possibly negative) net change from unconfirmed transactions.
+#[cfg(feature = "electrum")]
+pub fn electrum_balance<S: Store>(store: &S, scripthash: &ScriptHash) -> ElectrumBalance {
+    let history = some_or_ret!(store.get_history(scripthash), ElectrumBalance::default());
+    let (mut confirmed, mut unconfirmed) = (0i64, 0i64);
+    for tx_entry in history
+        .iter()
+        .filter_map(|hist| store.get_tx_entry(&hist.txid))
+    {
+        let pruned = tx_entry.pruned.get(scripthash).copied().unwrap_or_default();
+        let funded = tx_entry
+            .funding
+            .values()
+            .filter(|FundingInfo(sh, _)| sh == scripthash)
+            .map(|FundingInfo(_, amount)| amount)
+            .sum::<u64>()
+            + pruned.received;
+        let spent = tx_entry
+            .spending
+            .values()
+            .filter(|SpendingInfo(sh, ..)| sh == scripthash)
+            .map(|SpendingInfo(_, _, amount)| amount)
+            .sum::<u64>()
+            + pruned.sent;
+        let net = funded as i64 - spent as i64;
+        match tx_entry.status {
+            TxStatus::Confirmed(_) => confirmed += net,
+            TxStatus::Unconfirmed => unconfirmed += net,
+            TxStatus::Conflicted => (),
+        }
+    }
+    ElectrumBalance {
+        // spends of outputs skipped as dust may take it below zero
+        confirmed: confirmed.max(0) as u64,
+        unconfirmed,
+    }
+}
+
+/// Get the Electrum status hashes of multiple scripthashes in one pass. The hashes are
+/// computed in parallel, each one independently over the same (read-only) store state.
+#[cfg(feature = "electrum")]
+pub fn status_hashes<S: Store>(
+    store: &S,
+    scripthashes: &[ScriptHash],
+) -> Vec<(ScriptHash, Option<StatusHash>)> {
+    scripthashes
+        .par_iter()
+        .with_min_len(STATUS_HASH_MIN_CHUNK)
+        .map(|scripthash| (*scripthash, status_hash(store, scripthash)))
+        .collect()
+}
 
 impl MemoryStore {
     pub fn new() -> Self {
         Default::default()
     }
 
-    pub fn index_scripthash(
+    /// Create a store that skips indexing funding outputs with an amount below `dust_threshold`
+    pub fn with_dust_threshold(dust_threshold: Option<u64>) -> Self {
+        MemoryStore {
+            dust_threshold,
+            ..Default::default()
+        }
+    }
+
+    /// Retain purged conflicted transactions for `retention`, see `Store::get_conflicted_txs()`
+    pub fn with_conflicted_retention(mut self, retention: Option<Duration>) -> Self {
+        self.conflicted_retention = retention;
+        self
+    }
+
+    /// Count the unspent wallet outputs across all scripthashes, considering outputs spent by
+    /// unconfirmed transactions as spent. Useful for monitoring UTXO fragmentation.
+    ///
+    /// Uses the spends index when the `track-spends` feature is enabled. Without it, the spent
+    /// outputs are collected by scanning the spending inputs of all indexed transactions instead.
+    pub fn utxo_count(&self) -> usize {
+        #[cfg(feature = "track-spends")]
+        let is_spent = |outpoint: &OutPoint| self.txo_spends.contains_key(outpoint);
+
+        #[cfg(not(feature = "track-spends"))]
+        let spent_outpoints: HashSet<&OutPoint> = self
+            .transactions
+            .values()
+            .flat_map(|tx_entry| tx_entry.spending.values())
+            .map(|SpendingInfo(_, prevout, _)| prevout)
+            .collect();
+        #[cfg(not(feature = "track-spends"))]
+        let is_spent = |outpoint: &OutPoint| spent_outpoints.contains(outpoint);
+
+        self.transactions
+            .iter()
+            .flat_map(|(txid, tx_entry)| {
+                tx_entry
+                    .funding
+                    .keys()
+                    .map(move |vout| OutPoint::new(*txid, *vout))
+            })
+            .filter(|outpoint| !is_spent(outpoint))
+            .count()
+    }
+
+    fn index_history_entry(&mut self, scripthash: &ScriptHash, txhist: HistoryEntry) -> bool {
+        trace!(
+            "index history entry: scripthash={} txid={} status={:?}",
+            scripthash,
+            txhist.txid,
+            txhist.status
+        );
+
+        let added = self
+            .scripthashes
+            .get_mut(scripthash)
+            .expect("missing expected scripthash entry")
+            .history
+            .insert(txhist);
+
+        if added {
+            trace!("new history entry for {:?}", scripthash);
+        }
+
+        added
+    }
+
+    /// Update the scripthash history index to reflect the new tx status
+    fn update_tx_status(&mut self, txid: &Txid, old_status: TxStatus, new_status: TxStatus) {
+        trace!(
+            "transition tx {:?} from={:?} to={:?}",
+            txid,
+            old_status,
+            new_status
+        );
+
+        let tx_entry = self
+            .transactions
+            .get(txid)
+            .expect("missing expected tx entry");
+
+        let old_txhist = HistoryEntry::new(*txid, old_status);
+        let new_txhist = HistoryEntry::new(*txid, new_status);
+
+        for scripthash in tx_entry.scripthashes() {
+            let scriptentry = self
+                .scripthashes
+                .get_mut(scripthash)
+                .expect("missing expected script entry");
+            assert!(scriptentry.history.remove(&old_txhist));
+            assert!(scriptentry.history.insert(new_txhist.clone()));
+        }
+
+        match (old_status, new_status) {
+            (TxStatus::Unconfirmed, _) => assert!(self.mempool.remove(txid).is_some()),
+            (_, TxStatus::Unconfirmed) => assert!(self.mempool.insert(*txid, None).is_none()),
+            _ => (),
+        };
+    }
+
+    /// Validate the cross-references between the store's indexes, returning the list of
+    /// violations found (if any). Does not panic on inconsistencies.
+    pub fn audit(&self) -> Result<(), Vec<AuditError>> {
+        let mut errors = vec![];
+
+        for (scripthash, script_entry) in &self.scripthashes {
+            if script_entry.history.is_empty() {
+                errors.push(AuditError::EmptyHistory(*scripthash));
+            }
+            for hist in &script_entry.history {
+                match self.transactions.get(&hist.txid) {
+                    None => errors.push(AuditError::MissingTxEntry(*scripthash, hist.txid)),
+                    Some(tx_entry) if tx_entry.status != hist.status => errors.push(
+                        AuditError::StatusMismatch(*scripthash, hist.txid, hist.status),
+                    ),
+                    Some(tx_entry) if !tx_entry.scripthashes().contains(scripthash) => {
+                        errors.push(AuditError::UnrelatedHistory(*scripthash, hist.txid))
+                    }
+                    Some(_) => (),
+                }
+            }
+        }
+
+        for (txid, tx_entry) in &self.transactions {
+            let txhist = HistoryEntry::new(*txid, tx_entry.status);
+            for scripthash in tx_entry.scripthashes() {
+                match self.scripthashes.get(scripthash) {
+                    None => errors.push(AuditError::MissingScriptEntry(*txid, *scripthash)),
+                    Some(script_entry) if !script_entry.history.contains(&txhist) => {
+                        errors.push(AuditError::MissingHistoryEntry(*txid, *scripthash))
+                    }
+                    Some(_) => (),
+                }
+            }
+            if tx_entry.status.is_unconfirmed() && !self.mempool.contains_key(txid) {
+                errors.push(AuditError::MissingMempoolEntry(*txid));
+            }
+        }
+
+        for txid in self.mempool.keys() {
+            if self.get_tx_status(txid) != Some(TxStatus::Unconfirmed) {
+                errors.push(AuditError::StaleMempoolEntry(*txid));
+            }
+        }
+
+        #[cfg(feature = "track-spends")]
+        for (prevout, spending_input) in &self.txo_spends {
+            if self.lookup_txo_fund(prevout).is_none() {
+                errors.push(AuditError::UnknownSpentTxo(*prevout));
+            }
+            let spent = self
+                .transactions
+                .get(&spending_input.txid)
+                .and_then(|tx_entry| tx_entry.spending.get(&spending_input.vin))
+                .map(|SpendingInfo(_, spent, _)| spent);
+            if spent != Some(prevout) {
+                errors.push(AuditError::UnknownSpendingInput(*prevout, *spending_input));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+impl Store for MemoryStore {
+    fn fork_empty(&self) -> Self {
+        MemoryStore::with_dust_threshold(self.dust_threshold)
+            .with_conflicted_retention(self.conflicted_retention)
+    }
+
+    fn index_script(
         &mut self,
         scripthash: &ScriptHash,
         origin: &KeyOrigin,
-        address: &Address,
+        script_pubkey: &Script,
+        address: Option<&Address>,
     ) -> bool {
         trace!(
-            "tracking scripthash={:?} address={:?} origin={:?}",
+            "tracking scripthash={:?} script={:x} address={:?} origin={:?}",
             scripthash,
+            script_pubkey,
             address,
             origin
         );
 
+        assert_eq!(
+            ScriptHash::from_script(script_pubkey),
+            *scripthash,
+            "scripthash mismatch for {:x}",
+            script_pubkey
+        );
+        if let Some(address) = address {
+            assert_eq!(
+                address.script_pubkey(),
+                *script_pubkey,
+                "scriptPubKey mismatch for {}",
+                address
+            );
+        }
+
         let mut existed = false;
 
         self.scripthashes
             .entry(*scripthash)
             .and_modify(|curr_entry| {
-                assert_eq!(
-                    curr_entry.origin, *origin,
-                    "unexpected stored origin for {:?}",
-                    scripthash
-                );
+                if curr_entry.origin != *origin {
+                    // the script is derivable from multiple origins (overlapping descriptors, or an
+                    // address that is also tracked as standalone). keep the lowest one, so that the
+                    // choice doesn't depend on the order in which they get indexed.
+                    let preferred = cmp::min(&curr_entry.origin, origin).clone();
+                    warn!(
+                        "scripthash {} is derivable from multiple origins ({} and {}), using {}",
+                        scripthash, curr_entry.origin, origin, preferred
+                    );
+                    curr_entry.origin = preferred;
+                }
                 existed = true;
             })
             .or_insert_with(|| ScriptEntry {
-                address: address.clone(),
+                address: address.cloned(),
+                raw_script: address.is_none().do_then(|| script_pubkey.clone()),
                 origin: origin.clone(),
                 history: BTreeSet::new(),
             });
 
         if !existed {
             trace!(
-                "new script entry: scripthash={} address={} origin={:?}",
+                "new script entry: scripthash={} script={:x} address={:?} origin={:?}",
                 scripthash,
+                script_pubkey,
                 address,
                 origin
             );
@@ -117,7 +1127,7 @@ impl MemoryStore {
         !existed
     }
 
-    pub fn upsert_tx(&mut self, txid: &Txid, status: TxStatus) -> bool {
+    fn upsert_tx(&mut self, txid: &Txid, status: TxStatus) -> bool {
         let mut status_change = None;
         let mut updated = false;
 
@@ -136,6 +1146,11 @@ impl MemoryStore {
                 TxEntry::new(status)
             });
 
+        // a retained conflicted transaction that became active again is no longer conflicted
+        if status != TxStatus::Conflicted {
+            self.conflicted.remove(txid);
+        }
+
         if updated {
             match (status_change, status) {
                 // update existing transactions with an updated confirmation status
@@ -155,37 +1170,49 @@ impl MemoryStore {
         updated
     }
 
-    // index a single txo received by the wallet (there may be more txos from the same tx coming)
-    pub fn index_tx_output_funding(
+    fn index_tx_outputs_funding(
         &mut self,
         txid: &Txid,
-        vout: u32,
-        funding_info: FundingInfo,
-    ) -> bool {
-        trace!("index tx output {}:{}: {:?}", txid, vout, funding_info);
-        let mut added = None;
+        outputs: Vec<(u32, FundingInfo)>,
+    ) -> Vec<u32> {
+        trace!("index {} tx outputs of {}", outputs.len(), txid);
+        let outputs: Vec<_> = outputs
+            .into_iter()
+            .filter(|(vout, funding_info)| {
+                let is_dust = self.is_dust(funding_info.1);
+                if is_dust {
+                    trace!("skipping dust txo {}:{}", txid, vout);
+                }
+                !is_dust
+            })
+            .collect();
 
-        {
+        let mut added_vouts = vec![];
+        let mut added_scripthashes = HashSet::new();
+
+        let status = {
             // the tx must already exists by now
             let tx_entry = self.transactions.get_mut(txid).unwrap();
-            let status = tx_entry.status;
-            tx_entry.funding.entry(vout).or_insert_with(|| {
-                trace!("new txo added {}:{}: {:?}", txid, vout, funding_info);
-                added = Some((funding_info.0, status));
-                funding_info
-            });
-        }
+            for (vout, funding_info) in outputs {
+                if let hash_map::Entry::Vacant(entry) = tx_entry.funding.entry(vout) {
+                    trace!("new txo added {}:{}: {:?}", txid, vout, funding_info);
+                    added_scripthashes.insert(funding_info.0);
+                    added_vouts.push(vout);
+                    entry.insert(funding_info);
+                }
+            }
+            tx_entry.status
+            // drop mutable ref
+        };
 
-        if let Some((scripthash, status)) = added {
-            self.index_history_entry(&scripthash, HistoryEntry::new(*txid, status));
-            true
-        } else {
-            false
+        let tx_hist = HistoryEntry::new(*txid, status);
+        for scripthash in added_scripthashes {
+            self.index_history_entry(&scripthash, tx_hist.clone());
         }
+        added_vouts
     }
 
-    // index the full set of spending inputs for this transaction
-    pub fn index_tx_inputs_spending(
+    fn index_tx_inputs_spending(
         &mut self,
         txid: &Txid,
         spending: HashMap<u32, SpendingInfo>,
@@ -209,30 +1236,21 @@ impl MemoryStore {
         }
     }
 
-    fn index_history_entry(&mut self, scripthash: &ScriptHash, txhist: HistoryEntry) -> bool {
-        trace!(
-            "index history entry: scripthash={} txid={} status={:?}",
-            scripthash,
-            txhist.txid,
-            txhist.status
-        );
-
-        let added = self
-            .scripthashes
-            .get_mut(scripthash)
-            .expect("missing expected scripthash entry")
-            .history
-            .insert(txhist);
-
-        if added {
-            trace!("new history entry for {:?}", scripthash);
-        }
+    fn set_tx_fee(&mut self, txid: &Txid, fee: TxFee) {
+        // the tx must already exists by now
+        let tx_entry = self.transactions.get_mut(txid).unwrap();
+        tx_entry.fee = Some(fee);
+    }
 
-        added
+    #[cfg(feature = "op-return")]
+    fn set_tx_op_return(&mut self, txid: &Txid, op_return: Vec<Vec<u8>>) {
+        // the tx must already exists by now
+        let tx_entry = self.transactions.get_mut(txid).unwrap();
+        tx_entry.op_return = Some(op_return);
     }
 
     #[cfg(feature = "track-spends")]
-    pub fn index_txo_spend(&mut self, spent_prevout: OutPoint, spending_input: InPoint) -> bool {
+    fn index_txo_spend(&mut self, spent_prevout: OutPoint, spending_input: InPoint) -> bool {
         trace!(
             "index txo spend: prevout={:?} spending={:?}",
             spent_prevout,
@@ -251,40 +1269,58 @@ impl MemoryStore {
         was_unspent
     }
 
-    /// Update the scripthash history index to reflect the new tx status
-    fn update_tx_status(&mut self, txid: &Txid, old_status: TxStatus, new_status: TxStatus) {
-        trace!(
-            "transition tx {:?} from={:?} to={:?}",
-            txid,
-            old_status,
-            new_status
-        );
+    fn purge_conflicted_tx(&mut self, txid: &Txid) -> bool {
+        let retained_entry = self
+            .conflicted_retention
+            .and_then(|_| self.transactions.get(txid).cloned());
+        let purged = self.purge_tx(txid);
+        if let (true, Some(mut tx_entry)) = (purged, retained_entry) {
+            tx_entry.status = TxStatus::Conflicted;
+            self.conflicted.insert(*txid, (tx_entry, now_secs()));
+        }
+        purged
+    }
 
-        let tx_entry = self
-            .transactions
-            .get(txid)
-            .expect("missing expected tx entry");
+    fn get_conflicted_txs(&self) -> Vec<(Txid, &TxEntry, u64)> {
+        let mut conflicted: Vec<_> = self
+            .conflicted
+            .iter()
+            .map(|(txid, (tx_entry, purged_at))| (*txid, tx_entry, *purged_at))
+            .collect();
+        conflicted.sort_by_key(|(txid, _, purged_at)| (Reverse(*purged_at), *txid));
+        conflicted
+    }
 
-        let old_txhist = HistoryEntry::new(*txid, old_status);
-        let new_txhist = HistoryEntry::new(*txid, new_status);
+    fn expire_conflicted(&mut self) -> usize {
+        let retention = some_or_ret!(self.conflicted_retention, 0).as_secs();
+        let now = now_secs();
+        let before = self.conflicted.len();
+        self.conflicted
+            .retain(|_, (_, purged_at)| now.saturating_sub(*purged_at) <= retention);
+        before - self.conflicted.len()
+    }
 
-        for scripthash in tx_entry.scripthashes() {
-            let scriptentry = self
-                .scripthashes
-                .get_mut(scripthash)
-                .expect("missing expected script entry");
-            assert!(scriptentry.history.remove(&old_txhist));
-            assert!(scriptentry.history.insert(new_txhist.clone()));
+    fn mark_replaced(&mut self, txid: &Txid, replaced_by: &Txid) {
+        if self.replaced_by.insert(*txid, *replaced_by) != Some(*replaced_by) {
+            debug!("tx {} was replaced by {}", txid, replaced_by);
         }
+        // the replacing transaction is now the active one, in case it was previously replaced too
+        self.replaced_by.remove(replaced_by);
+    }
 
-        match (old_status, new_status) {
-            (TxStatus::Unconfirmed, _) => assert!(self.mempool.remove(txid).is_some()),
-            (_, TxStatus::Unconfirmed) => assert!(self.mempool.insert(*txid, None).is_none()),
-            _ => (),
-        };
+    fn get_replaced_by(&self, txid: &Txid) -> Option<Txid> {
+        self.replaced_by.get(txid).copied()
+    }
+
+    fn get_replaces(&self, txid: &Txid) -> Option<Txid> {
+        self.replaced_by
+            .iter()
+            .filter(|(_, replaced_by)| *replaced_by == txid)
+            .map(|(replaced, _)| *replaced)
+            .min()
     }
 
-    pub fn purge_tx(&mut self, txid: &Txid) -> bool {
+    fn purge_tx(&mut self, txid: &Txid) -> bool {
         // XXX should replaced transactions be kept around instead of purged entirely?
         if let Some(old_entry) = self.transactions.remove(txid) {
             debug!("purge tx {:?}", txid);
@@ -322,20 +1358,98 @@ impl MemoryStore {
         }
     }
 
-    /// Get a mutable reference to the mempool.
-    pub fn mempool_mut(&mut self) -> &mut HashMap<Txid, Option<MempoolEntry>> {
-        &mut self.mempool
+    fn prune_below(&mut self, max_height: u32) -> usize {
+        let prunable_txids: Vec<Txid> = self
+            .transactions
+            .iter()
+            .filter(|(_, tx_entry)| {
+                !tx_entry.spending.is_empty()
+                    && matches!(tx_entry.status, TxStatus::Confirmed(height) if height <= max_height)
+            })
+            .map(|(txid, _)| *txid)
+            .collect();
+
+        let mut pruned_count = 0;
+        for txid in prunable_txids {
+            let spending = {
+                let tx_entry = self.transactions.get_mut(&txid).unwrap();
+                let spending = std::mem::take(&mut tx_entry.spending);
+                for SpendingInfo(scripthash, _, amount) in spending.values() {
+                    tx_entry.pruned.entry(*scripthash).or_default().sent += amount;
+                }
+                spending
+            };
+
+            for (_, SpendingInfo(_, prevout, _)) in spending {
+                // the funding tx must be confirmed at or below the spending tx height, and is
+                // therefore also safe to prune
+                let funding_entry = self
+                    .transactions
+                    .get_mut(&prevout.txid)
+                    .expect("missing expected funding tx entry");
+                if let Some(FundingInfo(scripthash, amount)) =
+                    funding_entry.funding.remove(&prevout.vout)
+                {
+                    funding_entry.pruned.entry(scripthash).or_default().received += amount;
+                }
+
+                #[cfg(feature = "track-spends")]
+                assert!(self.txo_spends.remove(&prevout).is_some());
+
+                pruned_count += 1;
+            }
+        }
+
+        if pruned_count > 0 {
+            debug!(
+                "pruned {} spent outputs at or below height {}",
+                pruned_count, max_height
+            );
+        }
+        pruned_count
     }
 
-    /// Get a mempool entry. Returns `None` for non-mempool transactions, as well as for
-    /// mempool transactions that don't have the MempoolEntry data populated yet.
-    pub fn get_mempool_entry(&self, txid: &Txid) -> Option<&MempoolEntry> {
+    fn get_mempool_txids(&self, missing_entry_only: bool) -> Vec<Txid> {
         self.mempool
-            .get(txid)
-            .and_then(|opt_entry| opt_entry.as_ref())
+            .iter()
+            .filter(|(_, opt_entry)| !missing_entry_only || opt_entry.is_none())
+            .map(|(txid, _)| *txid)
+            .collect()
+    }
+
+    fn mempool_transactions(&self) -> Vec<(Txid, Option<&MempoolEntry>)> {
+        let mut txs: Vec<_> = self
+            .mempool
+            .iter()
+            .map(|(txid, opt_entry)| (*txid, opt_entry.as_ref()))
+            .collect();
+        txs.sort_by_key(|(txid, opt_entry)| {
+            (opt_entry.map_or(u64::MAX, |entry| entry.first_seen), *txid)
+        });
+        txs
+    }
+
+    fn set_mempool_entry(&mut self, txid: &Txid, entry: MempoolEntry) {
+        if let Some(opt_entry) = self.mempool.get_mut(txid) {
+            *opt_entry = Some(entry);
+        }
+    }
+
+    fn set_mempool_descendants(&mut self, txid: &Txid, descendants: Vec<Txid>) {
+        if let Some(Some(entry)) = self.mempool.get_mut(txid) {
+            entry.descendants = descendants;
+        }
+    }
+
+    fn mempool_status(&self, txid: &Txid) -> MempoolStatus<'_> {
+        match self.mempool.get(txid) {
+            Some(Some(entry)) => MempoolStatus::InMempool(entry),
+            Some(None) => MempoolStatus::InMempoolPending,
+            None => MempoolStatus::NotInMempool,
+        }
     }
 
-    pub fn lookup_txo_fund(&self, outpoint: &OutPoint) -> Option<FundingInfo> {
+    fn lookup_txo_fund(&self, outpoint: &OutPoint) -> Option<FundingInfo> {
         self.transactions
             .get(&outpoint.txid)?
             .funding
@@ -344,45 +1458,80 @@ impl MemoryStore {
     }
 
     #[cfg(feature = "track-spends")]
-    pub fn lookup_txo_spend(&self, outpoint: &OutPoint) -> Option<InPoint> {
+    fn lookup_txo_spend(&self, outpoint: &OutPoint) -> Option<InPoint> {
         self.txo_spends.get(outpoint).copied()
     }
 
-    pub fn get_history(&self, scripthash: &ScriptHash) -> Option<&BTreeSet<HistoryEntry>> {
+    #[cfg(feature = "track-spends")]
+    fn pending_spends(&self) -> Vec<(OutPoint, InPoint)> {
+        let mut spends: Vec<_> = self
+            .txo_spends
+            .iter()
+            .filter(|(_, inpoint)| self.get_tx_status(&inpoint.txid) == Some(TxStatus::Unconfirmed))
+            .map(|(outpoint, inpoint)| (*outpoint, *inpoint))
+            .collect();
+        spends.sort_by_key(|(outpoint, _)| *outpoint);
+        spends
+    }
+
+    fn get_history(&self, scripthash: &ScriptHash) -> Option<&BTreeSet<HistoryEntry>> {
         Some(&self.scripthashes.get(scripthash)?.history)
     }
 
-    pub fn has_history(&self, scripthash: &ScriptHash) -> bool {
+    fn has_history(&self, scripthash: &ScriptHash) -> bool {
         // if the scriptentry exists, it must have some history
         self.scripthashes.contains_key(scripthash)
     }
 
-    pub fn get_tx_count(&self, scripthash: &ScriptHash) -> usize {
+    fn get_tx_count(&self, scripthash: &ScriptHash) -> usize {
         self.scripthashes
             .get(scripthash)
             .map_or(0, |script_entry| script_entry.history.len())
     }
 
-    pub fn get_tx_entry(&self, txid: &Txid) -> Option<&TxEntry> {
+    fn get_tx_entry(&self, txid: &Txid) -> Option<&TxEntry> {
         self.transactions.get(txid)
     }
 
-    pub fn get_tx_status(&self, txid: &Txid) -> Option<TxStatus> {
+    fn get_tx_status(&self, txid: &Txid) -> Option<TxStatus> {
         Some(self.transactions.get(txid)?.status)
     }
 
-    pub fn get_script_info(&self, scripthash: &ScriptHash) -> Option<ScriptInfo> {
+    fn get_script_info(&self, scripthash: &ScriptHash) -> Option<ScriptInfo> {
         let script_entry = self.scripthashes.get(scripthash)?;
-        Some(ScriptInfo::from_entry(*scripthash, script_entry))
+        let mut script_info = ScriptInfo::from_entry(*scripthash, script_entry);
+        script_info.reused = is_reused(self, scripthash);
+        Some(script_info)
     }
 
-    pub fn get_script_address(&self, scripthash: &ScriptHash) -> Option<Address> {
-        Some(self.scripthashes.get(scripthash)?.address.clone())
+    fn get_script_address(&self, scripthash: &ScriptHash) -> Option<Address> {
+        self.scripthashes.get(scripthash)?.address.clone()
     }
 
-    /// Get all history entries for all scripthashes since `min_block_height` (including
-    /// unconfirmed transactions) as refs, ordered with oldest first.
-    pub fn get_history_since(&self, min_block_height: u32) -> Vec<&HistoryEntry> {
+    fn list_descriptor_scripts(&self, checksum: &Checksum) -> Vec<(u32, ScriptHash)> {
+        let mut scripts: Vec<_> = self
+            .scripthashes
+            .iter()
+            .filter_map(|(scripthash, script_entry)| match &script_entry.origin {
+                KeyOrigin::Descriptor(desc_checksum, index) if desc_checksum == checksum => {
+                    Some((*index, *scripthash))
+                }
+                _ => None,
+            })
+            .collect();
+        scripts.sort_unstable();
+        scripts
+    }
+
+    fn get_script_pubkey(&self, scripthash: &ScriptHash) -> Option<Script> {
+        let script_entry = self.scripthashes.get(scripthash)?;
+        match &script_entry.address {
+            Some(address) => Some(address.script_pubkey()),
+            None => script_entry.raw_script.clone(),
+        }
+    }
+
+    fn get_history_since(&self, min_block_height: u32) -> Vec<&HistoryEntry> {
         // TODO consider keeping a combined sorted index to avoid a full scan
         let mut entries = self
             .scripthashes
@@ -400,22 +1549,41 @@ impl MemoryStore {
             })
             .flatten()
             .collect::<Vec<_>>();
+        // a transaction has the same status in the history of all the scripthashes it touches,
+        // so its entries are fully equal under `Ord` and get sorted next to each other for dedup
         entries.sort_unstable();
         entries.dedup();
         entries
     }
 
-    pub fn stats(&self) -> StoreStats {
+    fn is_dust(&self, amount: u64) -> bool {
+        matches!(self.dust_threshold, Some(dust_threshold) if amount < dust_threshold)
+    }
+
+    fn stats(&self) -> StoreStats {
         StoreStats {
             transaction_count: self.transactions.len(),
             scripthash_count: self.scripthashes.len(),
+            utxo_count: self.utxo_count(),
         }
     }
 }
 
+/// A wallet input, resolved to the address and amount of the previous output it spends
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ResolvedInput {
+    pub vin: u32,
+    pub prevout: OutPoint,
+    pub scripthash: ScriptHash,
+    pub address: Option<Address>,
+    #[serde(serialize_with = "amount::serialize")]
+    pub amount: u64,
+}
+
 #[derive(Serialize, Debug, Clone)]
 pub struct ScriptInfo {
-    pub address: Address,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<Address>,
     pub scripthash: ScriptHash,
     #[serde(skip_serializing_if = "KeyOrigin::is_standalone")]
     pub origin: KeyOrigin,
@@ -425,6 +1593,10 @@ pub struct ScriptInfo {
     pub desc: Option<ExtendedDescriptor>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub bip32_origins: Option<Vec<Bip32Origin>>,
+
+    // Whether the address received funds in more than one transaction, only serialized when true
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub reused: bool,
 }
 
 impl ScriptInfo {
@@ -436,21 +1608,42 @@ impl ScriptInfo {
     ) -> Self {
         ScriptInfo {
             scripthash: ScriptHash::from(&address),
-            address,
+            address: Some(address),
             origin,
             desc: Some(desc),
             bip32_origins: Some(bip32_origins),
+            reused: false,
         }
     }
     pub fn from_address(address: Address, origin: KeyOrigin) -> Self {
         ScriptInfo {
             scripthash: ScriptHash::from(&address),
-            address,
+            address: Some(address),
             origin,
             desc: None,
             bip32_origins: None,
+            reused: false,
+        }
+    }
+    /// Make a BIP 21 payment URI for this address, with an optional amount (in satoshis) and label.
+    /// Returns `None` for scripts without an address form.
+    pub fn to_bip21(&self, amount: Option<u64>, label: Option<&str>) -> Option<String> {
+        let address = self.address.as_ref()?;
+        let mut params = vec![];
+        if let Some(amount) = amount {
+            params.push(format!("amount={}", amount::format_btc_trimmed(amount)));
+        }
+        if let Some(label) = label {
+            params.push(format!("label={}", percent_encode(label)));
         }
+        let mut uri = format!("bitcoin:{}", address);
+        if !params.is_empty() {
+            uri.push('?');
+            uri.push_str(&params.join("&"));
+        }
+        Some(uri)
     }
+
     fn from_entry(scripthash: ScriptHash, script_entry: &ScriptEntry) -> Self {
         ScriptInfo {
             scripthash: scripthash,
@@ -458,10 +1651,30 @@ impl ScriptInfo {
             origin: script_entry.origin.clone(),
             desc: None,
             bip32_origins: None,
+            reused: false,
         }
     }
 }
 
+// The current time as a unix timestamp
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+// Percent-encode everything except for RFC 3986 unreserved characters
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
 impl Ord for HistoryEntry {
     fn cmp(&self, other: &HistoryEntry) -> Ordering {
         self.status
@@ -479,4 +1692,1152 @@ impl PartialOrd for HistoryEntry {
 pub struct StoreStats {
     pub transaction_count: usize,
     pub scripthash_count: usize,
+    /// The number of unspent wallet outputs, see `MemoryStore::utxo_count()`
+    pub utxo_count: usize,
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum AuditError {
+    #[error("Scripthash {0} has no history entries")]
+    EmptyHistory(ScriptHash),
+
+    #[error("History of {0} references an unknown transaction {1}")]
+    MissingTxEntry(ScriptHash, Txid),
+
+    #[error("History of {0} has transaction {1} with an outdated status {2:?}")]
+    StatusMismatch(ScriptHash, Txid, TxStatus),
+
+    #[error("History of {0} references transaction {1}, which does not fund or spend from it")]
+    UnrelatedHistory(ScriptHash, Txid),
+
+    #[error("Transaction {0} references an unknown scripthash {1}")]
+    MissingScriptEntry(Txid, ScriptHash),
+
+    #[error("Transaction {0} is missing from the history of {1}")]
+    MissingHistoryEntry(Txid, ScriptHash),
+
+    #[error("Unconfirmed transaction {0} is missing from the mempool index")]
+    MissingMempoolEntry(Txid),
+
+    #[error("Mempool index contains a transaction that is not unconfirmed: {0}")]
+    StaleMempoolEntry(Txid),
+
+    #[cfg(feature = "track-spends")]
+    #[error("Spent output {0} has no known funding entry")]
+    UnknownSpentTxo(OutPoint),
+
+    #[cfg(feature = "track-spends")]
+    #[error("Spent output {0} references an input that does not spend it: {1:?}")]
+    UnknownSpendingInput(OutPoint, InPoint),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin_hashes::Hash;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_prune_below() {
+        let mut store = MemoryStore::new();
+        let address = Address::p2wsh(&bitcoin::Script::new(), bitcoin::Network::Regtest);
+        let scripthash = ScriptHash::from(&address);
+        store.index_scripthash(&scripthash, &KeyOrigin::Standalone, &address);
+
+        let (funding_txid, spending_txid) =
+            (Txid::default(), Txid::from_str(&"11".repeat(32)).unwrap());
+        store.upsert_tx(&funding_txid, TxStatus::Confirmed(10));
+        store.index_tx_output_funding(&funding_txid, 0, FundingInfo(scripthash, 5000));
+        store.index_tx_output_funding(&funding_txid, 1, FundingInfo(scripthash, 7000));
+
+        let prevout = OutPoint::new(funding_txid, 0);
+        store.upsert_tx(&spending_txid, TxStatus::Confirmed(12));
+        let spending = vec![(0, SpendingInfo(scripthash, prevout, 5000))];
+        store.index_tx_inputs_spending(&spending_txid, spending.into_iter().collect(), false);
+        #[cfg(feature = "track-spends")]
+        store.index_txo_spend(prevout, InPoint::new(spending_txid, 0));
+
+        assert_eq!(store.utxo_count(), 1);
+
+        // nothing is buried deep enough yet
+        assert_eq!(store.prune_below(11), 0);
+        let totals = get_script_totals(&store, &scripthash, false);
+        let net_amount = store.get_tx_entry(&spending_txid).unwrap().net_amount();
+        assert_eq!(store.prune_below(12), 1);
+
+        // the totals and the net amounts are kept accurate using the pruned amounts summary
+        assert_eq!(get_script_totals(&store, &scripthash, false), totals);
+        assert_eq!(totals, (12000, 5000));
+        let spending_entry = store.get_tx_entry(&spending_txid).unwrap();
+        assert_eq!(spending_entry.net_amount(), net_amount);
+        assert_eq!(spending_entry.direction(), TxDirection::Outgoing);
+
+        // the spent output is pruned, the unspent one is kept
+        assert!(store.lookup_txo_fund(&prevout).is_none());
+        assert!(store
+            .lookup_txo_fund(&OutPoint::new(funding_txid, 1))
+            .is_some());
+        assert!(store
+            .get_tx_entry(&spending_txid)
+            .unwrap()
+            .spending
+            .is_empty());
+        #[cfg(feature = "track-spends")]
+        assert!(store.lookup_txo_spend(&prevout).is_none());
+        assert_eq!(store.utxo_count(), 1);
+
+        // the history and the tx status are kept and can still be updated
+        assert_eq!(store.get_tx_count(&scripthash), 2);
+        store.upsert_tx(&spending_txid, TxStatus::Confirmed(13));
+        assert!(store.purge_tx(&spending_txid));
+        assert_eq!(store.get_tx_count(&scripthash), 1);
+    }
+
+    #[test]
+    fn test_is_reused() {
+        let mut store = MemoryStore::new();
+        let address = Address::p2wsh(&bitcoin::Script::new(), bitcoin::Network::Regtest);
+        let scripthash = ScriptHash::from(&address);
+        store.index_scripthash(&scripthash, &KeyOrigin::Standalone, &address);
+
+        // two outputs funded by the same transaction don't count as reuse
+        let txid1 = Txid::default();
+        store.upsert_tx(&txid1, TxStatus::Confirmed(10));
+        store.index_tx_output_funding(&txid1, 0, FundingInfo(scripthash, 5000));
+        store.index_tx_output_funding(&txid1, 1, FundingInfo(scripthash, 7000));
+        assert!(!is_reused(&store, &scripthash));
+
+        // neither does spending from it
+        let txid2 = Txid::from_str(&"22".repeat(32)).unwrap();
+        store.upsert_tx(&txid2, TxStatus::Unconfirmed);
+        let spending = vec![(0, SpendingInfo(scripthash, OutPoint::new(txid1, 0), 5000))];
+        store.index_tx_inputs_spending(&txid2, spending.into_iter().collect(), false);
+        assert!(!is_reused(&store, &scripthash));
+
+        let txid3 = Txid::from_str(&"33".repeat(32)).unwrap();
+        store.upsert_tx(&txid3, TxStatus::Unconfirmed);
+        store.index_tx_output_funding(&txid3, 0, FundingInfo(scripthash, 1000));
+        assert!(is_reused(&store, &scripthash));
+        assert!(store.get_script_info(&scripthash).unwrap().reused);
+    }
+
+    #[test]
+    fn test_tx_unspent_outputs() {
+        let mut store = MemoryStore::new();
+        let address = Address::p2wsh(&bitcoin::Script::new(), bitcoin::Network::Regtest);
+        let scripthash = ScriptHash::from(&address);
+        store.index_scripthash(&scripthash, &KeyOrigin::Standalone, &address);
+
+        let (funding_txid, spending_txid) =
+            (Txid::default(), Txid::from_str(&"11".repeat(32)).unwrap());
+        store.upsert_tx(&funding_txid, TxStatus::Confirmed(10));
+        store.index_tx_output_funding(&funding_txid, 2, FundingInfo(scripthash, 7000));
+        store.index_tx_output_funding(&funding_txid, 0, FundingInfo(scripthash, 5000));
+
+        let prevout = OutPoint::new(funding_txid, 0);
+        store.upsert_tx(&spending_txid, TxStatus::Unconfirmed);
+        let spending = vec![(0, SpendingInfo(scripthash, prevout, 5000))];
+        store.index_tx_inputs_spending(&spending_txid, spending.into_iter().collect(), false);
+        #[cfg(feature = "track-spends")]
+        store.index_txo_spend(prevout, InPoint::new(spending_txid, 0));
+
+        let vouts = |store: &MemoryStore| -> Vec<u32> {
+            let unspent = get_tx_unspent_outputs(store, &funding_txid);
+            unspent.into_iter().map(|(vout, _)| vout).collect()
+        };
+        #[cfg(feature = "track-spends")]
+        assert_eq!(vouts(&store), vec![2]);
+        #[cfg(not(feature = "track-spends"))]
+        assert_eq!(vouts(&store), vec![0, 2]);
+
+        assert!(get_tx_unspent_outputs(&store, &spending_txid).is_empty());
+    }
+
+    #[test]
+    fn test_mempool_status() {
+        let mut store = MemoryStore::new();
+        let txid = Txid::default();
+        assert!(matches!(
+            store.mempool_status(&txid),
+            MempoolStatus::NotInMempool
+        ));
+
+        store.upsert_tx(&txid, TxStatus::Unconfirmed);
+        assert!(matches!(
+            store.mempool_status(&txid),
+            MempoolStatus::InMempoolPending
+        ));
+        assert!(get_mempool_entry(&store, &txid).is_none());
+        assert_eq!(store.get_mempool_txids(true), vec![txid]);
+
+        let entry = MempoolEntry {
+            vsize: 100,
+            fee: 500,
+            ancestor_vsize: 100,
+            ancestor_fee: 500,
+            bip125_replaceable: false,
+            first_seen: 0,
+            descendants: vec![],
+        };
+        store.set_mempool_entry(&txid, entry);
+        assert!(matches!(store.mempool_status(&txid), MempoolStatus::InMempool(e) if e.fee == 500));
+        assert!(store.get_mempool_txids(true).is_empty());
+
+        let child_txid = Txid::from_str(&"22".repeat(32)).unwrap();
+        store.set_mempool_descendants(&txid, vec![child_txid]);
+        assert_eq!(mempool_descendants(&store, &txid), vec![child_txid]);
+
+        // transactions with a populated entry are ordered before ones without it
+        let pending_txid = Txid::from_str(&"11".repeat(32)).unwrap();
+        store.upsert_tx(&pending_txid, TxStatus::Unconfirmed);
+        let mempool_txs = store.mempool_transactions();
+        assert_eq!(mempool_txs.len(), 2);
+        assert!(matches!(mempool_txs[0], (t, Some(e)) if t == txid && e.fee == 500));
+        assert!(matches!(mempool_txs[1], (t, None) if t == pending_txid));
+
+        store.upsert_tx(&txid, TxStatus::Confirmed(10));
+        assert!(matches!(
+            store.mempool_status(&txid),
+            MempoolStatus::NotInMempool
+        ));
+        // mined transactions no longer report their (possibly still unconfirmed) descendants
+        assert!(mempool_descendants(&store, &txid).is_empty());
+        assert_eq!(store.get_mempool_txids(false), vec![pending_txid]);
+        assert!(matches!(store.mempool_transactions()[..], [(t, None)] if t == pending_txid));
+    }
+
+    #[test]
+    fn test_confirmed_height() {
+        let mut store = MemoryStore::new();
+        let txid = Txid::default();
+        assert_eq!(get_confirmed_height(&store, &txid), None);
+        assert_eq!(get_confirmations(&store, &txid, 100), None);
+
+        store.upsert_tx(&txid, TxStatus::Unconfirmed);
+        assert_eq!(get_confirmed_height(&store, &txid), None);
+        assert_eq!(get_confirmations(&store, &txid, 100), Some(0));
+
+        store.upsert_tx(&txid, TxStatus::Confirmed(95));
+        assert_eq!(get_confirmed_height(&store, &txid), Some(95));
+        assert_eq!(get_confirmations(&store, &txid, 100), Some(6));
+    }
+
+    #[test]
+    fn test_history_since_dedup() {
+        let mut store = MemoryStore::new();
+        let scripthashes: Vec<ScriptHash> = (0..3)
+            .map(|i| {
+                let script = bitcoin::Script::from(vec![0x51 + i]);
+                let address = Address::p2wsh(&script, bitcoin::Network::Regtest);
+                let scripthash = ScriptHash::from(&address);
+                store.index_scripthash(&scripthash, &KeyOrigin::Standalone, &address);
+                scripthash
+            })
+            .collect();
+
+        // transactions at the same height, some of them shared between the first and last
+        // scripthashes with an unrelated one sorted in between them
+        let mut txids: Vec<Txid> = (0..4u8).map(|i| Txid::hash(&[i])).collect();
+        txids.sort();
+        let txs = [
+            (txids[0], 10, vec![0, 2]),
+            (txids[1], 10, vec![1]),
+            (txids[2], 10, vec![2, 0, 1]),
+            (txids[3], 5, vec![2, 0]),
+        ];
+        for (txid, height, owners) in &txs {
+            store.upsert_tx(txid, TxStatus::Confirmed(*height));
+            for (vout, owner) in owners.iter().enumerate() {
+                let funding = FundingInfo(scripthashes[*owner], 1000);
+                store.index_tx_output_funding(txid, vout as u32, funding);
+            }
+        }
+        let unconfirmed_txid = Txid::hash(&[9]);
+        store.upsert_tx(&unconfirmed_txid, TxStatus::Unconfirmed);
+        store.index_tx_output_funding(&unconfirmed_txid, 0, FundingInfo(scripthashes[1], 1000));
+        store.index_tx_output_funding(&unconfirmed_txid, 1, FundingInfo(scripthashes[2], 1000));
+
+        let history_txids = |store: &MemoryStore, min_height| {
+            store
+                .get_history_since(min_height)
+                .into_iter()
+                .map(|entry| entry.txid)
+                .collect::<Vec<_>>()
+        };
+        let expected = vec![txids[3], txids[0], txids[1], txids[2], unconfirmed_txid];
+        assert_eq!(history_txids(&store, 0), expected);
+        assert_eq!(history_txids(&store, 10), expected[1..].to_vec());
+        assert_eq!(history_txids(&store, 11), vec![unconfirmed_txid]);
+
+        // unconfirmed transactions already known to the client are skipped
+        let known_mempool: HashSet<Txid> = vec![unconfirmed_txid].into_iter().collect();
+        let snapshot_txids = |store: &MemoryStore, min_height| {
+            get_history_since_snapshot(store, min_height, &known_mempool)
+                .into_iter()
+                .map(|entry| entry.txid)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(snapshot_txids(&store, 10), expected[1..4].to_vec());
+        assert_eq!(snapshot_txids(&store, 11), vec![]);
+
+        // confirming a shared transaction moves all of its entries together
+        store.upsert_tx(&unconfirmed_txid, TxStatus::Confirmed(7));
+        let expected = vec![txids[3], unconfirmed_txid, txids[0], txids[1], txids[2]];
+        assert_eq!(history_txids(&store, 0), expected);
+        // and gets reported again despite being in the client's known mempool
+        assert_eq!(snapshot_txids(&store, 0), expected);
+        assert_eq!(store.audit(), Ok(()));
+    }
+
+    #[test]
+    fn test_index_large_tx_outputs() {
+        // a payout batch with 2000 outputs, funding 500 scripthashes with 4 outputs each
+        let scripts: Vec<_> = (0..500u32)
+            .map(|i| bitcoin::Script::from(i.to_le_bytes().to_vec()))
+            .collect();
+        let scripthashes: Vec<_> = scripts.iter().map(ScriptHash::from_script).collect();
+        let outputs: Vec<_> = (0..2000u32)
+            .map(|vout| {
+                let scripthash = scripthashes[vout as usize % scripthashes.len()];
+                (vout, FundingInfo(scripthash, 1000 + vout as u64))
+            })
+            .collect();
+
+        let make_store = || {
+            let mut store = MemoryStore::new();
+            for (scripthash, script) in scripthashes.iter().zip(&scripts) {
+                store.index_script(scripthash, &KeyOrigin::Standalone, script, None);
+            }
+            store.upsert_tx(&Txid::default(), TxStatus::Confirmed(10));
+            store
+        };
+        let txid = Txid::default();
+
+        let mut batch_store = make_store();
+        let added = batch_store.index_tx_outputs_funding(&txid, outputs.clone());
+        assert_eq!(added.len(), 2000);
+        // indexing the same outputs again adds nothing
+        assert!(batch_store
+            .index_tx_outputs_funding(&txid, outputs.clone())
+            .is_empty());
+
+        let mut single_store = make_store();
+        for (vout, funding_info) in outputs {
+            assert!(single_store.index_tx_output_funding(&txid, vout, funding_info));
+        }
+
+        for store in &[&batch_store, &single_store] {
+            let tx_entry = store.get_tx_entry(&txid).unwrap();
+            assert_eq!(tx_entry.funding.len(), 2000);
+            assert_eq!(tx_entry.funding[&1999].1, 2999);
+            for scripthash in &scripthashes {
+                assert_eq!(store.get_history(scripthash).unwrap().len(), 1);
+            }
+            assert_eq!(get_tx_unspent_outputs(*store, &txid).len(), 2000);
+            assert_eq!(store.audit(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_dust_threshold() {
+        let mut store = MemoryStore::with_dust_threshold(Some(546));
+        let address = Address::p2wsh(&bitcoin::Script::new(), bitcoin::Network::Regtest);
+        let scripthash = ScriptHash::from(&address);
+        store.index_scripthash(&scripthash, &KeyOrigin::Standalone, &address);
+
+        let txid = Txid::default();
+        store.upsert_tx(&txid, TxStatus::Confirmed(10));
+        assert!(!store.index_tx_output_funding(&txid, 0, FundingInfo(scripthash, 1)));
+        assert!(store.index_tx_output_funding(&txid, 1, FundingInfo(scripthash, 546)));
+
+        assert!(store.lookup_txo_fund(&OutPoint::new(txid, 0)).is_none());
+        assert!(store.lookup_txo_fund(&OutPoint::new(txid, 1)).is_some());
+        assert_eq!(get_script_totals(&store, &scripthash, false), (546, 0));
+
+        // no threshold by default
+        let mut store = MemoryStore::new();
+        store.index_scripthash(&scripthash, &KeyOrigin::Standalone, &address);
+        store.upsert_tx(&txid, TxStatus::Confirmed(10));
+        assert!(store.index_tx_output_funding(&txid, 0, FundingInfo(scripthash, 1)));
+    }
+
+    #[test]
+    fn test_tx_direction() {
+        let mut store = MemoryStore::new();
+        let address_a = Address::p2wsh(&bitcoin::Script::new(), bitcoin::Network::Regtest);
+        let address_b = Address::p2wsh(&bitcoin::Script::from(vec![0x51]), address_a.network);
+        let (scripthash_a, scripthash_b) =
+            (ScriptHash::from(&address_a), ScriptHash::from(&address_b));
+        store.index_scripthash(&scripthash_a, &KeyOrigin::Standalone, &address_a);
+        store.index_scripthash(&scripthash_b, &KeyOrigin::Standalone, &address_b);
+
+        let txid1 = Txid::default();
+        store.upsert_tx(&txid1, TxStatus::Confirmed(10));
+        store.index_tx_output_funding(&txid1, 0, FundingInfo(scripthash_a, 5000));
+        store.index_tx_output_funding(&txid1, 1, FundingInfo(scripthash_a, 7000));
+        let tx_entry = store.get_tx_entry(&txid1).unwrap();
+        assert_eq!(tx_entry.direction(), TxDirection::Incoming);
+        assert_eq!(tx_entry.net_amount(), 12000);
+
+        // consolidation of both outputs back into A
+        let txid2 = Txid::from_str(&"22".repeat(32)).unwrap();
+        store.upsert_tx(&txid2, TxStatus::Confirmed(11));
+        let spending = vec![
+            (0, SpendingInfo(scripthash_a, OutPoint::new(txid1, 0), 5000)),
+            (1, SpendingInfo(scripthash_a, OutPoint::new(txid1, 1), 7000)),
+        ];
+        store.index_tx_inputs_spending(&txid2, spending.into_iter().collect(), false);
+        store.index_tx_output_funding(&txid2, 0, FundingInfo(scripthash_a, 11800));
+        store.set_tx_fee(
+            &txid2,
+            TxFee {
+                fee: 200,
+                vsize: 200,
+            },
+        );
+        let tx_entry = store.get_tx_entry(&txid2).unwrap();
+        assert_eq!(tx_entry.scripthashes().len(), 1);
+        assert_eq!(tx_entry.direction(), TxDirection::SelfTransfer);
+        assert_eq!(tx_entry.net_amount(), -200);
+
+        // spending from A to fund B, both tracked
+        let txid3 = Txid::from_str(&"33".repeat(32)).unwrap();
+        store.upsert_tx(&txid3, TxStatus::Unconfirmed);
+        let spending = vec![(
+            0,
+            SpendingInfo(scripthash_a, OutPoint::new(txid2, 0), 11800),
+        )];
+        store.index_tx_inputs_spending(&txid3, spending.into_iter().collect(), false);
+        store.index_tx_output_funding(&txid3, 0, FundingInfo(scripthash_b, 11700));
+        store.set_tx_fee(
+            &txid3,
+            TxFee {
+                fee: 100,
+                vsize: 100,
+            },
+        );
+        let tx_entry = store.get_tx_entry(&txid3).unwrap();
+        assert_eq!(tx_entry.scripthashes().len(), 2);
+        assert_eq!(tx_entry.direction(), TxDirection::SelfTransfer);
+        assert_eq!(tx_entry.net_amount(), -100);
+        let (amounts_a, amounts_b) = (
+            tx_entry.script_amounts(&scripthash_a),
+            tx_entry.script_amounts(&scripthash_b),
+        );
+        assert_eq!((amounts_a.received, amounts_a.sent), (0, 11800));
+        assert_eq!((amounts_b.received, amounts_b.sent), (11700, 0));
+
+        let mut expected = vec![scripthash_a, scripthash_b];
+        expected.sort();
+        assert_eq!(get_tx_scripthashes(&store, &txid3), Some(expected.clone()));
+        let addresses = get_tx_addresses(&store, &txid3).unwrap();
+        let address_scripthashes: Vec<ScriptHash> =
+            addresses.iter().map(ScriptHash::from).collect();
+        assert_eq!(address_scripthashes, expected);
+        assert_eq!(get_tx_scripthashes(&store, &Txid::hash(&[0xff])), None);
+
+        // spending from B to an external address, with change back to B
+        let txid4 = Txid::from_str(&"44".repeat(32)).unwrap();
+        store.upsert_tx(&txid4, TxStatus::Unconfirmed);
+        let spending = vec![(
+            0,
+            SpendingInfo(scripthash_b, OutPoint::new(txid3, 0), 11700),
+        )];
+        store.index_tx_inputs_spending(&txid4, spending.into_iter().collect(), false);
+        store.index_tx_output_funding(&txid4, 1, FundingInfo(scripthash_b, 1600));
+        store.set_tx_fee(
+            &txid4,
+            TxFee {
+                fee: 100,
+                vsize: 100,
+            },
+        );
+        let tx_entry = store.get_tx_entry(&txid4).unwrap();
+        assert_eq!(tx_entry.direction(), TxDirection::Outgoing);
+        assert_eq!(tx_entry.net_amount(), -10100);
+    }
+
+    #[test]
+    fn test_audit() {
+        let mut store = MemoryStore::new();
+        let address = Address::p2wsh(&bitcoin::Script::new(), bitcoin::Network::Regtest);
+        let scripthash = ScriptHash::from(&address);
+        store.index_scripthash(&scripthash, &KeyOrigin::Standalone, &address);
+
+        let (funding_txid, spending_txid) =
+            (Txid::default(), Txid::from_str(&"11".repeat(32)).unwrap());
+        store.upsert_tx(&funding_txid, TxStatus::Confirmed(10));
+        store.index_tx_output_funding(&funding_txid, 0, FundingInfo(scripthash, 5000));
+        store.upsert_tx(&spending_txid, TxStatus::Unconfirmed);
+        let prevout = OutPoint::new(funding_txid, 0);
+        let spending = vec![(0, SpendingInfo(scripthash, prevout, 5000))];
+        store.index_tx_inputs_spending(&spending_txid, spending.into_iter().collect(), false);
+        #[cfg(feature = "track-spends")]
+        store.index_txo_spend(prevout, InPoint::new(spending_txid, 0));
+
+        assert_eq!(store.audit(), Ok(()));
+
+        // corrupt the indexes behind the store's back
+        store.mempool.clear();
+        store.transactions.get_mut(&funding_txid).unwrap().status = TxStatus::Confirmed(11);
+
+        let errors = store.audit().unwrap_err();
+        assert_eq!(errors.len(), 3);
+        assert!(errors.contains(&AuditError::MissingMempoolEntry(spending_txid)));
+        assert!(errors.contains(&AuditError::StatusMismatch(
+            scripthash,
+            funding_txid,
+            TxStatus::Confirmed(10)
+        )));
+        assert!(errors.contains(&AuditError::MissingHistoryEntry(funding_txid, scripthash)));
+    }
+
+    #[test]
+    fn test_script_totals() {
+        let mut store = MemoryStore::new();
+        let address = Address::p2wsh(&bitcoin::Script::new(), bitcoin::Network::Regtest);
+        let scripthash = ScriptHash::from(&address);
+        let other_address = Address::p2wsh(&bitcoin::Script::from(vec![0x51]), address.network);
+        let other_scripthash = ScriptHash::from(&other_address);
+        store.index_scripthash(&scripthash, &KeyOrigin::Standalone, &address);
+        store.index_scripthash(&other_scripthash, &KeyOrigin::Standalone, &other_address);
+        assert_eq!(get_script_totals(&store, &scripthash, false), (0, 0));
+
+        let txids: Vec<Txid> = (1..=4)
+            .map(|i| Txid::from_str(&format!("{:02x}", i).repeat(32)).unwrap())
+            .collect();
+
+        // two confirmed fundings, one of them with two outputs
+        store.upsert_tx(&txids[0], TxStatus::Confirmed(10));
+        store.index_tx_output_funding(&txids[0], 0, FundingInfo(scripthash, 5000));
+        store.index_tx_output_funding(&txids[0], 1, FundingInfo(scripthash, 1000));
+        store.index_tx_output_funding(&txids[0], 2, FundingInfo(other_scripthash, 9000));
+        store.upsert_tx(&txids[1], TxStatus::Confirmed(11));
+        store.index_tx_output_funding(&txids[1], 0, FundingInfo(scripthash, 3000));
+
+        // a confirmed spend of two outputs, sending change back to the same address
+        store.upsert_tx(&txids[2], TxStatus::Confirmed(12));
+        let spending = vec![
+            (
+                0,
+                SpendingInfo(scripthash, OutPoint::new(txids[0], 0), 5000),
+            ),
+            (
+                1,
+                SpendingInfo(scripthash, OutPoint::new(txids[1], 0), 3000),
+            ),
+        ];
+        store.index_tx_inputs_spending(&txids[2], spending.into_iter().collect(), false);
+        store.index_tx_output_funding(&txids[2], 0, FundingInfo(scripthash, 2500));
+
+        // an unconfirmed spend
+        store.upsert_tx(&txids[3], TxStatus::Unconfirmed);
+        let spending = vec![(
+            0,
+            SpendingInfo(scripthash, OutPoint::new(txids[0], 1), 1000),
+        )];
+        store.index_tx_inputs_spending(&txids[3], spending.into_iter().collect(), false);
+
+        assert_eq!(get_script_totals(&store, &scripthash, true), (11500, 8000));
+        assert_eq!(get_script_totals(&store, &scripthash, false), (11500, 9000));
+        assert_eq!(
+            get_script_totals(&store, &other_scripthash, false),
+            (9000, 0)
+        );
+    }
+
+    #[test]
+    fn test_bip21() {
+        let address = Address::from_str("bc1qaxlg48awxth5k72ltgrjp6qyegzdmfkfupyhhg").unwrap();
+        let script_info = ScriptInfo::from_address(address, KeyOrigin::Standalone);
+        assert_eq!(
+            script_info.to_bip21(None, None).unwrap(),
+            "bitcoin:bc1qaxlg48awxth5k72ltgrjp6qyegzdmfkfupyhhg"
+        );
+        assert_eq!(
+            script_info.to_bip21(Some(150_000_000), None).unwrap(),
+            "bitcoin:bc1qaxlg48awxth5k72ltgrjp6qyegzdmfkfupyhhg?amount=1.5"
+        );
+        assert_eq!(
+            script_info.to_bip21(Some(1), Some("Coffee & cake")).unwrap(),
+            "bitcoin:bc1qaxlg48awxth5k72ltgrjp6qyegzdmfkfupyhhg?amount=0.00000001&label=Coffee%20%26%20cake"
+        );
+        assert_eq!(
+            script_info
+                .to_bip21(Some(2_100_000_000_000_000), Some(""))
+                .unwrap(),
+            "bitcoin:bc1qaxlg48awxth5k72ltgrjp6qyegzdmfkfupyhhg?amount=21000000&label="
+        );
+    }
+
+    #[test]
+    fn test_history_direction() {
+        use crate::types::TxDirection::{Incoming, Outgoing, SelfTransfer};
+
+        let mut store = MemoryStore::new();
+        let address = Address::p2wsh(&bitcoin::Script::new(), bitcoin::Network::Regtest);
+        let scripthash = ScriptHash::from(&address);
+        store.index_scripthash(&scripthash, &KeyOrigin::Standalone, &address);
+        let txids: Vec<Txid> = (1..=4)
+            .map(|n| Txid::from_str(&format!("{:02x}", n).repeat(32)).unwrap())
+            .collect();
+
+        store.upsert_tx(&txids[0], TxStatus::Confirmed(10));
+        store.index_tx_output_funding(&txids[0], 0, FundingInfo(scripthash, 5000));
+        store.index_tx_output_funding(&txids[0], 1, FundingInfo(scripthash, 1000));
+        store.upsert_tx(&txids[1], TxStatus::Confirmed(11));
+        store.index_tx_output_funding(&txids[1], 0, FundingInfo(scripthash, 3000));
+
+        // a spend sending change back to the same address
+        store.upsert_tx(&txids[2], TxStatus::Confirmed(12));
+        let spending = vec![
+            (
+                0,
+                SpendingInfo(scripthash, OutPoint::new(txids[0], 0), 5000),
+            ),
+            (
+                1,
+                SpendingInfo(scripthash, OutPoint::new(txids[1], 0), 3000),
+            ),
+        ];
+        store.index_tx_inputs_spending(&txids[2], spending.into_iter().collect(), false);
+        store.index_tx_output_funding(&txids[2], 0, FundingInfo(scripthash, 2500));
+
+        // an unconfirmed spend without change
+        store.upsert_tx(&txids[3], TxStatus::Unconfirmed);
+        let spending = vec![(
+            0,
+            SpendingInfo(scripthash, OutPoint::new(txids[0], 1), 1000),
+        )];
+        store.index_tx_inputs_spending(&txids[3], spending.into_iter().collect(), false);
+
+        let filtered = |direction| {
+            let entries = get_script_history_filtered(&store, &scripthash, direction);
+            entries.iter().map(|h| h.txid).collect::<Vec<_>>()
+        };
+        assert_eq!(filtered(None), txids);
+        assert_eq!(filtered(Some(Incoming)), txids[0..3].to_vec());
+        assert_eq!(filtered(Some(Outgoing)), txids[2..4].to_vec());
+        assert_eq!(filtered(Some(SelfTransfer)), vec![txids[2]]);
+
+        // paginating over the filtered entries
+        let (page, cursor) = get_history_after_filtered(
+            &store,
+            &scripthash,
+            None,
+            1,
+            SortOrder::OldestFirst,
+            Some(Outgoing),
+        );
+        assert_eq!(
+            page.iter().map(|h| h.txid).collect::<Vec<_>>(),
+            vec![txids[2]]
+        );
+        assert_eq!(cursor, Some((TxStatus::Confirmed(12), txids[2])));
+        let (page, cursor) = get_history_after_filtered(
+            &store,
+            &scripthash,
+            cursor,
+            1,
+            SortOrder::OldestFirst,
+            Some(Outgoing),
+        );
+        assert_eq!(
+            page.iter().map(|h| h.txid).collect::<Vec<_>>(),
+            vec![txids[3]]
+        );
+        assert_eq!(cursor, None);
+    }
+
+    #[test]
+    fn test_history_after() {
+        let mut store = MemoryStore::new();
+        let address = Address::p2wsh(&bitcoin::Script::new(), bitcoin::Network::Regtest);
+        let scripthash = ScriptHash::from(&address);
+        store.index_scripthash(&scripthash, &KeyOrigin::Standalone, &address);
+
+        let fund = |store: &mut MemoryStore, n: u8, status| {
+            let txid = Txid::from_str(&format!("{:02x}", n).repeat(32)).unwrap();
+            store.upsert_tx(&txid, status);
+            store.index_tx_output_funding(&txid, 0, FundingInfo(scripthash, 5000));
+            txid
+        };
+        let txid1 = fund(&mut store, 1, TxStatus::Confirmed(10));
+        let txid2 = fund(&mut store, 2, TxStatus::Confirmed(11));
+        let txid3 = fund(&mut store, 3, TxStatus::Unconfirmed);
+
+        let txids =
+            |entries: Vec<&HistoryEntry>| entries.iter().map(|h| h.txid).collect::<Vec<_>>();
+
+        let (page, cursor) =
+            get_history_after(&store, &scripthash, None, 2, SortOrder::OldestFirst);
+        assert_eq!(txids(page), vec![txid1, txid2]);
+        assert_eq!(cursor, Some((TxStatus::Confirmed(11), txid2)));
+
+        // a new entry that sorts before the cursor does not shift the next page
+        let txid0 = fund(&mut store, 0, TxStatus::Confirmed(9));
+        let (page, cursor) =
+            get_history_after(&store, &scripthash, cursor, 2, SortOrder::OldestFirst);
+        assert_eq!(txids(page), vec![txid3]);
+        assert_eq!(cursor, None);
+
+        // the cursor remains usable after its own entry gets confirmed
+        let (page, cursor) =
+            get_history_after(&store, &scripthash, None, 2, SortOrder::NewestFirst);
+        assert_eq!(txids(page), vec![txid3, txid2]);
+        assert_eq!(cursor, Some((TxStatus::Confirmed(11), txid2)));
+        store.upsert_tx(&txid2, TxStatus::Confirmed(12));
+        let (page, cursor) =
+            get_history_after(&store, &scripthash, cursor, 5, SortOrder::NewestFirst);
+        assert_eq!(txids(page), vec![txid1, txid0]);
+        assert_eq!(cursor, None);
+    }
+
+    #[test]
+    fn test_resolve_spending() {
+        let mut store = MemoryStore::new();
+        let address = Address::p2wsh(&bitcoin::Script::new(), bitcoin::Network::Regtest);
+        let scripthash = ScriptHash::from(&address);
+        store.index_scripthash(&scripthash, &KeyOrigin::Standalone, &address);
+
+        let (funding_txid, spending_txid) =
+            (Txid::default(), Txid::from_str(&"11".repeat(32)).unwrap());
+        store.upsert_tx(&funding_txid, TxStatus::Confirmed(10));
+        store.index_tx_output_funding(&funding_txid, 0, FundingInfo(scripthash, 5000));
+        store.index_tx_output_funding(&funding_txid, 1, FundingInfo(scripthash, 7000));
+
+        store.upsert_tx(&spending_txid, TxStatus::Unconfirmed);
+        let spending = vec![
+            (
+                2,
+                SpendingInfo(scripthash, OutPoint::new(funding_txid, 0), 5000),
+            ),
+            (
+                0,
+                SpendingInfo(scripthash, OutPoint::new(funding_txid, 1), 7000),
+            ),
+        ];
+        store.index_tx_inputs_spending(&spending_txid, spending.into_iter().collect(), false);
+
+        let inputs = resolve_spending(&store, &spending_txid);
+        assert_eq!(inputs.iter().map(|i| i.vin).collect::<Vec<_>>(), vec![0, 2]);
+        assert_eq!(inputs[0].prevout, OutPoint::new(funding_txid, 1));
+        assert_eq!(inputs[0].address.as_ref(), Some(&address));
+        assert_eq!(inputs[0].amount, 7000);
+        assert_eq!(inputs[1].amount, 5000);
+
+        assert!(resolve_spending(&store, &funding_txid).is_empty());
+    }
+
+    #[test]
+    fn test_pending_maturity() {
+        let mut store = MemoryStore::new();
+        let address = Address::p2wsh(&bitcoin::Script::new(), bitcoin::Network::Regtest);
+        let scripthash = ScriptHash::from(&address);
+        store.index_scripthash(&scripthash, &KeyOrigin::Standalone, &address);
+
+        let txids: Vec<Txid> = (1..=4)
+            .map(|i| Txid::from_str(&format!("{:02x}", i).repeat(32)).unwrap())
+            .collect();
+        let statuses = [
+            TxStatus::Confirmed(95), // 6 confirmations, mature
+            TxStatus::Confirmed(99), // 2 confirmations
+            TxStatus::Unconfirmed,
+            TxStatus::Confirmed(100), // 1 confirmation
+        ];
+        for (i, (txid, status)) in txids.iter().zip(&statuses).enumerate() {
+            store.upsert_tx(txid, *status);
+            store.index_tx_output_funding(txid, 0, FundingInfo(scripthash, 1000 * (i as u64 + 1)));
+        }
+
+        assert_eq!(
+            pending_maturity(&store, &scripthash, 100, 6),
+            vec![(2000, 4), (4000, 5), (3000, 6)]
+        );
+        assert_eq!(
+            pending_maturity(&store, &scripthash, 100, 1),
+            vec![(3000, 1)]
+        );
+        assert!(pending_maturity(&store, &scripthash, 100, 0).is_empty());
+    }
+
+    #[test]
+    fn test_sweep_stale_mempool() {
+        let mut store = MemoryStore::new();
+        let address = Address::p2wsh(&bitcoin::Script::new(), bitcoin::Network::Regtest);
+        let scripthash = ScriptHash::from(&address);
+        store.index_scripthash(&scripthash, &KeyOrigin::Standalone, &address);
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let make_entry = |first_seen| MempoolEntry {
+            vsize: 100,
+            fee: 500,
+            ancestor_vsize: 100,
+            ancestor_fee: 500,
+            bip125_replaceable: false,
+            first_seen,
+            descendants: vec![],
+        };
+        let txids: Vec<Txid> = (1..=4)
+            .map(|i| Txid::from_str(&format!("{:02x}", i).repeat(32)).unwrap())
+            .collect();
+        for txid in &txids {
+            store.upsert_tx(txid, TxStatus::Unconfirmed);
+            store.index_tx_output_funding(txid, 0, FundingInfo(scripthash, 1000));
+        }
+        let day = 86400;
+        store.set_mempool_entry(&txids[0], make_entry(now - 20 * day)); // stale and gone
+        store.set_mempool_entry(&txids[1], make_entry(now - 20 * day)); // stale, but still in the mempool
+        store.set_mempool_entry(&txids[2], make_entry(now - day)); // recent
+                                                                   // the entry of txids[3] is not populated, making its age unknown
+
+        let max_age = Duration::from_secs(14 * day);
+        let swept = sweep_stale_mempool(&mut store, max_age, |txid| *txid != txids[1]);
+        assert_eq!(swept, vec![txids[0]]);
+        assert!(store.get_tx_entry(&txids[0]).is_none());
+        assert_eq!(store.get_tx_count(&scripthash), 3);
+        assert!(store.audit().is_ok());
+
+        assert_eq!(
+            sweep_stale_mempool(&mut store, max_age, |_| true),
+            vec![txids[1]]
+        );
+    }
+
+    #[test]
+    fn test_get_script_pubkey() {
+        let mut store = MemoryStore::new();
+        let address = Address::p2wsh(&bitcoin::Script::new(), bitcoin::Network::Regtest);
+        let scripthash = ScriptHash::from(&address);
+        assert_eq!(store.get_script_pubkey(&scripthash), None);
+
+        store.index_scripthash(&scripthash, &KeyOrigin::Standalone, &address);
+        assert_eq!(
+            store.get_script_pubkey(&scripthash),
+            Some(address.script_pubkey())
+        );
+    }
+
+    #[test]
+    fn test_index_raw_script() {
+        let mut store = MemoryStore::new();
+        // a bare OP_TRUE script, which has no address form
+        let script = bitcoin::blockdata::script::Builder::new()
+            .push_int(1)
+            .into_script();
+        let scripthash = ScriptHash::from_script(&script);
+
+        assert!(store.index_script(&scripthash, &KeyOrigin::Standalone, &script, None));
+        assert!(!store.index_script(&scripthash, &KeyOrigin::Standalone, &script, None));
+        store.upsert_tx(&Txid::default(), TxStatus::Confirmed(10));
+        store.index_tx_output_funding(&Txid::default(), 0, FundingInfo(scripthash, 5000));
+
+        assert_eq!(store.get_script_pubkey(&scripthash), Some(script));
+        assert_eq!(store.get_script_address(&scripthash), None);
+        let script_info = store.get_script_info(&scripthash).unwrap();
+        assert_eq!(script_info.address, None);
+        assert_eq!(script_info.to_bip21(None, None), None);
+        assert_eq!(store.get_tx_count(&scripthash), 1);
+        assert_eq!(store.audit(), Ok(()));
+    }
+
+    #[test]
+    #[should_panic(expected = "scripthash mismatch")]
+    fn test_index_scripthash_mismatch() {
+        let mut store = MemoryStore::new();
+        let address = Address::p2wsh(&bitcoin::Script::new(), bitcoin::Network::Regtest);
+        store.index_scripthash(&ScriptHash::default(), &KeyOrigin::Standalone, &address);
+    }
+
+    #[test]
+    #[cfg(feature = "electrum")]
+    fn test_electrum_balance() {
+        let mut store = MemoryStore::new();
+        let address = Address::p2wsh(&bitcoin::Script::new(), bitcoin::Network::Regtest);
+        let scripthash = ScriptHash::from(&address);
+        store.index_scripthash(&scripthash, &KeyOrigin::Standalone, &address);
+        let txid = |i: u8| Txid::from_str(&format!("{:02x}", i).repeat(32)).unwrap();
+        let balance = |store: &MemoryStore| electrum_balance(store, &scripthash);
+
+        store.upsert_tx(&txid(1), TxStatus::Confirmed(10));
+        store.index_tx_output_funding(&txid(1), 0, FundingInfo(scripthash, 5000));
+        let expected = ElectrumBalance {
+            confirmed: 5000,
+            unconfirmed: 0,
+        };
+        assert_eq!(balance(&store), expected);
+
+        // unconfirmed incoming
+        store.upsert_tx(&txid(2), TxStatus::Unconfirmed);
+        store.index_tx_output_funding(&txid(2), 0, FundingInfo(scripthash, 3000));
+        let expected = ElectrumBalance {
+            confirmed: 5000,
+            unconfirmed: 3000,
+        };
+        assert_eq!(balance(&store), expected);
+
+        // unconfirmed outgoing, spending the confirmed output. mixed with the unconfirmed incoming
+        // transaction above, the unconfirmed balance turns negative.
+        store.upsert_tx(&txid(3), TxStatus::Unconfirmed);
+        let prevout = OutPoint::new(txid(1), 0);
+        let spending = vec![(0, SpendingInfo(scripthash, prevout, 5000))];
+        store.index_tx_inputs_spending(&txid(3), spending.into_iter().collect(), false);
+        let expected = ElectrumBalance {
+            confirmed: 5000,
+            unconfirmed: -2000,
+        };
+        assert_eq!(balance(&store), expected);
+
+        // a mixed transaction spending the unconfirmed output and sending change back
+        store.upsert_tx(&txid(4), TxStatus::Unconfirmed);
+        let prevout = OutPoint::new(txid(2), 0);
+        let spending = vec![(0, SpendingInfo(scripthash, prevout, 3000))];
+        store.index_tx_inputs_spending(&txid(4), spending.into_iter().collect(), false);
+        store.index_tx_output_funding(&txid(4), 1, FundingInfo(scripthash, 1000));
+        let expected = ElectrumBalance {
+            confirmed: 5000,
+            unconfirmed: -4000,
+        };
+        assert_eq!(balance(&store), expected);
+
+        // once the spends confirm, they're reflected in the confirmed balance
+        store.upsert_tx(&txid(2), TxStatus::Confirmed(11));
+        store.upsert_tx(&txid(3), TxStatus::Confirmed(11));
+        store.upsert_tx(&txid(4), TxStatus::Confirmed(11));
+        let expected = ElectrumBalance {
+            confirmed: 1000,
+            unconfirmed: 0,
+        };
+        assert_eq!(balance(&store), expected);
+    }
+
+    #[test]
+    fn test_replacement_chain() {
+        let mut store = MemoryStore::new();
+        let txid = |i: u8| Txid::from_str(&format!("{:02x}", i).repeat(32)).unwrap();
+        let (v1, v2, v3) = (txid(1), txid(2), txid(3));
+        assert_eq!(replacement_chain(&store, &v1), vec![v1]);
+
+        store.mark_replaced(&v1, &v2);
+        store.mark_replaced(&v2, &v3);
+        // marking the same replacement again is a no-op
+        store.mark_replaced(&v2, &v3);
+
+        for v in &[v1, v2, v3] {
+            assert_eq!(replacement_chain(&store, v), vec![v1, v2, v3]);
+        }
+        assert_eq!(store.get_replaced_by(&v1), Some(v2));
+        assert_eq!(store.get_replaced_by(&v3), None);
+        assert_eq!(store.get_replaces(&v3), Some(v2));
+        assert_eq!(store.get_replaces(&v1), None);
+
+        // v1 becoming the active transaction again (i.e. after a reorg) doesn't create a cycle
+        store.mark_replaced(&v3, &v1);
+        assert_eq!(store.get_replaced_by(&v1), None);
+        assert_eq!(replacement_chain(&store, &v1), vec![v2, v3, v1]);
+        assert_eq!(replacement_chain(&store, &v3), vec![v2, v3, v1]);
+    }
+
+    #[test]
+    fn test_tracks_spends() {
+        let store = MemoryStore::new();
+        assert_eq!(store.tracks_spends(), cfg!(feature = "track-spends"));
+    }
+
+    #[test]
+    #[cfg(feature = "track-spends")]
+    fn test_pending_spends() {
+        let mut store = MemoryStore::new();
+        let address = Address::p2wsh(&bitcoin::Script::new(), bitcoin::Network::Regtest);
+        let scripthash = ScriptHash::from(&address);
+        store.index_scripthash(&scripthash, &KeyOrigin::Standalone, &address);
+
+        let funding_txid = Txid::default();
+        store.upsert_tx(&funding_txid, TxStatus::Confirmed(10));
+        store.index_tx_output_funding(&funding_txid, 0, FundingInfo(scripthash, 5000));
+        store.index_tx_output_funding(&funding_txid, 1, FundingInfo(scripthash, 7000));
+        let (prevout0, prevout1) = (
+            OutPoint::new(funding_txid, 0),
+            OutPoint::new(funding_txid, 1),
+        );
+
+        // one output spent by a confirmed transaction, the other by an unconfirmed one
+        let (confirmed_txid, unconfirmed_txid) = (
+            Txid::from_str(&"11".repeat(32)).unwrap(),
+            Txid::from_str(&"22".repeat(32)).unwrap(),
+        );
+        store.upsert_tx(&confirmed_txid, TxStatus::Confirmed(11));
+        store.index_txo_spend(prevout0, InPoint::new(confirmed_txid, 0));
+        store.upsert_tx(&unconfirmed_txid, TxStatus::Unconfirmed);
+        store.index_txo_spend(prevout1, InPoint::new(unconfirmed_txid, 1));
+
+        assert_eq!(
+            store.pending_spends(),
+            vec![(prevout1, InPoint::new(unconfirmed_txid, 1))]
+        );
+
+        store.upsert_tx(&unconfirmed_txid, TxStatus::Confirmed(12));
+        assert_eq!(store.pending_spends(), vec![]);
+    }
+
+    #[test]
+    fn test_conflicted_retention() {
+        let address = Address::p2wsh(&bitcoin::Script::new(), bitcoin::Network::Regtest);
+        let scripthash = ScriptHash::from(&address);
+        let txid = Txid::default();
+        let index = |store: &mut MemoryStore| {
+            store.index_scripthash(&scripthash, &KeyOrigin::Standalone, &address);
+            store.upsert_tx(&txid, TxStatus::Unconfirmed);
+            store.index_tx_output_funding(&txid, 0, FundingInfo(scripthash, 5000));
+        };
+
+        // not retained by default
+        let mut store = MemoryStore::new();
+        index(&mut store);
+        assert!(store.purge_conflicted_tx(&txid));
+        assert!(store.get_conflicted_txs().is_empty());
+
+        let mut store =
+            MemoryStore::new().with_conflicted_retention(Some(Duration::from_secs(3600)));
+        index(&mut store);
+        assert!(store.purge_conflicted_tx(&txid));
+        assert!(store.get_tx_entry(&txid).is_none());
+        let conflicted = store.get_conflicted_txs();
+        assert_eq!(conflicted.len(), 1);
+        assert_eq!(conflicted[0].0, txid);
+        assert_eq!(conflicted[0].1.status, TxStatus::Conflicted);
+        assert_eq!(conflicted[0].1.net_amount(), 5000);
+        assert_eq!(store.expire_conflicted(), 0);
+
+        // no longer conflicted once it gets re-added
+        index(&mut store);
+        assert!(store.get_conflicted_txs().is_empty());
+
+        // dropped once the retention window passes
+        store.purge_conflicted_tx(&txid);
+        store.conflicted.get_mut(&txid).unwrap().1 -= 3601;
+        assert_eq!(store.expire_conflicted(), 1);
+        assert!(store.get_conflicted_txs().is_empty());
+    }
+
+    #[test]
+    fn test_descriptor_balance() {
+        let mut store = MemoryStore::new();
+        let (checksum_a, checksum_b) = (
+            Checksum::from_str("xjm8w0el").unwrap(),
+            Checksum::from_str("s4ct0xfk").unwrap(),
+        );
+        let scripthashes: Vec<_> = vec![
+            KeyOrigin::Descriptor(checksum_a.clone(), 1),
+            KeyOrigin::Descriptor(checksum_a.clone(), 0),
+            KeyOrigin::Descriptor(checksum_b.clone(), 0),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, origin)| {
+            let script = bitcoin::Script::from(vec![0x51 + i as u8]);
+            let address = Address::p2wsh(&script, bitcoin::Network::Regtest);
+            let scripthash = ScriptHash::from(&address);
+            store.index_scripthash(&scripthash, &origin, &address);
+            scripthash
+        })
+        .collect();
+        assert_eq!(
+            store.list_descriptor_scripts(&checksum_a),
+            vec![(0, scripthashes[1]), (1, scripthashes[0])]
+        );
+
+        let txid1 = Txid::default();
+        store.upsert_tx(&txid1, TxStatus::Confirmed(10));
+        store.index_tx_output_funding(&txid1, 0, FundingInfo(scripthashes[0], 5000));
+        store.index_tx_output_funding(&txid1, 1, FundingInfo(scripthashes[1], 7000));
+        store.index_tx_output_funding(&txid1, 2, FundingInfo(scripthashes[2], 9000));
+
+        // spend from both of A's addresses, with change back to A
+        let txid2 = Txid::from_str(&"22".repeat(32)).unwrap();
+        store.upsert_tx(&txid2, TxStatus::Unconfirmed);
+        let spending = vec![
+            (
+                0,
+                SpendingInfo(scripthashes[0], OutPoint::new(txid1, 0), 5000),
+            ),
+            (
+                1,
+                SpendingInfo(scripthashes[1], OutPoint::new(txid1, 1), 7000),
+            ),
+        ];
+        store.index_tx_inputs_spending(&txid2, spending.into_iter().collect(), false);
+        store.index_tx_output_funding(&txid2, 1, FundingInfo(scripthashes[1], 3000));
+
+        assert_eq!(get_descriptor_balance(&store, &checksum_a), (12000, 3000));
+        assert_eq!(get_descriptor_balance(&store, &checksum_b), (9000, 9000));
+
+        let unknown = Checksum::from_str("9xfy5ahq").unwrap();
+        assert!(store.list_descriptor_scripts(&unknown).is_empty());
+        assert_eq!(get_descriptor_balance(&store, &unknown), (0, 0));
+    }
+
+    #[test]
+    fn test_overlapping_descriptors() {
+        use crate::types::RescanSince;
+        use crate::util::descriptor::DescriptorExt;
+        use crate::wallet::Wallet;
+
+        // the same descriptor with and without the key origin info, deriving the same addresses
+        // under different checksums
+        const XPUB: &str = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+        let descs: Vec<_> = [
+            format!("wpkh({}/0/*)", XPUB),
+            format!("wpkh([d34db33f/84h]{}/0/*)", XPUB),
+        ]
+        .iter()
+        .map(|desc| ExtendedDescriptor::from_str(desc).unwrap())
+        .collect();
+        let addresses: Vec<_> = descs
+            .iter()
+            .map(|desc| {
+                let wallet = Wallet::from_descriptor(
+                    desc.clone(),
+                    bitcoin::Network::Bitcoin,
+                    5,
+                    5,
+                    RescanSince::Now,
+                );
+                wallet.unwrap().derive_address(3)
+            })
+            .collect();
+        assert_eq!(addresses[0], addresses[1]);
+        assert_ne!(descs[0].checksum(), descs[1].checksum());
+
+        let address = &addresses[0];
+        let scripthash = ScriptHash::from(address);
+        let origins: Vec<_> = descs
+            .iter()
+            .map(|desc| KeyOrigin::Descriptor(desc.checksum(), 3))
+            .collect();
+        let preferred = origins.iter().min().unwrap().clone();
+
+        // indexing it under both origins resolves to the same one, regardless of the order
+        for (first, second) in [(0, 1), (1, 0)] {
+            let mut store = MemoryStore::new();
+            assert!(store.index_scripthash(&scripthash, &origins[first], address));
+            assert!(!store.index_scripthash(&scripthash, &origins[second], address));
+            assert_eq!(
+                store.get_script_info(&scripthash).unwrap().origin,
+                preferred
+            );
+        }
+
+        // descriptor origins are preferred over standalone ones
+        let mut store = MemoryStore::new();
+        store.index_scripthash(&scripthash, &KeyOrigin::Standalone, address);
+        store.index_scripthash(&scripthash, &origins[1], address);
+        assert_eq!(
+            store.get_script_info(&scripthash).unwrap().origin,
+            origins[1]
+        );
+    }
 }
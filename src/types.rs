@@ -1,12 +1,13 @@
 use std::cmp::Ordering;
 use std::str::FromStr;
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-use bitcoin::{Address, BlockHash, Txid};
+use bitcoin::{Address, BlockHash, Script, Txid};
 use bitcoin_hashes::{sha256, Hash};
 use bitcoincore_rpc::json::GetMempoolEntryResult;
 
+use crate::util::amount;
 pub use crate::util::bitcoincore_ext::RescanSince;
 
 hash_newtype!(
@@ -17,9 +18,16 @@ hash_newtype!(
     true
 );
 
+impl ScriptHash {
+    /// Get the scripthash of an arbitrary scriptPubKey, including ones without an address form
+    pub fn from_script(script: &Script) -> Self {
+        ScriptHash::hash(script.as_bytes())
+    }
+}
+
 impl From<&Address> for ScriptHash {
     fn from(address: &Address) -> Self {
-        ScriptHash::hash(&address.script_pubkey().into_bytes())
+        ScriptHash::from_script(&address.script_pubkey())
     }
 }
 
@@ -32,6 +40,15 @@ impl From<Address> for ScriptHash {
 #[cfg(feature = "electrum")]
 hash_newtype!(StatusHash, sha256::Hash, 32, doc = "The status hash.");
 
+/// The scripthash balance, as returned by Electrum's `blockchain.scripthash.get_balance`.
+/// The unconfirmed balance is the net change from mempool transactions and may be negative.
+#[cfg(feature = "electrum")]
+#[derive(Serialize, Debug, PartialEq, Clone, Copy, Default)]
+pub struct ElectrumBalance {
+    pub confirmed: u64,
+    pub unconfirmed: i64,
+}
+
 #[derive(Serialize, Debug, PartialEq, Clone, Copy)]
 pub struct BlockId(pub u32, pub BlockHash);
 
@@ -50,7 +67,7 @@ impl std::fmt::Display for BlockId {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct InPoint {
     pub txid: Txid,
     pub vin: u32,
@@ -101,6 +118,32 @@ impl TxStatus {
         }
     }
 
+    /// Get the number of confirmations given the current tip height, or None for conflicted txs
+    pub fn confirmations(self, tip_height: u32) -> Option<u32> {
+        match self {
+            TxStatus::Confirmed(height) => Some(tip_height.saturating_sub(height) + 1),
+            TxStatus::Unconfirmed => Some(0),
+            TxStatus::Conflicted => None,
+        }
+    }
+
+    /// Get the confirmation block height, or None for unconfirmed and conflicted txs
+    pub fn height(self) -> Option<u32> {
+        match self {
+            TxStatus::Confirmed(height) => Some(height),
+            TxStatus::Unconfirmed | TxStatus::Conflicted => None,
+        }
+    }
+
+    /// Check whether the transaction has at least `threshold` confirmations, making it final
+    /// (settled) rather than confirming. Unconfirmed and conflicted transactions are never final.
+    pub fn is_final(self, tip_height: u32, threshold: u32) -> bool {
+        match self {
+            TxStatus::Confirmed(_) => self.confirmations(tip_height).unwrap() >= threshold,
+            TxStatus::Unconfirmed | TxStatus::Conflicted => false,
+        }
+    }
+
     pub fn is_viable(self) -> bool {
         match self {
             TxStatus::Confirmed(_) | TxStatus::Unconfirmed => true,
@@ -123,7 +166,7 @@ impl TxStatus {
     }
 }
 
-// Serialize confirmed transactions as the block height, unconfirmed as null and confliced as -1
+// Serialize confirmed transactions as the block height, unconfirmed as null and conflicted as -1
 impl serde::Serialize for TxStatus {
     fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
     where
@@ -137,6 +180,26 @@ impl serde::Serialize for TxStatus {
     }
 }
 
+// Deserialize using the same encoding, to allow reloading serialized statuses
+impl<'de> serde::Deserialize<'de> for TxStatus {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+        Ok(match Option::<i64>::deserialize(deserializer)? {
+            None => TxStatus::Unconfirmed,
+            Some(-1) => TxStatus::Conflicted,
+            Some(height) if height >= 0 && height <= u32::MAX as i64 => {
+                TxStatus::Confirmed(height as u32)
+            }
+            Some(invalid) => {
+                return Err(D::Error::custom(format!("invalid tx status {}", invalid)))
+            }
+        })
+    }
+}
+
 impl Ord for TxStatus {
     fn cmp(&self, other: &TxStatus) -> Ordering {
         match (self, other) {
@@ -159,18 +222,129 @@ impl PartialOrd for TxStatus {
     }
 }
 
+/// A range of confirmation counts used for grouping wallet outputs by their maturity
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ConfBucket {
+    Unconfirmed,
+    /// Between the first and second confirmation counts (inclusive), or at least the first one
+    /// for the last bucket
+    Confirmed(u32, Option<u32>),
+}
+
+impl ConfBucket {
+    /// Classify a confirmation count into the bucket it falls in, given the confirmation counts at
+    /// which the buckets start. Confirmed outputs below the first boundary get a bucket of their own.
+    pub fn classify(confirmations: u32, boundaries: &[u32]) -> Self {
+        if confirmations == 0 {
+            return ConfBucket::Unconfirmed;
+        }
+        let mut boundaries: Vec<u32> = boundaries.iter().copied().filter(|b| *b > 0).collect();
+        boundaries.push(1);
+        boundaries.sort_unstable();
+        boundaries.dedup();
+
+        // the last boundary that was reached, followed by the next one (if any)
+        let next_pos = boundaries.partition_point(|b| *b <= confirmations);
+        let start = boundaries[next_pos - 1];
+        let end = boundaries.get(next_pos).map(|next| next - 1);
+        ConfBucket::Confirmed(start, end)
+    }
+}
+
+impl std::fmt::Display for ConfBucket {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfBucket::Unconfirmed => write!(f, "unconfirmed"),
+            ConfBucket::Confirmed(start, Some(end)) if start == end => write!(f, "{}", start),
+            ConfBucket::Confirmed(start, Some(end)) => write!(f, "{}-{}", start, end),
+            ConfBucket::Confirmed(start, None) => write!(f, "{}+", start),
+        }
+    }
+}
+
+// Serialize as a human readable string, e.g. "unconfirmed", "1-5" or "6+", usable as a map key
+impl serde::Serialize for ConfBucket {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: ::serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+/// The order in which history entries are returned.
+///
+/// History is ordered by the confirming block height, with unconfirmed transactions considered
+/// newer than any confirmed one. Transactions with the same status are ordered by their txid.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub enum SortOrder {
+    /// Confirmed transactions by ascending block height, followed by unconfirmed ones
+    #[default]
+    #[serde(rename = "oldest")]
+    OldestFirst,
+    /// Unconfirmed transactions, followed by confirmed ones by descending block height
+    #[serde(rename = "newest")]
+    NewestFirst,
+}
+
+/// The direction of funds moved by a wallet transaction
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TxDirection {
+    /// The transaction only funds wallet outputs
+    Incoming,
+    /// The transaction spends wallet outputs, possibly with some change back to the wallet
+    Outgoing,
+    /// The transaction spends wallet outputs back to the wallet itself, with only the fee
+    /// leaving the wallet (for example, consolidations)
+    #[serde(rename = "self")]
+    SelfTransfer,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputSpendStatus {
+    Unspent,
+    SpentConfirmed(Txid, u32), // (spending txid, height)
+    SpentUnconfirmed(Txid),
+}
+
+// Serialize unspent outputs as null, and spent ones as the spending txid and its block height (null for unconfirmed)
+impl serde::Serialize for OutputSpendStatus {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: ::serde::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+        let (txid, height) = match self {
+            OutputSpendStatus::Unspent => return serializer.serialize_none(),
+            OutputSpendStatus::SpentConfirmed(txid, height) => (txid, Some(height)),
+            OutputSpendStatus::SpentUnconfirmed(txid) => (txid, None),
+        };
+        let mut s = serializer.serialize_struct("OutputSpendStatus", 2)?;
+        s.serialize_field("txid", txid)?;
+        s.serialize_field("block_height", &height)?;
+        s.end()
+    }
+}
+
 #[derive(Serialize, Clone, Debug)]
 pub struct MempoolEntry {
     /// The vsize of this transaction
     pub vsize: u64,
     /// The direct fee paid by this transaction
+    #[serde(serialize_with = "amount::serialize")]
     pub fee: u64,
     /// The total vsize of in-mempool ancestors (including this tx)
     pub ancestor_vsize: u64,
     /// The total fee paid by in-mempool ancestors (including this tx)
+    #[serde(serialize_with = "amount::serialize")]
     pub ancestor_fee: u64,
     /// Whether this transaction could be replaced due to BIP125 (replace-by-fee)
     pub bip125_replaceable: bool,
+    /// The time this transaction entered the mempool, as a unix timestamp
+    pub first_seen: u64,
+    /// The in-mempool descendants of this transaction (excluding itself), refreshed on every sync
+    pub descendants: Vec<Txid>,
 }
 
 impl MempoolEntry {
@@ -200,6 +374,59 @@ impl From<GetMempoolEntryResult> for MempoolEntry {
             ancestor_vsize: entry.ancestor_size,
             ancestor_fee: entry.fees.ancestor.as_sat(),
             bip125_replaceable: entry.bip125_replaceable,
+            first_seen: entry.time,
+            // populated separately using getmempooldescendants
+            descendants: vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tx_status_json() {
+        for (status, json) in &[
+            (TxStatus::Confirmed(800000), "800000"),
+            (TxStatus::Confirmed(0), "0"),
+            (TxStatus::Unconfirmed, "null"),
+            (TxStatus::Conflicted, "-1"),
+        ] {
+            assert_eq!(serde_json::to_string(status).unwrap(), *json);
+            assert_eq!(serde_json::from_str::<TxStatus>(json).unwrap(), *status);
         }
+        assert!(serde_json::from_str::<TxStatus>("-2").is_err());
+        assert!(serde_json::from_str::<TxStatus>("true").is_err());
+    }
+
+    #[test]
+    fn test_tx_status_is_final() {
+        let status = TxStatus::Confirmed(100);
+        assert!(!status.is_final(104, 6));
+        assert!(status.is_final(105, 6));
+        assert!(status.is_final(100, 1));
+        assert!(!TxStatus::Unconfirmed.is_final(105, 0));
+        assert!(!TxStatus::Conflicted.is_final(105, 0));
+    }
+
+    #[test]
+    fn test_conf_bucket() {
+        let buckets = [1, 6];
+        assert_eq!(ConfBucket::classify(0, &buckets), ConfBucket::Unconfirmed);
+        assert_eq!(ConfBucket::classify(1, &buckets).to_string(), "1-5");
+        assert_eq!(ConfBucket::classify(5, &buckets).to_string(), "1-5");
+        assert_eq!(ConfBucket::classify(6, &buckets).to_string(), "6+");
+        assert_eq!(ConfBucket::classify(1000, &buckets).to_string(), "6+");
+
+        // confirmations below the first boundary get a bucket of their own
+        let buckets = [3, 4, 100];
+        assert_eq!(ConfBucket::classify(2, &buckets).to_string(), "1-2");
+        assert_eq!(ConfBucket::classify(3, &buckets).to_string(), "3");
+        assert_eq!(ConfBucket::classify(99, &buckets).to_string(), "4-99");
+        assert_eq!(ConfBucket::classify(1, &[]).to_string(), "1+");
+
+        assert!(ConfBucket::Unconfirmed < ConfBucket::classify(1, &buckets));
+        assert!(ConfBucket::classify(2, &buckets) < ConfBucket::classify(3, &buckets));
     }
 }
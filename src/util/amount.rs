@@ -0,0 +1,182 @@
+use std::cell::Cell;
+use std::str::FromStr;
+
+use serde::{de, Serialize, Serializer};
+
+use crate::error::{Error, Result};
+
+thread_local! {
+    // The format used by the amount serializers below. Only changed for the duration of a
+    // `WithAmountFormat` serialization, amounts are in satoshis otherwise.
+    static AMOUNT_FORMAT: Cell<AmountFormat> = const { Cell::new(AmountFormat::Sat) };
+}
+
+/// The format used for serializing amounts in API responses and notifications
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum AmountFormat {
+    /// Integer satoshis (the default)
+    #[default]
+    Sat,
+    /// BTC as a decimal string with 8 digits of precision (e.g. `"0.00123456"`)
+    Btc,
+}
+
+impl AmountFormat {
+    /// Wrap `value` so that the amounts within it get serialized using this format
+    pub fn wrap<T: Serialize + ?Sized>(self, value: &T) -> WithAmountFormat<'_, T> {
+        WithAmountFormat {
+            format: self,
+            value,
+        }
+    }
+
+    fn current() -> Self {
+        AMOUNT_FORMAT.with(Cell::get)
+    }
+}
+
+/// A serializable value whose amounts are serialized using the given `AmountFormat`
+pub struct WithAmountFormat<'a, T: ?Sized> {
+    format: AmountFormat,
+    value: &'a T,
+}
+
+impl<T: Serialize + ?Sized> Serialize for WithAmountFormat<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let _guard = FormatGuard(AMOUNT_FORMAT.with(|format| format.replace(self.format)));
+        self.value.serialize(serializer)
+    }
+}
+
+// Restores the previous format once the wrapped value is serialized (or its serialization panics)
+struct FormatGuard(AmountFormat);
+
+impl Drop for FormatGuard {
+    fn drop(&mut self) {
+        AMOUNT_FORMAT.with(|format| format.set(self.0));
+    }
+}
+
+impl FromStr for AmountFormat {
+    type Err = Error;
+
+    fn from_str(inp: &str) -> Result<Self> {
+        Ok(match inp {
+            "sat" | "sats" => AmountFormat::Sat,
+            "btc" => AmountFormat::Btc,
+            _ => bail!("Invalid amount format {}, expected 'sat' or 'btc'", inp),
+        })
+    }
+}
+
+impl<'de> de::Deserialize<'de> for AmountFormat {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        FromStr::from_str(&s).map_err(de::Error::custom)
+    }
+}
+
+/// Format a satoshi amount as a BTC decimal string with 8 digits of precision, without going
+/// through floats
+pub fn format_btc(amount: u64) -> String {
+    format!("{}.{:08}", amount / 100_000_000, amount % 100_000_000)
+}
+
+/// Format a satoshi amount as a BTC decimal string with the trailing zeros dropped, as BIP 21
+/// URIs typically do
+pub fn format_btc_trimmed(amount: u64) -> String {
+    let btc = format_btc(amount);
+    btc.trim_end_matches('0').trim_end_matches('.').into()
+}
+
+/// Format a signed satoshi amount as a BTC decimal string
+pub fn format_btc_signed(amount: i64) -> String {
+    let sign = iif!(amount < 0, "-", "");
+    format!("{}{}", sign, format_btc(amount.unsigned_abs()))
+}
+
+/// Serialize an amount (in satoshis) according to the current `AmountFormat`
+pub fn serialize<S: Serializer>(
+    amount: &u64,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    match AmountFormat::current() {
+        AmountFormat::Sat => serializer.serialize_u64(*amount),
+        AmountFormat::Btc => serializer.serialize_str(&format_btc(*amount)),
+    }
+}
+
+/// Serialize a signed amount (in satoshis) according to the current `AmountFormat`
+pub fn serialize_signed<S: Serializer>(
+    amount: &i64,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    match AmountFormat::current() {
+        AmountFormat::Sat => serializer.serialize_i64(*amount),
+        AmountFormat::Btc => serializer.serialize_str(&format_btc_signed(*amount)),
+    }
+}
+
+/// Serialize an optional amount (in satoshis) according to the current `AmountFormat`
+pub fn serialize_opt<S: Serializer>(
+    amount: &Option<u64>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    match amount {
+        Some(amount) => serialize(amount, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_btc() {
+        assert_eq!(format_btc(0), "0.00000000");
+        assert_eq!(format_btc(123456), "0.00123456");
+        assert_eq!(format_btc(100_000_000), "1.00000000");
+        assert_eq!(format_btc(2_100_000_000_000_000), "21000000.00000000");
+        assert_eq!(format_btc(u64::MAX), "184467440737.09551615");
+        assert_eq!(format_btc_trimmed(0), "0");
+        assert_eq!(format_btc_trimmed(150_000_000), "1.5");
+        assert_eq!(format_btc_trimmed(100_000_000), "1");
+        assert_eq!(format_btc_trimmed(123456), "0.00123456");
+        assert_eq!(format_btc_signed(-123456), "-0.00123456");
+        assert_eq!(format_btc_signed(100_000_000), "1.00000000");
+    }
+
+    #[test]
+    fn test_amount_format_wrap() {
+        #[derive(Serialize)]
+        struct Payment {
+            #[serde(serialize_with = "serialize")]
+            amount: u64,
+            #[serde(serialize_with = "serialize_signed")]
+            change: i64,
+        }
+        let payment = Payment {
+            amount: 150_000_000,
+            change: -123456,
+        };
+        let to_json = |value| serde_json::to_value(value).unwrap();
+
+        assert_eq!(
+            to_json(AmountFormat::Btc.wrap(&payment)),
+            serde_json::json!({ "amount": "1.50000000", "change": "-0.00123456" })
+        );
+        assert_eq!(
+            to_json(AmountFormat::Sat.wrap(&payment)),
+            serde_json::json!({ "amount": 150_000_000, "change": -123456 })
+        );
+        // the format only applies within the wrapped serialization
+        assert_eq!(
+            serde_json::to_value(&payment).unwrap(),
+            serde_json::json!({ "amount": 150_000_000, "change": -123456 })
+        );
+    }
+}
@@ -1,5 +1,5 @@
 use rand::Rng;
-use std::{fs, io, net, path};
+use std::{fs, io, path};
 
 use crate::error::Error;
 
@@ -65,11 +65,11 @@ fn read_write_cookie(file: &path::Path) -> Result<String, io::Error> {
 /// For clients that support setting a SOCKS5 proxy but not a password for it, the token can also
 /// be provided as the destination hostname.
 #[cfg(feature = "electrum")]
-pub fn electrum_socks5_auth(
-    mut stream: net::TcpStream,
+pub fn electrum_socks5_auth<S: io::Read + io::Write>(
+    stream: S,
     access_token: &str,
-) -> Result<net::TcpStream, Error> {
-    use std::io::{BufReader, Read, Write};
+) -> Result<S, Error> {
+    use std::io::{BufReader, Read};
 
     const SOCKS5: u8 = 0x05;
     const AUTH_VER: u8 = 0x01;
@@ -96,7 +96,7 @@ pub fn electrum_socks5_auth(
         Ok(buf)
     };
 
-    let mut reader = BufReader::new(stream.try_clone().expect("failed to clone TcpStream"));
+    let mut reader = BufReader::new(stream);
 
     // Client greeting: VER=0x05, <AUTH_LEN><AUTH_METHODS>
     ensure!(read_byte(&mut reader)? == SOCKS5, "invalid version");
@@ -104,7 +104,7 @@ pub fn electrum_socks5_auth(
     let mut authenticated = false;
 
     if auth_methods.contains(&AUTH_USERPWD) {
-        stream.write_all(&[SOCKS5, AUTH_USERPWD])?;
+        reader.get_mut().write_all(&[SOCKS5, AUTH_USERPWD])?;
 
         // Client authentication: VER=0x01, <USERLEN><USER>, <PWDLEN><PWD>
         ensure!(read_byte(&mut reader)? == AUTH_VER, "invalid auth version");
@@ -112,10 +112,10 @@ pub fn electrum_socks5_auth(
         let password = String::from_utf8(read_var(&mut reader)?)?;
         ensure!(password == access_token, "invalid token (userpwd)");
         authenticated = true;
-        stream.write_all(&[AUTH_VER, SUCCESS])?;
+        reader.get_mut().write_all(&[AUTH_VER, SUCCESS])?;
     } else if auth_methods.contains(&AUTH_NONE) {
         // Allow no authentication for now, require hostname-based authentication instead (below)
-        stream.write_all(&[SOCKS5, AUTH_NONE])?;
+        reader.get_mut().write_all(&[SOCKS5, AUTH_NONE])?;
     } else {
         bail!("incompatible auth methods");
     }
@@ -142,12 +142,12 @@ pub fn electrum_socks5_auth(
     reader.read_exact(&mut [0; 2])?;
 
     // Server response: VER, STATUS, RSV, BNDADDR={IPv4(0x01), =0.0.0.0}, BNDPORT=0x0000
-    stream.write_all(&[
+    reader.get_mut().write_all(&[
         SOCKS5, SUCCESS, RSV, ADDR_IPV4, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
     ])?;
 
-    // Hand the TCP socket back to the Electrum server
-    Ok(stream)
+    // Hand the socket back to the Electrum server
+    Ok(reader.into_inner())
 }
 
 /// Wrap filter for HTTP basic authentication
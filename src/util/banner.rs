@@ -1,8 +1,9 @@
 use std::time::{Duration, UNIX_EPOCH};
 
 use bitcoin::{blockdata::constants, Amount};
-use bitcoincore_rpc::{self as rpc, RpcApi};
+use bitcoincore_rpc::{self as rpc, json::EstimateMode, RpcApi};
 
+use crate::store::Store;
 use crate::util::{fmt_duration, RpcApiExt};
 use crate::{Query, Result};
 
@@ -11,7 +12,7 @@ const TARGET_BLOCK_SPACING: u64 = constants::TARGET_BLOCK_SPACING as u64;
 const INITIAL_REWARD: u64 = 50 * constants::COIN_VALUE;
 const HALVING_INTERVAL: u64 = 210_000;
 
-pub fn get_welcome_banner(query: &Query, omit_donation: bool) -> Result<String> {
+pub fn get_welcome_banner<S: Store>(query: &Query<S>, omit_donation: bool) -> Result<String> {
     let rpc = query.rpc();
 
     let net_info = rpc.get_network_info()?;
@@ -33,7 +34,10 @@ pub fn get_welcome_banner(query: &Query, omit_donation: bool) -> Result<String>
     )?;
 
     let est_fee = |target| {
-        let rate = query.estimate_fee(target).ok().flatten();
+        let rate = query
+            .estimate_fee(target, EstimateMode::Unset)
+            .ok()
+            .flatten();
         rate.map_or("ɴ/ᴀ".into(), |rate| format!("{:.0}", rate))
     };
     let est_20m = est_fee(2u16);
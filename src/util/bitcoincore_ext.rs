@@ -1,15 +1,23 @@
 use serde::{de, Serialize};
 use std::collections::HashMap;
 use std::fmt::{self, Formatter};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::{cmp, thread, time};
 
 use bitcoin::Address;
 use bitcoincore_rpc::json::{self, ImportMultiRescanSince};
 use bitcoincore_rpc::{self as rpc, jsonrpc, Client, Result as RpcResult, RpcApi};
 
 #[cfg(feature = "proxy")]
-use super::jsonrpc_proxy::SimpleHttpTransport;
+use super::jsonrpc_proxy::{Error as HttpError, SimpleHttpTransport};
 #[cfg(not(feature = "proxy"))]
-use jsonrpc::simple_http::SimpleHttpTransport;
+use jsonrpc::simple_http::{Error as HttpError, SimpleHttpTransport};
+
+const RETRY_INITIAL_DELAY: time::Duration = time::Duration::from_millis(500);
+const RETRY_MAX_DELAY: time::Duration = time::Duration::from_secs(30);
+const RETRY_MAX_ATTEMPTS: u32 = 10;
 
 // Extensions for rust-bitcoincore-rpc
 
@@ -55,6 +63,23 @@ pub trait RpcApiExt: RpcApi {
         self.call("getblockstats", &[json!(blockhash), json!(fields)])
     }
 
+    // Retrieve a wallet transaction, returning an Ok(None) if the wallet doesn't know it
+    fn get_transaction_opt(
+        &self,
+        txid: &bitcoin::Txid,
+    ) -> RpcResult<Option<json::GetTransactionResult>> {
+        match self.get_transaction(txid, Some(true)) {
+            Ok(gtx) => Ok(Some(gtx)),
+            // "Invalid or non-wallet transaction id"
+            Err(rpc::Error::JsonRpc(rpc::jsonrpc::Error::Rpc(e)))
+                if e.code == RPC_INVALID_ADDRESS_OR_KEY =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
     // Retrieve a mempool entry, returning an Ok(None) if it doesn't exists
     fn get_mempool_entry_opt(
         &self,
@@ -112,6 +137,70 @@ pub trait RpcApiExt: RpcApi {
 
 impl RpcApiExt for Client {}
 
+/// The bitcoind RPC interface used by the `Indexer`. Typed calls are provided by `RpcApi` (and
+/// `RpcApiExt`) on top of its `call()` method, leaving only batching to be implemented.
+///
+/// Implemented by the rpc `Client`, and by `MockNode` for testing without a bitcoind node.
+pub trait NodeRpc: RpcApiExt + Send + Sync + 'static {
+    /// Call the same method with each of the given params as a single batch, with a separate
+    /// result for each call in the same order as the params
+    fn batch_call_each<T: de::DeserializeOwned>(
+        &self,
+        method: &str,
+        params: &[Vec<serde_json::Value>],
+    ) -> RpcResult<Vec<RpcResult<T>>>;
+}
+
+impl NodeRpc for Client {
+    fn batch_call_each<T: de::DeserializeOwned>(
+        &self,
+        method: &str,
+        params: &[Vec<serde_json::Value>],
+    ) -> RpcResult<Vec<RpcResult<T>>> {
+        batch_call_each(self, method, params)
+    }
+}
+
+/// Call the same method with each of the given params using a single batch request. The results
+/// are returned in the same order as the params.
+pub fn batch_call<T: de::DeserializeOwned>(
+    rpc: &Client,
+    method: &str,
+    params: &[Vec<serde_json::Value>],
+) -> RpcResult<Vec<T>> {
+    batch_call_each(rpc, method, params)?.into_iter().collect()
+}
+
+/// Like `batch_call()`, but with a separate result for each call, so that individual failures
+/// don't fail the whole batch
+pub fn batch_call_each<T: de::DeserializeOwned>(
+    rpc: &Client,
+    method: &str,
+    params: &[Vec<serde_json::Value>],
+) -> RpcResult<Vec<RpcResult<T>>> {
+    if params.is_empty() {
+        return Ok(vec![]);
+    }
+    let client = rpc.get_jsonrpc_client();
+    let raw_params = params
+        .iter()
+        .map(|args| args.iter().map(serde_json::value::to_raw_value).collect())
+        .collect::<Result<Vec<Vec<_>>, _>>()?;
+    let requests: Vec<_> = raw_params
+        .iter()
+        .map(|args| client.build_request(method, args))
+        .collect();
+    Ok(client
+        .send_batch(&requests)?
+        .into_iter()
+        .map(|res| {
+            Ok(res
+                .ok_or(jsonrpc::Error::WrongBatchResponseSize)?
+                .result()?)
+        })
+        .collect())
+}
+
 pub fn create_rpc_client(config: &crate::Config) -> Result<Client, crate::Error> {
     let mut builder = SimpleHttpTransport::builder().url(&config.bitcoind_url())?;
 
@@ -128,11 +217,184 @@ pub fn create_rpc_client(config: &crate::Config) -> Result<Client, crate::Error>
         builder = builder.proxy(proxy_addr)?;
     }
 
+    let inner = LimitedTransport {
+        inner: builder.build(),
+        permits: config.bitcoind_max_concurrent.map(Semaphore::new),
+    };
     Ok(Client::from_jsonrpc(jsonrpc::Client::with_transport(
-        builder.build(),
+        RetryTransport {
+            inner,
+            connected: AtomicBool::new(false),
+        },
     )))
 }
 
+/// A JSON-RPC transport that limits the number of requests in flight, to avoid overwhelming
+/// bitcoind's RPC work queue. Requests over the limit wait for a permit rather than failing.
+struct LimitedTransport<T: jsonrpc::Transport> {
+    inner: T,
+    permits: Option<Semaphore>,
+}
+
+impl<T: jsonrpc::Transport> jsonrpc::Transport for LimitedTransport<T> {
+    fn send_request(&self, req: jsonrpc::Request) -> Result<jsonrpc::Response, jsonrpc::Error> {
+        let _permit = self.permits.as_ref().map(Semaphore::acquire);
+        self.inner.send_request(req)
+    }
+    fn send_batch(
+        &self,
+        reqs: &[jsonrpc::Request],
+    ) -> Result<Vec<jsonrpc::Response>, jsonrpc::Error> {
+        let _permit = self.permits.as_ref().map(Semaphore::acquire);
+        self.inner.send_batch(reqs)
+    }
+    fn fmt_target(&self, f: &mut Formatter) -> fmt::Result {
+        self.inner.fmt_target(f)
+    }
+}
+
+// A counting semaphore, with permits released when their guard is dropped
+struct Semaphore {
+    available: Mutex<usize>,
+    cvar: Condvar,
+}
+
+struct SemaphoreGuard<'a>(&'a Semaphore);
+
+impl Semaphore {
+    fn new(permits: usize) -> Self {
+        Semaphore {
+            // a limit of 0 would block forever
+            available: Mutex::new(permits.max(1)),
+            cvar: Condvar::new(),
+        }
+    }
+
+    fn acquire(&self) -> SemaphoreGuard<'_> {
+        let mut available = self.available.lock().unwrap();
+        while *available == 0 {
+            available = self.cvar.wait(available).unwrap();
+        }
+        *available -= 1;
+        SemaphoreGuard(self)
+    }
+}
+
+impl Drop for SemaphoreGuard<'_> {
+    fn drop(&mut self) {
+        *self.0.available.lock().unwrap() += 1;
+        self.0.cvar.notify_one();
+    }
+}
+
+/// A JSON-RPC transport that retries requests failing due to connection errors (for example while
+/// bitcoind is restarting) with an exponential backoff. RPC errors returned by bitcoind are not
+/// retried and get propagated immediately.
+///
+/// Failures that may have happened after the request reached bitcoind (like read timeouts or
+/// truncated responses) are only retried for read-only methods, so that calls with side effects
+/// (`importmulti`, `sendrawtransaction`, etc) never get executed twice.
+///
+/// Retrying only kicks in after the first successful request, so that misconfigurations are
+/// reported right away on startup.
+struct RetryTransport<T: jsonrpc::Transport> {
+    inner: T,
+    connected: AtomicBool,
+}
+
+impl<T: jsonrpc::Transport> RetryTransport<T> {
+    fn with_retry<R>(
+        &self,
+        read_only: bool,
+        f: impl Fn() -> Result<R, jsonrpc::Error>,
+    ) -> Result<R, jsonrpc::Error> {
+        let mut delay = RETRY_INITIAL_DELAY;
+        let mut attempt = 1;
+        loop {
+            match f() {
+                Err(e)
+                    if attempt < RETRY_MAX_ATTEMPTS
+                        && (is_unsent_error(&e) || (read_only && is_connection_error(&e)))
+                        && self.connected.load(Ordering::Relaxed) =>
+                {
+                    warn!(
+                        "bitcoind rpc connection failed ({}), reconnecting in {:?} (attempt {}/{})",
+                        e, delay, attempt, RETRY_MAX_ATTEMPTS
+                    );
+                    thread::sleep(delay);
+                    delay = cmp::min(delay * 2, RETRY_MAX_DELAY);
+                    attempt += 1;
+                }
+                res => {
+                    if res.is_ok() {
+                        self.connected.store(true, Ordering::Relaxed);
+                        if attempt > 1 {
+                            info!("reconnected to bitcoind rpc");
+                        }
+                    }
+                    return res;
+                }
+            }
+        }
+    }
+}
+
+impl<T: jsonrpc::Transport> jsonrpc::Transport for RetryTransport<T> {
+    fn send_request(&self, req: jsonrpc::Request) -> Result<jsonrpc::Response, jsonrpc::Error> {
+        self.with_retry(is_read_only(req.method), || {
+            self.inner.send_request(req.clone())
+        })
+    }
+    fn send_batch(
+        &self,
+        reqs: &[jsonrpc::Request],
+    ) -> Result<Vec<jsonrpc::Response>, jsonrpc::Error> {
+        let read_only = reqs.iter().all(|req| is_read_only(req.method));
+        self.with_retry(read_only, || self.inner.send_batch(reqs))
+    }
+    fn fmt_target(&self, f: &mut Formatter) -> fmt::Result {
+        self.inner.fmt_target(f)
+    }
+}
+
+// Check whether the error is a transient transport failure, as opposed to an error returned by
+// bitcoind or a failure that is unlikely to be resolved by retrying. The request may or may not
+// have been processed by bitcoind.
+fn is_connection_error(error: &jsonrpc::Error) -> bool {
+    is_unsent_error(error)
+        || match error {
+            jsonrpc::Error::Transport(e) => matches!(
+                e.downcast_ref::<HttpError>(),
+                Some(HttpError::SocketError(_)) | Some(HttpError::HttpParseError)
+            ),
+            _ => false,
+        }
+}
+
+// Check whether the error is a transient transport failure that happened before the request got
+// processed by bitcoind, making it safe to retry any method
+fn is_unsent_error(error: &jsonrpc::Error) -> bool {
+    match error {
+        jsonrpc::Error::Transport(e) => match e.downcast_ref::<HttpError>() {
+            // a new connection is opened for every request, a refused one means nothing was sent
+            Some(HttpError::SocketError(e)) => e.kind() == io::ErrorKind::ConnectionRefused,
+            // returned when bitcoind's rpc work queue is full, before the request is executed
+            Some(HttpError::HttpErrorCode(503)) => true,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+// Check whether the RPC method has no side effects and can be safely executed more than once
+fn is_read_only(method: &str) -> bool {
+    match method {
+        "getnewaddress" | "getrawchangeaddress" => false,
+        "estimatesmartfee" | "uptime" => true,
+        _ => method.starts_with("get") || method.starts_with("list"),
+    }
+}
+
 // Copied from rust-bitcoincore-rpc where it is private, pending
 // https://github.com/rust-bitcoin/rust-bitcoincore-rpc/pull/205
 fn get_user_pass(auth: rpc::Auth) -> rpc::Result<(Option<String>, Option<String>)> {
@@ -294,3 +556,132 @@ pub struct WalletTxInfo {
     #[serde(rename = "walletconflicts")]
     pub wallet_conflicts: Vec<bitcoin::Txid>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_semaphore() {
+        let semaphore = Arc::new(Semaphore::new(2));
+        let (in_flight, max_in_flight) =
+            (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let (semaphore, in_flight, max_in_flight) =
+                    (semaphore.clone(), in_flight.clone(), max_in_flight.clone());
+                thread::spawn(move || {
+                    let _permit = semaphore.acquire();
+                    let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_in_flight.fetch_max(current, Ordering::SeqCst);
+                    thread::sleep(time::Duration::from_millis(20));
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 2);
+        assert_eq!(*semaphore.available.lock().unwrap(), 2);
+    }
+
+    // A transport that fails with the queued errors, then succeeds
+    struct StubTransport {
+        failures: Mutex<Vec<jsonrpc::Error>>,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl jsonrpc::Transport for StubTransport {
+        fn send_request(&self, req: jsonrpc::Request) -> Result<jsonrpc::Response, jsonrpc::Error> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            match self.failures.lock().unwrap().pop() {
+                Some(error) => Err(error),
+                None => Ok(jsonrpc::Response {
+                    result: None,
+                    error: Some(jsonrpc::error::RpcError {
+                        code: RPC_MISC_ERROR,
+                        message: "stub".into(),
+                        data: None,
+                    }),
+                    id: req.id,
+                    jsonrpc: Some("2.0".into()),
+                }),
+            }
+        }
+        fn send_batch(
+            &self,
+            _reqs: &[jsonrpc::Request],
+        ) -> Result<Vec<jsonrpc::Response>, jsonrpc::Error> {
+            unimplemented!()
+        }
+        fn fmt_target(&self, f: &mut Formatter) -> fmt::Result {
+            f.write_str("stub")
+        }
+    }
+
+    fn transport_error(error: HttpError) -> jsonrpc::Error {
+        jsonrpc::Error::Transport(Box::new(error))
+    }
+
+    fn socket_error(kind: io::ErrorKind) -> jsonrpc::Error {
+        transport_error(HttpError::SocketError(kind.into()))
+    }
+
+    // Send a request through a connected RetryTransport, returning the number of attempts made
+    // and whether the request eventually got through to the (stub) rpc server
+    fn send_with_failures(method: &str, failures: Vec<jsonrpc::Error>) -> (usize, bool) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let transport = RetryTransport {
+            inner: StubTransport {
+                failures: Mutex::new(failures),
+                calls: calls.clone(),
+            },
+            connected: AtomicBool::new(true),
+        };
+        let client = jsonrpc::Client::with_transport(transport);
+        let result = client.send_request(client.build_request(method, &[]));
+        (calls.load(Ordering::SeqCst), result.is_ok())
+    }
+
+    #[test]
+    fn test_retry_errors() {
+        use io::ErrorKind::{ConnectionRefused, ConnectionReset, TimedOut};
+
+        // rpc errors are never retried
+        assert_eq!(send_with_failures("getblockcount", vec![]), (1, true));
+
+        // failures before the request was sent are retried for all methods
+        let refused = || vec![socket_error(ConnectionRefused)];
+        assert_eq!(send_with_failures("getblockcount", refused()), (2, true));
+        assert_eq!(send_with_failures("importmulti", refused()), (2, true));
+        let busy = || vec![transport_error(HttpError::HttpErrorCode(503))];
+        assert_eq!(send_with_failures("sendrawtransaction", busy()), (2, true));
+
+        // failures that may have happened after bitcoind received the request are only retried
+        // for read-only methods
+        for &kind in &[TimedOut, ConnectionReset] {
+            let failure = || vec![socket_error(kind)];
+            assert_eq!(send_with_failures("listsinceblock", failure()), (2, true));
+            assert_eq!(send_with_failures("importmulti", failure()), (1, false));
+            assert_eq!(send_with_failures("getnewaddress", failure()), (1, false));
+        }
+        let truncated = || vec![transport_error(HttpError::HttpParseError)];
+        assert_eq!(send_with_failures("gettransaction", truncated()), (2, true));
+        assert_eq!(
+            send_with_failures("sendrawtransaction", truncated()),
+            (1, false)
+        );
+
+        // other http errors are not retried
+        let unauthorized = || vec![transport_error(HttpError::HttpErrorCode(401))];
+        assert_eq!(
+            send_with_failures("getblockcount", unauthorized()),
+            (1, false)
+        );
+    }
+}
@@ -1,16 +1,17 @@
 use std::str::FromStr;
 
 use bitcoin::secp256k1::{self, Secp256k1};
-use bitcoin::{Address, Network};
+use bitcoin::util::bip32::ChildNumber;
+use bitcoin::{Address, Network, Script};
 use miniscript::descriptor::{Descriptor, DescriptorPublicKey, DescriptorTrait, Wildcard};
 use miniscript::{ForEachKey, TranslatePk2};
 
-use crate::error::{Error, OptionExt, Result};
+use crate::error::{BwtError, Error, OptionExt, Result};
 use crate::util::xpub::{xpub_matches_network, Bip32Origin};
 
 pub type ExtendedDescriptor = Descriptor<DescriptorPublicKey>;
 
-#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+#[derive(Debug, Clone, Hash, Eq, PartialEq, PartialOrd, Ord)]
 pub struct Checksum(String);
 
 impl_string_serializer!(Checksum, c, c.0);
@@ -27,6 +28,20 @@ pub fn derive_address(desc: &ExtendedDescriptor, index: u32, network: Network) -
         .ok()
 }
 
+/// Make an `addr()` descriptor for the address, including the checksum
+pub fn addr_descriptor(address: &Address) -> String {
+    let desc = format!("addr({})", address);
+    let checksum = desc_checksum(&desc);
+    format!("{}#{}", desc, checksum)
+}
+
+/// Make a `raw()` descriptor for the scriptPubKey, including the checksum
+pub fn raw_descriptor(script: &Script) -> String {
+    let desc = format!("raw({:x})", script);
+    let checksum = desc_checksum(&desc);
+    format!("{}#{}", desc, checksum)
+}
+
 #[derive(Debug, Clone)]
 pub struct DescKeyInfo {
     pub bip32_origin: Bip32Origin,
@@ -34,6 +49,61 @@ pub struct DescKeyInfo {
 }
 
 const CHECKSUM_CHARSET: &str = "qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const INPUT_CHARSET: &str = "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+
+// Compute the descriptor checksum as defined in BIP 380. This is available in miniscript, but not
+// exposed publicly and not usable for descriptor types it does not support (like `addr()`).
+// Panics if the descriptor contains characters outside of INPUT_CHARSET.
+fn desc_checksum(desc: &str) -> Checksum {
+    fn poly_mod(mut c: u64, val: u64) -> u64 {
+        let c0 = c >> 35;
+        c = ((c & 0x7ffffffff) << 5) ^ val;
+        for (i, gen) in [
+            0xf5dee51989,
+            0xa9fdca3312,
+            0x1bab10e32d,
+            0x3706b1677a,
+            0x644d626ffd,
+        ]
+        .iter()
+        .enumerate()
+        {
+            if c0 & (1 << i) != 0 {
+                c ^= gen;
+            }
+        }
+        c
+    }
+
+    let mut c = 1;
+    let mut cls = 0;
+    let mut clscount = 0;
+    for ch in desc.chars() {
+        let pos = INPUT_CHARSET
+            .find(ch)
+            .expect("invalid descriptor character") as u64;
+        c = poly_mod(c, pos & 31);
+        cls = cls * 3 + (pos >> 5);
+        clscount += 1;
+        if clscount == 3 {
+            c = poly_mod(c, cls);
+            cls = 0;
+            clscount = 0;
+        }
+    }
+    if clscount > 0 {
+        c = poly_mod(c, cls);
+    }
+    (0..8).for_each(|_| c = poly_mod(c, 0));
+    c ^= 1;
+
+    let charset = CHECKSUM_CHARSET.as_bytes();
+    Checksum(
+        (0..8)
+            .map(|j| charset[((c >> (5 * (7 - j))) & 31) as usize] as char)
+            .collect(),
+    )
+}
 
 impl FromStr for Checksum {
     type Err = Error;
@@ -52,10 +122,24 @@ impl FromStr for Checksum {
 impl DescKeyInfo {
     pub fn extract(desc: &ExtendedDescriptor, network: Network) -> Result<Vec<DescKeyInfo>> {
         let mut keys_info = vec![];
+        let mut hardened_key = None;
+        let mut mismatched_key = None;
 
-        let is_valid = desc.for_each_key(|fe| {
+        desc.for_each_key(|fe| {
             match fe.as_key() {
                 DescriptorPublicKey::XPub(desc_xpub) => {
+                    // Hardened steps following the xpub (including hardened wildcards) cannot be derived
+                    // without the private key. Hardened steps that lead up to the xpub are fine and are
+                    // recorded as part of its origin.
+                    if desc_xpub.wildcard == Wildcard::Hardened
+                        || desc_xpub
+                            .derivation_path
+                            .into_iter()
+                            .any(ChildNumber::is_hardened)
+                    {
+                        hardened_key.get_or_insert_with(|| fe.as_key().to_string());
+                    }
+
                     // Get key origin information from the descriptor, fallback to extracting from the
                     // xpub itself.
                     let bip32_origin = desc_xpub
@@ -69,7 +153,11 @@ impl DescKeyInfo {
                         is_wildcard: desc_xpub.wildcard != Wildcard::None,
                     });
 
-                    xpub_matches_network(&desc_xpub.xkey, network)
+                    if !xpub_matches_network(&desc_xpub.xkey, network) {
+                        mismatched_key.get_or_insert_with(|| {
+                            (desc_xpub.xkey.to_string(), desc_xpub.xkey.network)
+                        });
+                    }
                 }
                 DescriptorPublicKey::SinglePub(desc_single) => {
                     if let Some(bip32_origin) = &desc_single.origin {
@@ -78,16 +166,21 @@ impl DescKeyInfo {
                             is_wildcard: false,
                         });
                     }
-                    true
                 }
             }
+            true
         });
 
-        ensure!(
-            is_valid,
-            "xpubs do not match the configured network {}",
-            network
-        );
+        if let Some(hardened_key) = hardened_key {
+            bail!(BwtError::HardenedDerivation(hardened_key));
+        }
+        if let Some((mismatched_key, key_network)) = mismatched_key {
+            bail!(BwtError::NetworkMismatch(
+                mismatched_key,
+                key_network,
+                network
+            ));
+        }
 
         Ok(keys_info)
     }
@@ -126,3 +219,112 @@ impl DescriptorExt for ExtendedDescriptor {
         Ok(desc)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::util::bip32::ExtendedPubKey;
+
+    const XPUB: &str = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+
+    fn extract(desc_str: &str) -> Result<Vec<DescKeyInfo>> {
+        let desc = ExtendedDescriptor::from_str(desc_str).unwrap();
+        DescKeyInfo::extract(&desc, Network::Bitcoin)
+    }
+
+    #[test]
+    fn test_hardened_derivation_rejected() {
+        for desc_str in &[
+            format!("wpkh({}/0/*h)", XPUB),
+            format!("wpkh({}/0h/*)", XPUB),
+            format!("wpkh([00000000/84h/0h/0h]{}/1h/5)", XPUB),
+        ] {
+            let err = extract(desc_str).unwrap_err();
+            assert!(
+                matches!(
+                    err.downcast_ref::<BwtError>(),
+                    Some(BwtError::HardenedDerivation(_))
+                ),
+                "expected a hardened derivation error for {}, got {:?}",
+                desc_str,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_hardened_origin_with_unhardened_tail() {
+        let keys_info = extract(&format!("wpkh([d34db33f/84h/0h/0h]{}/1/*)", XPUB)).unwrap();
+        assert_eq!(keys_info.len(), 1);
+        assert!(keys_info[0].is_wildcard);
+        assert_eq!(
+            keys_info[0].bip32_origin.to_string(),
+            "d34db33f/84'/0'/0'/1"
+        );
+
+        let desc = ExtendedDescriptor::from_str(&format!("wpkh({}/1/*)", XPUB)).unwrap();
+        assert!(derive_address(&desc, 7, Network::Bitcoin).is_some());
+    }
+
+    #[test]
+    fn test_desc_checksum() {
+        // test vector from BIP 380
+        assert_eq!(desc_checksum("raw(deadbeef)").0, "89f8spxm");
+
+        let desc = ExtendedDescriptor::from_str(&format!("wpkh({}/0/*)", XPUB)).unwrap();
+        assert_eq!(
+            desc_checksum(&desc.to_string_no_checksum()),
+            desc.checksum()
+        );
+
+        let address = Address::from_str("bc1qaxlg48awxth5k72ltgrjp6qyegzdmfkfupyhhg").unwrap();
+        let addr_desc = addr_descriptor(&address);
+        assert!(addr_desc.starts_with("addr(bc1qaxlg48awxth5k72ltgrjp6qyegzdmfkfupyhhg)#"));
+        assert_eq!(addr_desc.len(), "addr()#".len() + 42 + 8);
+
+        let script = Script::from(vec![0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(raw_descriptor(&script), "raw(deadbeef)#89f8spxm");
+    }
+
+    #[test]
+    fn test_network_mismatch() {
+        let mut tpub = ExtendedPubKey::from_str(XPUB).unwrap();
+        tpub.network = Network::Testnet;
+        let tpub = tpub.to_string();
+
+        let cases = [
+            (XPUB, Network::Bitcoin, true),
+            (XPUB, Network::Testnet, false),
+            (XPUB, Network::Regtest, false),
+            (XPUB, Network::Signet, false),
+            (&tpub, Network::Bitcoin, false),
+            (&tpub, Network::Testnet, true),
+            (&tpub, Network::Regtest, true),
+            (&tpub, Network::Signet, true),
+        ];
+        for (xpub, network, is_valid) in &cases {
+            let desc = ExtendedDescriptor::from_str(&format!("wpkh({}/0/*)", xpub)).unwrap();
+            match DescKeyInfo::extract(&desc, *network) {
+                Ok(_) => assert!(is_valid, "expected {} to mismatch {}", xpub, network),
+                Err(err) => {
+                    assert!(!is_valid, "expected {} to match {}", xpub, network);
+                    assert!(
+                        matches!(
+                            err.downcast_ref::<BwtError>(),
+                            Some(BwtError::NetworkMismatch(key, _, net)) if key == *xpub && net == network
+                        ),
+                        "expected a network mismatch error, got {:?}",
+                        err
+                    );
+                }
+            }
+        }
+
+        // mixing keys from different networks is rejected too
+        let desc =
+            ExtendedDescriptor::from_str(&format!("wsh(multi(1,{}/0/*,{}/0/*))", XPUB, tpub))
+                .unwrap();
+        assert!(DescKeyInfo::extract(&desc, Network::Bitcoin).is_err());
+        assert!(DescKeyInfo::extract(&desc, Network::Testnet).is_err());
+    }
+}
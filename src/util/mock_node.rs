@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use serde::de;
+use serde_json::Value;
+
+use bitcoincore_rpc::{jsonrpc, Error as RpcError, Result as RpcResult, RpcApi};
+
+use super::bitcoincore_ext::{NodeRpc, RpcApiExt, RPC_METHOD_NOT_FOUND};
+
+type Handler = Box<dyn FnMut(&[Value]) -> RpcResult<Value> + Send>;
+
+/// A `NodeRpc` implementation serving canned responses, for testing without a bitcoind node.
+/// Calls to methods without a registered response fail with bitcoind's "Method not found" error.
+#[derive(Default)]
+pub struct MockNode {
+    handlers: Mutex<HashMap<String, Handler>>,
+    calls: Mutex<Vec<(String, Vec<Value>)>>,
+}
+
+impl MockNode {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reply to calls to `method` with a fixed result, replacing any previous response
+    pub fn respond(&self, method: &str, result: Value) {
+        self.respond_with(method, move |_| Ok(result.clone()))
+    }
+
+    /// Reply to calls to `method` using `handler`, which gets the call arguments
+    pub fn respond_with(
+        &self,
+        method: &str,
+        handler: impl FnMut(&[Value]) -> RpcResult<Value> + Send + 'static,
+    ) {
+        let mut handlers = self.handlers.lock().unwrap();
+        handlers.insert(method.into(), Box::new(handler));
+    }
+
+    /// Get the arguments of the calls made to `method` so far
+    pub fn calls(&self, method: &str) -> Vec<Vec<Value>> {
+        let calls = self.calls.lock().unwrap();
+        calls
+            .iter()
+            .filter(|(called, _)| called == method)
+            .map(|(_, args)| args.clone())
+            .collect()
+    }
+}
+
+/// Make an error like the ones returned by bitcoind
+pub fn rpc_error(code: i32, message: &str) -> RpcError {
+    RpcError::JsonRpc(jsonrpc::Error::Rpc(jsonrpc::error::RpcError {
+        code,
+        message: message.into(),
+        data: None,
+    }))
+}
+
+impl RpcApi for MockNode {
+    fn call<T: de::DeserializeOwned>(&self, cmd: &str, args: &[Value]) -> RpcResult<T> {
+        self.calls
+            .lock()
+            .unwrap()
+            .push((cmd.to_string(), args.to_vec()));
+
+        let mut handlers = self.handlers.lock().unwrap();
+        let handler = handlers
+            .get_mut(cmd)
+            .ok_or_else(|| rpc_error(RPC_METHOD_NOT_FOUND, "Method not found"))?;
+        Ok(serde_json::from_value(handler(args)?)?)
+    }
+}
+
+impl RpcApiExt for MockNode {}
+
+impl NodeRpc for MockNode {
+    fn batch_call_each<T: de::DeserializeOwned>(
+        &self,
+        method: &str,
+        params: &[Vec<Value>],
+    ) -> RpcResult<Vec<RpcResult<T>>> {
+        Ok(params.iter().map(|args| self.call(method, args)).collect())
+    }
+}
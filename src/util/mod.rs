@@ -9,6 +9,7 @@ use bitcoin::Txid;
 #[macro_use]
 mod macros;
 
+pub mod amount;
 pub mod auth;
 pub mod banner;
 pub mod bitcoincore_ext;
@@ -20,6 +21,9 @@ pub mod xpub;
 #[cfg(feature = "proxy")]
 pub mod jsonrpc_proxy;
 
+#[cfg(test)]
+pub mod mock_node;
+
 pub use bitcoincore_ext::RpcApiExt;
 
 const VSIZE_BIN_WIDTH: u32 = 50_000; // vbytes
@@ -86,6 +90,11 @@ pub fn fmt_date(unix: u64) -> String {
     dt.format("%Y-%m-%d").to_string()
 }
 
+pub fn fmt_datetime(unix: u64) -> String {
+    let dt = chrono::NaiveDateTime::from_timestamp(unix as i64, 0);
+    dt.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
 pub fn fmt_duration(dur: &Duration) -> String {
     const DAY: u64 = 86400;
     const HOUR: u64 = 3600;
@@ -1,4 +1,4 @@
-use std::{fmt::Write, sync::mpsc, thread, time};
+use std::{fmt::Write, sync::mpsc, sync::Arc, thread, time};
 
 use bitcoincore_rpc::json::{self, ScanningDetails};
 use bitcoincore_rpc::{self as rpc, Client, RpcApi};
@@ -11,8 +11,18 @@ const LT: &str = "bwt::progress";
 
 #[derive(Debug, Clone)]
 pub enum Progress {
-    Sync { progress_f: f32, tip: u64 },
-    Scan { progress_f: f32, eta: u64 },
+    Sync {
+        progress_f: f32,
+        tip: u64,
+    },
+    Scan {
+        progress_f: f32,
+        eta: u64,
+    },
+    Index {
+        processed_txs: usize,
+        estimated_total: usize,
+    },
     Done,
 }
 
@@ -110,7 +120,7 @@ pub fn is_synced(info: &json::GetBlockchainInfoResult) -> bool {
 
 /// Wait for bitcoind to finish rescanning for wallet activity.
 pub fn wait_wallet_scan(
-    rpc: &Client,
+    rpc: &impl RpcApi,
     progress_tx: Option<mpsc::Sender<Progress>>,
     shutdown_rx: Option<mpsc::Receiver<()>>,
     interval: time::Duration,
@@ -181,3 +191,98 @@ pub fn wait_wallet_scan(
 
     Ok(info)
 }
+
+/// Scan the UTXO set for the given descriptors using `scantxoutset`, logging the progress while
+/// the scan is running. The scan is aborted if it doesn't complete within `timeout`.
+pub fn scan_txout_set(
+    rpc: Arc<Client>,
+    requests: Vec<json::ScanTxOutRequest>,
+    interval: time::Duration,
+    timeout: time::Duration,
+) -> Result<json::ScanTxOutResult> {
+    let start = time::Instant::now();
+    let (result_tx, result_rx) = mpsc::sync_channel(1);
+
+    let scan_rpc = rpc.clone();
+    thread::spawn(move || {
+        result_tx
+            .send(scan_rpc.scan_tx_out_set_blocking(&requests))
+            .ok();
+    });
+
+    loop {
+        match result_rx.recv_timeout(interval) {
+            Ok(result) => break Ok(result?),
+            Err(mpsc::RecvTimeoutError::Disconnected) => bail!(BwtError::Canceled),
+            Err(mpsc::RecvTimeoutError::Timeout) => (),
+        }
+
+        if start.elapsed() > timeout {
+            rpc.call::<bool>("scantxoutset", &["abort".into()])?;
+            bail!("UTXO set scan timed out after {:?}", timeout);
+        }
+
+        // returns null if the scan finished in the meanwhile
+        let status: serde_json::Value = rpc.call("scantxoutset", &["status".into()])?;
+        if let Some(progress) = status["progress"].as_f64() {
+            info!(
+                target: LT,
+                "bitcoind scanning the UTXO set... [{:.1}% completed]", progress
+            );
+        }
+    }
+}
+
+/// Report the progress of indexing the wallet transactions during the initial sync. The total
+/// is an estimate, as outgoing transactions are only discovered while processing the listing.
+pub struct IndexProgress {
+    progress_tx: Option<mpsc::Sender<Progress>>,
+    processed_txs: usize,
+    estimated_total: usize,
+    last_report: time::Instant,
+}
+
+impl IndexProgress {
+    pub fn new(progress_tx: Option<mpsc::Sender<Progress>>) -> Self {
+        IndexProgress {
+            progress_tx,
+            processed_txs: 0,
+            estimated_total: 0,
+            last_report: time::Instant::now(),
+        }
+    }
+
+    pub fn add_to_total(&mut self, count: usize) {
+        self.estimated_total += count;
+    }
+
+    pub fn tick(&mut self) -> Result<()> {
+        const INTERVAL_SLOW: time::Duration = time::Duration::from_secs(6);
+        const INTERVAL_FAST: time::Duration = time::Duration::from_millis(1500);
+
+        self.processed_txs += 1;
+
+        // use the fast interval if we're reporting progress to a channel, or the slow one if its only for CLI
+        let interval = iif!(self.progress_tx.is_some(), INTERVAL_FAST, INTERVAL_SLOW);
+        if self.last_report.elapsed() < interval {
+            return Ok(());
+        }
+        self.last_report = time::Instant::now();
+
+        if let Some(ref progress_tx) = self.progress_tx {
+            let progress = Progress::Index {
+                processed_txs: self.processed_txs,
+                estimated_total: self.estimated_total,
+            };
+            ensure!(progress_tx.send(progress).is_ok(), BwtError::Canceled);
+        } else {
+            info!(
+                target: LT,
+                "indexing wallet transactions... [{} of ~{} processed]",
+                self.processed_txs,
+                self.estimated_total
+            );
+        }
+        Ok(())
+    }
+}
@@ -20,22 +20,34 @@ pub fn xpub_matches_network(xpub: &ExtendedPubKey, network: Network) -> bool {
             && matches!(network, Network::Regtest | Network::Signet))
 }
 
-/// An extended public key with an associated script type.
+/// An extended public key with one or more associated script types.
 /// Used to represent SLIP 32 [xyz]pubs, as well as simple p2*pkh descriptors.
 #[derive(Clone)]
 pub struct XyzPubKey {
-    script_type: ScriptType,
+    script_types: Vec<ScriptType>,
     xpub: ExtendedPubKey,
 }
 
 impl_string_serializer!(XyzPubKey, xyzpub, xyzpub.xpub.to_string());
 impl_debug_display!(XyzPubKey);
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Bip32Origin(pub Fingerprint, pub DerivationPath);
 
 impl XyzPubKey {
-    pub fn as_descriptor(&self, derivation_path: DerivationPath) -> ExtendedDescriptor {
+    /// Get a descriptor for each of the script types, in the order they were specified
+    pub fn as_descriptors(&self, derivation_path: DerivationPath) -> Vec<ExtendedDescriptor> {
+        self.script_types
+            .iter()
+            .map(|script_type| self.as_descriptor(*script_type, derivation_path.clone()))
+            .collect()
+    }
+
+    fn as_descriptor(
+        &self,
+        script_type: ScriptType,
+        derivation_path: DerivationPath,
+    ) -> ExtendedDescriptor {
         let bip32_origin = (self.xpub.depth > 0).do_then(|| {
             (
                 self.xpub.parent_fingerprint,
@@ -50,7 +62,7 @@ impl XyzPubKey {
             wildcard: Wildcard::Unhardened,
         });
 
-        match self.script_type {
+        match script_type {
             ScriptType::P2pkh => Descriptor::new_pkh(desc_key),
             ScriptType::P2wpkh => Descriptor::new_wpkh(desc_key).expect("no uncompressed"),
             ScriptType::P2shP2wpkh => Descriptor::new_sh_wpkh(desc_key).expect("no uncompressed"),
@@ -74,16 +86,27 @@ impl FromStr for XyzPubKey {
         // a modified key that uses the version bytes it expects.
 
         let version = &data[0..4];
-        let (network, mut script_type) = parse_xyz_version(version)?;
+        let (network, script_type) = parse_xyz_version(version)?;
         data.splice(0..4, get_xpub_p2pkh_version(network).iter().cloned());
 
         let xpub = ExtendedPubKey::decode(&data)?;
 
-        if let Some(explicit_type_str) = parts.next() {
-            script_type = explicit_type_str.parse()?;
-        }
-
-        Ok(XyzPubKey { xpub, script_type })
+        // multiple script types can be specified as a comma separated list
+        let script_types = match parts.next() {
+            Some(explicit_types_str) => {
+                let mut script_types = vec![];
+                for script_type in explicit_types_str.split(',') {
+                    let script_type = script_type.parse()?;
+                    if !script_types.contains(&script_type) {
+                        script_types.push(script_type);
+                    }
+                }
+                script_types
+            }
+            None => vec![script_type],
+        };
+
+        Ok(XyzPubKey { xpub, script_types })
     }
 }
 
@@ -185,9 +208,28 @@ mod tests {
         ];
         for (xyz_str, expected_desc) in &test_cases {
             let xyzpub = xyz_str.parse::<XyzPubKey>().unwrap();
-            let desc = xyzpub.as_descriptor([][..].into());
+            let descs = xyzpub.as_descriptors([][..].into());
 
-            assert_eq!(desc.to_string(), *expected_desc);
+            assert_eq!(descs.len(), 1);
+            assert_eq!(descs[0].to_string(), *expected_desc);
         }
     }
+
+    #[test]
+    fn test_multiple_script_types() {
+        let xpub = "xpub661MyMwAqRbcFLqTBCNzuoj4FYE1xRxmCjrSWC6LUjKHo46Du4NacKgxdrJPWhzLjkPsXqnjAUwn1raMSWfxWZKysPoBNQMZMs8b5JM8egC";
+        let xyzpub = format!("{}:shwpkh,wpkh,shwpkh", xpub)
+            .parse::<XyzPubKey>()
+            .unwrap();
+        let descs = xyzpub.as_descriptors([][..].into());
+        assert_eq!(
+            descs.iter().map(|d| d.to_string()).collect::<Vec<_>>(),
+            vec![
+                format!("sh(wpkh({}/*))#s49cq0me", xpub),
+                format!("wpkh({}/*)#pyf5ce6k", xpub),
+            ]
+        );
+
+        assert!(format!("{}:wpkh,foo", xpub).parse::<XyzPubKey>().is_err());
+    }
 }
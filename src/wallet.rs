@@ -1,23 +1,32 @@
 use serde::Serialize;
+use std::cmp::Reverse;
 use std::collections::{HashMap, HashSet};
+use std::path::Path;
 use std::result::Result as StdResult;
+use std::{fs, str::FromStr};
 
+use bitcoin::util::bip32::ChildNumber;
 use bitcoin::{Address, Network};
-use bitcoincore_rpc::json::{ImportMultiRequest, ImportMultiRequestScriptPubkey};
-use bitcoincore_rpc::{self as rpc, Client as RpcClient, RpcApi};
+use bitcoincore_rpc as rpc;
+use bitcoincore_rpc::json::{
+    ImportMultiOptions, ImportMultiRequest, ImportMultiRequestScriptPubkey,
+};
 use miniscript::DescriptorTrait;
 
 use crate::error::{Context, Result};
-use crate::store::MemoryStore;
-use crate::types::RescanSince;
+use crate::store::{ScriptInfo, Store};
+use crate::types::{RescanSince, ScriptHash};
 use crate::util::descriptor::{self, Checksum, DescKeyInfo, DescriptorExt, ExtendedDescriptor};
 use crate::util::xpub::{Bip32Origin, XyzPubKey};
-use crate::util::RpcApiExt;
+use crate::util::{BoolThen, RpcApiExt};
 use crate::Config;
 
 const LABEL_PREFIX: &str = "bwt";
 
-#[derive(Debug)]
+// The maximum number of addresses that can be derived on demand in a single call
+const MAX_DERIVE_COUNT: u32 = 1000;
+
+#[derive(Debug, Clone)]
 pub struct WalletWatcher {
     network: Network,
     /// Descriptor wallets
@@ -26,8 +35,17 @@ pub struct WalletWatcher {
     pending_standalone: Vec<AddressImport>,
     /// Force rescan on the first run
     force_rescan: bool,
+    /// Track every address known to the bitcoind wallet, without importing anything
+    watch_all: bool,
+    /// The maximum number of addresses to import in a single `importmulti` call
+    import_batch_size: usize,
+    /// The scripthashes within the look-ahead window of the descriptor wallets, mapped to their
+    /// wallet checksum and derivation index. Extended as the window moves forward.
+    derived_scripthashes: HashMap<ScriptHash, (Checksum, u32)>,
 }
 
+pub const DEFAULT_IMPORT_BATCH_SIZE: usize = 1000;
+
 type AddressImport = (Address, RescanSince);
 
 impl WalletWatcher {
@@ -55,15 +73,52 @@ impl WalletWatcher {
             );
         }
 
-        Ok(Self {
+        let mut watcher = Self {
             network,
             wallets,
             pending_standalone: addresses,
             force_rescan,
-        })
+            watch_all: false,
+            import_batch_size: DEFAULT_IMPORT_BATCH_SIZE,
+            derived_scripthashes: HashMap::new(),
+        };
+        watcher.derive_lookahead();
+        Ok(watcher)
+    }
+
+    /// Create a watcher that tracks all the addresses already known to the bitcoind wallet.
+    ///
+    /// No addresses get imported in this mode, and all wallet activity is attributed to standalone addresses.
+    pub fn watch_all(network: Network) -> Self {
+        Self {
+            network,
+            wallets: HashMap::new(),
+            pending_standalone: vec![],
+            force_rescan: false,
+            watch_all: true,
+            import_batch_size: DEFAULT_IMPORT_BATCH_SIZE,
+            derived_scripthashes: HashMap::new(),
+        }
     }
 
     pub fn from_config(config: &Config) -> Result<Self> {
+        if config.watch_all {
+            ensure!(
+                config.descriptors.is_empty()
+                    && config.xpubs.is_empty()
+                    && config.descriptors_file.is_none()
+                    && config.addresses.is_empty()
+                    && config.addresses_file.is_none(),
+                "--watch-all cannot be used together with descriptors/xpubs/addresses"
+            );
+            ensure!(
+                config.block_scan_from.is_none(),
+                "--watch-all cannot be used together with --block-scan-from"
+            );
+            info!("tracking all the addresses known to the bitcoind wallet");
+            return Ok(Self::watch_all(config.network));
+        }
+
         let mut wallets = vec![];
         for desc in &config.descriptors {
             wallets.push(
@@ -78,7 +133,7 @@ impl WalletWatcher {
             );
         }
         for xpub in &config.xpubs {
-            // each xpub results in two wallets
+            // each xpub results in two wallets (external and internal) per script type
             wallets.append(
                 &mut Wallet::from_xpub(
                     xpub.clone(),
@@ -97,7 +152,19 @@ impl WalletWatcher {
             .map(|address| (address, config.rescan_since))
             .collect::<Vec<_>>();
 
-        if wallets.is_empty() && addresses.is_empty() {
+        let mut watcher = Self::new(config.network, wallets, addresses, config.force_rescan)?;
+        watcher.import_batch_size = config.import_batch_size;
+
+        if let Some(descriptors_file) = &config.descriptors_file {
+            watcher.add_descriptors_file(
+                descriptors_file,
+                config.gap_limit,
+                config.initial_import_size,
+                config.rescan_since,
+            )?;
+        }
+
+        if watcher.wallets.is_empty() && watcher.pending_standalone.is_empty() {
             if config.require_addresses {
                 error!("Please provide at least one descriptors/xpubs/addresses to track (via --descriptor, --xpub or --address).");
                 bail!("No descriptors/xpubs/addresses provided");
@@ -106,7 +173,86 @@ impl WalletWatcher {
             }
         }
 
-        Self::new(config.network, wallets, addresses, config.force_rescan)
+        Ok(watcher)
+    }
+
+    /// Create a watcher for the descriptors/xpubs listed in a wallet backup file.
+    /// See `add_descriptors_file()` for the supported format.
+    pub fn from_descriptors_file(
+        path: &Path,
+        network: Network,
+        gap_limit: u32,
+        initial_import_size: u32,
+        rescan_since: RescanSince,
+    ) -> Result<(Self, DescriptorsFileSummary)> {
+        let mut watcher = Self::new(network, vec![], vec![], false)?;
+        let summary =
+            watcher.add_descriptors_file(path, gap_limit, initial_import_size, rescan_since)?;
+        Ok((watcher, summary))
+    }
+
+    /// Register the descriptors/xpubs listed in a wallet backup file, given as a JSON array or as
+    /// newline-delimited entries. Each entry may be followed by a custom gap limit, separated by
+    /// whitespace (e.g. `wpkh(xpub.../0/*)#checksum 50`).
+    ///
+    /// Invalid entries are skipped and reported in the returned summary rather than failing the
+    /// whole load. Entries that are already tracked are skipped too.
+    pub fn add_descriptors_file(
+        &mut self,
+        path: &Path,
+        gap_limit: u32,
+        initial_import_size: u32,
+        rescan_since: RescanSince,
+    ) -> Result<DescriptorsFileSummary> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("failed reading descriptors file {}", path.display()))?;
+        let entries = parse_descriptors_file(&contents)?;
+
+        let mut summary = DescriptorsFileSummary::default();
+        for (line, entry) in entries {
+            let wallets = parse_descriptors_entry(
+                &entry,
+                self.network,
+                gap_limit,
+                initial_import_size,
+                rescan_since,
+            );
+            match wallets {
+                Ok(wallets) => {
+                    let mut added = false;
+                    for wallet in wallets {
+                        if !self.wallets.contains_key(&wallet.checksum) {
+                            self.wallets.insert(wallet.checksum.clone(), wallet);
+                            added = true;
+                        }
+                    }
+                    iif!(added, summary.registered += 1, summary.duplicates += 1);
+                }
+                Err(err) => {
+                    warn!(
+                        "skipping invalid entry #{} in {}: {:?}",
+                        line,
+                        path.display(),
+                        err
+                    );
+                    summary.invalid.push((line, err.to_string()));
+                }
+            }
+        }
+
+        info!(
+            "loaded {} sources from {} ({} duplicates, {} invalid)",
+            summary.registered,
+            path.display(),
+            summary.duplicates,
+            summary.invalid.len()
+        );
+        self.derive_lookahead();
+        Ok(summary)
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
     }
 
     pub fn wallets(&self) -> &HashMap<Checksum, Wallet> {
@@ -117,6 +263,116 @@ impl WalletWatcher {
         self.wallets.get(checksum)
     }
 
+    /// Whether all the addresses known to the bitcoind wallet are tracked
+    pub fn watches_all(&self) -> bool {
+        self.watch_all
+    }
+
+    /// Determine the origin of an address in the bitcoind wallet, reconstructing it from the
+    /// tracked descriptors rather than relying on the wallet label alone.
+    ///
+    /// The label is used as a hint for the derivation index, which is verified against the
+    /// descriptor. If the label was tampered with, the index is searched for in the range of
+    /// imported addresses instead. Labels referring to descriptors that aren't tracked are used as-is.
+    pub fn resolve_origin(&self, address: &Address, label: Option<&str>) -> Option<KeyOrigin> {
+        let label_origin = label.and_then(KeyOrigin::from_label)?;
+        if let KeyOrigin::Descriptor(checksum, index) = &label_origin {
+            if let Some(wallet) = self.wallets.get(checksum) {
+                if !wallet.is_valid_index(*index) || wallet.derive_address(*index) != *address {
+                    warn!(
+                        "label for {} does not match descriptor {} at index {}, searching for it",
+                        address, checksum, index
+                    );
+                    return self.find_origin(address);
+                }
+            }
+        }
+        Some(label_origin)
+    }
+
+    /// Find the origin of an address by deriving the imported addresses of all tracked descriptors
+    pub fn find_origin(&self, address: &Address) -> Option<KeyOrigin> {
+        self.wallets.iter().find_map(|(checksum, wallet)| {
+            let index = wallet.find_index(address)?;
+            Some(KeyOrigin::Descriptor(checksum.clone(), index))
+        })
+    }
+
+    /// Get the import state of the `checksum` wallet. The state is recovered from the labels of the
+    /// bitcoind wallet on startup by `check_imports()`, so fully imported wallets don't get
+    /// re-imported (and rescanned) after a restart.
+    pub fn import_state(&self, checksum: &Checksum) -> Option<ImportState> {
+        Some(self.wallets.get(checksum)?.import_state())
+    }
+
+    /// Get the next unused change address of the `checksum` change wallet, or of the only tracked
+    /// change (internal) wallet if unspecified. Addresses funded by any transaction in the `store`
+    /// are skipped, including unconfirmed ones.
+    pub fn next_change_address(
+        &self,
+        checksum: Option<&Checksum>,
+        store: &impl Store,
+    ) -> Result<ScriptInfo> {
+        let wallet = match checksum {
+            Some(checksum) => {
+                let wallet = self
+                    .wallets
+                    .get(checksum)
+                    .with_context(|| format!("Unknown wallet {}", checksum))?;
+                ensure!(
+                    wallet.is_internal(),
+                    "Wallet {} is not a change wallet",
+                    checksum
+                );
+                wallet
+            }
+            None => {
+                let mut change_wallets = self.wallets.values().filter(|w| w.is_internal());
+                let wallet = change_wallets
+                    .next()
+                    .context("No change wallet is being tracked")?;
+                ensure!(
+                    change_wallets.next().is_none(),
+                    "Multiple change wallets are tracked, the wallet needs to be specified"
+                );
+                wallet
+            }
+        };
+
+        let index = (wallet.get_next_index()..)
+            .take_while(|index| wallet.is_valid_index(*index))
+            .find(|index| !store.has_history(&wallet.derive_address(*index).into()))
+            .context("Change wallet derivation indexes exhausted")?;
+        Ok(wallet.script_info(index))
+    }
+
+    /// Derive the script info of a scripthash that belongs to one of the tracked descriptors
+    /// within their gap limit, regardless of whether it has any history.
+    pub fn derive_script_info(&self, scripthash: &ScriptHash) -> Option<ScriptInfo> {
+        let (checksum, index) = self.derived_scripthashes.get(scripthash)?;
+        Some(self.wallets.get(checksum)?.script_info(*index))
+    }
+
+    // Derive the scripthashes that entered the look-ahead window of the descriptor wallets since
+    // the last call. Needs to be called whenever the window might have moved forward.
+    fn derive_lookahead(&mut self) {
+        for (checksum, wallet) in &mut self.wallets {
+            for (scripthash, index) in wallet.derive_lookahead() {
+                self.derived_scripthashes
+                    .insert(scripthash, (checksum.clone(), index));
+            }
+        }
+    }
+
+    /// Check whether the origin is an internal (change) derivation. Returns None for standalone
+    /// addresses and for unknown descriptors, where this cannot be determined.
+    pub fn is_change(&self, origin: &KeyOrigin) -> Option<bool> {
+        match origin {
+            KeyOrigin::Descriptor(checksum, _) => Some(self.wallets.get(checksum)?.is_internal()),
+            KeyOrigin::Standalone => None,
+        }
+    }
+
     // Mark an address as funded
     pub fn mark_funded(&mut self, origin: &KeyOrigin) {
         if let KeyOrigin::Descriptor(checksum, index) = origin {
@@ -129,11 +385,15 @@ impl WalletWatcher {
                     wallet.max_funded_index = Some(*index);
                 }
             }
+            self.derive_lookahead();
         }
     }
 
     /// Check previous imports and update our state
-    pub fn check_imports(&mut self, rpc: &RpcClient) -> Result<()> {
+    pub fn check_imports(&mut self, rpc: &impl RpcApiExt) -> Result<()> {
+        if self.watch_all {
+            return Ok(());
+        }
         debug!("checking previous imports");
 
         // Lookup descriptor wallet imports and update their max_imported_index
@@ -150,14 +410,15 @@ impl WalletWatcher {
             }
         }
         for (checksum, max_imported_index) in imported_indexes {
+            let wallet = self.wallets.get_mut(&checksum).unwrap();
+            wallet.max_imported_index = Some(max_imported_index);
             trace!(
-                "wallet {} was imported up to index {}",
+                "wallet {} import state: {:?}",
                 checksum,
-                max_imported_index
+                wallet.import_state()
             );
-            let wallet = self.wallets.get_mut(&checksum).unwrap();
-            wallet.max_imported_index = Some(max_imported_index);
         }
+        self.derive_lookahead();
 
         // Lookup previously imported standalone addresses and remove them from the pending import queue
         if !self.force_rescan {
@@ -177,8 +438,77 @@ impl WalletWatcher {
         Ok(())
     }
 
-    pub fn do_imports(&mut self, rpc: &RpcClient, rescan: bool) -> Result<bool> {
-        let mut import_reqs = vec![];
+    /// Compare the addresses expected to be imported against the bitcoind wallet, reporting
+    /// addresses that are missing from it, addresses it has that aren't tracked, and addresses
+    /// whose label does not match their descriptor. Useful for explaining unexpected balances.
+    pub fn verify_imports(&self, rpc: &impl RpcApiExt) -> Result<Vec<ImportDiscrepancy>> {
+        // everything in the bitcoind wallet is tracked as-is
+        if self.watch_all {
+            return Ok(vec![]);
+        }
+        let core_labels = rpc
+            .list_received_by_address(None, Some(0), Some(true), Some(true))?
+            .into_iter()
+            .map(|entry| (entry.address, entry.label))
+            .collect();
+        Ok(self.diff_imports(&core_labels))
+    }
+
+    fn diff_imports(&self, core_labels: &HashMap<Address, String>) -> Vec<ImportDiscrepancy> {
+        let mut discrepancies = vec![];
+
+        for (address, label) in core_labels {
+            match KeyOrigin::from_label(label) {
+                Some(KeyOrigin::Standalone) => (),
+                Some(KeyOrigin::Descriptor(checksum, index)) => match self.wallets.get(&checksum) {
+                    Some(wallet) => {
+                        if !wallet.is_valid_index(index) || wallet.derive_address(index) != *address
+                        {
+                            discrepancies.push(ImportDiscrepancy::Mislabeled {
+                                address: address.clone(),
+                                label: label.clone(),
+                                origin: self.find_origin(address),
+                            });
+                        }
+                    }
+                    None => discrepancies.push(ImportDiscrepancy::Untracked {
+                        address: address.clone(),
+                        label: label.clone(),
+                    }),
+                },
+                None => discrepancies.push(ImportDiscrepancy::Untracked {
+                    address: address.clone(),
+                    label: label.clone(),
+                }),
+            }
+        }
+
+        let mut wallets = self
+            .wallets
+            .values()
+            .filter_map(|wallet| Some((wallet, wallet.max_imported_index?)))
+            .collect::<Vec<_>>();
+        wallets.sort_unstable_by_key(|(wallet, _)| &wallet.checksum);
+        for (wallet, max_imported_index) in wallets {
+            for index in 0..=max_imported_index {
+                let address = wallet.derive_address(index);
+                if !core_labels.contains_key(&address) {
+                    let origin = KeyOrigin::Descriptor(wallet.checksum.clone(), index);
+                    discrepancies.push(ImportDiscrepancy::Missing { address, origin });
+                }
+            }
+        }
+
+        discrepancies
+    }
+
+    pub fn do_imports(&mut self, rpc: &impl RpcApiExt, rescan: bool) -> Result<bool> {
+        // nothing to import, the bitcoind wallet is used as-is
+        if self.watch_all {
+            return Ok(false);
+        }
+
+        let mut import_reqs = self.standalone_imports();
         let mut pending_updates = vec![];
 
         for (_, wallet) in self.wallets.iter_mut() {
@@ -192,25 +522,15 @@ impl WalletWatcher {
             }
         }
 
-        if !self.pending_standalone.is_empty() {
-            let label = KeyOrigin::standalone_label();
-            import_reqs.extend(
-                self.pending_standalone
-                    .iter()
-                    .cloned()
-                    .map(|(address, rescan)| (address, rescan, label.into())),
-            );
-        }
-
         let has_imports = !import_reqs.is_empty();
 
         if has_imports {
             info!(
-                "importing batch of {} addresses... (this may take awhile)",
+                "importing {} addresses... (this may take awhile)",
                 import_reqs.len()
             );
-            batch_import(rpc, import_reqs)?;
-            debug!("done importing batch");
+            batch_import(rpc, import_reqs, self.import_batch_size)?;
+            debug!("done importing");
 
             for (wallet, imported_index) in pending_updates {
                 wallet.max_imported_index = Some(imported_index);
@@ -221,11 +541,104 @@ impl WalletWatcher {
 
             // we don't need to keep standalone addresses around once they get imported
             self.pending_standalone.clear();
+
+            self.derive_lookahead();
         }
 
         Ok(has_imports)
     }
 
+    // the import requests for the pending standalone addresses, each with its own rescan timestamp
+    fn standalone_imports(&self) -> Vec<(Address, RescanSince, String)> {
+        let label = KeyOrigin::standalone_label();
+        self.pending_standalone
+            .iter()
+            .cloned()
+            .map(|(address, rescan)| (address, rescan, label.into()))
+            .collect()
+    }
+
+    /// Widen the gap limit of the `checksum` wallet. Returns the additional addresses that need to
+    /// be imported with a rescan, if any, see `GapRescan`.
+    ///
+    /// Shrinking the gap limit is not supported, as addresses cannot be safely un-imported.
+    pub fn set_gap_limit(
+        &mut self,
+        checksum: &Checksum,
+        gap_limit: u32,
+    ) -> Result<Option<GapRescan>> {
+        let wallet = self
+            .wallets
+            .get_mut(checksum)
+            .with_context(|| format!("Unknown wallet {}", checksum))?;
+        let start_index = wallet.import_start_index();
+        let widened = gap_limit > wallet.gap_limit;
+        wallet.set_gap_limit(gap_limit)?;
+        self.derive_lookahead();
+        Ok(if widened {
+            self.make_gap_rescan(checksum, start_index)
+        } else {
+            None
+        })
+    }
+
+    /// Mark the addresses of a completed `GapRescan` as imported. Returns the next addresses to
+    /// import with a rescan if the gap moved forward following the history discovered since.
+    pub fn gap_rescanned(&mut self, rescan: &GapRescan) -> Option<GapRescan> {
+        let wallet = self.wallets.get_mut(&rescan.checksum)?;
+        wallet.max_imported_index = wallet.max_imported_index.max(Some(rescan.end_index));
+        self.derive_lookahead();
+        self.make_gap_rescan(&rescan.checksum, rescan.end_index + 1)
+    }
+
+    fn make_gap_rescan(&self, checksum: &Checksum, start_index: u32) -> Option<GapRescan> {
+        let wallet = self.wallets.get(checksum)?;
+        let end_index = wallet.import_end_index(/*is_rescan=*/ true);
+        (wallet.is_wildcard && start_index <= end_index).do_then(|| GapRescan {
+            checksum: checksum.clone(),
+            end_index,
+            import_reqs: wallet.make_imports(start_index, end_index, /*rescan=*/ true),
+            batch_size: self.import_batch_size,
+        })
+    }
+
+    /// Derive `count` addresses of the `checksum` wallet starting at index `start`, regardless of
+    /// whether they were imported or used. This is read-only and does not affect the import state.
+    ///
+    /// Receive and change chains are tracked as separate wallets, each with its own checksum.
+    pub fn derive_addresses(
+        &self,
+        checksum: &Checksum,
+        start: u32,
+        count: u32,
+    ) -> Result<Vec<ScriptInfo>> {
+        let wallet = self
+            .wallets
+            .get(checksum)
+            .with_context(|| format!("Unknown wallet {}", checksum))?;
+        ensure!(
+            count <= MAX_DERIVE_COUNT,
+            "Cannot derive more than {} addresses at once",
+            MAX_DERIVE_COUNT
+        );
+        if count == 0 {
+            return Ok(vec![]);
+        }
+        let end = start
+            .checked_add(count - 1)
+            .context("Invalid index range")?;
+        ensure!(
+            wallet.is_valid_index(start) && wallet.is_valid_index(end),
+            "Invalid index range {}-{} for {}",
+            start,
+            end,
+            checksum
+        );
+        Ok((start..=end)
+            .map(|index| wallet.script_info(index))
+            .collect())
+    }
+
     /// Add an address to be tracked
     ///
     /// The address will be added to the list of pending imports and will get imported on the next sync run.
@@ -235,9 +648,164 @@ impl WalletWatcher {
             "Invalid network for address {}",
             address
         );
+        ensure!(
+            !self.watch_all,
+            "Cannot import addresses when tracking the whole bitcoind wallet"
+        );
         self.pending_standalone.push((address, rescan_since));
         Ok(())
     }
+
+    /// Derive the addresses to look for when scanning blocks directly, without importing them into
+    /// the bitcoind wallet. Covers the gap past the last funded index of every descriptor wallet,
+    /// and only returns the addresses that weren't returned by previous calls.
+    pub fn derive_scan_targets(&mut self) -> Vec<(Address, KeyOrigin)> {
+        let mut targets = vec![];
+        for (checksum, wallet) in &mut self.wallets {
+            let start_index = wallet.import_start_index();
+            let end_index = wallet.import_end_index(/*is_rescan=*/ true);
+            if start_index > end_index {
+                continue;
+            }
+            targets.extend((start_index..=end_index).map(|index| {
+                let origin = KeyOrigin::Descriptor(checksum.clone(), index);
+                (wallet.derive_address(index), origin)
+            }));
+            wallet.max_imported_index = Some(end_index);
+        }
+        self.derive_lookahead();
+        targets.extend(
+            self.pending_standalone
+                .drain(..)
+                .map(|(address, _)| (address, KeyOrigin::Standalone)),
+        );
+        targets
+    }
+}
+
+/// The look-ahead window status of a descriptor wallet, see `Wallet::gap_status()`. Receive and
+/// change chains are tracked as separate wallets and distinguished by `is_internal`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GapStatus {
+    pub is_internal: bool,
+    pub highest_used_index: Option<u32>,
+    pub highest_imported_index: Option<u32>,
+    /// The number of imported addresses past the highest used one
+    pub gap_remaining: u32,
+}
+
+/// The import state of a descriptor wallet in the bitcoind wallet, see `Wallet::import_state()`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ImportState {
+    /// No addresses were imported yet
+    NotImported,
+    /// Some addresses were imported, but more are pending import (and possibly rescan)
+    Importing { range_end: u32 },
+    /// All the addresses required by the gap limit were imported, up to `range_end` (inclusive)
+    Imported { range_end: u32 },
+}
+
+/// The result of loading a descriptors file, see `WalletWatcher::add_descriptors_file()`
+#[derive(Debug, Default, Serialize)]
+pub struct DescriptorsFileSummary {
+    /// The number of descriptors/xpubs that got registered
+    pub registered: usize,
+    /// The number of entries skipped because they were already tracked
+    pub duplicates: usize,
+    /// The invalid entries, as (line/position number, error message) tuples
+    pub invalid: Vec<(usize, String)>,
+}
+
+/// A mismatch between bwt's tracked addresses and the bitcoind wallet, see `WalletWatcher::verify_imports()`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ImportDiscrepancy {
+    /// An address expected to be imported that the bitcoind wallet does not have
+    Missing { address: Address, origin: KeyOrigin },
+    /// An address in the bitcoind wallet that is not tracked by bwt
+    Untracked { address: Address, label: String },
+    /// An address labeled with a tracked descriptor origin that does not derive it. Includes the
+    /// actual origin, if the address could be found within the imported range of another index.
+    Mislabeled {
+        address: Address,
+        label: String,
+        origin: Option<KeyOrigin>,
+    },
+}
+
+/// The additional addresses of a wallet whose gap limit was widened, which need to be imported
+/// with a rescan to pick up their history. See `WalletWatcher::set_gap_limit()`.
+///
+/// The rescan may take awhile. It only needs the RPC client, so that it can run without holding
+/// on to the watcher (and the indexer lock), before getting handed back to `gap_rescanned()`.
+#[derive(Debug)]
+pub struct GapRescan {
+    checksum: Checksum,
+    end_index: u32,
+    import_reqs: Vec<(Address, RescanSince, String)>,
+    batch_size: usize,
+}
+
+impl GapRescan {
+    pub fn import(&self, rpc: &impl RpcApiExt) -> Result<()> {
+        info!(
+            "importing {} addresses of {} with rescan... (this may take awhile)",
+            self.import_reqs.len(),
+            self.checksum
+        );
+        batch_import(rpc, self.import_reqs.clone(), self.batch_size)?;
+        debug!("done importing");
+        Ok(())
+    }
+}
+
+/// Parse the descriptors file contents into a list of (line/position number, entry) tuples
+fn parse_descriptors_file(contents: &str) -> Result<Vec<(usize, String)>> {
+    let contents = contents.trim();
+    Ok(if contents.starts_with('[') {
+        let entries: Vec<String> =
+            serde_json::from_str(contents).context("invalid JSON descriptors file")?;
+        (1..).zip(entries).collect()
+    } else {
+        (1..)
+            .zip(contents.lines())
+            .map(|(line, entry)| (line, entry.trim()))
+            .filter(|(_, entry)| !entry.is_empty() && !entry.starts_with('#'))
+            .map(|(line, entry)| (line, entry.to_string()))
+            .collect()
+    })
+}
+
+/// Parse a single descriptors file entry, as a descriptor or an xpub with an optional gap limit
+fn parse_descriptors_entry(
+    entry: &str,
+    network: Network,
+    gap_limit: u32,
+    initial_import_size: u32,
+    rescan_since: RescanSince,
+) -> Result<Vec<Wallet>> {
+    let mut parts = entry.split_whitespace();
+    let source = parts.next().context("empty entry")?;
+    let gap_limit = match parts.next() {
+        Some(gap_limit) => gap_limit.parse().context("invalid gap limit")?,
+        None => gap_limit,
+    };
+    ensure!(parts.next().is_none(), "unexpected trailing data");
+
+    if let Ok(xpub) = XyzPubKey::from_str(source) {
+        Wallet::from_xpub(xpub, network, gap_limit, initial_import_size, rescan_since)
+    } else {
+        // validates the checksum if one is provided
+        let desc = ExtendedDescriptor::parse_canonical(source)?;
+        Ok(vec![Wallet::from_descriptor(
+            desc,
+            network,
+            gap_limit,
+            initial_import_size,
+            rescan_since,
+        )?])
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -253,6 +821,8 @@ pub struct Wallet {
 
     max_funded_index: Option<u32>,
     max_imported_index: Option<u32>,
+    /// The highest index whose scripthash was derived for `WalletWatcher::derived_scripthashes`
+    max_derived_index: Option<u32>,
 }
 
 impl Wallet {
@@ -285,6 +855,7 @@ impl Wallet {
             rescan_since,
             max_funded_index: None,
             max_imported_index: None,
+            max_derived_index: None,
         })
     }
 
@@ -295,24 +866,37 @@ impl Wallet {
         initial_import_size: u32,
         rescan_since: RescanSince,
     ) -> Result<Vec<Self>> {
-        Ok(vec![
-            // external chain (receive)
-            Self::from_descriptor(
-                xpub.as_descriptor([0.into()][..].into()),
-                network,
-                gap_limit,
-                initial_import_size,
-                rescan_since,
-            )?,
-            // internal chain (change)
-            Self::from_descriptor(
-                xpub.as_descriptor([1.into()][..].into()),
-                network,
-                gap_limit,
-                initial_import_size,
-                rescan_since,
-            )?,
-        ])
+        let external_descs = xpub.as_descriptors([0.into()][..].into());
+        let internal_descs = xpub.as_descriptors([1.into()][..].into());
+
+        // each script type gets its own pair of wallets (and descriptor checksums),
+        // which tells apart the addresses derived at the same index
+        external_descs
+            .into_iter()
+            .zip(internal_descs)
+            .flat_map(|(external_desc, internal_desc)| vec![external_desc, internal_desc])
+            .map(|desc| {
+                Self::from_descriptor(desc, network, gap_limit, initial_import_size, rescan_since)
+            })
+            .collect()
+    }
+
+    fn set_gap_limit(&mut self, gap_limit: u32) -> Result<()> {
+        ensure!(
+            gap_limit >= self.gap_limit,
+            "The gap limit cannot be reduced (from {} to {})",
+            self.gap_limit,
+            gap_limit
+        );
+        if gap_limit > self.gap_limit {
+            info!(
+                "widening the gap limit of {} from {} to {}",
+                self.checksum, self.gap_limit, gap_limit
+            );
+            self.gap_limit = gap_limit;
+            self.initial_import_size = self.initial_import_size.max(gap_limit);
+        }
+        Ok(())
     }
 
     fn needs_imports(&self) -> bool {
@@ -374,6 +958,18 @@ impl Wallet {
             .collect()
     }
 
+    /// Whether this is an internal (change) chain, following the BIP 44 convention of deriving
+    /// change addresses under `1/*`. All the wildcard keys are required to agree.
+    pub fn is_internal(&self) -> bool {
+        let change_step = ChildNumber::Normal { index: 1 };
+        self.is_wildcard
+            && self
+                .keys_info
+                .iter()
+                .filter(|info| info.is_wildcard)
+                .all(|info| info.bip32_origin.1.as_ref().last() == Some(&change_step))
+    }
+
     /// Derive an address using the given non-hardended child derivation index.
     /// Assumes the index is valid and panics if not. See is_valid_index().
     pub fn derive_address(&self, index: u32) -> Address {
@@ -385,6 +981,17 @@ impl Wallet {
         self.desc.derive(index)
     }
 
+    /// Get the address, descriptor and bip32 origins at the provided index.
+    /// Assumes the index is valid and panics if not. See is_valid_index().
+    pub fn script_info(&self, index: u32) -> ScriptInfo {
+        ScriptInfo::from_desc(
+            KeyOrigin::Descriptor(self.checksum.clone(), index),
+            self.derive_address(index),
+            self.derive_desc(index),
+            self.bip32_origins(index),
+        )
+    }
+
     pub fn get_next_index(&self) -> u32 {
         if self.is_wildcard {
             self.max_funded_index
@@ -394,6 +1001,26 @@ impl Wallet {
         }
     }
 
+    // Derive the scripthashes within the look-ahead window past the highest used index (including
+    // addresses that were not imported yet) that were not derived by previous calls
+    fn derive_lookahead(&mut self) -> Vec<(ScriptHash, u32)> {
+        let start_index = self.max_derived_index.map_or(0, |max| max + 1);
+        let end_index = self.import_end_index(/*is_rescan=*/ false);
+        if start_index > end_index {
+            return vec![];
+        }
+        self.max_derived_index = Some(end_index);
+        (start_index..=end_index)
+            .map(|index| (ScriptHash::from(&self.derive_address(index)), index))
+            .collect()
+    }
+
+    /// Find the derivation index of the address within the range of imported addresses
+    pub fn find_index(&self, address: &Address) -> Option<u32> {
+        let max_index = iif!(self.is_wildcard, self.max_imported_index?, 0);
+        (0..=max_index).find(|index| self.derive_address(*index) == *address)
+    }
+
     pub fn is_valid_index(&self, index: u32) -> bool {
         if self.is_wildcard {
             // non-hardended derivation only
@@ -403,7 +1030,33 @@ impl Wallet {
         }
     }
 
-    pub fn find_gap(&self, store: &MemoryStore) -> Option<usize> {
+    /// Get how much of the look-ahead window of imported addresses is left past the highest used
+    /// index. Once it runs out, additional addresses get imported by the next sync.
+    pub fn gap_status(&self) -> GapStatus {
+        let gap_remaining = match (self.max_funded_index, self.max_imported_index) {
+            (Some(funded), Some(imported)) => imported.saturating_sub(funded),
+            (None, Some(imported)) => imported + 1,
+            (_, None) => 0,
+        };
+        GapStatus {
+            is_internal: self.is_internal(),
+            highest_used_index: self.max_funded_index,
+            highest_imported_index: self.max_imported_index,
+            gap_remaining,
+        }
+    }
+
+    /// Get whether the addresses required by the gap limit were imported. Wallets that are fully
+    /// imported are skipped by `WalletWatcher::do_imports()`.
+    pub fn import_state(&self) -> ImportState {
+        match self.max_imported_index {
+            None => ImportState::NotImported,
+            Some(range_end) if self.needs_imports() => ImportState::Importing { range_end },
+            Some(range_end) => ImportState::Imported { range_end },
+        }
+    }
+
+    pub fn find_gap(&self, store: &impl Store) -> Option<usize> {
         // return None if this wallet has no history at all
         let max_funded_index = self.max_funded_index?;
 
@@ -438,37 +1091,102 @@ impl Wallet {
     }
 }
 
-fn batch_import(rpc: &RpcClient, import_reqs: Vec<(Address, RescanSince, String)>) -> Result<()> {
+fn batch_import(
+    rpc: &impl RpcApiExt,
+    import_reqs: Vec<(Address, RescanSince, String)>,
+    batch_size: usize,
+) -> Result<()> {
     // XXX use importmulti with ranged descriptors? the key derivation info won't be
     //     directly available on `listtransactions` and would require an additional rpc all.
 
-    let results = rpc.import_multi(
-        &import_reqs
-            .iter()
-            .map(|(address, rescan, label)| ImportMultiRequest {
-                label: Some(&label),
-                watchonly: Some(true),
-                timestamp: (*rescan).into(),
-                script_pubkey: Some(ImportMultiRequestScriptPubkey::Address(&address)),
-                ..Default::default()
-            })
-            .collect::<Vec<_>>(),
-        None,
-    )?;
+    let batches = make_import_batches(import_reqs, batch_size);
+    let num_batches = batches.len();
 
-    for (i, result) in results.iter().enumerate() {
-        if !result.success {
-            let req = import_reqs.get(i).unwrap(); // should not fail unless bitcoind is messing with us
-            bail!("import for {:?} failed: {:?}", req, result);
-        } else if !result.warnings.is_empty() {
-            debug!("import succeed with warnings: {:?}", result);
+    for (i, batch) in batches.iter().enumerate() {
+        // only rescan once, after the final batch. it holds the earliest rescan timestamps,
+        // which bitcoind uses as the starting point for rescanning all the imported addresses.
+        let rescan = i == num_batches - 1;
+        if num_batches > 1 {
+            info!(
+                "importing batch {}/{} of {} addresses{}",
+                i + 1,
+                num_batches,
+                batch.len(),
+                iif!(rescan, " (with rescan)", "")
+            );
+        }
+
+        // imports are idempotent, allowing to simply retry batches that failed (i.e. due to timeouts)
+        let results = import_multi(rpc, batch, rescan).or_else(|e| {
+            warn!(
+                "import batch {}/{} failed: {}, retrying",
+                i + 1,
+                num_batches,
+                e
+            );
+            import_multi(rpc, batch, rescan)
+        })?;
+
+        for (i, result) in results.iter().enumerate() {
+            if !result.success {
+                let req = batch.get(i).unwrap(); // should not fail unless bitcoind is messing with us
+                bail!("import for {:?} failed: {:?}", req, result);
+            } else if !result.warnings.is_empty() {
+                debug!("import succeed with warnings: {:?}", result);
+            }
         }
     }
 
     Ok(())
 }
 
-#[derive(Debug, Clone, PartialEq)]
+// Split the imports into batches of up to `batch_size`, with the earliest rescan timestamps last
+fn make_import_batches(
+    mut import_reqs: Vec<(Address, RescanSince, String)>,
+    batch_size: usize,
+) -> Vec<Vec<(Address, RescanSince, String)>> {
+    import_reqs.sort_by_key(|(_, rescan, _)| match rescan {
+        RescanSince::Now => Reverse(u64::MAX),
+        RescanSince::Timestamp(timestamp) => Reverse(*timestamp),
+    });
+    import_reqs
+        .chunks(batch_size.max(1))
+        .map(|batch| batch.to_vec())
+        .collect()
+}
+
+fn import_multi(
+    rpc: &impl RpcApiExt,
+    import_reqs: &[(Address, RescanSince, String)],
+    rescan: bool,
+) -> rpc::Result<Vec<rpc::json::ImportMultiResult>> {
+    rpc.import_multi(
+        &import_reqs
+            .iter()
+            .map(make_import_request)
+            .collect::<Vec<_>>(),
+        Some(&ImportMultiOptions {
+            rescan: Some(rescan),
+        }),
+    )
+}
+
+// The rescan timestamp is forwarded to bitcoind, which starts rescanning from the earliest
+// timestamp in the batch (or skips the rescan entirely if they're all `now`)
+fn make_import_request(
+    (address, rescan_since, label): &(Address, RescanSince, String),
+) -> ImportMultiRequest<'_> {
+    ImportMultiRequest {
+        label: Some(label),
+        watchonly: Some(true),
+        timestamp: (*rescan_since).into(),
+        script_pubkey: Some(ImportMultiRequestScriptPubkey::Address(address)),
+        ..Default::default()
+    }
+}
+
+// Descriptor origins are ordered before standalone ones, by their checksum and derivation index
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum KeyOrigin {
     Descriptor(Checksum, u32),
     Standalone,
@@ -507,6 +1225,23 @@ impl KeyOrigin {
         }
     }
 
+    /// Determine the origin of a key from its bip32 origin, by matching it against the keys of
+    /// the tracked descriptors. Unlike `from_label()`, this does not rely on the bitcoind wallet.
+    pub fn from_bip32_origin(bip32_origin: &Bip32Origin, watcher: &WalletWatcher) -> Option<Self> {
+        // the child index of wildcard descriptors is the last derivation step
+        let child_index = match bip32_origin.1.as_ref().last() {
+            Some(ChildNumber::Normal { index }) => Some(*index),
+            _ => None,
+        };
+        watcher.wallets.iter().find_map(|(checksum, wallet)| {
+            let index = iif!(wallet.is_wildcard, child_index?, 0);
+            wallet
+                .bip32_origins(index)
+                .contains(bip32_origin)
+                .do_then(|| KeyOrigin::Descriptor(checksum.clone(), index))
+        })
+    }
+
     pub fn is_standalone(origin: &KeyOrigin) -> bool {
         match origin {
             KeyOrigin::Standalone => true,
@@ -562,3 +1297,518 @@ impl Serialize for Wallet {
         rgb.end()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::BwtError;
+    use bitcoincore_rpc::json::ImportMultiRescanSince;
+    use std::str::FromStr;
+
+    const XPUB: &str = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+
+    #[test]
+    fn test_widen_gap_limit() {
+        let desc = ExtendedDescriptor::from_str(&format!("wpkh({}/0/*)", XPUB)).unwrap();
+        let mut wallet =
+            Wallet::from_descriptor(desc, Network::Bitcoin, 5, 5, RescanSince::Timestamp(0))
+                .unwrap();
+        let checksum = wallet.checksum.clone();
+
+        // index 2 is funded and the gap is fully imported, but the wallet also used index 15
+        wallet.max_funded_index = Some(2);
+        wallet.max_imported_index = Some(7);
+        assert!(!wallet.needs_imports());
+        let missed_address = wallet.derive_address(15);
+        let mut watcher =
+            WalletWatcher::new(Network::Bitcoin, vec![wallet], vec![], false).unwrap();
+
+        assert!(watcher.set_gap_limit(&checksum, 4).is_err());
+        assert!(watcher.set_gap_limit(&checksum, 5).unwrap().is_none());
+        let rescan = watcher.set_gap_limit(&checksum, 20).unwrap().unwrap();
+        assert_eq!(rescan.import_reqs.len(), 15); // indexes 8-22
+
+        let (_, rescan_since, label) = rescan
+            .import_reqs
+            .iter()
+            .find(|(address, _, _)| *address == missed_address)
+            .expect("missed address should get imported");
+        assert_eq!(*rescan_since, RescanSince::Timestamp(0));
+        assert_eq!(
+            KeyOrigin::from_label(label),
+            Some(KeyOrigin::Descriptor(checksum.clone(), 15))
+        );
+
+        // the history found by the rescan moves the gap forward, requiring another one
+        watcher.mark_funded(&KeyOrigin::Descriptor(checksum.clone(), 15));
+        let rescan = watcher.gap_rescanned(&rescan).unwrap();
+        let indexes: Vec<_> = rescan
+            .import_reqs
+            .iter()
+            .filter_map(|(_, _, label)| match KeyOrigin::from_label(label)? {
+                KeyOrigin::Descriptor(_, index) => Some(index),
+                KeyOrigin::Standalone => None,
+            })
+            .collect();
+        assert_eq!(indexes, (23..=35).collect::<Vec<_>>());
+
+        assert!(watcher.gap_rescanned(&rescan).is_none());
+        assert_eq!(
+            watcher.import_state(&checksum),
+            Some(ImportState::Imported { range_end: 35 })
+        );
+    }
+
+    #[test]
+    fn test_gap_status() {
+        let desc = ExtendedDescriptor::from_str(&format!("wpkh({}/1/*)", XPUB)).unwrap();
+        let mut wallet =
+            Wallet::from_descriptor(desc, Network::Bitcoin, 5, 5, RescanSince::Timestamp(0))
+                .unwrap();
+        let status = wallet.gap_status();
+        assert!(status.is_internal);
+        assert_eq!(status.highest_imported_index, None);
+        assert_eq!(status.gap_remaining, 0);
+
+        wallet.max_imported_index = Some(4);
+        assert_eq!(wallet.gap_status().gap_remaining, 5);
+
+        wallet.max_funded_index = Some(3);
+        let status = wallet.gap_status();
+        assert_eq!(status.highest_used_index, Some(3));
+        assert_eq!(status.highest_imported_index, Some(4));
+        assert_eq!(status.gap_remaining, 1);
+    }
+
+    #[test]
+    fn test_import_state() {
+        let desc = ExtendedDescriptor::from_str(&format!("wpkh({}/0/*)", XPUB)).unwrap();
+        let wallet =
+            Wallet::from_descriptor(desc, Network::Bitcoin, 5, 5, RescanSince::Timestamp(0))
+                .unwrap();
+        let checksum = wallet.checksum.clone();
+        let mut watcher =
+            WalletWatcher::new(Network::Bitcoin, vec![wallet], vec![], false).unwrap();
+        let state = |watcher: &WalletWatcher| watcher.import_state(&checksum).unwrap();
+        assert_eq!(state(&watcher), ImportState::NotImported);
+
+        // recovered from the bitcoind wallet labels on startup
+        watcher
+            .wallets
+            .get_mut(&checksum)
+            .unwrap()
+            .max_imported_index = Some(4);
+        assert_eq!(state(&watcher), ImportState::Imported { range_end: 4 });
+
+        // funds received close to the end of the imported range require importing more
+        watcher.mark_funded(&KeyOrigin::Descriptor(checksum.clone(), 2));
+        assert_eq!(state(&watcher), ImportState::Importing { range_end: 4 });
+        watcher
+            .wallets
+            .get_mut(&checksum)
+            .unwrap()
+            .max_imported_index = Some(7);
+        assert_eq!(state(&watcher), ImportState::Imported { range_end: 7 });
+
+        // widening the gap limit requires importing (and rescanning) the additional addresses
+        watcher.set_gap_limit(&checksum, 10).unwrap();
+        assert_eq!(state(&watcher), ImportState::Importing { range_end: 7 });
+
+        let unknown = Checksum::from_str("xjm8w0el").unwrap();
+        assert_eq!(watcher.import_state(&unknown), None);
+    }
+
+    #[test]
+    fn test_derive_script_info() {
+        let desc = ExtendedDescriptor::from_str(&format!("wpkh({}/0/*)", XPUB)).unwrap();
+        let mut wallet =
+            Wallet::from_descriptor(desc, Network::Bitcoin, 5, 5, RescanSince::Timestamp(0))
+                .unwrap();
+        wallet.max_funded_index = Some(2);
+        let checksum = wallet.checksum.clone();
+        let mut watcher =
+            WalletWatcher::new(Network::Bitcoin, vec![wallet.clone()], vec![], false).unwrap();
+
+        // derivable up to the gap limit past the highest funded index, but no further
+        let scripthash = ScriptHash::from(&wallet.derive_address(7));
+        let script_info = watcher.derive_script_info(&scripthash).unwrap();
+        assert_eq!(
+            script_info.origin,
+            KeyOrigin::Descriptor(checksum.clone(), 7)
+        );
+        assert_eq!(script_info.address, Some(wallet.derive_address(7)));
+        let scripthash = ScriptHash::from(&wallet.derive_address(8));
+        assert!(watcher.derive_script_info(&scripthash).is_none());
+
+        // the window moves forward as addresses get funded and when the gap limit is widened
+        watcher.mark_funded(&KeyOrigin::Descriptor(checksum.clone(), 3));
+        assert!(watcher.derive_script_info(&scripthash).is_some());
+        let scripthash = ScriptHash::from(&wallet.derive_address(11));
+        assert!(watcher.derive_script_info(&scripthash).is_none());
+        watcher.set_gap_limit(&checksum, 8).unwrap();
+        let script_info = watcher.derive_script_info(&scripthash).unwrap();
+        assert_eq!(script_info.origin, KeyOrigin::Descriptor(checksum, 11));
+    }
+
+    #[test]
+    fn test_next_change_address() {
+        use crate::store::{FundingInfo, MemoryStore};
+        use crate::types::TxStatus;
+        use bitcoin::Txid;
+
+        let external_desc = ExtendedDescriptor::from_str(&format!("wpkh({}/0/*)", XPUB)).unwrap();
+        let internal_desc = ExtendedDescriptor::from_str(&format!("wpkh({}/1/*)", XPUB)).unwrap();
+        let external =
+            Wallet::from_descriptor(external_desc, Network::Bitcoin, 5, 5, RescanSince::Now)
+                .unwrap();
+        let mut internal =
+            Wallet::from_descriptor(internal_desc, Network::Bitcoin, 5, 5, RescanSince::Now)
+                .unwrap();
+        internal.max_funded_index = Some(1);
+        let checksum = internal.checksum.clone();
+        let watcher = WalletWatcher::new(
+            Network::Bitcoin,
+            vec![external.clone(), internal.clone()],
+            vec![],
+            false,
+        )
+        .unwrap();
+
+        // an address funded by a pending transaction that wasn't marked as funded yet is skipped
+        let mut store = MemoryStore::new();
+        let address = internal.derive_address(2);
+        let scripthash = ScriptHash::from(&address);
+        store.index_scripthash(
+            &scripthash,
+            &KeyOrigin::Descriptor(checksum.clone(), 2),
+            &address,
+        );
+        store.upsert_tx(&Txid::default(), TxStatus::Unconfirmed);
+        store.index_tx_output_funding(&Txid::default(), 0, FundingInfo(scripthash, 5000));
+
+        let script_info = watcher.next_change_address(None, &store).unwrap();
+        assert_eq!(
+            script_info.origin,
+            KeyOrigin::Descriptor(checksum.clone(), 3)
+        );
+        let script_info = watcher
+            .next_change_address(Some(&checksum), &store)
+            .unwrap();
+        assert_eq!(script_info.address, Some(internal.derive_address(3)));
+
+        assert!(watcher
+            .next_change_address(Some(&external.checksum), &store)
+            .is_err());
+        let watcher = WalletWatcher::new(Network::Bitcoin, vec![external], vec![], false).unwrap();
+        assert!(watcher.next_change_address(None, &store).is_err());
+    }
+
+    #[test]
+    fn test_derive_addresses() {
+        let desc = ExtendedDescriptor::from_str(&format!("wpkh([a7bea80d/84h/0h/0h]{}/1/*)", XPUB))
+            .unwrap();
+        let wallet =
+            Wallet::from_descriptor(desc, Network::Bitcoin, 5, 5, RescanSince::Timestamp(0))
+                .unwrap();
+        let checksum = wallet.checksum.clone();
+        let watcher =
+            WalletWatcher::new(Network::Bitcoin, vec![wallet.clone()], vec![], false).unwrap();
+
+        // addresses far past the gap limit can be derived without importing them
+        let script_infos = watcher.derive_addresses(&checksum, 100, 21).unwrap();
+        assert_eq!(script_infos.len(), 21);
+        let last = script_infos.last().unwrap();
+        assert_eq!(last.origin, KeyOrigin::Descriptor(checksum.clone(), 120));
+        assert_eq!(last.address, Some(wallet.derive_address(120)));
+        assert_eq!(last.desc, Some(wallet.derive_desc(120)));
+        assert_eq!(
+            last.bip32_origins.as_ref().unwrap()[0].to_string(),
+            "a7bea80d/84'/0'/0'/1/120"
+        );
+        assert_eq!(watcher.get(&checksum).unwrap().max_imported_index, None);
+
+        assert!(watcher
+            .derive_addresses(&checksum, 0, 0)
+            .unwrap()
+            .is_empty());
+        assert!(watcher.derive_addresses(&checksum, 0, 5000).is_err());
+        assert!(watcher
+            .derive_addresses(&checksum, (1 << 31) - 1, 2)
+            .is_err());
+        let unknown = ExtendedDescriptor::from_str(&format!("wpkh({}/0/*)", XPUB)).unwrap();
+        assert!(watcher.derive_addresses(&unknown.checksum(), 0, 1).is_err());
+    }
+
+    #[test]
+    fn test_verify_imports() {
+        let desc = ExtendedDescriptor::from_str(&format!("wpkh({}/0/*)", XPUB)).unwrap();
+        let mut wallet =
+            Wallet::from_descriptor(desc, Network::Bitcoin, 5, 5, RescanSince::Timestamp(0))
+                .unwrap();
+        wallet.max_imported_index = Some(3);
+        let checksum = wallet.checksum.clone();
+        let watcher =
+            WalletWatcher::new(Network::Bitcoin, vec![wallet.clone()], vec![], false).unwrap();
+
+        let other_desc = ExtendedDescriptor::from_str(&format!("wpkh({}/1/*)", XPUB)).unwrap();
+        let other_origin = KeyOrigin::Descriptor(other_desc.checksum(), 0);
+        let other_address = descriptor::derive_address(&other_desc, 0, Network::Bitcoin).unwrap();
+        let manual_address = descriptor::derive_address(&other_desc, 1, Network::Bitcoin).unwrap();
+        let standalone_address =
+            descriptor::derive_address(&other_desc, 2, Network::Bitcoin).unwrap();
+
+        let label = |index| KeyOrigin::Descriptor(checksum.clone(), index).to_label();
+        let core_labels = vec![
+            (wallet.derive_address(0), label(0)),
+            (wallet.derive_address(1), label(1)),
+            // index 2 is missing, index 3 is labeled with the wrong index
+            (wallet.derive_address(3), label(2)),
+            (other_address.clone(), other_origin.to_label()),
+            (manual_address.clone(), "".into()),
+            (
+                standalone_address,
+                KeyOrigin::standalone_label().to_string(),
+            ),
+        ]
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+
+        let sorted = |mut discrepancies: Vec<ImportDiscrepancy>| {
+            discrepancies.sort_by_key(|d| format!("{:?}", d));
+            discrepancies
+        };
+        assert_eq!(
+            sorted(watcher.diff_imports(&core_labels)),
+            sorted(vec![
+                ImportDiscrepancy::Mislabeled {
+                    address: wallet.derive_address(3),
+                    label: label(2),
+                    origin: Some(KeyOrigin::Descriptor(checksum.clone(), 3)),
+                },
+                ImportDiscrepancy::Missing {
+                    address: wallet.derive_address(2),
+                    origin: KeyOrigin::Descriptor(checksum.clone(), 2),
+                },
+                ImportDiscrepancy::Untracked {
+                    address: manual_address,
+                    label: "".into(),
+                },
+                ImportDiscrepancy::Untracked {
+                    address: other_address,
+                    label: other_origin.to_label(),
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_track_address_rescan_since() {
+        let desc = ExtendedDescriptor::from_str(&format!("wpkh({}/0/*)", XPUB)).unwrap();
+        let address_a = descriptor::derive_address(&desc, 0, Network::Bitcoin).unwrap();
+        let address_b = descriptor::derive_address(&desc, 1, Network::Bitcoin).unwrap();
+        let mut watcher = WalletWatcher::new(Network::Bitcoin, vec![], vec![], false).unwrap();
+        watcher
+            .track_address(address_a.clone(), RescanSince::Timestamp(1600000000))
+            .unwrap();
+        watcher
+            .track_address(address_b.clone(), RescanSince::Now)
+            .unwrap();
+
+        let testnet_address = descriptor::derive_address(&desc, 2, Network::Testnet).unwrap();
+        assert!(watcher
+            .track_address(testnet_address, RescanSince::Now)
+            .is_err());
+
+        let import_reqs = watcher.standalone_imports();
+        let requests: Vec<_> = import_reqs.iter().map(make_import_request).collect();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            requests[0].script_pubkey,
+            Some(ImportMultiRequestScriptPubkey::Address(&address_a))
+        );
+        assert_eq!(
+            requests[0].timestamp,
+            ImportMultiRescanSince::Timestamp(1600000000)
+        );
+        assert_eq!(
+            requests[1].script_pubkey,
+            Some(ImportMultiRequestScriptPubkey::Address(&address_b))
+        );
+        assert_eq!(requests[1].timestamp, ImportMultiRescanSince::Now);
+        assert_eq!(requests[1].label, Some(KeyOrigin::standalone_label()));
+    }
+
+    #[test]
+    fn test_xpub_network_mismatch() {
+        let xpub = XyzPubKey::from_str(XPUB).unwrap();
+        let err =
+            Wallet::from_xpub(xpub, Network::Regtest, 5, 5, RescanSince::Timestamp(0)).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<BwtError>(),
+            Some(BwtError::NetworkMismatch(
+                _,
+                Network::Bitcoin,
+                Network::Regtest
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_resolve_origin() {
+        let desc = ExtendedDescriptor::from_str(&format!("wpkh([a7bea80d/84h/0h/0h]{}/0/*)", XPUB))
+            .unwrap();
+        let mut wallet =
+            Wallet::from_descriptor(desc, Network::Bitcoin, 5, 5, RescanSince::Timestamp(0))
+                .unwrap();
+        wallet.max_imported_index = Some(9);
+        let checksum = wallet.checksum.clone();
+        let watcher =
+            WalletWatcher::new(Network::Bitcoin, vec![wallet.clone()], vec![], false).unwrap();
+
+        let address = wallet.derive_address(7);
+        let origin = KeyOrigin::Descriptor(checksum.clone(), 7);
+
+        // labels are verified against the descriptor, with a fallback to searching for the index
+        assert_eq!(
+            watcher.resolve_origin(&address, Some(&origin.to_label())),
+            Some(origin.clone())
+        );
+        let tampered_label = KeyOrigin::Descriptor(checksum.clone(), 3).to_label();
+        assert_eq!(
+            watcher.resolve_origin(&address, Some(&tampered_label)),
+            Some(origin.clone())
+        );
+        assert_eq!(watcher.resolve_origin(&address, None), None);
+        assert_eq!(watcher.find_origin(&wallet.derive_address(10)), None);
+
+        // the origin can be determined from the bip32 origin of the key, without any label
+        let bip32_origin = wallet.bip32_origins(7).remove(0);
+        assert_eq!(bip32_origin.to_string(), "a7bea80d/84'/0'/0'/0/7");
+        assert_eq!(
+            KeyOrigin::from_bip32_origin(&bip32_origin, &watcher),
+            Some(origin)
+        );
+        let unknown_origin = Bip32Origin(bip32_origin.0, "m/84'/0'/1'/0/7".parse().unwrap());
+        assert_eq!(
+            KeyOrigin::from_bip32_origin(&unknown_origin, &watcher),
+            None
+        );
+    }
+
+    #[test]
+    fn test_xpub_multiple_script_types() {
+        let xpub = XyzPubKey::from_str(&format!("{}:shwpkh,wpkh", XPUB)).unwrap();
+        let mut wallets =
+            Wallet::from_xpub(xpub, Network::Bitcoin, 5, 5, RescanSince::Timestamp(0)).unwrap();
+        assert_eq!(wallets.len(), 4);
+        wallets
+            .iter_mut()
+            .for_each(|w| w.max_imported_index = Some(4));
+        let (wrapped, native) = (&wallets[0], &wallets[2]);
+        assert_ne!(wrapped.checksum, native.checksum);
+        let watcher = WalletWatcher::new(Network::Bitcoin, wallets.clone(), vec![], false).unwrap();
+
+        // the same index derives a different address for each script type
+        let (wrapped_addr, native_addr) = (wrapped.derive_address(0), native.derive_address(0));
+        assert!(wrapped_addr.to_string().starts_with('3'));
+        assert!(native_addr.to_string().starts_with("bc1q"));
+
+        // incoming funds to either address are matched to its own script type's wallet
+        let wrapped_origin = KeyOrigin::Descriptor(wrapped.checksum.clone(), 0);
+        let native_origin = KeyOrigin::Descriptor(native.checksum.clone(), 0);
+        assert_eq!(
+            watcher.resolve_origin(&wrapped_addr, Some(&wrapped_origin.to_label())),
+            Some(wrapped_origin.clone())
+        );
+        assert_eq!(
+            watcher.resolve_origin(&native_addr, Some(&native_origin.to_label())),
+            Some(native_origin.clone())
+        );
+        // including with the label of the other script type
+        assert_eq!(
+            watcher.resolve_origin(&wrapped_addr, Some(&native_origin.to_label())),
+            Some(wrapped_origin)
+        );
+    }
+
+    #[test]
+    fn test_import_batches() {
+        let desc = ExtendedDescriptor::from_str(&format!("wpkh({}/0/*)", XPUB)).unwrap();
+        let wallet = Wallet::from_descriptor(
+            desc,
+            Network::Bitcoin,
+            2500,
+            2500,
+            RescanSince::Timestamp(0),
+        )
+        .unwrap();
+        let end_index = wallet.import_end_index(true);
+        assert_eq!(end_index, 2499);
+        let mut import_reqs = wallet.make_imports(0, end_index, true);
+
+        // a standalone address with a later rescan timestamp and one without rescan
+        let standalone_label = KeyOrigin::standalone_label().to_string();
+        let address = wallet.derive_address(5000);
+        import_reqs.push((
+            address.clone(),
+            RescanSince::Timestamp(1000),
+            standalone_label.clone(),
+        ));
+        import_reqs.push((address, RescanSince::Now, standalone_label));
+
+        let batches = make_import_batches(import_reqs, DEFAULT_IMPORT_BATCH_SIZE);
+        assert_eq!(
+            batches.iter().map(Vec::len).collect::<Vec<_>>(),
+            vec![1000, 1000, 502]
+        );
+        // the final batch, which triggers the rescan, includes the earliest timestamp
+        assert_eq!(batches[0][0].1, RescanSince::Now);
+        assert_eq!(batches[0][1].1, RescanSince::Timestamp(1000));
+        assert_eq!(batches[2].last().unwrap().1, RescanSince::Timestamp(0));
+
+        assert_eq!(
+            make_import_batches(vec![], DEFAULT_IMPORT_BATCH_SIZE).len(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_parse_descriptors_file() {
+        let desc = ExtendedDescriptor::from_str(&format!("wpkh({}/0/*)", XPUB)).unwrap();
+        let contents = format!(
+            "# my wallet\n{}\n\n{} 50\nwpkh({}/1/*)#aaaaaaaa\nnot a descriptor\n",
+            desc, XPUB, XPUB
+        );
+        let entries = parse_descriptors_file(&contents).unwrap();
+        assert_eq!(
+            entries.iter().map(|e| e.0).collect::<Vec<_>>(),
+            vec![2, 4, 5, 6]
+        );
+
+        let parse = |entry: &str| {
+            parse_descriptors_entry(entry, Network::Bitcoin, 20, 20, RescanSince::Now)
+        };
+        let wallets = parse(&entries[0].1).unwrap();
+        assert_eq!(wallets.len(), 1);
+        assert_eq!(wallets[0].checksum, desc.checksum());
+        assert_eq!(wallets[0].gap_limit, 20);
+
+        // xpubs result in multiple wallets, using the custom gap limit
+        let wallets = parse(&entries[1].1).unwrap();
+        assert!(wallets.len() > 1);
+        assert!(wallets.iter().all(|w| w.gap_limit == 50));
+
+        // invalid checksum and garbage entries
+        assert!(parse(&entries[2].1).is_err());
+        assert!(parse(&entries[3].1).is_err());
+        assert!(parse(&format!("{} 10 20", desc)).is_err());
+
+        // JSON array format
+        let json = serde_json::to_string(&vec![desc.to_string(), format!("{} 30", XPUB)]).unwrap();
+        let entries = parse_descriptors_file(&json).unwrap();
+        assert_eq!(
+            entries,
+            vec![(1, desc.to_string()), (2, format!("{} 30", XPUB))]
+        );
+    }
+}
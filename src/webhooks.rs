@@ -4,6 +4,7 @@ use std::thread;
 use ::reqwest::blocking as reqwest;
 
 use crate::indexer::IndexChange;
+use crate::util::amount::AmountFormat;
 
 pub struct WebHookNotifier {
     _thread: thread::JoinHandle<()>,
@@ -12,7 +13,7 @@ pub struct WebHookNotifier {
 }
 
 impl WebHookNotifier {
-    pub fn start(urls: Vec<String>) -> Self {
+    pub fn start(urls: Vec<String>, amount_format: AmountFormat) -> Self {
         let (tx, rx) = mpsc::channel::<Vec<IndexChange>>();
         let num_urls = urls.len();
 
@@ -27,7 +28,7 @@ impl WebHookNotifier {
                         debug!("notifying {} with {} events", url, changelog.len());
                         client
                             .post(url)
-                            .json(&changelog)
+                            .json(&amount_format.wrap(&changelog))
                             .send()
                             .map(|r| debug!("notifying {} succeed: {:?}", url, r.status()))
                             .map_err(|e| warn!("notifying {} failed: {:?}", url, e))
@@ -44,6 +44,10 @@ if [[ $FEATURES == *"electrum"* ]]; then
   test `jq -r length <<< "$utxos"` == 2
   test `jq -r .[0].address <<< "$utxos"` == $addr
   test `jq -r '.[] | select(.height != 0) | .value' <<< "$utxos"` == 1.234
+
+  echo - Testing unix socket
+  version=`nc -NU $BWT_ELECTRUM_SOCKET <<< '{"id":1,"method":"server.version","params":[]}'`
+  test `jq -r .result[1] <<< "$version"` == 1.4
 fi
 
 # Test HTTP API
@@ -69,12 +73,39 @@ if [[ $FEATURES == *"http"* ]]; then
   echo - Testing /address/:address
   test `get_jq .origin /address/$addr | cut -d/ -f2` == 0
 
+  echo - Testing /block/synced-tip
+  test `get_jq .height /block/synced-tip` == `btc getblockcount`
+
+  echo - Testing /reindex
+  dump_before=`get /dump | jq -S .`
+  get /reindex -X POST > /dev/null
+  sleep 2
+  test "`get /dump | jq -S .`" == "$dump_before"
+
   echo - Testing /address/:address/stats
   test `get_jq .confirmed_balance /address/$addr/stats` == 123400000
 
   echo - Testing /address/:address/txs
   test `get_jq .[0].funding[0].address /address/$addr/txs` == $addr
 
+  echo - Testing /address/:address/txs/compact pagination
+  cursor=`get "/address/$addr/txs/compact?limit=1" -D - -o /dev/null | grep -i '^x-next-cursor:' | cut -d' ' -f2 | tr -d '\r'`
+  test `get_jq '.[0][1]' "/address/$addr/txs/compact?limit=1&after=$cursor"` == null
+  test "`get "/address/$addr/txs?compact=true"`" == "`get "/address/$addr/txs/compact"`"
+
+  echo - Testing /address/:address/descriptor
+  desc=`get /address/$addr/descriptor`
+  test `btc deriveaddresses "$desc" | jq -r .[0]` == $addr
+
+  echo - Testing /address/:address/sign-message with a watch-only wallet
+  test `get /address/$addr/sign-message -X POST -H 'Content-Type: application/json' -d '{"message":"hi"}' -o /dev/null -w '%{http_code}'` == 400
+
+  echo - Testing /utxos/spendable
+  spk=`btc validateaddress $addr | jq -r .scriptPubKey`
+  # the unconfirmed output received from outside keys is unsafe to spend and excluded
+  test `get_jq "map(select(.script_pubkey == \"$spk\")) | length" /utxos/spendable` == 1
+  test `get_jq "map(select(.script_pubkey == \"$spk\")) | .[0].value" /utxos/spendable` == 123400000
+
   echo - Testing /address/:address/utxos
   test `get_jq '.[] | select(.block_height == null) | .amount' /address/$addr/utxos` == 567800000
 
@@ -90,6 +121,32 @@ if [[ $FEATURES == *"http"* ]]; then
   txid=`jq -r .[0] <<< "$evt_Transaction"`
   test `get_jq .funding[0].amount /tx/$txid` == 977700000
   test `jq -r .[0] <<< "$evt_TxoFunded" | cut -d: -f1` == $txid
+
+  echo - Testing --watch-all with a pre-populated wallet
+  btc createwallet prepopulated > /dev/null
+  pre_addr=`btc -rpcwallet=prepopulated getnewaddress`
+  btc sendtoaddress $pre_addr 2.5 > /dev/null
+  btc sendtoaddress `btc -rpcwallet=prepopulated getnewaddress` 1.5 > /dev/null
+  legacy_addr=`btc -rpcwallet=prepopulated getnewaddress "" legacy`
+  btc sendtoaddress $legacy_addr 0.5 > /dev/null
+  btc generatetoaddress 1 `btc getnewaddress` > /dev/null
+  WATCH_ALL_HTTP_ADDR=127.0.0.1:3061
+  # use the same binary as runbwt, falling back to cargo run for local development
+  if [ -n "$BWT_BIN" ]; then bwt_cmd=($BWT_BIN); else bwt_cmd=(cargo run -q --no-default-features --features "$FEATURES" --); fi
+  "${bwt_cmd[@]}" --no-startup-banner --network regtest \
+    --bitcoind-dir $BTC_DIR --bitcoind-url http://localhost:$BTC_RPC_PORT/ --bitcoind-wallet prepopulated \
+    --http-addr $WATCH_ALL_HTTP_ADDR --watch-all &> $DIR/bwt-watch-all.log &
+  watch_all_pid=$!
+  sed '/HTTP REST API server running/ q' <(tail -F -n+0 $DIR/bwt-watch-all.log 2> /dev/null) > /dev/null
+  BWT_HTTP_ADDR=$WATCH_ALL_HTTP_ADDR
+  test `get_jq length /txs/since/0` == 3
+  test `get_jq .origin /address/$pre_addr` == standalone
+  test `get_jq .confirmed_balance /address/$pre_addr/stats` == 250000000
+
+  echo - Testing /address/:address/sign-message with a hot wallet
+  signature=`get /address/$legacy_addr/sign-message -X POST -H 'Content-Type: application/json' -d '{"message":"hello bwt"}'`
+  test `btc verifymessage $legacy_addr "$signature" "hello bwt"` == true
+  kill $watch_all_pid
 fi
 
 echo -e "\e[32mAll tests pass.\e[0m"